@@ -0,0 +1,204 @@
+//! Compares the per-packet cost of the original dispatch path, which copied
+//! every frame into a fresh `Vec` and parsed it a second time in the listener,
+//! with the pooled single-parse path.  The capture thread's share and the
+//! listener's share are measured separately, as either one falling behind
+//! ends in packets being dropped by the kernel.  Captured packets are dropped
+//! on another thread, as they are by the listeners, so that the cost of
+//! freeing memory across threads is not hidden.  Run with:
+//!
+//!     cargo bench --bench dispatch
+
+use std::{
+	hint::black_box,
+	sync::{Arc, mpsc},
+	thread,
+	time::{Duration, Instant},
+};
+
+use criterion::{Bencher, Criterion, Throughput, criterion_group, criterion_main};
+use etherparse::{PacketBuilder, SlicedPacket, TransportSlice};
+use pcap::PacketHeader;
+use psniff_rs::{
+	devices::{self, Matcher},
+	packet::{BufferPool, Frame, Layers},
+	state::interface::Interface,
+};
+
+fn frames() -> Vec<Vec<u8>> {
+	let tcp = |payload: &[u8]| {
+		let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+			.ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64)
+			.tcp(40000, 443, 1000, 4096)
+			.ack(1);
+		let mut frame = vec![];
+		builder.write(&mut frame, payload).unwrap();
+		frame
+	};
+	let udp = |payload: &[u8]| {
+		let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+			.ipv6([0xfe; 16], [0xfd; 16], 64)
+			.udp(40000, 53);
+		let mut frame = vec![];
+		builder.write(&mut frame, payload).unwrap();
+		frame
+	};
+
+	// A mix of bare ACKs, full sized segments and small datagrams
+	vec![
+		tcp(&[]),
+		tcp(&[0xab; 1448]),
+		udp(&[0xcd; 64]),
+		tcp(&[0xab; 1448]),
+	]
+}
+
+fn header(len: usize) -> PacketHeader {
+	PacketHeader {
+		ts: libc::timeval {
+			tv_sec: 1,
+			tv_usec: 0,
+		},
+		caplen: len as u32,
+		len: len as u32,
+	}
+}
+
+/// The message the capture thread used to send
+struct MovingPacket {
+	_iface: Arc<Interface>,
+	_header: PacketHeader,
+	data: Vec<u8>,
+}
+
+/// What the capture thread used to do with a packet
+fn capture_copy(iface: &Arc<Interface>, data: &[u8]) -> Option<MovingPacket> {
+	let sliced = SlicedPacket::from_ethernet(data).ok()?;
+	devices::classify(&sliced).filter(|m| *m != Matcher::Missing)?;
+
+	Some(MovingPacket {
+		_iface: iface.clone(),
+		_header: header(data.len()),
+		data: data.to_vec(),
+	})
+}
+
+/// What a listener used to do with it
+fn listen_reparse(packet: &MovingPacket) -> usize {
+	match SlicedPacket::from_ethernet(&packet.data).unwrap().transport {
+		Some(TransportSlice::Tcp(tcp)) => tcp.payload().len(),
+		Some(TransportSlice::Udp(udp)) => udp.payload().len(),
+		_ => 0,
+	}
+}
+
+/// What the capture thread does now
+fn capture_pooled(iface: &Arc<Interface>, pool: &mut BufferPool, data: &[u8]) -> Option<Frame> {
+	let layers = Layers::parse(data).ok()?;
+	layers.matcher().filter(|m| *m != Matcher::Missing)?;
+
+	Some(Frame::new(
+		iface.clone(),
+		header(data.len()),
+		pool.copy_from(data),
+		layers,
+	))
+}
+
+/// What a listener does now
+fn listen_frame(frame: &Frame) -> usize {
+	match (frame.tcp(), frame.udp()) {
+		(Some(tcp), _) => tcp.payload().len(),
+		(_, Some(udp)) => udp.payload().len(),
+		_ => 0,
+	}
+}
+
+/// Times `capture` over every frame, handing what it produces to another thread
+/// to be dropped outside of the timed section
+fn bench_capture<T: Send + 'static>(
+	b: &mut Bencher,
+	frames: &[Vec<u8>],
+	mut capture: impl FnMut(&[u8]) -> Option<T>,
+) {
+	const BATCH: u64 = 256;
+
+	let (drop_tx, drop_rx) = mpsc::sync_channel::<Vec<Option<T>>>(0);
+	let (done_tx, done_rx) = mpsc::sync_channel::<()>(0);
+	let dropper = thread::spawn(move || {
+		for captured in drop_rx {
+			drop(captured);
+			done_tx.send(()).unwrap();
+		}
+	});
+
+	b.iter_custom(|iters| {
+		let mut elapsed = Duration::ZERO;
+		let mut remaining = iters;
+		while remaining > 0 {
+			let n = remaining.min(BATCH);
+			remaining -= n;
+
+			let mut captured = Vec::with_capacity(n as usize * frames.len());
+			let start = Instant::now();
+			for _ in 0..n {
+				for data in frames {
+					captured.push(capture(black_box(data)));
+				}
+			}
+			elapsed += start.elapsed();
+
+			drop_tx.send(captured).unwrap();
+			done_rx.recv().unwrap();
+		}
+		elapsed
+	});
+
+	drop(drop_tx);
+	dropper.join().unwrap();
+}
+
+fn bench_dispatch(c: &mut Criterion) {
+	let frames = frames();
+	let iface = Arc::new(Interface::new("bench0".to_string()));
+	let mut pool = BufferPool::default();
+
+	let mut group = c.benchmark_group("capture");
+	group.throughput(Throughput::Elements(frames.len() as u64));
+	group.bench_function("copy_and_reparse", |b| {
+		bench_capture(b, &frames, |data| capture_copy(&iface, data))
+	});
+	group.bench_function("pooled_single_parse", |b| {
+		bench_capture(b, &frames, |data| capture_pooled(&iface, &mut pool, data))
+	});
+	group.finish();
+
+	let moved: Vec<MovingPacket> = frames
+		.iter()
+		.filter_map(|data| capture_copy(&iface, data))
+		.collect();
+	let parsed: Vec<Frame> = frames
+		.iter()
+		.filter_map(|data| capture_pooled(&iface, &mut pool, data))
+		.collect();
+
+	let mut group = c.benchmark_group("listener");
+	group.throughput(Throughput::Elements(frames.len() as u64));
+	group.bench_function("copy_and_reparse", |b| {
+		b.iter(|| {
+			for packet in &moved {
+				black_box(listen_reparse(black_box(packet)));
+			}
+		})
+	});
+	group.bench_function("pooled_single_parse", |b| {
+		b.iter(|| {
+			for frame in &parsed {
+				black_box(listen_frame(black_box(frame)));
+			}
+		})
+	});
+	group.finish();
+}
+
+criterion_group!(benches, bench_dispatch);
+criterion_main!(benches);
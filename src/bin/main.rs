@@ -1,109 +1,333 @@
+use std::{collections::HashMap, time::Duration};
+
 use anyhow::Result;
 use axum::{extract::State, routing::get};
 use clap::Parser;
+use log::LevelFilter;
 use psniff_rs::{
-	cli::{Cli, Commands, logging},
-	config::RunConfig,
-	devices::{self, Matcher, ReceivedPacketData, list, listen},
-	http::{route, routes::status::process, service as http_s},
-	packet_listeners::{arp_listener, ipv4_tcp_listener, ipv4_udp_listener},
-	runtime::{self, BlockingRunnableBuilder, RunnableBuilder},
-	state::appstate::{self, AppState},
+	cli::{Cli, Commands, ConfigCommands, logging},
+	config::{File, LISTENERS, RunConfig},
+	devices::{self, Matcher, ReceivedPacketData, Tap, list, listen},
+	events::output,
+	flow::reassembly::{DEFAULT_FLOW_LIMIT, MemoryBudget},
+	http::{
+		route,
+		routes::{arp, capture, dns, flows, metrics, status::process, stream},
+		service as http_s,
+	},
+	packet_listeners::{
+		arp_listener, icmp_listener,
+		ip_version::{V4, V6},
+		pcapng_listener::{self, Retention, Rotation},
+		ring_listener, tcp_listener, udp_listener,
+	},
+	protocols, queue,
+	reload::{Load, Outputs, Reloader},
+	runtime::{self, BlockingRunnableBuilder, Reload, RunnableBuilder},
+	state::{
+		appstate::{self, AppState},
+		ring::{Limits, PacketRing},
+	},
 	version,
 };
-use tokio::sync::mpsc::channel;
 
 fn main() -> Result<()> {
 	let c = Cli::parse();
-
-	// Set up logging
-	logging::init(c.log_level.into());
+	let log_level = c.log_level.map(LevelFilter::from);
 
 	match &c.command {
 		Some(Commands::List {}) => {
+			logging::init(log_level.unwrap_or(LevelFilter::Info));
 			list()?;
 		},
 		Some(Commands::Listen(args)) => {
+			logging::init(log_level.unwrap_or(LevelFilter::Info));
 			listen(args.into())?;
 		},
 		Some(Commands::Run(args)) => {
-			let rc: RunConfig = args.into();
+			let file = args.load(log_level)?;
+			logging::init(file.log_level);
+			let rc = RunConfig::try_from(file.clone())?;
 
-			// Construct the state
-			let app_state = appstate::new();
+			// SIGHUP reads the config file again, still overridden by the
+			// command line
+			let args = args.clone();
+			run(rc, file, Box::new(move || args.load(log_level)))?;
+		},
+		Some(Commands::Config(ConfigCommands::Check(args))) => {
+			let file = args.load(log_level)?;
+			RunConfig::try_from(file.clone())?;
+			print!("{}", file.to_toml()?);
+		},
+		Some(Commands::Version) => {
+			version::dump();
+		},
+		None => todo!(),
+	}
+	Ok(())
+}
 
-			let (arp_sender, arp_receiver) = channel::<ReceivedPacketData>(1024);
-			let (ipv4_tcp_sender, ipv4_tcp_receiver) = channel::<ReceivedPacketData>(1024);
-			let (ipv4_udp_sender, ipv4_udp_receiver) = channel::<ReceivedPacketData>(1024);
+fn run(mut rc: RunConfig, file: File, load: Load) -> Result<()> {
+	// Construct the state
+	let app_state = appstate::new();
+	app_state
+		.arp
+		.lock()
+		.unwrap()
+		.set_mac_ip_threshold(rc.alerts.mac_ip_threshold);
 
-			// Create guard at the start of your program (only when feature is enabled)
-			#[cfg(feature = "channels-console")]
-			let _guard = channels_console::ChannelsGuard::new();
+	// Construct the HTTP routes and builder
+	let route = match route::new() {
+		Ok(r) => r,
+		Err(e) => {
+			return Err(anyhow::anyhow!(e.to_string()));
+		},
+	}
+	.add("/status/ready", get(|| async { "wat" }))
+	.add(
+		"/status",
+		get(|State(_state): State<AppState>| async { "yup" }),
+	)
+	.add("/foo", get(process))
+	.add("/metrics", get(metrics::process))
+	.add("/arp", get(arp::bindings))
+	.add("/arp/events", get(arp::events))
+	.add("/capture/recent", get(capture::recent))
+	.add("/flows", get(flows::list))
+	.add("/flows/{id}", get(flows::detail))
+	.add("/flows/{id}/pcap", get(flows::pcap))
+	.add("/dns/recent", get(dns::recent))
+	.add("/dns/stats", get(dns::stats))
+	.add("/stream/events", get(stream::sse))
+	.add("/ws/events", get(stream::ws));
 
-			#[cfg(feature = "channels-console")]
-			let (arp_sender, arp_receiver) =
-				channels_console::instrument!((arp_sender, arp_receiver), label = "packet-queue-arp");
+	// let http_builder = http_s::new::<AppState<'static,()>>(rc.api_http)
+	let http_builder = http_s::Builder::<AppState>::new(rc.api_http.clone())
+		.set_routes(route)
+		.with_state(app_state.clone());
 
-			#[cfg(feature = "channels-console")]
-			let (ipv4_tcp_sender, ipv4_tcp_receiver) = channels_console::instrument!(
-				(ipv4_tcp_sender, ipv4_tcp_receiver),
-				label = "packet-queue-ipv4-tcp"
-			);
+	let mut v: Vec<Box<dyn RunnableBuilder + 'static>> = vec![Box::new(http_builder)];
 
-			#[cfg(feature = "channels-console")]
-			let (ipv4_udp_sender, ipv4_udp_receiver) = channels_console::instrument!(
-				(ipv4_udp_sender, ipv4_udp_receiver),
-				label = "packet-queue-ipv4-udp"
-			);
+	// Both TCP listeners draw reassembly buffers from the same budget
+	let stream_budget = MemoryBudget::default();
 
-			// Construct the packet listener builders
-			let arp_listener_builder = arp_listener::new().set_receiver(arp_receiver);
-
-			let ipv4_tcp_listener_builder = ipv4_tcp_listener::new().set_receiver(ipv4_tcp_receiver);
-
-			let ipv4_udp_listener_builder = ipv4_udp_listener::new().set_receiver(ipv4_udp_receiver);
-
-			// Construct the HTTP routes and builder
-			let route = match route::new() {
-				Ok(r) => r,
-				Err(e) => {
-					return Err(anyhow::anyhow!(e.to_string()));
-				},
-			}
-			.add("/status/ready", get(|| async { "wat" }))
-			.add(
-				"/status",
-				get(|State(_state): State<AppState>| async { "yup" }),
-			)
-			.add("/foo", get(process));
-
-			// let http_builder = http_s::new::<AppState<'static,()>>(rc.api_http)
-			let http_builder = http_s::Builder::<AppState>::new(rc.api_http)
-				.set_routes(route)
-				.with_state(app_state.clone());
-
-			// Construct the network device listener
-			let d = devices::Builder::new()
-				.with_interface("en0".to_string())
-				.with_state(app_state)
-				.set_typed_sender(Matcher::Arp, arp_sender)
-				.set_typed_sender(Matcher::IPv4_TCP, ipv4_tcp_sender)
-				.set_typed_sender(Matcher::IPv4_UDP, ipv4_udp_sender);
-
-			let blocking_v: Vec<Box<dyn BlockingRunnableBuilder>> = vec![Box::new(d)];
-
-			let v: Vec<Box<dyn RunnableBuilder + 'static>> = vec![
-				Box::new(http_builder),
-				Box::new(arp_listener_builder),
-				Box::new(ipv4_tcp_listener_builder),
-				Box::new(ipv4_udp_listener_builder),
-			];
-			let _ = runtime::run(blocking_v, v);
-		},
-		Some(Commands::Version) => {
-			version::dump();
-		},
-		None => todo!(),
+	// A live capture starts every listener, so that a reload can enable any of
+	// them; disabled listeners are simply not fed
+	let listeners = match rc.replay {
+		Some(_) => rc.listeners.clone(),
+		None => LISTENERS.to_vec(),
+	};
+
+	// Each listener has its own queue, so that a flood of one kind of traffic
+	// cannot stall the capture of the others
+	let mut senders = HashMap::new();
+	for m in listeners {
+		let (sender, receiver) =
+			queue::channel::<ReceivedPacketData>(rc.queues.capacity(m), rc.queues.policy(m));
+		senders.insert(m, sender);
+
+		// ICMP errors are attributed to the TCP and UDP flows, and DNS is decoded
+		// from both transports
+		let listener: Box<dyn RunnableBuilder> = match m {
+			Matcher::Arp => Box::new(
+				arp_listener::new()
+					.set_receiver(receiver)
+					.with_table(app_state.arp.clone())
+					.with_events(app_state.events.clone()),
+			),
+			Matcher::IPv4_ICMPv4 => Box::new(
+				icmp_listener::new::<V4>()
+					.set_receiver(receiver)
+					.with_shared_flows(app_state.flows.clone())
+					.with_events(app_state.events.clone()),
+			),
+			Matcher::IPv4_TCP => Box::new(
+				tcp_listener::new::<V4>()
+					.set_receiver(receiver)
+					.with_stream_limits(DEFAULT_FLOW_LIMIT, stream_budget.clone())
+					.add_stream_consumer(Box::new(protocols::dns::StreamFactory::new(
+						app_state.dns.clone(),
+						app_state.events.clone(),
+					)))
+					.add_stream_consumer(Box::new(protocols::tls::StreamFactory::new(
+						app_state.flows.clone(),
+						app_state.events.clone(),
+					)))
+					.add_stream_consumer(Box::new(
+						protocols::http::StreamFactory::new(app_state.events.clone())
+							.with_body_limit(rc.decoders.http_body_limit),
+					))
+					.with_shared_flows(app_state.flows.clone())
+					.with_events(app_state.events.clone()),
+			),
+			Matcher::IPv4_UDP => Box::new(
+				udp_listener::new::<V4>()
+					.set_receiver(receiver)
+					.with_shared_flows(app_state.flows.clone())
+					.with_events(app_state.events.clone())
+					.with_dns(app_state.dns.clone())
+					.with_quic(),
+			),
+			Matcher::IPv6_ICMPv6 => Box::new(
+				icmp_listener::new::<V6>()
+					.set_receiver(receiver)
+					.with_shared_flows(app_state.flows.clone())
+					.with_events(app_state.events.clone()),
+			),
+			Matcher::IPv6_TCP => Box::new(
+				tcp_listener::new::<V6>()
+					.set_receiver(receiver)
+					.with_stream_limits(DEFAULT_FLOW_LIMIT, stream_budget.clone())
+					.add_stream_consumer(Box::new(protocols::dns::StreamFactory::new(
+						app_state.dns.clone(),
+						app_state.events.clone(),
+					)))
+					.add_stream_consumer(Box::new(protocols::tls::StreamFactory::new(
+						app_state.flows.clone(),
+						app_state.events.clone(),
+					)))
+					.add_stream_consumer(Box::new(
+						protocols::http::StreamFactory::new(app_state.events.clone())
+							.with_body_limit(rc.decoders.http_body_limit),
+					))
+					.with_shared_flows(app_state.flows.clone())
+					.with_events(app_state.events.clone()),
+			),
+			Matcher::IPv6_UDP => Box::new(
+				udp_listener::new::<V6>()
+					.set_receiver(receiver)
+					.with_shared_flows(app_state.flows.clone())
+					.with_events(app_state.events.clone())
+					.with_dns(app_state.dns.clone())
+					.with_quic(),
+			),
+			m => unreachable!("{} has no listener", m.name()),
+		};
+		v.push(listener);
 	}
+
+	// Construct the network device listeners, one per interface, all
+	// feeding the same listener queues
+	let devices_builder = |taps: &[Tap]| {
+		let d = senders
+			.iter()
+			.filter(|(m, _)| rc.listeners.contains(m))
+			.fold(
+				devices::Builder::new()
+					.with_state(app_state.clone())
+					.with_capture_options(rc.capture.clone()),
+				|d, (m, sender)| d.set_typed_sender(*m, sender.clone()),
+			);
+		let d = taps.iter().fold(d, |d, tap| d.add_tap(tap.clone()));
+
+		match &rc.filter {
+			Some(filter) => d.with_filter(filter.clone()),
+			None => d,
+		}
+	};
+
+	let mut outputs = outputs(app_state.clone());
+	let (blocking_v, reload): (Vec<Box<dyn BlockingRunnableBuilder>>, _) =
+		match (rc.replay.take(), rc.interfaces.take()) {
+			(Some(replay), _) => {
+				let (taps, runnables) = outputs(&rc);
+				v.extend(runnables);
+				let d = devices_builder(&taps).with_file(replay.path, replay.pacing);
+				(vec![Box::new(d)], None)
+			},
+			(None, Some(interfaces)) => {
+				let names = devices::resolve_interfaces(interfaces)?;
+				let (reloader, updates) =
+					Reloader::new(load, file, &rc, senders.clone(), outputs, app_state.clone());
+				let taps = updates.borrow().taps.clone();
+				let v = names
+					.into_iter()
+					.map(|name| {
+						let d = devices_builder(&taps)
+							.with_interface(name)
+							.with_updates(updates.clone());
+						Box::new(d) as Box<dyn BlockingRunnableBuilder>
+					})
+					.collect();
+				(v, Some(Box::new(reloader) as Box<dyn Reload>))
+			},
+			(None, None) => return Err(anyhow::anyhow!("no capture source was given")),
+		};
+
+	// The device listeners and the reloader hold their own clones
+	drop(senders);
+
+	let _ = runtime::run(blocking_v, v, reload);
 	Ok(())
 }
+
+/// Builds the event output and the sinks, which a reload may build again
+fn outputs(app_state: AppState) -> Outputs {
+	// The ring keeps what it holds until its limits change
+	let mut ring_limits = None;
+
+	Box::new(move |rc: &RunConfig| {
+		// The output subscribes before any listener can publish
+		let output_builder = output::new()
+			.set_receiver(app_state.events.subscribe())
+			.with_format(rc.event_format)
+			.with_target(rc.event_target.clone());
+
+		let mut v: Vec<Box<dyn RunnableBuilder>> = vec![Box::new(output_builder)];
+
+		// Sinks are tapped into every capture, with a queue of their own
+		let mut taps = vec![];
+		for sink in &rc.sinks.pcapng {
+			let (sender, receiver) =
+				queue::channel::<ReceivedPacketData>(rc.queues.capacity, rc.queues.policy);
+			taps.push(Tap {
+				matchers: sink.matchers.clone(),
+				sender,
+			});
+
+			let listener = pcapng_listener::new()
+				.set_receiver(receiver)
+				.with_directory(sink.directory.clone())
+				.with_template(sink.template.clone())
+				.with_rotation(Rotation {
+					bytes: sink.rotate_bytes,
+					interval: sink.rotate_seconds.map(Duration::from_secs),
+				})
+				.with_retention(Retention {
+					files: sink.keep_files,
+					bytes: sink.keep_bytes,
+				});
+			v.push(match &sink.filter {
+				Some(filter) => Box::new(listener.with_filter(filter.clone())),
+				None => Box::new(listener),
+			});
+		}
+
+		if rc.sinks.ring != ring_limits {
+			*app_state.ring.lock().unwrap() = match &rc.sinks.ring {
+				Some(ring) => PacketRing::new(Limits {
+					bytes: ring.bytes,
+					age: ring.seconds.map(Duration::from_secs),
+				}),
+				None => PacketRing::default(),
+			};
+			ring_limits = rc.sinks.ring.clone();
+		}
+
+		if rc.sinks.ring.is_some() {
+			let (sender, receiver) =
+				queue::channel::<ReceivedPacketData>(rc.queues.capacity, rc.queues.policy);
+			taps.push(Tap {
+				matchers: None,
+				sender,
+			});
+			v.push(Box::new(
+				ring_listener::new()
+					.set_receiver(receiver)
+					.with_ring(app_state.ring.clone()),
+			));
+		}
+
+		(taps, v)
+	})
+}
@@ -3,6 +3,8 @@ use clap::{ValueEnum, builder::PossibleValue};
 // use dirs::{config_local_dir, home_dir};
 use log::LevelFilter;
 
+use crate::{devices::Matcher, queue::Policy};
+
 /// ArgLevelFilter is a newtype for LevelFilter, so that ValueEnum can be
 /// implemented
 #[derive(Clone)]
@@ -34,6 +36,42 @@ impl ValueEnum for ArgLevelFilter {
 	}
 }
 
+/// QueueSetting is a listener queue option, given either for every queue or
+/// for the queue of a single Matcher as `MATCHER=VALUE`
+#[derive(Clone, Debug)]
+pub struct QueueSetting<T> {
+	pub matcher: Option<Matcher>,
+	pub value: T,
+}
+
+pub fn parse_queue_capacity(s: &str) -> Result<QueueSetting<usize>, String> {
+	parse_queue_setting(s, |v| match v.parse::<usize>() {
+		Ok(0) => Err("queue capacity must be positive".to_string()),
+		Ok(n) => Ok(n),
+		Err(e) => Err(format!("invalid queue capacity '{}': {}", v, e)),
+	})
+}
+
+pub fn parse_queue_policy(s: &str) -> Result<QueueSetting<Policy>, String> {
+	parse_queue_setting(s, |v| Policy::from_str(v, true))
+}
+
+fn parse_queue_setting<T>(
+	s: &str,
+	parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<QueueSetting<T>, String> {
+	match s.split_once('=') {
+		Some((m, v)) => Ok(QueueSetting {
+			matcher: Some(m.parse()?),
+			value: parse(v)?,
+		}),
+		None => Ok(QueueSetting {
+			matcher: None,
+			value: parse(s)?,
+		}),
+	}
+}
+
 // // pub struct Args<'a>{
 // pub struct Args {
 //   c: Command,
@@ -4,9 +4,17 @@ use log::LevelFilter;
 use structured_logger::{Builder, json::new_writer};
 
 pub fn init(level: LevelFilter) {
-	// Set up logging
-	Builder::with_level(level.as_str())
+	// Set up logging.  The logger itself lets everything through, so that the
+	// level can be changed later with set_level.
+	Builder::with_level(LevelFilter::Trace.as_str())
 		// .with_target_writer(targets, writer)
 		.with_target_writer("*", new_writer(stderr()))
 		.init();
+
+	set_level(level);
+}
+
+/// Changes how much is logged from now on
+pub fn set_level(level: LevelFilter) {
+	log::set_max_level(level);
 }
@@ -1,18 +1,25 @@
 pub mod args;
 pub mod logging;
 
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Parser, Subcommand};
+use log::LevelFilter;
 
 use crate::{
-	cli::args::ArgLevelFilter,
-	config::{Http, ListenConfig, RunConfig},
+	cli::args::{ArgLevelFilter, QueueSetting, parse_queue_capacity, parse_queue_policy},
+	config::{self, File, Interfaces, ListenConfig, Queues},
+	devices::{Matcher, Pacing, Precision},
+	events::output::Format,
+	queue::Policy,
 };
 
 #[derive(Parser)]
 #[command(arg_required_else_help = true)]
 pub struct Cli {
-	#[arg(default_value = "info", long)]
-	pub log_level: ArgLevelFilter,
+	/// How much is logged [default: info]
+	#[arg(long)]
+	pub log_level: Option<ArgLevelFilter>,
 
 	#[command(subcommand)]
 	pub command: Option<Commands>,
@@ -29,42 +36,238 @@ pub enum Commands {
 	/// Run
 	Run(ArgsRun),
 
+	/// Work with configuration files
+	#[command(subcommand)]
+	Config(ConfigCommands),
+
 	Version,
 }
 
 #[derive(Parser)]
+#[command(group(ArgGroup::new("interfaces_group").required(true)))]
 pub struct ArgsListen {
-	#[arg(group = "interfaces_group", long)]
+	/// Listen to the given comma-separated interfaces
+	#[arg(group = "interfaces_group", long, value_delimiter = ',')]
 	pub interfaces: Option<Vec<String>>,
 
+	/// Listen to every interface which is up and running
 	#[arg(default_value_t = false, group = "interfaces_group", long)]
 	pub all_interfaces: bool,
+
+	/// Only capture packets matching this BPF expression
+	#[arg(long)]
+	pub filter: Option<String>,
 }
 
 impl From<&ArgsListen> for ListenConfig {
 	fn from(val: &ArgsListen) -> Self {
 		ListenConfig {
-			interfaces: val.interfaces.clone(),
+			interfaces: select_interfaces(&val.interfaces, val.all_interfaces),
+			filter: val.filter.clone(),
 		}
 	}
 }
 
-#[derive(Parser)]
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+	/// Validate the configuration of run, and print it as run would use it
+	Check(ArgsRun),
+}
+
+#[derive(Clone, Parser)]
+#[command(group(ArgGroup::new("source").args(["interfaces", "all_interfaces", "read"])))]
 pub struct ArgsRun {
-	#[arg(default_value = "127.0.0.1")]
-	pub host: String,
+	/// Read options from this TOML file; options given here take precedence
+	#[arg(long)]
+	pub config: Option<PathBuf>,
+
+	/// Address the HTTP API listens on [default: 127.0.0.1]
+	pub host: Option<String>,
+
+	/// Port the HTTP API listens on [default: 3000]
+	pub port: Option<u16>,
+
+	/// Capture from the given comma-separated interfaces
+	#[arg(long, value_delimiter = ',')]
+	pub interfaces: Option<Vec<String>>,
+
+	/// Capture from every interface which is up and running
+	#[arg(default_value_t = false, long)]
+	pub all_interfaces: bool,
+
+	/// Only capture packets matching this BPF expression
+	#[arg(long)]
+	pub filter: Option<String>,
+
+	/// Replay packets from a pcap or pcapng file instead of a live interface
+	#[arg(long)]
+	pub read: Option<PathBuf>,
+
+	/// How quickly packets are replayed from the file given by --read
+	#[arg(long, value_enum)]
+	pub pacing: Option<Pacing>,
+
+	/// Capture at most this many bytes of each packet; a small snaplen keeps
+	/// little more than the headers
+	#[arg(long)]
+	pub snaplen: Option<i32>,
+
+	/// Size in bytes of the buffer holding packets until they are read
+	#[arg(long)]
+	pub buffer_size: Option<i32>,
+
+	/// Whether interfaces are put in promiscuous mode [default: true]
+	#[arg(long)]
+	pub promiscuous: Option<bool>,
+
+	/// Whether packets are delivered as soon as they arrive, rather than in
+	/// batches [default: false]
+	#[arg(long)]
+	pub immediate_mode: Option<bool>,
+
+	/// How many milliseconds to wait for a batch of packets [default: 100]
+	#[arg(long)]
+	pub timeout_ms: Option<i32>,
+
+	/// Precision of packet timestamps [default: micro]
+	#[arg(long, value_enum)]
+	pub timestamp_precision: Option<Precision>,
+
+	/// Only run the listeners for these comma-separated matchers, e.g.
+	/// "arp,ipv4_tcp"
+	#[arg(long, value_delimiter = ',')]
+	pub listeners: Option<Vec<Matcher>>,
+
+	/// How many packets each listener queue holds, either for every queue or
+	/// for one as MATCHER=CAPACITY, e.g. "1024,ipv4_udp=4096"
+	#[arg(long, value_delimiter = ',', value_parser = parse_queue_capacity)]
+	pub queue_capacity: Vec<QueueSetting<usize>>,
+
+	/// What happens when a listener queue is full: block, drop-newest or
+	/// drop-oldest, either for every queue or for one as MATCHER=POLICY, e.g.
+	/// "block,ipv4_udp=drop-oldest"
+	#[arg(long, value_delimiter = ',', value_parser = parse_queue_policy)]
+	pub queue_policy: Vec<QueueSetting<Policy>>,
+
+	/// How events are written [default: text]
+	#[arg(long, value_enum)]
+	pub event_format: Option<Format>,
+
+	/// Append events to this file rather than printing them
+	#[arg(conflicts_with = "event_socket", long)]
+	pub event_file: Option<PathBuf>,
+
+	/// Send events to this Unix socket rather than printing them
+	#[arg(long)]
+	pub event_socket: Option<PathBuf>,
+
+	/// How many bytes of each HTTP body to keep with its transaction
+	/// [default: 0, bodies are only counted]
+	#[arg(long, value_name = "BYTES")]
+	pub http_body_limit: Option<usize>,
+}
+
+impl ArgsRun {
+	/// Reads the config file, if one was given, and overrides it with the
+	/// options given on the command line
+	pub fn load(&self, log_level: Option<LevelFilter>) -> Result<File, config::Error> {
+		let mut file = match &self.config {
+			Some(path) => File::load(path.clone())?,
+			None => File::default(),
+		};
+
+		if let Some(log_level) = log_level {
+			file.log_level = log_level;
+		}
+		if let Some(host) = &self.host {
+			file.http.host = host.clone();
+		}
+		if let Some(port) = self.port {
+			file.http.port = port;
+		}
+
+		// A source on the command line replaces the file's
+		let capture = &mut file.capture;
+		if self.interfaces.is_some() || self.all_interfaces || self.read.is_some() {
+			capture.interfaces = self.interfaces.clone();
+			capture.all_interfaces = self.all_interfaces;
+			capture.read = self.read.clone();
+		}
+		if let Some(pacing) = self.pacing {
+			capture.pacing = pacing;
+		}
+		if let Some(filter) = &self.filter {
+			capture.filter = Some(filter.clone());
+		}
+		if self.snaplen.is_some() {
+			capture.snaplen = self.snaplen;
+		}
+		if self.buffer_size.is_some() {
+			capture.buffer_size = self.buffer_size;
+		}
+		if let Some(promiscuous) = self.promiscuous {
+			capture.promiscuous = promiscuous;
+		}
+		if let Some(immediate_mode) = self.immediate_mode {
+			capture.immediate_mode = immediate_mode;
+		}
+		if let Some(timeout_ms) = self.timeout_ms {
+			capture.timeout_ms = timeout_ms;
+		}
+		if let Some(precision) = self.timestamp_precision {
+			capture.timestamp_precision = precision;
+		}
+
+		if let Some(listeners) = &self.listeners {
+			file.listeners = listeners.clone();
+		}
+		override_queues(&mut file.queues, &self.queue_capacity, &self.queue_policy);
+
+		// An output on the command line replaces the file's
+		let events = &mut file.events;
+		if let Some(format) = self.event_format {
+			events.format = format;
+		}
+		if self.event_file.is_some() || self.event_socket.is_some() {
+			events.file = self.event_file.clone();
+			events.socket = self.event_socket.clone();
+		}
+		if let Some(limit) = self.http_body_limit {
+			file.decoders.http_body_limit = limit;
+		}
 
-	#[arg(default_value_t = 3000)]
-	pub port: u16,
+		Ok(file)
+	}
 }
 
-impl From<&ArgsRun> for RunConfig {
-	fn from(value: &ArgsRun) -> Self {
-		RunConfig {
-			api_http: Http {
-				host: value.host.clone(),
-				port: value.port,
+fn select_interfaces(interfaces: &Option<Vec<String>>, all_interfaces: bool) -> Option<Interfaces> {
+	match (interfaces, all_interfaces) {
+		(_, true) => Some(Interfaces::All),
+		(Some(names), false) => Some(Interfaces::Named(names.clone())),
+		(None, false) => None,
+	}
+}
+
+fn override_queues(
+	queues: &mut Queues,
+	capacities: &[QueueSetting<usize>],
+	policies: &[QueueSetting<Policy>],
+) {
+	for setting in capacities {
+		match setting.matcher {
+			Some(m) => {
+				queues.capacities.insert(m, setting.value);
+			},
+			None => queues.capacity = setting.value,
+		}
+	}
+
+	for setting in policies {
+		match setting.matcher {
+			Some(m) => {
+				queues.policies.insert(m, setting.value);
 			},
+			None => queues.policy = setting.value,
 		}
 	}
 }
@@ -1,17 +1,548 @@
-use serde::Deserialize;
+use std::{collections::BTreeMap, fs, io, net::IpAddr, path::PathBuf, str::FromStr};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+	devices::{self, CaptureOptions, InterfaceName, Matcher, Pacing, Precision},
+	events::output::{Format, Target},
+	packet_listeners::pcapng_listener,
+	queue::{self, Policy},
+	state::arp::DEFAULT_MAC_IP_THRESHOLD,
+};
+
+/// The matchers which have a listener of their own in `run`
+pub const LISTENERS: [Matcher; 7] = [
+	Matcher::Arp,
+	Matcher::IPv4_ICMPv4,
+	Matcher::IPv4_TCP,
+	Matcher::IPv4_UDP,
+	Matcher::IPv6_ICMPv6,
+	Matcher::IPv6_TCP,
+	Matcher::IPv6_UDP,
+];
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("config file '{path}' could not be read")]
+	Read { path: PathBuf, source: io::Error },
+
+	#[error("config file '{path}' is not valid")]
+	Parse {
+		path: PathBuf,
+		source: Box<toml::de::Error>,
+	},
+
+	#[error("invalid configuration: {0}")]
+	Invalid(String),
+}
 
 pub struct ListConfig {}
 
 pub struct ListenConfig {
-	pub interfaces: Option<Vec<String>>,
+	pub interfaces: Option<Interfaces>,
+	pub filter: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Http {
 	pub host: String,
 	pub port: u16,
 }
 
+impl Default for Http {
+	fn default() -> Self {
+		Http {
+			host: "127.0.0.1".to_string(),
+			port: 3000,
+		}
+	}
+}
+
+pub enum Interfaces {
+	All,
+	Named(Vec<InterfaceName>),
+}
+
+/// Capture selects where packets come from and how they are captured
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Capture {
+	pub interfaces: Option<Vec<InterfaceName>>,
+	pub all_interfaces: bool,
+	pub read: Option<PathBuf>,
+	pub pacing: Pacing,
+	pub filter: Option<String>,
+	pub snaplen: Option<i32>,
+	pub promiscuous: bool,
+	pub buffer_size: Option<i32>,
+	pub immediate_mode: bool,
+	pub timeout_ms: i32,
+	pub timestamp_precision: Precision,
+}
+
+impl Default for Capture {
+	fn default() -> Self {
+		Capture {
+			interfaces: None,
+			all_interfaces: false,
+			read: None,
+			pacing: Pacing::default(),
+			filter: None,
+			snaplen: None,
+			promiscuous: true,
+			buffer_size: None,
+			immediate_mode: false,
+			timeout_ms: devices::DEFAULT_TIMEOUT_MS,
+			timestamp_precision: Precision::default(),
+		}
+	}
+}
+
+/// Queues sizes the channel feeding each listener, and decides what happens
+/// when a listener cannot keep up.  Matchers without an override use the
+/// defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Queues {
+	pub capacity: usize,
+	pub policy: Policy,
+	pub capacities: BTreeMap<Matcher, usize>,
+	pub policies: BTreeMap<Matcher, Policy>,
+}
+
+impl Default for Queues {
+	fn default() -> Self {
+		Queues {
+			capacity: queue::DEFAULT_CAPACITY,
+			policy: Policy::default(),
+			capacities: BTreeMap::new(),
+			policies: BTreeMap::new(),
+		}
+	}
+}
+
+impl Queues {
+	pub fn capacity(&self, m: Matcher) -> usize {
+		self.capacities.get(&m).copied().unwrap_or(self.capacity)
+	}
+
+	pub fn policy(&self, m: Matcher) -> Policy {
+		self.policies.get(&m).copied().unwrap_or(self.policy)
+	}
+}
+
+/// Sinks keep copies of what is captured, beyond what the listeners track
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sinks {
+	pub pcapng: Vec<PcapngSink>,
+	pub ring: Option<RingSink>,
+}
+
+/// RingSink keeps recent packets in memory for `/capture/recent`, bounded
+/// by either limit or both
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RingSink {
+	pub bytes: Option<u64>,
+	pub seconds: Option<u64>,
+}
+
+/// PcapngSink writes captured packets to a rotating set of pcapng files
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PcapngSink {
+	pub directory: PathBuf,
+
+	/// strftime template for file names, filled in with the time of each
+	/// file's first packet
+	pub template: String,
+
+	/// Only write packets of these matchers, rather than every packet
+	pub matchers: Option<Vec<Matcher>>,
+	pub filter: Option<String>,
+	pub rotate_bytes: Option<u64>,
+	pub rotate_seconds: Option<u64>,
+	pub keep_files: Option<usize>,
+	pub keep_bytes: Option<u64>,
+}
+
+impl Default for PcapngSink {
+	fn default() -> Self {
+		PcapngSink {
+			directory: PathBuf::new(),
+			template: pcapng_listener::DEFAULT_TEMPLATE.to_string(),
+			matchers: None,
+			filter: None,
+			rotate_bytes: None,
+			rotate_seconds: None,
+			keep_files: None,
+			keep_bytes: None,
+		}
+	}
+}
+
+/// Events selects how events are written; to stdout unless a file or a
+/// socket is given
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Events {
+	pub format: Format,
+	pub file: Option<PathBuf>,
+	pub socket: Option<PathBuf>,
+}
+
+/// Decoders tunes the application protocol decoders
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Decoders {
+	/// How many bytes of each HTTP body are kept with its transaction; bodies
+	/// are only counted when this is 0
+	pub http_body_limit: usize,
+}
+
+/// Alerts tunes when suspicious traffic is reported
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Alerts {
+	/// How many IPs a single MAC may claim before it is reported
+	pub mac_ip_threshold: usize,
+}
+
+impl Default for Alerts {
+	fn default() -> Self {
+		Alerts {
+			mac_ip_threshold: DEFAULT_MAC_IP_THRESHOLD,
+		}
+	}
+}
+
+/// File is the configuration of `run`, as read from the file given with
+/// --config.  Anything the file leaves out takes its default, and options
+/// given on the command line take precedence over the file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct File {
+	pub log_level: LevelFilter,
+	pub listeners: Vec<Matcher>,
+	pub capture: Capture,
+	pub queues: Queues,
+	pub http: Http,
+	pub sinks: Sinks,
+	pub events: Events,
+	pub decoders: Decoders,
+	pub alerts: Alerts,
+}
+
+impl Default for File {
+	fn default() -> Self {
+		File {
+			log_level: LevelFilter::Info,
+			listeners: LISTENERS.to_vec(),
+			capture: Capture::default(),
+			queues: Queues::default(),
+			http: Http::default(),
+			sinks: Sinks::default(),
+			events: Events::default(),
+			decoders: Decoders::default(),
+			alerts: Alerts::default(),
+		}
+	}
+}
+
+impl File {
+	pub fn load(path: PathBuf) -> Result<File, Error> {
+		let s = match fs::read_to_string(&path) {
+			Ok(s) => s,
+			Err(source) => return Err(Error::Read { path, source }),
+		};
+
+		toml::from_str(&s).map_err(|e| Error::Parse {
+			path,
+			source: Box::new(e),
+		})
+	}
+
+	/// The configuration as it would be written to a file
+	pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+		toml::to_string_pretty(self)
+	}
+}
+
+pub struct Replay {
+	pub path: PathBuf,
+	pub pacing: Pacing,
+}
+
 pub struct RunConfig {
 	pub api_http: Http,
+	pub filter: Option<String>,
+	pub interfaces: Option<Interfaces>,
+	pub listeners: Vec<Matcher>,
+	pub queues: Queues,
+	pub replay: Option<Replay>,
+	pub capture: CaptureOptions,
+	pub sinks: Sinks,
+	pub event_format: Format,
+	pub event_target: Target,
+	pub decoders: Decoders,
+	pub alerts: Alerts,
+}
+
+impl TryFrom<File> for RunConfig {
+	type Error = Error;
+
+	/// Checks everything that can be checked before capturing starts
+	fn try_from(file: File) -> Result<Self, Self::Error> {
+		let invalid = |s: String| Err(Error::Invalid(s));
+
+		if IpAddr::from_str(&file.http.host).is_err() {
+			return invalid(format!(
+				"http host '{}' is not an IP address",
+				file.http.host
+			));
+		}
+
+		for m in &file.listeners {
+			if !LISTENERS.contains(m) {
+				return invalid(format!("'{}' has no listener", m.name()));
+			}
+		}
+
+		let capacities = file.queues.capacities.iter().map(|(m, c)| (m.name(), c));
+		for (name, capacity) in [("default", &file.queues.capacity)]
+			.into_iter()
+			.chain(capacities)
+		{
+			if *capacity == 0 {
+				return invalid(format!("{} queue capacity must be positive", name));
+			}
+		}
+
+		let capture = file.capture;
+		for (name, value) in [
+			("snaplen", capture.snaplen),
+			("buffer_size", capture.buffer_size),
+			("timeout_ms", Some(capture.timeout_ms)),
+		] {
+			if let Some(v) = value
+				&& v <= 0
+			{
+				return invalid(format!("{} must be positive", name));
+			}
+		}
+
+		// libpcap needs room in its buffer for at least one whole packet
+		if let (Some(snaplen), Some(buffer_size)) = (capture.snaplen, capture.buffer_size)
+			&& buffer_size < snaplen
+		{
+			return invalid(format!(
+				"buffer_size {} is smaller than snaplen {}",
+				buffer_size, snaplen
+			));
+		}
+
+		if let Some(filter) = &capture.filter {
+			devices::validate_filter(filter).map_err(|e| Error::Invalid(e.to_string()))?;
+		}
+
+		let interfaces = match (capture.interfaces, capture.all_interfaces) {
+			(_, true) => Some(Interfaces::All),
+			(Some(names), false) => Some(Interfaces::Named(names)),
+			(None, false) => None,
+		};
+
+		for sink in &file.sinks.pcapng {
+			if sink.directory.as_os_str().is_empty() {
+				return invalid("pcapng sink has no directory".to_string());
+			}
+			pcapng_listener::validate_template(&sink.template)
+				.map_err(|e| Error::Invalid(e.to_string()))?;
+			if let Some(filter) = &sink.filter {
+				devices::validate_filter(filter).map_err(|e| Error::Invalid(e.to_string()))?;
+			}
+
+			let limits = [
+				("rotate_bytes", sink.rotate_bytes),
+				("rotate_seconds", sink.rotate_seconds),
+				("keep_files", sink.keep_files.map(|f| f as u64)),
+				("keep_bytes", sink.keep_bytes),
+			];
+			for (name, value) in limits {
+				if value == Some(0) {
+					return invalid(format!("pcapng sink {} must be positive", name));
+				}
+			}
+		}
+
+		if let Some(ring) = &file.sinks.ring {
+			if ring.bytes.is_none() && ring.seconds.is_none() {
+				return invalid("ring sink needs a bytes or seconds limit".to_string());
+			}
+			if ring.bytes == Some(0) || ring.seconds == Some(0) {
+				return invalid("ring sink limits must be positive".to_string());
+			}
+		}
+
+		let event_target = match (file.events.file, file.events.socket) {
+			(Some(_), Some(_)) => {
+				return invalid("events cannot go to both a file and a socket".to_string());
+			},
+			(Some(path), None) => Target::File(path),
+			(None, Some(path)) => Target::Socket(path),
+			(None, None) => Target::Stdout,
+		};
+
+		if file.alerts.mac_ip_threshold == 0 {
+			return invalid("alerts mac_ip_threshold must be positive".to_string());
+		}
+
+		if interfaces.is_some() && capture.read.is_some() {
+			return invalid("capture interfaces and a file to read cannot both be given".to_string());
+		}
+
+		Ok(RunConfig {
+			api_http: file.http,
+			filter: capture.filter,
+			interfaces,
+			listeners: file.listeners,
+			queues: file.queues,
+			replay: capture.read.map(|path| Replay {
+				path,
+				pacing: capture.pacing,
+			}),
+			capture: CaptureOptions {
+				snaplen: capture.snaplen,
+				promisc: capture.promiscuous,
+				buffer_size: capture.buffer_size,
+				immediate: capture.immediate_mode,
+				timeout: capture.timeout_ms,
+				precision: capture.timestamp_precision,
+			},
+			sinks: file.sinks,
+			event_format: file.events.format,
+			event_target,
+			decoders: file.decoders,
+			alerts: file.alerts,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use log::LevelFilter;
+
+	use crate::{
+		config::{File, PcapngSink, RingSink, RunConfig},
+		devices::{Matcher, Precision},
+		events::output::{Format, Target},
+		queue::Policy,
+	};
+
+	#[test]
+	fn test_file_defaults_and_validation() {
+		let file: File = toml::from_str(
+			r#"
+			log_level = "debug"
+			listeners = ["ipv4_tcp", "ipv4_udp"]
+
+			[capture]
+			interfaces = ["eth0"]
+			snaplen = 128
+			timestamp_precision = "nano"
+
+			[queues]
+			capacity = 2048
+			policies = { ipv4_udp = "drop-oldest" }
+
+			[http]
+			port = 9000
+
+			[[sinks.pcapng]]
+			directory = "/var/lib/psniff"
+			matchers = ["ipv4_tcp"]
+			rotate_seconds = 3600
+			keep_files = 24
+
+			[sinks.ring]
+			bytes = 67108864
+
+			[events]
+			format = "json"
+			socket = "/run/psniff/events.sock"
+
+			[decoders]
+			http_body_limit = 4096
+
+			[alerts]
+			mac_ip_threshold = 16
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(LevelFilter::Debug, file.log_level);
+		assert!(file.capture.promiscuous);
+		assert_eq!("127.0.0.1", file.http.host);
+
+		// The effective configuration reads back the same
+		let again: File = toml::from_str(&file.to_toml().unwrap()).unwrap();
+		assert_eq!(Some(128), again.capture.snaplen);
+
+		let rc = RunConfig::try_from(again).unwrap();
+		assert_eq!(vec![Matcher::IPv4_TCP, Matcher::IPv4_UDP], rc.listeners);
+		assert_eq!(2048, rc.queues.capacity(Matcher::IPv4_UDP));
+		assert_eq!(Policy::DropOldest, rc.queues.policy(Matcher::IPv4_UDP));
+		assert_eq!(Policy::Block, rc.queues.policy(Matcher::IPv4_TCP));
+		assert_eq!(9000, rc.api_http.port);
+		assert_eq!(Precision::Nano, rc.capture.precision);
+		assert_eq!(100, rc.capture.timeout);
+		assert_eq!("psniff-%Y%m%d-%H%M%S.pcapng", rc.sinks.pcapng[0].template);
+		assert_eq!(Format::Json, rc.event_format);
+		assert_eq!(
+			Target::Socket("/run/psniff/events.sock".into()),
+			rc.event_target
+		);
+		assert_eq!(4096, rc.decoders.http_body_limit);
+		assert_eq!(16, rc.alerts.mac_ip_threshold);
+
+		let mut file = File::default();
+		file.sinks.pcapng.push(PcapngSink {
+			directory: "/tmp".into(),
+			keep_files: Some(0),
+			..Default::default()
+		});
+		assert!(RunConfig::try_from(file).is_err());
+
+		let mut file = File::default();
+		file.sinks.ring = Some(RingSink::default());
+		assert!(RunConfig::try_from(file).is_err());
+
+		let mut file = File::default();
+		file.events.file = Some("events.jsonl".into());
+		file.events.socket = Some("events.sock".into());
+		assert!(RunConfig::try_from(file).is_err());
+
+		assert!(toml::from_str::<File>("[capture]\nsnaplength = 1").is_err());
+
+		let mut file = File::default();
+		file.alerts.mac_ip_threshold = 0;
+		assert!(RunConfig::try_from(file).is_err());
+
+		let mut file = File::default();
+		file.listeners.push(Matcher::Missing);
+		assert!(RunConfig::try_from(file).is_err());
+
+		let mut file = File::default();
+		file.capture.snaplen = Some(2048);
+		file.capture.buffer_size = Some(1024);
+		assert!(RunConfig::try_from(file).is_err());
+
+		let mut file = File::default();
+		file.capture.interfaces = Some(vec!["eth0".to_string()]);
+		file.capture.read = Some("trace.pcap".into());
+		assert!(RunConfig::try_from(file).is_err());
+	}
 }
@@ -1,19 +1,157 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::HashMap,
+	ffi::{c_int, c_void},
+	fmt,
+	path::PathBuf,
+	str::FromStr,
+	sync::Arc,
+	thread,
+	time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use etherparse::{NetSlice, SlicedPacket, TransportSlice};
-use log::{error, info};
-use pcap::{Capture, Device, Inactive};
-use tokio::sync::{broadcast::Receiver, mpsc::Sender};
+use log::{error, info, warn};
+use pcap::{Active, Capture, Device, Linktype, Offline, Packet, PacketHeader};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{broadcast::Receiver, watch};
 
 use crate::{
-	config::ListenConfig,
-	runtime::{BlockingRunnable, BlockingRunnableBuilder},
-	state::{appstate::AppState, interface::Interface},
+	config::{Interfaces, ListenConfig},
+	events::output,
+	packet::{BufferPool, Frame, Layers},
+	packet_listeners::summary_listener,
+	queue::{self, Policy, Sender, Sent},
+	runtime::{self, BlockingRunnable, BlockingRunnableBuilder, RunnableBuilder},
+	state::{
+		appstate::{self, AppState},
+		interface::Interface,
+		packet_count::MatcherCount,
+	},
 };
 
 pub type InterfaceName = String;
 
+/// How often a live capture's counts are read
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// CaptureFile is a saved pcap or pcapng trace which is replayed in place of
+/// a live interface
+pub struct CaptureFile {
+	path: PathBuf,
+	pacing: Pacing,
+}
+
+/// Pacing determines how quickly packets are read from a capture file
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Pacing {
+	/// Read packets as fast as the listeners will accept them
+	#[default]
+	Fast,
+
+	/// Delay packets so that they are delivered with the same spacing as their
+	/// original capture timestamps
+	Original,
+}
+
+/// How long a live capture waits for packets before checking for shutdown,
+/// unless configured otherwise
+pub const DEFAULT_TIMEOUT_MS: i32 = 100;
+
+/// Precision of the timestamps libpcap gives captured packets
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+	#[default]
+	Micro,
+	Nano,
+}
+
+impl fmt::Display for Precision {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Precision::Micro => write!(f, "micro"),
+			Precision::Nano => write!(f, "nano"),
+		}
+	}
+}
+
+impl From<Precision> for pcap::Precision {
+	fn from(p: Precision) -> Self {
+		match p {
+			Precision::Micro => pcap::Precision::Micro,
+			Precision::Nano => pcap::Precision::Nano,
+		}
+	}
+}
+
+/// CaptureOptions tune how libpcap captures from a live interface.  Options
+/// left unset use the libpcap defaults.
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+	pub snaplen: Option<i32>,
+	pub promisc: bool,
+	pub buffer_size: Option<i32>,
+
+	/// Deliver packets as soon as they arrive, rather than in batches
+	pub immediate: bool,
+
+	/// Milliseconds to wait for a batch of packets; must be positive, as the
+	/// capture only checks for shutdown in between
+	pub timeout: i32,
+	pub precision: Precision,
+}
+
+impl Default for CaptureOptions {
+	fn default() -> Self {
+		CaptureOptions {
+			snaplen: None,
+			promisc: true,
+			buffer_size: None,
+			immediate: false,
+			timeout: DEFAULT_TIMEOUT_MS,
+			precision: Precision::default(),
+		}
+	}
+}
+
+/// Update changes what a running live capture filters and where it sends
+/// packets, without reopening the capture
+#[derive(Clone)]
+pub struct Update {
+	pub filter: Option<String>,
+	pub senders: HashMap<Matcher, Sender<ReceivedPacketData>>,
+	pub taps: Vec<Tap>,
+}
+
+/// Tap receives a copy of the packets of the given matchers, or of every
+/// packet, alongside the listener for each packet's matcher.  Packets which
+/// could not be parsed only reach taps which want everything.
+#[derive(Clone)]
+pub struct Tap {
+	pub matchers: Option<Vec<Matcher>>,
+	pub sender: Sender<ReceivedPacketData>,
+}
+
+impl Tap {
+	fn wants(&self, m: Option<Matcher>) -> bool {
+		match (&self.matchers, m) {
+			(None, _) => true,
+			(Some(matchers), Some(m)) => matchers.contains(&m),
+			(Some(_), None) => false,
+		}
+	}
+}
+
+/// Routes are where a capture sends the packets it has parsed
+struct Routes {
+	senders: HashMap<Matcher, Sender<ReceivedPacketData>>,
+	taps: Vec<Tap>,
+}
+
 // TypeState Builder pattern
 // https://www.youtube.com/watch?v=PDcfYf-g1jU&t=1s
 pub struct Unset {}
@@ -21,12 +159,15 @@ pub struct Unset {}
 pub trait InterfaceNameMarker {}
 impl InterfaceNameMarker for Unset {}
 impl InterfaceNameMarker for InterfaceName {}
+impl InterfaceNameMarker for CaptureFile {}
 
 pub trait StateMarker {}
 impl StateMarker for Unset {}
 impl StateMarker for AppState {}
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Matcher {
 	Arp,
 	IPv4_ICMPv4,
@@ -39,11 +180,50 @@ pub enum Matcher {
 	Unexpected,
 }
 
+impl Matcher {
+	pub const ALL: [Matcher; 9] = [
+		Matcher::Arp,
+		Matcher::IPv4_ICMPv4,
+		Matcher::IPv4_TCP,
+		Matcher::IPv4_UDP,
+		Matcher::IPv6_ICMPv6,
+		Matcher::IPv6_TCP,
+		Matcher::IPv6_UDP,
+		Matcher::Missing,
+		Matcher::Unexpected,
+	];
+
+	/// A stable lowercase name, for use in metrics and configuration
+	pub fn name(&self) -> &'static str {
+		match self {
+			Matcher::Arp => "arp",
+			Matcher::IPv4_ICMPv4 => "ipv4_icmpv4",
+			Matcher::IPv4_TCP => "ipv4_tcp",
+			Matcher::IPv4_UDP => "ipv4_udp",
+			Matcher::IPv6_ICMPv6 => "ipv6_icmpv6",
+			Matcher::IPv6_TCP => "ipv6_tcp",
+			Matcher::IPv6_UDP => "ipv6_udp",
+			Matcher::Missing => "missing",
+			Matcher::Unexpected => "unexpected",
+		}
+	}
+}
+
+impl FromStr for Matcher {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Matcher::ALL
+			.into_iter()
+			.find(|m| m.name() == s)
+			.ok_or_else(|| format!("unknown matcher '{}'", s))
+	}
+}
+
 pub enum ReceivedPacketData {
-	MovingPacket {
-		header: pcap::PacketHeader,
-		data: Vec<u8>,
-	},
+	/// A parsed frame, whose bytes are shared with every other listener it
+	/// was sent to
+	MovingPacket(Frame),
 
 	Counts {
 		total: u32,
@@ -52,33 +232,72 @@ pub enum ReceivedPacketData {
 	},
 }
 
+#[derive(Debug, Error)]
+pub enum BuildError {
+	#[error("invalid capture filter '{filter}': {reason}")]
+	InvalidFilter { filter: String, reason: String },
+
+	#[error("capture on '{iface}' could not be started: {reason}")]
+	Open {
+		iface: InterfaceName,
+		reason: String,
+	},
+}
+
 pub struct Builder<INM, SM>
 where
 	INM: InterfaceNameMarker,
 	SM: StateMarker,
 {
 	iface_name: INM,
+	filter: Option<String>,
+	options: CaptureOptions,
 	senders: HashMap<Matcher, Sender<ReceivedPacketData>>,
+	taps: Vec<Tap>,
+	updates: Option<watch::Receiver<Update>>,
 	state: SM,
 }
 
 pub struct Devices {
 	// iface: Arc<Mutex<Interface>>,
 	iface: Arc<Interface>,
-	cap: Capture<Inactive>,
-	senders: HashMap<Matcher, Sender<ReceivedPacketData>>,
+	source: Source,
+	routes: Routes,
+	updates: Option<watch::Receiver<Update>>,
+	counts: HashMap<Matcher, Arc<MatcherCount>>,
+	pool: BufferPool,
+}
+
+enum Source {
+	Live {
+		cap: Capture<Active>,
+	},
+	Offline {
+		cap: Capture<Offline>,
+		pacing: Pacing,
+	},
 }
 
 impl Builder<Unset, Unset> {
 	pub fn new() -> Builder<Unset, Unset> {
 		Builder {
 			iface_name: Unset {},
+			filter: None,
+			options: CaptureOptions::default(),
 			senders: HashMap::new(),
+			taps: vec![],
+			updates: None,
 			state: Unset {},
 		}
 	}
 }
 
+impl Default for Builder<Unset, Unset> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 impl<INM, SM> Builder<INM, SM>
 where
 	INM: InterfaceNameMarker,
@@ -87,7 +306,23 @@ where
 	pub fn with_interface(self, iface_name: InterfaceName) -> Builder<InterfaceName, SM> {
 		Builder {
 			iface_name,
+			filter: self.filter,
+			options: self.options,
+			senders: self.senders,
+			taps: self.taps,
+			updates: self.updates,
+			state: self.state,
+		}
+	}
+
+	pub fn with_file(self, path: PathBuf, pacing: Pacing) -> Builder<CaptureFile, SM> {
+		Builder {
+			iface_name: CaptureFile { path, pacing },
+			filter: self.filter,
+			options: self.options,
 			senders: self.senders,
+			taps: self.taps,
+			updates: self.updates,
 			state: self.state,
 		}
 	}
@@ -95,15 +330,45 @@ where
 	pub fn with_state(self, state: AppState) -> Builder<INM, AppState> {
 		Builder {
 			iface_name: self.iface_name,
+			filter: self.filter,
+			options: self.options,
 			senders: self.senders,
+			taps: self.taps,
+			updates: self.updates,
 			state,
 		}
 	}
 
+	/// Restricts the capture to packets matching the given BPF expression.  The
+	/// expression is compiled in the kernel (or libpcap) so that unwanted
+	/// packets are never copied to the listeners.
+	pub fn with_filter(mut self, filter: String) -> Self {
+		self.filter = Some(filter);
+		self
+	}
+
+	/// Tunes the capture of a live interface; replays ignore these options
+	pub fn with_capture_options(mut self, options: CaptureOptions) -> Self {
+		self.options = options;
+		self
+	}
+
+	/// Lets a live capture be changed while it runs; the latest update replaces
+	/// the filter and senders given to the builder
+	pub fn with_updates(mut self, updates: watch::Receiver<Update>) -> Self {
+		self.updates = Some(updates);
+		self
+	}
+
 	pub fn set_typed_sender(mut self, m: Matcher, sender: Sender<ReceivedPacketData>) -> Self {
 		self.senders.insert(m, sender);
 		self
 	}
+
+	pub fn add_tap(mut self, tap: Tap) -> Self {
+		self.taps.push(tap);
+		self
+	}
 }
 
 impl BlockingRunnableBuilder for Builder<InterfaceName, AppState> {
@@ -115,100 +380,160 @@ impl BlockingRunnableBuilder for Builder<InterfaceName, AppState> {
 			.find(|d| d.name == self.iface_name)
 			.with_context(|| format!("interface '{}' was not found", self.iface_name))?;
 
-		let iface = Arc::new(Interface::new(self.iface_name));
+		if let Some(filter) = &self.filter {
+			validate_filter(filter)?;
+		}
 
 		// device
-		let cap = Capture::from_device(device)?.promisc(true).timeout(100);
+		let options = &self.options;
+		let mut cap = Capture::from_device(device)?
+			.promisc(options.promisc)
+			.immediate_mode(options.immediate)
+			.timeout(options.timeout)
+			.precision(options.precision.into());
+		if let Some(snaplen) = options.snaplen {
+			cap = cap.snaplen(snaplen);
+		}
+		if let Some(buffer_size) = options.buffer_size {
+			cap = cap.buffer_size(buffer_size);
+		}
+
+		// Settings the platform rejects surface when the capture is activated
+		let mut cap = cap.open().map_err(|e| BuildError::Open {
+			iface: self.iface_name.clone(),
+			reason: e.to_string(),
+		})?;
+		if let Some(filter) = &self.filter {
+			cap.filter(filter, true)?;
+		}
+
+		// libpcap falls back to microseconds without complaint
+		let precision = active_precision(&cap);
+		if precision != options.precision {
+			warn!(
+				"{}: {}second timestamps are not supported, using {}seconds",
+				self.iface_name, options.precision, precision
+			);
+		}
+
+		let iface = Arc::new(
+			Interface::new(self.iface_name)
+				.with_filter(self.filter.clone())
+				.with_precision(precision),
+		);
 
 		// Add the interface to the appstate
 		self.state.interfaces.lock().unwrap().insert(iface.clone());
+		register_queues(&self.state, &self.senders);
 
 		Ok(Box::new(Devices {
 			iface,
-			cap,
-			senders: self.senders,
+			source: Source::Live { cap },
+			routes: Routes {
+				senders: self.senders,
+				taps: self.taps,
+			},
+			updates: self.updates,
+			counts: self.state.packet_counts.clone(),
+			pool: BufferPool::default(),
+		}))
+	}
+}
+
+impl BlockingRunnableBuilder for Builder<CaptureFile, AppState> {
+	fn build(
+		self: Box<Self>,
+	) -> Result<Box<dyn BlockingRunnable + Send>, Box<dyn std::error::Error>> {
+		let CaptureFile { path, pacing } = self.iface_name;
+
+		let mut cap = Capture::from_file(&path)
+			.with_context(|| format!("capture file '{}' could not be opened", path.display()))?;
+
+		if let Some(filter) = &self.filter {
+			cap
+				.filter(filter, true)
+				.map_err(|e| BuildError::InvalidFilter {
+					filter: filter.clone(),
+					reason: e.to_string(),
+				})?;
+		}
+
+		let iface = Arc::new(Interface::new(path.display().to_string()).with_filter(self.filter));
+
+		// Add the interface to the appstate
+		self.state.interfaces.lock().unwrap().insert(iface.clone());
+		register_queues(&self.state, &self.senders);
+
+		Ok(Box::new(Devices {
+			iface,
+			source: Source::Offline { cap, pacing },
+			routes: Routes {
+				senders: self.senders,
+				taps: self.taps,
+			},
+			updates: self.updates,
+			counts: self.state.packet_counts.clone(),
+			pool: BufferPool::default(),
 		}))
 	}
 }
 
 impl BlockingRunnable for Devices {
 	fn run(self: Box<Self>, cancel_rx: Receiver<()>) -> Result<(), Box<dyn std::error::Error>> {
-		let mut cap = self.cap.open()?;
+		let Devices {
+			iface,
+			source,
+			routes,
+			updates,
+			counts,
+			mut pool,
+		} = *self;
+
+		match source {
+			Source::Live { cap } => run_live(cap, iface, &mut pool, routes, updates, &counts, cancel_rx),
+			Source::Offline { cap, pacing } => {
+				run_offline(cap, pacing, iface, &mut pool, routes, &counts, cancel_rx)
+			},
+		}
+	}
+}
 
-		let (mut packet_count, mut os_dropped_count, mut if_dropped_count) = (0, 0, 0);
+fn run_live(
+	mut cap: Capture<Active>,
+	iface: Arc<Interface>,
+	pool: &mut BufferPool,
+	mut routes: Routes,
+	mut updates: Option<watch::Receiver<Update>>,
+	counts: &HashMap<Matcher, Arc<MatcherCount>>,
+	cancel_rx: Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let (mut packet_count, mut os_dropped_count, mut if_dropped_count) = (0, 0, 0);
+	let mut stats_at = Instant::now();
 
-		loop {
-			// Check to see if we need to exit
-			if !cancel_rx.is_empty() || cancel_rx.is_closed() {
-				break;
-			}
+	loop {
+		// Check to see if we need to exit
+		if !cancel_rx.is_empty() || cancel_rx.is_closed() {
+			break;
+		}
 
-			match cap.next_packet() {
-				Ok(packet) => {
-					let m = match SlicedPacket::from_ethernet(packet.data) {
-						Ok(sliced_packet) => match &sliced_packet.net {
-							Some(NetSlice::Arp(_)) => Matcher::Arp,
-							Some(NetSlice::Ipv4(ipv4_header)) => match &sliced_packet.transport {
-								Some(TransportSlice::Icmpv4(_)) => Matcher::IPv4_ICMPv4,
-								Some(TransportSlice::Icmpv6(_)) => Matcher::Unexpected,
-								Some(TransportSlice::Tcp(_)) => Matcher::IPv4_TCP,
-								Some(TransportSlice::Udp(_)) => Matcher::IPv4_UDP,
-								None => {
-									let ip_number = ipv4_header.payload_ip_number();
-									info!(
-										"IPv4-no-transport {} {}",
-										ip_number.keyword_str().unwrap_or("---"),
-										ip_number.protocol_str().unwrap_or("unknown")
-									);
-									continue;
-								},
-							},
-							Some(NetSlice::Ipv6(ipv6_header)) => match &sliced_packet.transport {
-								Some(TransportSlice::Icmpv4(_)) => Matcher::Unexpected,
-								Some(TransportSlice::Icmpv6(_)) => Matcher::IPv6_ICMPv6,
-								Some(TransportSlice::Tcp(_)) => Matcher::IPv6_TCP,
-								Some(TransportSlice::Udp(_)) => Matcher::IPv6_UDP,
-								None => {
-									let ip_number = ipv6_header.payload().ip_number;
-									info!(
-										"IPv6-no-transport {} {}",
-										ip_number.keyword_str().unwrap_or("---"),
-										ip_number.protocol_str().unwrap_or("unknown")
-									);
-									continue;
-								},
-							},
-							None => Matcher::Missing,
-						},
-						Err(err) => {
-							error!("Error parsing packet: {:?}", err);
-							continue;
-						},
-					};
-
-					let header_clone = *packet.header;
-					let data_clone = packet.data.to_vec();
-					let p0 = ReceivedPacketData::MovingPacket {
-						header: header_clone,
-						data: data_clone,
-					};
-					let s = match self.senders.get(&m) {
-						Some(x) => x,
-						None => continue,
-					};
-					let _ = s.blocking_send(p0);
-				},
-				Err(pcap::Error::TimeoutExpired) => {
-					// Just try again on timeout - this makes the program more responsive
-					let stats = cap.stats().unwrap();
-
-					if packet_count == stats.received
-						&& os_dropped_count == stats.dropped
-						&& if_dropped_count == stats.if_dropped
-					{
-						continue;
-					}
+		if let Some(updates) = &mut updates
+			&& updates.has_changed().unwrap_or(false)
+		{
+			let update = updates.borrow_and_update().clone();
+			apply_update(&mut cap, &iface, update.filter);
+			routes.senders = update.senders;
+			routes.taps = update.taps;
+		}
 
+		// Counts are read on a timer, as a busy link may never time out
+		if stats_at.elapsed() >= STATS_INTERVAL {
+			stats_at = Instant::now();
+			match cap.stats() {
+				Ok(stats)
+					if packet_count != stats.received
+						|| os_dropped_count != stats.dropped
+						|| if_dropped_count != stats.if_dropped =>
+				{
 					packet_count = stats.received;
 					os_dropped_count = stats.dropped;
 					if_dropped_count = stats.if_dropped;
@@ -218,81 +543,351 @@ impl BlockingRunnable for Devices {
 						stats.received, stats.dropped, stats.if_dropped
 					);
 
-					self
-						.iface
-						.update_counts(packet_count, os_dropped_count, if_dropped_count);
-
-					continue;
-				},
-				Err(e) => {
-					println!("Error: {}", e);
-					continue;
+					iface.update_counts(packet_count, os_dropped_count, if_dropped_count);
 				},
+				Ok(_) => {},
+				Err(e) => error!("{}: could not read capture statistics: {}", iface.name(), e),
 			}
 		}
 
-		Ok(())
+		match cap.next_packet() {
+			Ok(packet) => dispatch(&iface, pool, &packet, &routes, counts),
+			// Just try again on timeout - this makes the program more responsive
+			Err(pcap::Error::TimeoutExpired) => continue,
+			Err(e) => {
+				println!("Error: {}", e);
+				continue;
+			},
+		}
 	}
-}
-
-pub fn listen(cfg: ListenConfig) -> Result<()> {
-	let device = match cfg.interfaces.unwrap_or_default().first() {
-		Some(iface) => Device::list()?
-			.into_iter()
-			.find(|d| d.name == *iface)
-			.with_context(|| format!("interface '{}' was not found", iface))?,
-		None => {
-			return Ok(());
-		},
-	};
 
-	let mut cap = Capture::from_device(device)?
-		.promisc(true)
-		.timeout(100)
-		.open()?;
-
-	// match cap.filter("tcp", false) {
-	//   Ok(_) => {},
-	//   Err(e) => return Err(e.into()),
-	// };
+	Ok(())
+}
 
-	let (mut packet_count, mut dropped_count, mut if_dropped_count) = (0, 0, 0);
+fn run_offline(
+	mut cap: Capture<Offline>,
+	pacing: Pacing,
+	iface: Arc<Interface>,
+	pool: &mut BufferPool,
+	routes: Routes,
+	counts: &HashMap<Matcher, Arc<MatcherCount>>,
+	mut cancel_rx: Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let mut packet_count = 0;
+	let mut clock: Option<(Instant, Duration)> = None;
 
 	loop {
+		// Check to see if we need to exit
+		if !cancel_rx.is_empty() || cancel_rx.is_closed() {
+			return Ok(());
+		}
+
 		match cap.next_packet() {
 			Ok(packet) => {
-				match SlicedPacket::from_ethernet(packet.data) {
-					Ok(_value) => {
-						// analyze_packet(value/*, &mut sequences */);
-						todo!();
-					}, // analyze_packet(value),
-					Err(err) => println!("Error parsing packet: {:?}", err),
-				}
-			},
-			Err(pcap::Error::TimeoutExpired) => {
-				// Just try again on timeout - this makes the program more responsive
-				let stats = cap.stats().unwrap();
-				if packet_count != stats.received
-					|| dropped_count != stats.dropped
-					|| if_dropped_count != stats.if_dropped
-				{
-					println!(
-						"Received: {}, dropped: {}, if_dropped: {}",
-						stats.received, stats.dropped, stats.if_dropped
-					);
+				if pacing == Pacing::Original {
+					let ts = timestamp(packet.header, Precision::Micro);
+					let (started, first_ts) = *clock.get_or_insert((Instant::now(), ts));
+					let due = started + ts.saturating_sub(first_ts);
+					if !sleep_until(due, &cancel_rx) {
+						return Ok(());
+					}
 				}
 
-				packet_count = stats.received;
-				dropped_count = stats.dropped;
-				if_dropped_count = stats.if_dropped;
-				continue;
+				dispatch(&iface, pool, &packet, &routes, counts);
+
+				packet_count += 1;
+				iface.update_counts(packet_count, 0, 0);
 			},
+			Err(pcap::Error::NoMorePackets) => break,
+			// A file which cannot be read further will not recover
 			Err(e) => {
-				println!("Error: {}", e);
-				continue;
+				error!("{}: replay stopped: {}", iface.name(), e);
+				break;
 			},
 		}
 	}
+
+	info!("Replay complete, {} packets read", packet_count);
+
+	// Closing the channels lets the listeners drain what is queued; the
+	// remaining state stays available until shutdown is requested
+	drop(routes);
+	let _ = cancel_rx.blocking_recv();
+
+	Ok(())
+}
+
+/// Switches a running capture to a new filter.  The capture keeps its old
+/// filter if the new one is rejected.
+fn apply_update(cap: &mut Capture<Active>, iface: &Interface, filter: Option<String>) {
+	if filter.as_deref() == iface.filter().as_deref() {
+		return;
+	}
+
+	// An empty expression matches every packet
+	match cap.filter(filter.as_deref().unwrap_or(""), true) {
+		Ok(()) => {
+			info!(
+				"{}: capture filter is now '{}'",
+				iface.name(),
+				filter.as_deref().unwrap_or("")
+			);
+			iface.set_filter(filter);
+		},
+		Err(e) => error!("{}: capture filter was not changed: {}", iface.name(), e),
+	}
+}
+
+/// Compiles a BPF expression for an Ethernet link so that mistakes are reported
+/// before any capture is started
+pub fn validate_filter(filter: &str) -> Result<(), BuildError> {
+	let invalid = |e: pcap::Error| BuildError::InvalidFilter {
+		filter: filter.to_string(),
+		reason: e.to_string(),
+	};
+
+	Capture::dead(Linktype::ETHERNET)
+		.map_err(invalid)?
+		.compile(filter, true)
+		.map_err(invalid)?;
+
+	Ok(())
+}
+
+/// Sleeps until `due`, waking periodically to check for cancellation.  Returns
+/// false if cancellation was requested.
+fn sleep_until(due: Instant, cancel_rx: &Receiver<()>) -> bool {
+	loop {
+		if !cancel_rx.is_empty() || cancel_rx.is_closed() {
+			return false;
+		}
+
+		let now = Instant::now();
+		if now >= due {
+			return true;
+		}
+
+		thread::sleep((due - now).min(Duration::from_millis(100)));
+	}
+}
+
+/// The capture time of a packet, as an offset from the Unix epoch.  With
+/// nanosecond precision libpcap stores nanoseconds in place of microseconds.
+pub fn timestamp(header: &PacketHeader, precision: Precision) -> Duration {
+	let fraction = header.ts.tv_usec as u64;
+	let fraction = match precision {
+		Precision::Micro => Duration::from_micros(fraction),
+		Precision::Nano => Duration::from_nanos(fraction),
+	};
+	Duration::new(header.ts.tv_sec as u64, 0) + fraction
+}
+
+// The pcap crate sets the precision but has no binding to read it back.  The
+// getter came with pcap_set_tstamp_precision in libpcap 1.5.0, which the crate
+// already links against, so this needs nothing newer.
+unsafe extern "C" {
+	fn pcap_get_tstamp_precision(p: *mut c_void) -> c_int;
+}
+
+/// The timestamp precision an activated capture actually delivers
+fn active_precision(cap: &Capture<Active>) -> Precision {
+	// SAFETY: the handle stays valid while the capture is borrowed
+	match unsafe { pcap_get_tstamp_precision(cap.as_ptr().cast()) } {
+		1 => Precision::Nano,
+		_ => Precision::Micro,
+	}
+}
+
+/// Determines which listener a parsed packet belongs to.  Packets which are
+/// neither ARP nor carry a recognised transport are not classified.
+pub fn classify(sliced_packet: &SlicedPacket) -> Option<Matcher> {
+	let m = match &sliced_packet.net {
+		Some(NetSlice::Arp(_)) => Matcher::Arp,
+		Some(NetSlice::Ipv4(ipv4_header)) => match &sliced_packet.transport {
+			Some(TransportSlice::Icmpv4(_)) => Matcher::IPv4_ICMPv4,
+			Some(TransportSlice::Icmpv6(_)) => Matcher::Unexpected,
+			Some(TransportSlice::Tcp(_)) => Matcher::IPv4_TCP,
+			Some(TransportSlice::Udp(_)) => Matcher::IPv4_UDP,
+			None => {
+				let ip_number = ipv4_header.payload_ip_number();
+				info!(
+					"IPv4-no-transport {} {}",
+					ip_number.keyword_str().unwrap_or("---"),
+					ip_number.protocol_str().unwrap_or("unknown")
+				);
+				return None;
+			},
+		},
+		Some(NetSlice::Ipv6(ipv6_header)) => match &sliced_packet.transport {
+			Some(TransportSlice::Icmpv4(_)) => Matcher::Unexpected,
+			Some(TransportSlice::Icmpv6(_)) => Matcher::IPv6_ICMPv6,
+			Some(TransportSlice::Tcp(_)) => Matcher::IPv6_TCP,
+			Some(TransportSlice::Udp(_)) => Matcher::IPv6_UDP,
+			None => {
+				let ip_number = ipv6_header.payload().ip_number;
+				info!(
+					"IPv6-no-transport {} {}",
+					ip_number.keyword_str().unwrap_or("---"),
+					ip_number.protocol_str().unwrap_or("unknown")
+				);
+				return None;
+			},
+		},
+		None => Matcher::Missing,
+	};
+
+	Some(m)
+}
+
+/// Parses a packet once, on the capture thread, and hands it to the listener
+/// for its class and to any taps which want it.  Wanted packets are copied
+/// into a pooled buffer exactly once; listeners share the parsed frame rather
+/// than re-parsing it.
+fn dispatch(
+	iface: &Arc<Interface>,
+	pool: &mut BufferPool,
+	packet: &Packet,
+	routes: &Routes,
+	counts: &HashMap<Matcher, Arc<MatcherCount>>,
+) {
+	let layers = match Layers::parse(packet.data) {
+		Ok(layers) => Some(layers),
+		Err(err) => {
+			error!("Error parsing packet: {:?}", err);
+			None
+		},
+	};
+
+	let m = layers.and_then(|l| l.matcher());
+
+	let count = m.and_then(|m| counts.get(&m));
+	if let Some(count) = count {
+		count.record(packet.header.len as u64);
+	}
+
+	let s = m.and_then(|m| routes.senders.get(&m));
+	let mut taps = routes.taps.iter().filter(|t| t.wants(m)).peekable();
+	if s.is_none() && taps.peek().is_none() {
+		return;
+	}
+
+	let frame = Frame::new(
+		iface.clone(),
+		*packet.header,
+		pool.copy_from(packet.data),
+		layers.unwrap_or_default(),
+	);
+
+	// Taps apply their own queue policy; their drops are not the listener's
+	for tap in taps {
+		let _ = tap
+			.sender
+			.blocking_send(ReceivedPacketData::MovingPacket(frame.clone()));
+	}
+
+	let s = match s {
+		Some(x) => x,
+		None => return,
+	};
+
+	// A full queue either stalls here or sheds a packet, according to its policy
+	match s.blocking_send(ReceivedPacketData::MovingPacket(frame)) {
+		Ok(Sent::Queued) => {},
+		Ok(Sent::Dropped) | Err(_) => {
+			if let Some(count) = count {
+				count.record_drop();
+			}
+		},
+	}
+}
+
+/// Publishes the listener channels in the appstate, so that their depth can be
+/// reported
+pub fn register_queues(state: &AppState, senders: &HashMap<Matcher, Sender<ReceivedPacketData>>) {
+	let mut queues = state.queues.lock().unwrap();
+	for (m, s) in senders {
+		queues.insert(*m, s.downgrade());
+	}
+}
+
+/// Resolves an interface selection into the names of the devices to capture
+/// from.  Selecting all interfaces picks every device which is up and running,
+/// excluding the "any" pseudo-device so that packets are not seen twice.
+pub fn resolve_interfaces(interfaces: Interfaces) -> Result<Vec<InterfaceName>> {
+	let names = match interfaces {
+		Interfaces::All => Device::list()?
+			.into_iter()
+			.filter(|d| d.flags.is_up() && d.flags.is_running() && d.name != "any")
+			.map(|d| d.name)
+			.collect(),
+		Interfaces::Named(names) => {
+			let devices = Device::list()?;
+
+			let mut v: Vec<InterfaceName> = vec![];
+			for name in names {
+				if !devices.iter().any(|d| d.name == name) {
+					return Err(anyhow::anyhow!("interface '{}' was not found", name));
+				}
+				if !v.contains(&name) {
+					v.push(name);
+				}
+			}
+			v
+		},
+	};
+
+	if names.is_empty() {
+		return Err(anyhow::anyhow!("no interfaces selected"));
+	}
+
+	Ok(names)
+}
+
+/// Prints a one-line summary of every packet seen on the selected interfaces
+/// until interrupted
+pub fn listen(cfg: ListenConfig) -> Result<()> {
+	let names = match cfg.interfaces {
+		Some(interfaces) => resolve_interfaces(interfaces)?,
+		None => return Err(anyhow::anyhow!("no interfaces selected")),
+	};
+
+	if let Some(filter) = &cfg.filter {
+		validate_filter(filter)?;
+	}
+
+	let app_state = appstate::new();
+	let (sender, receiver) =
+		queue::channel::<ReceivedPacketData>(queue::DEFAULT_CAPACITY, Policy::Block);
+
+	let blocking_v: Vec<Box<dyn BlockingRunnableBuilder>> = names
+		.into_iter()
+		.map(|name| {
+			let mut d = Builder::new()
+				.with_interface(name)
+				.with_state(app_state.clone());
+			for m in Matcher::ALL {
+				d = d.set_typed_sender(m, sender.clone());
+			}
+			if let Some(filter) = &cfg.filter {
+				d = d.with_filter(filter.clone());
+			}
+			Box::new(d) as Box<dyn BlockingRunnableBuilder>
+		})
+		.collect();
+
+	// The device listeners hold their own clones
+	drop(sender);
+
+	let v: Vec<Box<dyn RunnableBuilder>> = vec![
+		Box::new(output::new().set_receiver(app_state.events.subscribe())),
+		Box::new(
+			summary_listener::new()
+				.set_receiver(receiver)
+				.with_events(app_state.events.clone()),
+		),
+	];
+
+	runtime::run(blocking_v, v, None)
 }
 
 pub fn list() -> Result<()> {
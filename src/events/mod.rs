@@ -0,0 +1,280 @@
+pub mod output;
+pub mod text;
+
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+	time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+	flow::Endpoint,
+	protocols::{dns, http, quic, tls},
+	state::{arp::ArpEventKind, flows::Counts, serialize_timestamp},
+};
+
+/// How many events an output may fall behind before it misses some
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// Event is something a listener observed, in the form every output shares
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+	#[serde(serialize_with = "serialize_timestamp")]
+	pub ts: Duration,
+	pub iface: String,
+	#[serde(flatten)]
+	pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+	FlowStart(FlowStart),
+	FlowEnd(FlowEnd),
+	Packet(Box<PacketSummary>),
+	Transaction(Transaction),
+	Alert(Alert),
+	Counters(Counters),
+}
+
+impl EventKind {
+	/// The name an event is tagged with, e.g. "flow_start"
+	pub fn name(&self) -> &'static str {
+		match self {
+			EventKind::FlowStart(_) => "flow_start",
+			EventKind::FlowEnd(_) => "flow_end",
+			EventKind::Packet(_) => "packet",
+			EventKind::Transaction(_) => "transaction",
+			EventKind::Alert(_) => "alert",
+			EventKind::Counters(_) => "counters",
+		}
+	}
+
+	/// The protocol the event is about, if it is about one
+	pub fn protocol(&self) -> Option<&str> {
+		match self {
+			EventKind::FlowStart(start) => Some(&start.protocol),
+			EventKind::FlowEnd(end) => Some(&end.protocol),
+			EventKind::Packet(packet) => Some(&packet.protocol),
+			EventKind::Transaction(Transaction::Dns(_)) => Some("DNS"),
+			EventKind::Transaction(Transaction::Tls(_)) => Some("TLS"),
+			EventKind::Transaction(Transaction::Http(_)) => Some("HTTP"),
+			EventKind::Transaction(Transaction::Quic(_)) => Some("QUIC"),
+			EventKind::Alert(Alert::Arp { .. }) => Some("ARP"),
+			EventKind::Counters(_) => None,
+		}
+	}
+
+	/// The hosts the event involves, with their ports where there are any
+	pub fn hosts(&self) -> Vec<(IpAddr, Option<u16>)> {
+		let endpoint = |e: &Endpoint| (e.ip, Some(e.port));
+		// Packet addresses are either endpoints or bare IP addresses; anything
+		// else, such as a MAC address, is not a host
+		let address = |s: &str| match s.parse::<SocketAddr>() {
+			Ok(addr) => Some((addr.ip(), Some(addr.port()))),
+			Err(_) => s.parse::<IpAddr>().ok().map(|ip| (ip, None)),
+		};
+		match self {
+			EventKind::FlowStart(start) => vec![endpoint(&start.client), endpoint(&start.server)],
+			EventKind::FlowEnd(end) => vec![endpoint(&end.client), endpoint(&end.server)],
+			EventKind::Packet(packet) => [address(&packet.src), address(&packet.dst)]
+				.into_iter()
+				.flatten()
+				.collect(),
+			EventKind::Transaction(Transaction::Dns(dns)) => {
+				vec![endpoint(&dns.client), endpoint(&dns.server)]
+			},
+			EventKind::Transaction(Transaction::Tls(tls)) => {
+				vec![endpoint(&tls.client), endpoint(&tls.server)]
+			},
+			EventKind::Transaction(Transaction::Http(http)) => {
+				vec![endpoint(&http.client), endpoint(&http.server)]
+			},
+			EventKind::Transaction(Transaction::Quic(quic)) => {
+				vec![endpoint(&quic.tls.client), endpoint(&quic.tls.server)]
+			},
+			EventKind::Alert(Alert::Arp { ip, .. }) => vec![(IpAddr::V4(*ip), None)],
+			EventKind::Counters(_) => vec![],
+		}
+	}
+}
+
+/// FlowStart is the first packet of a TCP connection or UDP flow
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowStart {
+	/// The IP version and transport, e.g. "IPv4-TCP"
+	pub protocol: String,
+	pub client: Endpoint,
+	pub server: Endpoint,
+}
+
+/// FlowEnd is a flow which has closed, or been idle for too long
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowEnd {
+	pub protocol: String,
+	pub client: Endpoint,
+	pub server: Endpoint,
+	/// Only set for TCP flows
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub state: Option<String>,
+	pub to_server: Counts,
+	pub to_client: Counts,
+}
+
+/// PacketSummary describes a single packet
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketSummary {
+	pub protocol: String,
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub src: String,
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub dst: String,
+	/// Transport payload bytes
+	pub bytes: u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tcp: Option<TcpSummary>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub icmp: Option<IcmpSummary>,
+	/// A one-line description of every layer, in the style of tcpdump
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub text: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ordering {
+	/// The segment started a new connection
+	New,
+	Empty,
+	InOrder,
+	Retransmission,
+	OutOfOrder,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TcpSummary {
+	pub flags: Vec<&'static str>,
+	pub seq: u32,
+	pub fragmented: bool,
+	pub ordering: Ordering,
+	pub state: String,
+	/// Set when the segment moved the connection to another state
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub previous_state: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IcmpSummary {
+	#[serde(rename = "type")]
+	pub type_u8: u8,
+	#[serde(rename = "code")]
+	pub code_u8: u8,
+	pub kind: String,
+	/// Only set for echo replies
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub reply: Option<EchoReply>,
+	/// Only set for error messages
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<IcmpError>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EchoReply {
+	/// Seconds since the matching request, if it was seen
+	pub rtt: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IcmpError {
+	/// The flow of the datagram the error quotes, if it could be read
+	pub flow: Option<QuotedFlow>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotedFlow {
+	pub protocol: String,
+	pub src: Endpoint,
+	pub dst: Endpoint,
+	/// Set when the flow is being tracked
+	pub tracked: Option<TrackedFlow>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TrackedFlow {
+	pub state: Option<String>,
+	pub packets: u64,
+	pub errors: u64,
+}
+
+/// Transaction is a request and its response in an application protocol,
+/// decoded from a flow
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum Transaction {
+	Dns(Box<dns::Transaction>),
+	Tls(Box<tls::Handshake>),
+	Http(Box<http::Transaction>),
+	Quic(Box<quic::Initial>),
+}
+
+/// Alert is something which deserves a closer look
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "alert", rename_all = "snake_case")]
+pub enum Alert {
+	Arp {
+		ip: std::net::Ipv4Addr,
+		mac: String,
+		#[serde(flatten)]
+		kind: ArpEventKind,
+	},
+}
+
+/// Counters are an interface's capture counters, as last reported by the
+/// capture
+#[derive(Clone, Debug, Serialize)]
+pub struct Counters {
+	pub received: u64,
+	pub os_dropped: u64,
+	pub if_dropped: u64,
+	/// Packets received since the previous counters of the interface
+	pub received_delta: u64,
+}
+
+/// Events carries events from the listeners to every output subscribed.  An
+/// output which falls too far behind misses events rather than stalling the
+/// listeners, and events published while nothing is subscribed are dropped.
+#[derive(Clone)]
+pub struct Events {
+	sender: broadcast::Sender<Arc<Event>>,
+}
+
+impl Default for Events {
+	fn default() -> Self {
+		Events::new(DEFAULT_CAPACITY)
+	}
+}
+
+impl Events {
+	pub fn new(capacity: usize) -> Events {
+		let (sender, _) = broadcast::channel(capacity);
+		Events { sender }
+	}
+
+	pub fn publish(&self, ts: Duration, iface: &str, kind: EventKind) {
+		if self.sender.receiver_count() == 0 {
+			return;
+		}
+		let _ = self.sender.send(Arc::new(Event {
+			ts,
+			iface: iface.to_string(),
+			kind,
+		}));
+	}
+
+	pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+		self.sender.subscribe()
+	}
+}
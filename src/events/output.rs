@@ -0,0 +1,209 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use clap::ValueEnum;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+	fs::OpenOptions,
+	io::{self, AsyncWrite, AsyncWriteExt, BufWriter},
+	net::UnixStream,
+	sync::broadcast::{self, error::RecvError},
+	time::Instant,
+};
+
+use crate::{
+	events::{Event, text},
+	runtime::{Runnable, RunnableBuilder},
+};
+
+/// How long a broken file or socket is left before it is opened again
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Format is how events are written
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+	/// The human-readable lines the listeners have always printed
+	#[default]
+	Text,
+
+	/// One JSON object per line
+	Json,
+}
+
+/// Target is where events are written
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Target {
+	#[default]
+	Stdout,
+
+	/// Appended to a file, which is created if need be
+	File(PathBuf),
+
+	/// Sent to a Unix stream socket which something else is listening on
+	Socket(PathBuf),
+}
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+	#[error("no receiver")]
+	NoReceiver,
+}
+
+pub struct OutputBuilder {
+	receiver: Option<broadcast::Receiver<Arc<Event>>>,
+	format: Format,
+	target: Target,
+}
+
+pub fn new() -> OutputBuilder {
+	OutputBuilder {
+		receiver: None,
+		format: Format::default(),
+		target: Target::default(),
+	}
+}
+
+impl OutputBuilder {
+	pub fn set_receiver(mut self, receiver: broadcast::Receiver<Arc<Event>>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	pub fn with_format(mut self, format: Format) -> Self {
+		self.format = format;
+		self
+	}
+
+	pub fn with_target(mut self, target: Target) -> Self {
+		self.target = target;
+		self
+	}
+}
+
+#[async_trait]
+impl RunnableBuilder for OutputBuilder {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+
+		// A target which cannot be opened at the start is a mistake, rather
+		// than something to retry
+		let writer = open(&self.target).await?;
+
+		Ok(Box::new(Output {
+			receiver,
+			format: self.format,
+			target: self.target,
+			writer: Some(writer),
+			opened: Instant::now(),
+			missed: 0,
+		}))
+	}
+}
+
+type Writer = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+
+async fn open(target: &Target) -> io::Result<Writer> {
+	let w: Box<dyn AsyncWrite + Send + Unpin> = match target {
+		Target::Stdout => Box::new(io::stdout()),
+		Target::File(path) => Box::new(
+			OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)
+				.await?,
+		),
+		Target::Socket(path) => Box::new(UnixStream::connect(path).await?),
+	};
+	Ok(BufWriter::new(w))
+}
+
+/// Output writes every event published to a single target.  A file or socket
+/// which breaks is opened again, and the events in between are counted as
+/// missed.
+pub struct Output {
+	receiver: broadcast::Receiver<Arc<Event>>,
+	format: Format,
+	target: Target,
+	writer: Option<Writer>,
+	opened: Instant,
+	missed: u64,
+}
+
+impl Output {
+	async fn write(&mut self, event: &Event) {
+		let line = match self.format {
+			Format::Text => text::format(event),
+			Format::Json => serde_json::to_string(event).ok(),
+		};
+		let Some(line) = line else {
+			return;
+		};
+
+		if self.writer.is_none() && self.opened.elapsed() >= REOPEN_INTERVAL {
+			self.opened = Instant::now();
+			match open(&self.target).await {
+				Ok(w) => {
+					info!(
+						"events: {:?} reopened, {} events missed",
+						self.target, self.missed
+					);
+					self.writer = Some(w);
+					self.missed = 0;
+				},
+				Err(e) => error!("events: {:?} could not be reopened: {}", self.target, e),
+			}
+		}
+		let Some(writer) = &mut self.writer else {
+			self.missed += 1;
+			return;
+		};
+
+		let mut result = writer.write_all(line.as_bytes()).await;
+		if result.is_ok() {
+			result = writer.write_all(b"\n").await;
+		}
+		// Lines are flushed once the listeners have nothing more to say
+		if result.is_ok() && self.receiver.is_empty() {
+			result = writer.flush().await;
+		}
+
+		if let Err(e) = result {
+			error!("events: {:?} could not be written: {}", self.target, e);
+			self.writer = None;
+			self.opened = Instant::now();
+			self.missed += 1;
+		}
+	}
+}
+
+#[async_trait]
+impl Runnable for Output {
+	async fn run(&mut self, mut cancel_rx: broadcast::Receiver<()>) {
+		loop {
+			tokio::select! {
+				_ = cancel_rx.recv() => break,
+				r = self.receiver.recv() => match r {
+					Ok(event) => self.write(&event).await,
+					Err(RecvError::Lagged(n)) => {
+						warn!("events: {:?} fell behind, {} events missed", self.target, n);
+					},
+					Err(RecvError::Closed) => break,
+				},
+			}
+		}
+
+		// Write what was published before the shutdown
+		while let Ok(event) = self.receiver.try_recv() {
+			self.write(&event).await;
+		}
+		if let Some(writer) = &mut self.writer {
+			let _ = writer.flush().await;
+		}
+	}
+}
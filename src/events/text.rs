@@ -0,0 +1,283 @@
+use crate::{
+	events::{Alert, Event, EventKind, IcmpSummary, Ordering, PacketSummary, Transaction},
+	flow::FlowKey,
+	state::arp::ArpEventKind,
+};
+
+/// Formats an event as the human-readable lines the listeners have always
+/// printed.  Flow starts are left out, as the first packet of a TCP
+/// connection is already marked with a '+'.
+pub fn format(event: &Event) -> Option<String> {
+	let iface = &event.iface;
+	match &event.kind {
+		EventKind::FlowStart(_) => None,
+		EventKind::FlowEnd(end) => {
+			let state = match &end.state {
+				Some(state) => format!("state={}, ", state),
+				None => String::new(),
+			};
+			Some(format!(
+				"{} {} [{}] expired {}packets={}/{}, bytes={}/{}",
+				end.protocol,
+				iface,
+				FlowKey::new(end.client, end.server),
+				state,
+				end.to_server.packets,
+				end.to_client.packets,
+				end.to_server.bytes,
+				end.to_client.bytes
+			))
+		},
+		EventKind::Packet(packet) => Some(packet_line(event, packet)),
+		EventKind::Transaction(Transaction::Dns(dns)) => {
+			let outcome = match (&dns.rcode, dns.latency) {
+				(Some(rcode), Some(latency)) => format!("{} in {:.3}ms", rcode, latency * 1000.0),
+				(Some(rcode), None) => rcode.clone(),
+				(None, _) => "unanswered".to_string(),
+			};
+			let answers: Vec<&str> = dns.answers.iter().map(|a| a.data.as_str()).collect();
+			Some(format!(
+				"DNS {} [{} -> {}] id={} {} {} {}{} [{}]",
+				iface,
+				dns.client,
+				dns.server,
+				dns.id,
+				dns.qtype,
+				dns.name,
+				outcome,
+				if dns.truncated { " truncated" } else { "" },
+				answers.join(", ")
+			))
+		},
+		EventKind::Transaction(Transaction::Tls(tls)) => Some(format!(
+			"TLS {} [{} -> {}] sni={} version={} alpn={} ja3={} ja4={}",
+			iface,
+			tls.client,
+			tls.server,
+			tls.sni.as_deref().unwrap_or("-"),
+			tls.version.as_deref().unwrap_or("-"),
+			tls.alpn.as_deref().unwrap_or("-"),
+			tls.ja3,
+			tls.ja4
+		)),
+		EventKind::Transaction(Transaction::Quic(quic)) => Some(format!(
+			"QUIC {} [{} -> {}] version={} dcid={} sni={} alpn={} ja4={}",
+			iface,
+			quic.tls.client,
+			quic.tls.server,
+			quic.quic_version,
+			quic.dcid,
+			quic.tls.sni.as_deref().unwrap_or("-"),
+			quic.tls.alpn_offered.join(","),
+			quic.tls.ja4
+		)),
+		EventKind::Transaction(Transaction::Http(http)) => {
+			let outcome = match (http.status, http.latency) {
+				(Some(status), Some(latency)) => {
+					format!("{} in {:.3}ms", status, latency * 1000.0)
+				},
+				(Some(status), None) => status.to_string(),
+				(None, _) => "unanswered".to_string(),
+			};
+			Some(format!(
+				"HTTP {} [{} -> {}] {} {}{} {} request={}B response={}B{}",
+				iface,
+				http.client,
+				http.server,
+				http.method,
+				http.host.as_deref().unwrap_or(""),
+				http.uri,
+				outcome,
+				http.request_bytes,
+				http.response_bytes,
+				http
+					.user_agent
+					.as_ref()
+					.map(|ua| format!(" ua={:?}", ua))
+					.unwrap_or_default()
+			))
+		},
+		EventKind::Alert(Alert::Arp { ip, mac, kind }) => {
+			let detail = match kind {
+				ArpEventKind::Gratuitous => "gratuitous".to_string(),
+				ArpEventKind::MacChanged { previous_mac } => {
+					format!("mac-changed previous={}", previous_mac)
+				},
+				ArpEventKind::ManyIps { count } => format!("many-ips count={}", count),
+			};
+			Some(format!("ARP {} [{} is-at {}] {}", iface, ip, mac, detail))
+		},
+		EventKind::Counters(c) => Some(format!(
+			"{} received={} (+{}) os_dropped={} if_dropped={}",
+			iface, c.received, c.received_delta, c.os_dropped, c.if_dropped
+		)),
+	}
+}
+
+fn packet_line(event: &Event, packet: &PacketSummary) -> String {
+	let PacketSummary {
+		protocol,
+		src,
+		dst,
+		bytes,
+		..
+	} = packet;
+	let iface = &event.iface;
+
+	if let Some(text) = &packet.text {
+		return format!(
+			"{}.{:06} {} {}",
+			event.ts.as_secs(),
+			event.ts.subsec_micros(),
+			iface,
+			text
+		);
+	}
+
+	if let Some(tcp) = &packet.tcp {
+		let marker = match tcp.ordering {
+			Ordering::New => "+",
+			Ordering::Empty | Ordering::InOrder => ">",
+			Ordering::Retransmission => "=",
+			Ordering::OutOfOrder => "!",
+		};
+		let flag = |name| tcp.flags.contains(&name);
+		let mut line = format!(
+			"{} {} {} [{} -> {}] SYN={} ACK={} FIN={} RST={} seq={}, frag={}, bytes={}, state={}",
+			marker,
+			protocol,
+			iface,
+			src,
+			dst,
+			flag("SYN"),
+			flag("ACK"),
+			flag("FIN"),
+			flag("RST"),
+			tcp.seq,
+			tcp.fragmented,
+			bytes,
+			tcp.state
+		);
+		if let Some(previous) = &tcp.previous_state {
+			line.push_str(&format!(
+				"\n{} {} [{} -> {}] {} -> {}",
+				protocol, iface, src, dst, previous, tcp.state
+			));
+		}
+		return line;
+	}
+
+	if let Some(icmp) = &packet.icmp {
+		return format!(
+			"{} {} [{} -> {}] type={} code={} {}{}",
+			protocol,
+			iface,
+			src,
+			dst,
+			icmp.type_u8,
+			icmp.code_u8,
+			icmp.kind,
+			icmp_detail(icmp)
+		);
+	}
+
+	format!(
+		"{} {} [{} -> {}] bytes={}",
+		protocol, iface, src, dst, bytes
+	)
+}
+
+fn icmp_detail(icmp: &IcmpSummary) -> String {
+	if let Some(reply) = &icmp.reply {
+		return match reply.rtt {
+			Some(rtt) => format!(" rtt={:.3}ms", rtt * 1000.0),
+			None => " rtt=unknown".to_string(),
+		};
+	}
+
+	let flow = match &icmp.error {
+		Some(error) => &error.flow,
+		None => return String::new(),
+	};
+	match flow {
+		Some(flow) => match &flow.tracked {
+			Some(tracked) => format!(
+				" flow={} [{} -> {}] state={} packets={} errors={}",
+				flow.protocol,
+				flow.src,
+				flow.dst,
+				tracked.state.as_deref().unwrap_or("-"),
+				tracked.packets,
+				tracked.errors
+			),
+			None => format!(
+				" flow={} [{} -> {}] untracked",
+				flow.protocol, flow.src, flow.dst
+			),
+		},
+		None => " flow=unknown".to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use crate::{
+		events::{Event, EventKind, FlowEnd, Ordering, PacketSummary, TcpSummary, text::format},
+		flow::Endpoint,
+		state::flows::Counts,
+	};
+
+	#[test]
+	fn test_text_lines() {
+		let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+		let server = Endpoint::new(IpAddr::from([10, 0, 0, 9]), 80);
+		let event = |kind| Event {
+			ts: Duration::new(1, 2_000),
+			iface: "eth0".to_string(),
+			kind,
+		};
+
+		let packet = event(EventKind::Packet(Box::new(PacketSummary {
+			protocol: "IPv4-TCP".to_string(),
+			src: client.to_string(),
+			dst: server.to_string(),
+			bytes: 0,
+			tcp: Some(TcpSummary {
+				flags: vec!["SYN"],
+				seq: 100,
+				fragmented: false,
+				ordering: Ordering::New,
+				state: "SYN_SENT".to_string(),
+				previous_state: None,
+			}),
+			icmp: None,
+			text: None,
+		})));
+		assert_eq!(
+			"+ IPv4-TCP eth0 [10.0.0.1:40000 -> 10.0.0.9:80] SYN=true ACK=false FIN=false RST=false seq=100, frag=false, bytes=0, state=SYN_SENT",
+			format(&packet).unwrap()
+		);
+
+		let end = event(EventKind::FlowEnd(FlowEnd {
+			protocol: "IPv4-UDP".to_string(),
+			client,
+			server,
+			state: None,
+			to_server: Counts {
+				packets: 2,
+				bytes: 20,
+			},
+			to_client: Counts::default(),
+		}));
+		assert_eq!(
+			"IPv4-UDP eth0 [10.0.0.1:40000 <-> 10.0.0.9:80] expired packets=2/0, bytes=20/0",
+			format(&end).unwrap()
+		);
+		assert_eq!(
+			r#"{"ts":1.000002,"iface":"eth0","event":"flow_end","protocol":"IPv4-UDP","client":"10.0.0.1:40000","server":"10.0.0.9:80","to_server":{"packets":2,"bytes":20},"to_client":{"packets":0,"bytes":0}}"#,
+			serde_json::to_string(&end).unwrap()
+		);
+	}
+}
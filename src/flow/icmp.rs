@@ -0,0 +1,186 @@
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use etherparse::{IpNumber, LaxIpSlice};
+
+use crate::flow::{Endpoint, Protocol};
+
+pub const DEFAULT_ECHO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often, in capture time, outstanding echo requests are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// QuotedFlow is the transport flow of the original datagram which is quoted
+/// inside an ICMP error message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QuotedFlow {
+	pub protocol: Protocol,
+	pub src: Endpoint,
+	pub dst: Endpoint,
+}
+
+/// Parses the original IP header carried in the body of an ICMP error.  Only
+/// the first eight bytes of the transport header are guaranteed to be quoted,
+/// which is just enough for the ports of TCP and UDP.
+pub fn quoted_flow(payload: &[u8]) -> Option<QuotedFlow> {
+	let (ip, _) = LaxIpSlice::from_slice(payload).ok()?;
+	let inner = ip.payload();
+
+	let protocol = match inner.ip_number {
+		IpNumber::TCP => Protocol::Tcp,
+		IpNumber::UDP => Protocol::Udp,
+		_ => return None,
+	};
+	if inner.fragmented || inner.payload.len() < 4 {
+		return None;
+	}
+
+	let ports = inner.payload;
+	Some(QuotedFlow {
+		protocol,
+		src: Endpoint::new(ip.source_addr(), u16::from_be_bytes([ports[0], ports[1]])),
+		dst: Endpoint::new(
+			ip.destination_addr(),
+			u16::from_be_bytes([ports[2], ports[3]]),
+		),
+	})
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct EchoKey {
+	requester: IpAddr,
+	target: IpAddr,
+	id: u16,
+	seq: u16,
+}
+
+/// EchoTracker matches echo replies to the requests which prompted them so
+/// the round trip time can be measured.  Requests which are never answered
+/// are forgotten after the timeout.
+pub struct EchoTracker {
+	outstanding: HashMap<EchoKey, Duration>,
+	timeout: Duration,
+	last_sweep: Duration,
+}
+
+impl Default for EchoTracker {
+	fn default() -> Self {
+		EchoTracker::new(DEFAULT_ECHO_TIMEOUT)
+	}
+}
+
+impl EchoTracker {
+	pub fn new(timeout: Duration) -> EchoTracker {
+		EchoTracker {
+			outstanding: HashMap::new(),
+			timeout,
+			last_sweep: Duration::ZERO,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.outstanding.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.outstanding.is_empty()
+	}
+
+	/// Records an echo request sent from `src` to `dst`
+	pub fn request(&mut self, src: IpAddr, dst: IpAddr, id: u16, seq: u16, ts: Duration) {
+		let key = EchoKey {
+			requester: src,
+			target: dst,
+			id,
+			seq,
+		};
+		self.outstanding.entry(key).or_insert(ts);
+	}
+
+	/// Matches an echo reply sent from `src` to `dst`, returning the round trip
+	/// time when the request was seen
+	pub fn reply(
+		&mut self,
+		src: IpAddr,
+		dst: IpAddr,
+		id: u16,
+		seq: u16,
+		ts: Duration,
+	) -> Option<Duration> {
+		let key = EchoKey {
+			requester: dst,
+			target: src,
+			id,
+			seq,
+		};
+		self
+			.outstanding
+			.remove(&key)
+			.map(|sent| ts.saturating_sub(sent))
+	}
+
+	/// Forgets requests older than the timeout, returning how many were
+	/// dropped.  Only sweeps once per second of capture time.
+	pub fn expire(&mut self, now: Duration) -> usize {
+		if now.saturating_sub(self.last_sweep) < SWEEP_INTERVAL {
+			return 0;
+		}
+		self.last_sweep = now;
+
+		let before = self.outstanding.len();
+		let timeout = self.timeout;
+		self
+			.outstanding
+			.retain(|_, sent| now.saturating_sub(*sent) < timeout);
+		before - self.outstanding.len()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use etherparse::PacketBuilder;
+
+	use crate::flow::{
+		Endpoint, Protocol,
+		icmp::{EchoTracker, quoted_flow},
+	};
+
+	#[test]
+	fn test_quoted_flow_truncated_tcp() {
+		let builder = PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64).tcp(40000, 443, 1, 4096);
+		let mut datagram = vec![];
+		builder.write(&mut datagram, b"hello").unwrap();
+
+		// Routers quote the IP header and only eight bytes of its payload
+		datagram.truncate(20 + 8);
+
+		let quoted = quoted_flow(&datagram).unwrap();
+		assert_eq!(Protocol::Tcp, quoted.protocol);
+		assert_eq!(
+			Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000),
+			quoted.src
+		);
+		assert_eq!(Endpoint::new(IpAddr::from([10, 0, 0, 9]), 443), quoted.dst);
+	}
+
+	#[test]
+	fn test_echo_rtt_and_expiry() {
+		let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 9]));
+		let mut tracker = EchoTracker::new(Duration::from_secs(5));
+
+		tracker.request(a, b, 7, 1, Duration::from_millis(1000));
+		tracker.request(a, b, 7, 2, Duration::from_millis(2000));
+
+		// A reply travelling the same way as the request does not match
+		assert_eq!(None, tracker.reply(a, b, 7, 1, Duration::from_millis(1010)));
+		assert_eq!(
+			Some(Duration::from_millis(25)),
+			tracker.reply(b, a, 7, 1, Duration::from_millis(1025))
+		);
+		assert_eq!(None, tracker.reply(b, a, 7, 1, Duration::from_millis(1030)));
+
+		assert_eq!(1, tracker.expire(Duration::from_secs(8)));
+		assert!(tracker.is_empty());
+	}
+}
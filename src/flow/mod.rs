@@ -0,0 +1,92 @@
+pub mod icmp;
+pub mod reassembly;
+pub mod tcp;
+
+use std::{
+	fmt,
+	net::{IpAddr, SocketAddr},
+};
+
+use serde::{Serialize, Serializer};
+
+/// Endpoint is one side of a transport-layer conversation
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Endpoint {
+	pub ip: IpAddr,
+	pub port: u16,
+}
+
+impl Endpoint {
+	pub fn new(ip: IpAddr, port: u16) -> Endpoint {
+		Endpoint { ip, port }
+	}
+}
+
+impl fmt::Display for Endpoint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		SocketAddr::new(self.ip, self.port).fmt(f)
+	}
+}
+
+impl Serialize for Endpoint {
+	fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+		s.collect_str(self)
+	}
+}
+
+/// Protocol is the transport protocol a flow is carried over
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Protocol {
+	Tcp,
+	Udp,
+}
+
+impl fmt::Display for Protocol {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Protocol::Tcp => write!(f, "TCP"),
+			Protocol::Udp => write!(f, "UDP"),
+		}
+	}
+}
+
+/// Direction is the way a packet travels within a connection
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+	ClientToServer,
+	ServerToClient,
+}
+
+/// FlowKey identifies a conversation regardless of the direction a packet is
+/// travelling in; both `(a, b)` and `(b, a)` produce the same key.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FlowKey {
+	lower: Endpoint,
+	upper: Endpoint,
+}
+
+impl FlowKey {
+	pub fn new(src: Endpoint, dst: Endpoint) -> FlowKey {
+		if src <= dst {
+			FlowKey {
+				lower: src,
+				upper: dst,
+			}
+		} else {
+			FlowKey {
+				lower: dst,
+				upper: src,
+			}
+		}
+	}
+
+	pub fn endpoints(&self) -> (Endpoint, Endpoint) {
+		(self.lower, self.upper)
+	}
+}
+
+impl fmt::Display for FlowKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} <-> {}", self.lower, self.upper)
+	}
+}
@@ -0,0 +1,726 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::{
+		Arc,
+		atomic::{AtomicUsize, Ordering},
+	},
+	time::Duration,
+};
+
+use crate::flow::{
+	Direction, Endpoint, FlowKey,
+	tcp::{Change, Segment, TcpFlow},
+};
+
+pub const DEFAULT_FLOW_LIMIT: usize = 1024 * 1024;
+pub const DEFAULT_GLOBAL_LIMIT: usize = 64 * 1024 * 1024;
+
+/// StreamInfo describes the connection a consumer is attached to
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+	pub key: FlowKey,
+	pub client: Endpoint,
+	pub server: Endpoint,
+	/// The interface the connection was first seen on
+	pub iface: String,
+}
+
+/// StreamConsumer receives the reassembled payload of one TCP connection.
+/// Bytes are delivered exactly once and in order for each direction;
+/// retransmitted and overlapping data has already been removed.
+pub trait StreamConsumer: Send {
+	/// Called with the next contiguous bytes sent in `direction`, at the
+	/// capture time of the segment which completed them
+	fn data(&mut self, direction: Direction, ts: Duration, data: &[u8]);
+
+	/// Called when `len` bytes sent in `direction` were never captured, or were
+	/// dropped to stay within the memory limits.  Delivery resumes after them.
+	fn gap(&mut self, _direction: Direction, _len: u64) {}
+
+	/// Called once, when the connection closes or is evicted
+	fn close(&mut self) {}
+
+	/// Whether the consumer wants nothing more of the connection.  Once every
+	/// consumer of a connection is done, it is closed and no longer
+	/// reassembled.
+	fn done(&self) -> bool {
+		false
+	}
+}
+
+/// StreamConsumerFactory decides which connections a parser is interested in,
+/// and creates a consumer for each of them
+pub trait StreamConsumerFactory: Send {
+	fn new_consumer(&mut self, info: &StreamInfo) -> Option<Box<dyn StreamConsumer>>;
+}
+
+/// MemoryBudget is a limit on buffered out-of-order data which is shared by
+/// every stream it is cloned into
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+	used: Arc<AtomicUsize>,
+	limit: usize,
+}
+
+impl MemoryBudget {
+	pub fn new(limit: usize) -> MemoryBudget {
+		MemoryBudget {
+			used: Arc::new(AtomicUsize::new(0)),
+			limit,
+		}
+	}
+
+	pub fn used(&self) -> usize {
+		self.used.load(Ordering::Relaxed)
+	}
+
+	fn try_reserve(&self, n: usize) -> bool {
+		self
+			.used
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+				(used + n <= self.limit).then_some(used + n)
+			})
+			.is_ok()
+	}
+
+	fn release(&self, n: usize) {
+		self.used.fetch_sub(n, Ordering::Relaxed);
+	}
+}
+
+impl Default for MemoryBudget {
+	fn default() -> Self {
+		MemoryBudget::new(DEFAULT_GLOBAL_LIMIT)
+	}
+}
+
+/// Reassembler turns the segments of one direction into a contiguous byte
+/// stream.  Offsets are tracked as 64-bit positions from the start of the
+/// stream, so the buffer is unaffected by sequence number wraparound.
+#[derive(Default)]
+struct Reassembler {
+	/// Sequence number of the byte at `delivered`
+	next_seq: Option<u32>,
+
+	/// Number of bytes handed to the consumers, including skipped gaps
+	delivered: u64,
+
+	/// Out-of-order data, keyed by stream offset
+	pending: BTreeMap<u64, Vec<u8>>,
+
+	/// Ranges ahead which were on the wire but not captured, as their start
+	/// and end offsets.  They hold no data, so do not count towards the limits.
+	holes: BTreeMap<u64, u64>,
+
+	finished: bool,
+}
+
+/// Limits which apply to one direction of a stream
+struct Limits<'a> {
+	flow_buffered: &'a mut usize,
+	flow_limit: usize,
+	budget: &'a MemoryBudget,
+}
+
+impl Reassembler {
+	fn insert(&mut self, seq: u32, data: &[u8], limits: Limits, deliver: &mut dyn FnMut(Delivery)) {
+		if self.finished {
+			return;
+		}
+
+		let next_seq = *self.next_seq.get_or_insert(seq);
+
+		// Position of this segment relative to the next expected byte; segments
+		// further than 2GiB away are treated as being behind
+		let rel = seq.wrapping_sub(next_seq) as i32 as i64;
+		let start = self.delivered as i64 + rel;
+		let end = start + data.len() as i64;
+
+		if end <= self.delivered as i64 || data.is_empty() {
+			return;
+		}
+
+		let skip = (self.delivered as i64 - start).max(0) as usize;
+		let start = start.max(self.delivered as i64) as u64;
+		let data = &data[skip..];
+
+		if start == self.delivered {
+			self.advance(data, deliver);
+			self.drain(limits.flow_buffered, limits.budget, deliver);
+			return;
+		}
+
+		self.buffer(start, data, limits, deliver);
+	}
+
+	/// Skips sequence space which was on the wire but not captured, such as
+	/// the payload of a frame cut short by the snaplen.  A hole at the front
+	/// is reported as a gap at once; one further on waits in `holes` until
+	/// the data before it has been delivered.
+	fn missing(&mut self, seq: u32, len: u32, limits: Limits, deliver: &mut dyn FnMut(Delivery)) {
+		if self.finished || len == 0 {
+			return;
+		}
+
+		let next_seq = *self.next_seq.get_or_insert(seq);
+		let start = self.delivered as i64 + seq.wrapping_sub(next_seq) as i32 as i64;
+		let end = start + len as i64;
+		if end <= self.delivered as i64 {
+			return;
+		}
+
+		if start > self.delivered as i64 {
+			let hole = self.holes.entry(start as u64).or_default();
+			*hole = (*hole).max(end as u64);
+			return;
+		}
+
+		let gap = (end - self.delivered as i64) as u64;
+		deliver(Delivery::Gap(gap));
+		self.skip(gap);
+		self.drain(limits.flow_buffered, limits.budget, deliver);
+	}
+
+	/// Stores out-of-order data, keeping whatever arrived first where segments
+	/// overlap
+	fn buffer(&mut self, start: u64, data: &[u8], limits: Limits, deliver: &mut dyn FnMut(Delivery)) {
+		let end = start + data.len() as u64;
+
+		let mut pieces = vec![];
+		let mut cursor = start;
+		for (&s, v) in self.pending.range(..end) {
+			let e = s + v.len() as u64;
+			if e <= cursor {
+				continue;
+			}
+			if s > cursor {
+				pieces.push((cursor, s));
+			}
+			cursor = cursor.max(e);
+		}
+		if cursor < end {
+			pieces.push((cursor, end));
+		}
+
+		let needed: usize = pieces.iter().map(|(s, e)| (e - s) as usize).sum();
+		if needed == 0 {
+			return;
+		}
+
+		if *limits.flow_buffered + needed > limits.flow_limit || !limits.budget.try_reserve(needed) {
+			// Give up on the missing data rather than exceeding the limits
+			self.flush(limits.flow_buffered, limits.budget, deliver);
+
+			// The flush may have delivered part of this segment already
+			if start > self.delivered {
+				let gap = start - self.delivered;
+				deliver(Delivery::Gap(gap));
+				self.skip(gap);
+				self.advance(data, deliver);
+			} else {
+				let skip = (self.delivered - start) as usize;
+				if skip < data.len() {
+					self.advance(&data[skip..], deliver);
+				}
+			}
+			return;
+		}
+
+		*limits.flow_buffered += needed;
+		for (s, e) in pieces {
+			let from = (s - start) as usize;
+			let to = (e - start) as usize;
+			self.pending.insert(s, data[from..to].to_vec());
+		}
+	}
+
+	fn advance(&mut self, data: &[u8], deliver: &mut dyn FnMut(Delivery)) {
+		deliver(Delivery::Data(data));
+		self.skip(data.len() as u64);
+	}
+
+	fn skip(&mut self, len: u64) {
+		self.delivered += len;
+		self.next_seq = self.next_seq.map(|s| s.wrapping_add(len as u32));
+	}
+
+	/// Delivers buffered data which has become contiguous, and reports the
+	/// holes it reaches as gaps.  Data is taken first when both are due.
+	fn drain(
+		&mut self,
+		flow_buffered: &mut usize,
+		budget: &MemoryBudget,
+		deliver: &mut dyn FnMut(Delivery),
+	) {
+		loop {
+			if let Some(entry) = self.pending.first_entry()
+				&& *entry.key() <= self.delivered
+			{
+				let (start, data) = entry.remove_entry();
+				*flow_buffered -= data.len();
+				budget.release(data.len());
+
+				let skip = (self.delivered - start) as usize;
+				if skip < data.len() {
+					self.advance(&data[skip..], deliver);
+				}
+				continue;
+			}
+
+			if let Some(entry) = self.holes.first_entry()
+				&& *entry.key() <= self.delivered
+			{
+				let end = entry.remove();
+				if end > self.delivered {
+					let gap = end - self.delivered;
+					deliver(Delivery::Gap(gap));
+					self.skip(gap);
+				}
+				continue;
+			}
+
+			break;
+		}
+	}
+
+	/// Delivers everything which is buffered, reporting the holes as gaps
+	fn flush(
+		&mut self,
+		flow_buffered: &mut usize,
+		budget: &MemoryBudget,
+		deliver: &mut dyn FnMut(Delivery),
+	) {
+		loop {
+			let start = match (self.pending.first_key_value(), self.holes.first_key_value()) {
+				(Some((&p, _)), Some((&h, _))) => p.min(h),
+				(Some((&p, _)), None) => p,
+				(None, Some((&h, _))) => h,
+				(None, None) => break,
+			};
+			if start > self.delivered {
+				let gap = start - self.delivered;
+				deliver(Delivery::Gap(gap));
+				self.skip(gap);
+			}
+			self.drain(flow_buffered, budget, deliver);
+		}
+	}
+}
+
+enum Delivery<'a> {
+	Data(&'a [u8]),
+	Gap(u64),
+}
+
+/// Stream holds the reassembly state and consumers of one connection
+struct Stream {
+	info: StreamInfo,
+	to_server: Reassembler,
+	to_client: Reassembler,
+	buffered: usize,
+	consumers: Vec<Box<dyn StreamConsumer>>,
+	/// Capture time of the latest segment
+	last_seen: Duration,
+}
+
+impl Stream {
+	fn close(mut self, budget: &MemoryBudget) {
+		let ts = self.last_seen;
+		for direction in [Direction::ClientToServer, Direction::ServerToClient] {
+			let consumers = &mut self.consumers;
+			let mut deliver = |d: Delivery| dispatch(consumers, direction, ts, d);
+			let reassembler = match direction {
+				Direction::ClientToServer => &mut self.to_server,
+				Direction::ServerToClient => &mut self.to_client,
+			};
+			reassembler.flush(&mut self.buffered, budget, &mut deliver);
+		}
+
+		for consumer in self.consumers.iter_mut() {
+			consumer.close();
+		}
+	}
+}
+
+fn dispatch(
+	consumers: &mut [Box<dyn StreamConsumer>],
+	direction: Direction,
+	ts: Duration,
+	delivery: Delivery,
+) {
+	for consumer in consumers.iter_mut() {
+		match delivery {
+			Delivery::Data(data) => consumer.data(direction, ts, data),
+			Delivery::Gap(len) => consumer.gap(direction, len),
+		}
+	}
+}
+
+/// StreamTable reassembles the connections which at least one consumer is
+/// interested in.  Connections nobody wants are not buffered at all.
+pub struct StreamTable {
+	streams: HashMap<FlowKey, Stream>,
+	factories: Vec<Box<dyn StreamConsumerFactory>>,
+	flow_limit: usize,
+	budget: MemoryBudget,
+}
+
+impl StreamTable {
+	pub fn new(
+		factories: Vec<Box<dyn StreamConsumerFactory>>,
+		flow_limit: usize,
+		budget: MemoryBudget,
+	) -> StreamTable {
+		StreamTable {
+			streams: HashMap::new(),
+			factories,
+			flow_limit,
+			budget,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.streams.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.streams.is_empty()
+	}
+
+	/// Feeds a segment which the flow table has already applied to `flow`
+	#[allow(clippy::too_many_arguments)]
+	pub fn process(
+		&mut self,
+		key: FlowKey,
+		flow: &TcpFlow,
+		change: &Change,
+		iface: &str,
+		src: Endpoint,
+		segment: &Segment,
+		payload: &[u8],
+		ts: Duration,
+	) {
+		if self.factories.is_empty() {
+			return;
+		}
+
+		// A new flow with an existing stream means the 4-tuple was reused
+		if change.new_flow {
+			self.close(&key);
+
+			let info = StreamInfo {
+				key,
+				client: flow.client,
+				server: flow.server,
+				iface: iface.to_string(),
+			};
+			let consumers: Vec<Box<dyn StreamConsumer>> = self
+				.factories
+				.iter_mut()
+				.filter_map(|f| f.new_consumer(&info))
+				.collect();
+			if !consumers.is_empty() {
+				self.streams.insert(
+					key,
+					Stream {
+						info,
+						to_server: Reassembler::default(),
+						to_client: Reassembler::default(),
+						buffered: 0,
+						consumers,
+						last_seen: ts,
+					},
+				);
+			}
+		}
+
+		let stream = match self.streams.get_mut(&key) {
+			Some(s) => s,
+			None => return,
+		};
+		stream.last_seen = ts;
+
+		let direction = if src == stream.info.client {
+			Direction::ClientToServer
+		} else {
+			Direction::ServerToClient
+		};
+		let reassembler = match direction {
+			Direction::ClientToServer => &mut stream.to_server,
+			Direction::ServerToClient => &mut stream.to_client,
+		};
+
+		// Data carried on a SYN starts after the SYN's sequence number
+		let seq = if segment.syn {
+			reassembler
+				.next_seq
+				.get_or_insert(segment.seq.wrapping_add(1));
+			segment.seq.wrapping_add(1)
+		} else {
+			segment.seq
+		};
+
+		let consumers = &mut stream.consumers;
+		let mut deliver = |d: Delivery| dispatch(consumers, direction, ts, d);
+		let limits = Limits {
+			flow_buffered: &mut stream.buffered,
+			flow_limit: self.flow_limit,
+			budget: &self.budget,
+		};
+		// Consumers are never handed part of a payload as if it were whole
+		if payload.len() < segment.payload_len as usize {
+			reassembler.missing(seq, segment.payload_len, limits, &mut deliver);
+		} else {
+			reassembler.insert(seq, payload, limits, &mut deliver);
+		}
+
+		if segment.fin {
+			reassembler.flush(&mut stream.buffered, &self.budget, &mut deliver);
+			reassembler.finished = true;
+		}
+
+		if change.state.is_closed() || stream.consumers.iter().all(|c| c.done()) {
+			self.close(&key);
+		}
+	}
+
+	/// Delivers whatever is still buffered for a connection and closes its
+	/// consumers
+	pub fn close(&mut self, key: &FlowKey) {
+		if let Some(stream) = self.streams.remove(key) {
+			stream.close(&self.budget);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::IpAddr,
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	use crate::flow::{
+		Direction, Endpoint,
+		reassembly::{MemoryBudget, StreamConsumer, StreamConsumerFactory, StreamInfo, StreamTable},
+		tcp::{Segment, TcpFlowTable},
+	};
+
+	#[derive(Clone, Default)]
+	struct Recorded {
+		to_server: Vec<u8>,
+		to_client: Vec<u8>,
+		gaps: Vec<(Direction, u64)>,
+		closed: bool,
+	}
+
+	struct Recorder(Arc<Mutex<Recorded>>);
+
+	impl StreamConsumer for Recorder {
+		fn data(&mut self, direction: Direction, _ts: Duration, data: &[u8]) {
+			let mut r = self.0.lock().unwrap();
+			match direction {
+				Direction::ClientToServer => r.to_server.extend_from_slice(data),
+				Direction::ServerToClient => r.to_client.extend_from_slice(data),
+			}
+		}
+
+		fn gap(&mut self, direction: Direction, len: u64) {
+			self.0.lock().unwrap().gaps.push((direction, len));
+		}
+
+		fn close(&mut self) {
+			self.0.lock().unwrap().closed = true;
+		}
+	}
+
+	struct RecorderFactory(Arc<Mutex<Recorded>>);
+
+	impl StreamConsumerFactory for RecorderFactory {
+		fn new_consumer(&mut self, _info: &StreamInfo) -> Option<Box<dyn StreamConsumer>> {
+			Some(Box::new(Recorder(self.0.clone())))
+		}
+	}
+
+	struct Harness {
+		flows: TcpFlowTable,
+		streams: StreamTable,
+		recorded: Arc<Mutex<Recorded>>,
+		client: Endpoint,
+		server: Endpoint,
+	}
+
+	impl Harness {
+		fn new(flow_limit: usize) -> Harness {
+			let recorded = Arc::new(Mutex::new(Recorded::default()));
+			Harness {
+				flows: TcpFlowTable::default(),
+				streams: StreamTable::new(
+					vec![Box::new(RecorderFactory(recorded.clone()))],
+					flow_limit,
+					MemoryBudget::new(1024),
+				),
+				recorded,
+				client: Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000),
+				server: Endpoint::new(IpAddr::from([10, 0, 0, 9]), 80),
+			}
+		}
+
+		fn send(&mut self, from_client: bool, seq: u32, flags: &str, payload: &[u8]) {
+			self.send_captured(from_client, seq, flags, payload, payload.len());
+		}
+
+		/// Sends a segment of which only the first `captured` bytes were
+		/// captured
+		fn send_captured(
+			&mut self,
+			from_client: bool,
+			seq: u32,
+			flags: &str,
+			payload: &[u8],
+			captured: usize,
+		) {
+			let (src, dst) = match from_client {
+				true => (self.client, self.server),
+				false => (self.server, self.client),
+			};
+			let segment = Segment {
+				seq,
+				ack: 0,
+				syn: flags.contains('S'),
+				ack_flag: flags.contains('.'),
+				fin: flags.contains('F'),
+				rst: flags.contains('R'),
+				payload_len: payload.len() as u32,
+				..Default::default()
+			};
+			let (key, change) = self.flows.process(src, dst, &segment, Duration::ZERO);
+			let flow = self.flows.get(&key).unwrap();
+			self.streams.process(
+				key,
+				flow,
+				&change,
+				"eth0",
+				src,
+				&segment,
+				&payload[..captured],
+				Duration::ZERO,
+			);
+		}
+
+		fn recorded(&self) -> Recorded {
+			self.recorded.lock().unwrap().clone()
+		}
+	}
+
+	#[test]
+	fn test_out_of_order_and_retransmission() {
+		let mut h = Harness::new(1024);
+		let isn = u32::MAX - 3;
+
+		h.send(true, isn, "S", b"");
+		h.send(false, 7000, "S.", b"");
+		h.send(true, isn.wrapping_add(6), ".", b"world");
+		h.send(true, isn.wrapping_add(1), ".", b"hello");
+		h.send(true, isn.wrapping_add(1), ".", b"hel");
+		h.send(true, isn.wrapping_add(4), ".", b"loXwo");
+		h.send(true, isn.wrapping_add(11), ".", b"!");
+		h.send(false, 7001, ".", b"ok");
+
+		let r = h.recorded();
+		assert_eq!(b"helloworld!".to_vec(), r.to_server);
+		assert_eq!(b"ok".to_vec(), r.to_client);
+		assert!(r.gaps.is_empty());
+		assert_eq!(0, h.streams.budget.used());
+	}
+
+	#[test]
+	fn test_gap_when_over_limit() {
+		let mut h = Harness::new(4);
+
+		h.send(true, 100, ".", b"ab");
+		h.send(true, 110, ".", b"cd");
+		h.send(true, 120, ".", b"efgh");
+
+		let r = h.recorded();
+		assert_eq!(b"abcdefgh".to_vec(), r.to_server);
+		assert_eq!(
+			vec![
+				(Direction::ClientToServer, 8),
+				(Direction::ClientToServer, 8)
+			],
+			r.gaps
+		);
+		assert_eq!(0, h.streams.budget.used());
+	}
+
+	#[test]
+	fn test_overlap_when_over_limit() {
+		let mut h = Harness::new(4);
+
+		h.send(true, 100, ".", b"ab");
+		h.send(true, 106, ".", b"ghij");
+		// Overlaps what is buffered, and would take the flow over its limit
+		h.send(true, 105, ".", b"Xghijkl");
+
+		let r = h.recorded();
+		assert_eq!(b"abghijkl".to_vec(), r.to_server);
+		assert_eq!(vec![(Direction::ClientToServer, 4)], r.gaps);
+		assert_eq!(0, h.streams.budget.used());
+	}
+
+	#[test]
+	fn test_truncated_segment_is_a_gap() {
+		let mut h = Harness::new(1024);
+
+		h.send(true, 100, ".", b"ab");
+		h.send(true, 104, ".", b"ef");
+		h.send_captured(true, 102, ".", b"cd", 1);
+		h.send(true, 106, ".", b"gh");
+
+		let r = h.recorded();
+		assert_eq!(b"abefgh".to_vec(), r.to_server);
+		assert_eq!(vec![(Direction::ClientToServer, 2)], r.gaps);
+	}
+
+	#[test]
+	fn test_truncated_segments_out_of_order() {
+		let mut h = Harness::new(1024);
+
+		// Under a short snaplen a reordered segment leaves the next truncated
+		// one ahead of the stream
+		h.send(true, 100, ".", b"ab");
+		h.send_captured(true, 104, ".", b"ef", 1);
+		h.send(true, 108, ".", b"ij");
+		h.send_captured(true, 102, ".", b"cd", 1);
+		h.send_captured(true, 106, ".", b"gh", 1);
+		h.send(true, 110, ".", b"kl");
+
+		let r = h.recorded();
+		assert_eq!(b"abijkl".to_vec(), r.to_server);
+		assert_eq!(
+			vec![
+				(Direction::ClientToServer, 2),
+				(Direction::ClientToServer, 2),
+				(Direction::ClientToServer, 2)
+			],
+			r.gaps
+		);
+	}
+
+	#[test]
+	fn test_close_flushes() {
+		let mut h = Harness::new(1024);
+
+		h.send(true, 100, ".", b"ab");
+		h.send(true, 104, ".", b"ef");
+		h.send(false, 500, "R", b"");
+
+		let r = h.recorded();
+		assert_eq!(b"abef".to_vec(), r.to_server);
+		assert_eq!(vec![(Direction::ClientToServer, 2)], r.gaps);
+		assert!(r.closed);
+		assert!(h.streams.is_empty());
+	}
+}
@@ -0,0 +1,585 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use etherparse::TcpSlice;
+
+use crate::flow::{Endpoint, FlowKey};
+
+/// Sequence numbers are compared using serial number arithmetic (RFC 1982), so
+/// that ordering is preserved when the 32-bit counter wraps around.
+pub fn seq_lt(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_le(a: u32, b: u32) -> bool {
+	a == b || seq_lt(a, b)
+}
+
+pub fn seq_gt(a: u32, b: u32) -> bool {
+	seq_lt(b, a)
+}
+
+pub fn seq_ge(a: u32, b: u32) -> bool {
+	a == b || seq_gt(a, b)
+}
+
+/// TcpState is the state of a connection as seen by a passive observer.  The
+/// names follow RFC 793, but describe the connection as a whole rather than
+/// either endpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpState {
+	/// The client has sent a SYN
+	SynSent,
+
+	/// The server has answered with a SYN-ACK
+	SynReceived,
+
+	/// The handshake has completed, or the connection was picked up mid-stream
+	Established,
+
+	/// One side has sent a FIN which has not been acknowledged
+	FinWait1,
+
+	/// One side has sent a FIN which has been acknowledged
+	FinWait2,
+
+	/// Both sides have sent a FIN, but not every FIN has been acknowledged
+	Closing,
+
+	/// Both sides have sent a FIN and both have been acknowledged
+	TimeWait,
+
+	/// The connection was aborted by a RST
+	Reset,
+}
+
+impl TcpState {
+	/// Whether the connection has finished, so that only stragglers are expected
+	pub fn is_closed(&self) -> bool {
+		matches!(self, TcpState::TimeWait | TcpState::Reset)
+	}
+}
+
+impl fmt::Display for TcpState {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let s = match self {
+			TcpState::SynSent => "SYN_SENT",
+			TcpState::SynReceived => "SYN_RECEIVED",
+			TcpState::Established => "ESTABLISHED",
+			TcpState::FinWait1 => "FIN_WAIT_1",
+			TcpState::FinWait2 => "FIN_WAIT_2",
+			TcpState::Closing => "CLOSING",
+			TcpState::TimeWait => "TIME_WAIT",
+			TcpState::Reset => "RESET",
+		};
+		f.write_str(s)
+	}
+}
+
+/// Flags is a set of TCP header flags, using the bits of the header
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Flags(pub u8);
+
+impl Flags {
+	const NAMES: [&str; 8] = ["FIN", "SYN", "RST", "PSH", "ACK", "URG", "ECE", "CWR"];
+
+	/// The names of the flags which are set, lowest bit first
+	pub fn names(&self) -> Vec<&'static str> {
+		Flags::NAMES
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| self.0 & (1 << i) != 0)
+			.map(|(_, name)| *name)
+			.collect()
+	}
+}
+
+/// Segment holds the parts of a TCP header the state machine needs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Segment {
+	pub seq: u32,
+	pub ack: u32,
+	pub syn: bool,
+	pub ack_flag: bool,
+	pub fin: bool,
+	pub rst: bool,
+	pub payload_len: u32,
+
+	/// Every flag, including those the state machine ignores
+	pub flags: Flags,
+}
+
+impl Segment {
+	/// The amount of sequence space this segment occupies; SYN and FIN each
+	/// count as one
+	pub fn seq_len(&self) -> u32 {
+		self.payload_len + self.syn as u32 + self.fin as u32
+	}
+
+	/// Reads a segment whose payload was `payload_len` bytes on the wire,
+	/// however much of it was captured
+	pub fn new(tcp: &TcpSlice<'_>, payload_len: u32) -> Segment {
+		Segment {
+			seq: tcp.sequence_number(),
+			ack: tcp.acknowledgment_number(),
+			syn: tcp.syn(),
+			ack_flag: tcp.ack(),
+			fin: tcp.fin(),
+			rst: tcp.rst(),
+			payload_len,
+			flags: Flags(tcp.slice()[13]),
+		}
+	}
+}
+
+/// Ordering describes where a segment falls relative to what has already been
+/// seen in the same direction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ordering {
+	/// The segment carries no sequence space (a bare ACK)
+	Empty,
+
+	/// The segment starts exactly where the previous one ended
+	InOrder,
+
+	/// The segment only covers sequence space which was already seen
+	Retransmission,
+
+	/// The segment starts beyond the next expected sequence number
+	OutOfOrder,
+}
+
+/// Half tracks one direction of a connection
+#[derive(Clone, Debug, Default)]
+pub struct Half {
+	pub packets: u64,
+	pub bytes: u64,
+	pub retransmissions: u64,
+	pub out_of_order: u64,
+
+	/// Every flag sent in this direction
+	pub flags: Flags,
+
+	/// Initial sequence number, when the SYN was seen
+	pub isn: Option<u32>,
+
+	/// The sequence number following the highest byte seen
+	pub next_seq: Option<u32>,
+
+	/// Sequence number of the FIN, if one was sent
+	pub fin_seq: Option<u32>,
+	pub fin_acked: bool,
+}
+
+impl Half {
+	fn update(&mut self, segment: &Segment) -> Ordering {
+		self.packets += 1;
+		self.bytes += segment.payload_len as u64;
+		self.flags.0 |= segment.flags.0;
+
+		let len = segment.seq_len();
+		let end = segment.seq.wrapping_add(len);
+
+		if segment.syn {
+			self.isn = Some(segment.seq);
+		}
+		if segment.fin {
+			self.fin_seq = Some(end.wrapping_sub(1));
+		}
+
+		let next = match self.next_seq {
+			Some(next) => next,
+			None => {
+				self.next_seq = Some(end);
+				return if len == 0 {
+					Ordering::Empty
+				} else {
+					Ordering::InOrder
+				};
+			},
+		};
+
+		if len == 0 {
+			return Ordering::Empty;
+		}
+
+		let ordering = if segment.seq == next {
+			Ordering::InOrder
+		} else if seq_lt(segment.seq, next) {
+			if seq_le(end, next) {
+				self.retransmissions += 1;
+				Ordering::Retransmission
+			} else {
+				// Overlaps what was seen, but also carries new data
+				Ordering::InOrder
+			}
+		} else {
+			self.out_of_order += 1;
+			Ordering::OutOfOrder
+		};
+
+		if seq_gt(end, next) {
+			self.next_seq = Some(end);
+		}
+
+		ordering
+	}
+
+	fn acknowledge(&mut self, ack: u32) {
+		if let Some(fin_seq) = self.fin_seq
+			&& seq_gt(ack, fin_seq)
+		{
+			self.fin_acked = true;
+		}
+	}
+}
+
+/// TcpFlow is a single bidirectional TCP connection
+#[derive(Clone, Debug)]
+pub struct TcpFlow {
+	pub state: TcpState,
+
+	/// The endpoint which opened the connection.  For connections picked up
+	/// mid-stream this is a guess.
+	pub client: Endpoint,
+	pub server: Endpoint,
+
+	pub client_half: Half,
+	pub server_half: Half,
+
+	/// Whether the handshake was missed
+	pub midstream: bool,
+
+	/// Capture timestamps of the first and latest packets
+	pub first_seen: Duration,
+	pub last_seen: Duration,
+
+	/// Capture timestamp of the client's SYN, when it was seen
+	pub syn_seen: Option<Duration>,
+
+	/// The handshake round trip as seen by the observer, from the SYN to the
+	/// ACK which completed it
+	pub rtt: Option<Duration>,
+}
+
+impl TcpFlow {
+	fn new(src: Endpoint, dst: Endpoint, segment: &Segment, ts: Duration) -> TcpFlow {
+		let (state, client, server, midstream) = if segment.syn && !segment.ack_flag {
+			(TcpState::SynSent, src, dst, false)
+		} else if segment.syn {
+			(TcpState::SynReceived, dst, src, false)
+		} else if src.port < 1024 && dst.port >= 1024 {
+			// Without a handshake, assume the well-known port is the server
+			(TcpState::Established, dst, src, true)
+		} else {
+			(TcpState::Established, src, dst, true)
+		};
+
+		TcpFlow {
+			state,
+			client,
+			server,
+			client_half: Half::default(),
+			server_half: Half::default(),
+			midstream,
+			first_seen: ts,
+			last_seen: ts,
+			syn_seen: (state == TcpState::SynSent).then_some(ts),
+			rtt: None,
+		}
+	}
+
+	/// Applies a segment sent by `src`, returning where the segment fell in the
+	/// sender's sequence space
+	pub fn update(&mut self, src: Endpoint, segment: &Segment, ts: Duration) -> Ordering {
+		self.last_seen = ts;
+
+		let from_client = src == self.client;
+		let (sender, receiver) = if from_client {
+			(&mut self.client_half, &mut self.server_half)
+		} else {
+			(&mut self.server_half, &mut self.client_half)
+		};
+
+		let ordering = sender.update(segment);
+		if segment.ack_flag {
+			receiver.acknowledge(segment.ack);
+		}
+
+		if segment.rst {
+			self.state = TcpState::Reset;
+			return ordering;
+		}
+
+		self.state = match self.state {
+			TcpState::SynSent if segment.syn && segment.ack_flag && !from_client => TcpState::SynReceived,
+			TcpState::SynReceived if segment.ack_flag && !segment.syn && from_client => {
+				let established = match self.server_half.isn {
+					Some(isn) => segment.ack == isn.wrapping_add(1),
+					None => true,
+				};
+				if !established {
+					TcpState::SynReceived
+				} else {
+					self.rtt = self.syn_seen.map(|syn| ts.saturating_sub(syn));
+					TcpState::Established
+				}
+			},
+			TcpState::Reset => TcpState::Reset,
+			state => self.closing_state().unwrap_or(state),
+		};
+
+		ordering
+	}
+
+	/// Derives the state from the FINs which have been seen, if any
+	fn closing_state(&self) -> Option<TcpState> {
+		let (c, s) = (&self.client_half, &self.server_half);
+		match (c.fin_seq.is_some(), s.fin_seq.is_some()) {
+			(false, false) => None,
+			(true, true) if c.fin_acked && s.fin_acked => Some(TcpState::TimeWait),
+			(true, true) => Some(TcpState::Closing),
+			(true, false) if c.fin_acked => Some(TcpState::FinWait2),
+			(false, true) if s.fin_acked => Some(TcpState::FinWait2),
+			_ => Some(TcpState::FinWait1),
+		}
+	}
+
+	/// Whether a SYN from `src` starts a new connection reusing this 4-tuple
+	fn is_reused_by(&self, src: Endpoint, segment: &Segment) -> bool {
+		segment.syn
+			&& !segment.ack_flag
+			&& self.state.is_closed()
+			&& (src != self.client || self.client_half.isn != Some(segment.seq))
+	}
+}
+
+/// Change reports what happened to a flow as a result of a packet
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Change {
+	pub ordering: Ordering,
+	pub new_flow: bool,
+	pub previous_state: Option<TcpState>,
+	pub state: TcpState,
+}
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub const DEFAULT_CLOSED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often, in capture time, the table is swept for expired flows
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// TcpFlowTable tracks every TCP connection seen, and evicts connections which
+/// are idle or have closed.  Time is taken from the capture timestamps so that
+/// replayed traces expire flows the same way live captures do.
+pub struct TcpFlowTable {
+	flows: HashMap<FlowKey, TcpFlow>,
+	idle_timeout: Duration,
+	closed_timeout: Duration,
+	last_sweep: Duration,
+}
+
+impl Default for TcpFlowTable {
+	fn default() -> Self {
+		TcpFlowTable::new(DEFAULT_IDLE_TIMEOUT, DEFAULT_CLOSED_TIMEOUT)
+	}
+}
+
+impl TcpFlowTable {
+	pub fn new(idle_timeout: Duration, closed_timeout: Duration) -> TcpFlowTable {
+		TcpFlowTable {
+			flows: HashMap::new(),
+			idle_timeout,
+			closed_timeout,
+			last_sweep: Duration::ZERO,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.flows.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.flows.is_empty()
+	}
+
+	pub fn get(&self, key: &FlowKey) -> Option<&TcpFlow> {
+		self.flows.get(key)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&FlowKey, &TcpFlow)> {
+		self.flows.iter()
+	}
+
+	/// Applies a segment travelling from `src` to `dst`
+	pub fn process(
+		&mut self,
+		src: Endpoint,
+		dst: Endpoint,
+		segment: &Segment,
+		ts: Duration,
+	) -> (FlowKey, Change) {
+		let key = FlowKey::new(src, dst);
+
+		if let Some(flow) = self.flows.get(&key)
+			&& flow.is_reused_by(src, segment)
+		{
+			self.flows.remove(&key);
+		}
+
+		let mut new_flow = false;
+		let flow = self.flows.entry(key).or_insert_with(|| {
+			new_flow = true;
+			TcpFlow::new(src, dst, segment, ts)
+		});
+
+		let previous_state = (!new_flow).then_some(flow.state);
+		let ordering = flow.update(src, segment, ts);
+
+		(
+			key,
+			Change {
+				ordering,
+				new_flow,
+				previous_state,
+				state: flow.state,
+			},
+		)
+	}
+
+	/// Removes and returns flows which have been idle for longer than the idle
+	/// timeout, or which closed longer ago than the closed timeout.  The table
+	/// is only swept once per second of capture time.
+	pub fn expire(&mut self, now: Duration) -> Vec<(FlowKey, TcpFlow)> {
+		if now.saturating_sub(self.last_sweep) < SWEEP_INTERVAL {
+			return vec![];
+		}
+		self.last_sweep = now;
+
+		let (idle_timeout, closed_timeout) = (self.idle_timeout, self.closed_timeout);
+		let expired: Vec<FlowKey> = self
+			.flows
+			.iter()
+			.filter(|(_, flow)| {
+				let idle = now.saturating_sub(flow.last_seen);
+				idle >= idle_timeout || (flow.state.is_closed() && idle >= closed_timeout)
+			})
+			.map(|(key, _)| *key)
+			.collect();
+
+		expired
+			.into_iter()
+			.filter_map(|key| self.flows.remove(&key).map(|flow| (key, flow)))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use crate::flow::{
+		Endpoint, FlowKey,
+		tcp::{Flags, Ordering, Segment, TcpFlowTable, TcpState, seq_gt, seq_lt},
+	};
+
+	fn endpoints() -> (Endpoint, Endpoint) {
+		(
+			Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000),
+			Endpoint::new(IpAddr::from([10, 0, 0, 9]), 80),
+		)
+	}
+
+	fn segment(seq: u32, ack: u32, flags: &str, payload_len: u32) -> Segment {
+		Segment {
+			seq,
+			ack,
+			syn: flags.contains('S'),
+			ack_flag: flags.contains('.'),
+			fin: flags.contains('F'),
+			rst: flags.contains('R'),
+			payload_len,
+			flags: Flags(
+				flags.contains('F') as u8
+					| (flags.contains('S') as u8) << 1
+					| (flags.contains('R') as u8) << 2
+					| (flags.contains('.') as u8) << 4,
+			),
+		}
+	}
+
+	#[test]
+	fn test_seq_wraparound() {
+		assert!(seq_lt(u32::MAX - 10, 5));
+		assert!(seq_gt(5, u32::MAX - 10));
+		assert!(seq_lt(1, 2));
+		assert!(!seq_lt(2, 2));
+	}
+
+	#[test]
+	fn test_lifecycle() {
+		let (c, s) = endpoints();
+		let mut table = TcpFlowTable::default();
+
+		let steps = [
+			(c, s, segment(100, 0, "S", 0), TcpState::SynSent),
+			(s, c, segment(500, 101, "S.", 0), TcpState::SynReceived),
+			(c, s, segment(101, 501, ".", 0), TcpState::Established),
+			(c, s, segment(101, 501, ".", 10), TcpState::Established),
+			(s, c, segment(501, 111, "F.", 0), TcpState::FinWait1),
+			(c, s, segment(111, 502, ".", 0), TcpState::FinWait2),
+			(c, s, segment(111, 502, "F.", 0), TcpState::Closing),
+			(s, c, segment(502, 112, ".", 0), TcpState::TimeWait),
+		];
+
+		for (i, (src, dst, seg, state)) in steps.into_iter().enumerate() {
+			let ts = Duration::from_millis(1000 + 10 * i as u64);
+			let (key, change) = table.process(src, dst, &seg, ts);
+			assert_eq!(FlowKey::new(c, s), key);
+			assert_eq!(state, change.state, "{:?}", seg);
+		}
+
+		assert_eq!(1, table.len());
+		let flow = table.get(&FlowKey::new(s, c)).unwrap();
+		assert_eq!(c, flow.client);
+		assert_eq!(10, flow.client_half.bytes);
+		assert!(!flow.midstream);
+		assert_eq!(Some(Duration::from_millis(20)), flow.rtt);
+		assert_eq!(vec!["FIN", "SYN", "ACK"], flow.client_half.flags.names());
+	}
+
+	#[test]
+	fn test_reset_and_expiry() {
+		let (c, s) = endpoints();
+		let mut table = TcpFlowTable::new(Duration::from_secs(60), Duration::from_secs(5));
+
+		table.process(c, s, &segment(1, 0, "S", 0), Duration::from_secs(1));
+		let (_, change) = table.process(s, c, &segment(0, 2, "R.", 0), Duration::from_secs(2));
+		assert_eq!(TcpState::Reset, change.state);
+
+		assert!(table.expire(Duration::from_secs(3)).is_empty());
+		assert_eq!(1, table.expire(Duration::from_secs(8)).len());
+		assert!(table.is_empty());
+	}
+
+	#[test]
+	fn test_ordering_across_wrap() {
+		let (c, s) = endpoints();
+		let mut table = TcpFlowTable::default();
+		let ts = Duration::from_secs(1);
+		let start = u32::MAX - 5;
+
+		let (_, change) = table.process(c, s, &segment(start, 1, ".", 10), ts);
+		assert!(change.new_flow);
+		assert_eq!(TcpState::Established, change.state);
+
+		let next = start.wrapping_add(10);
+		let (_, change) = table.process(c, s, &segment(next, 1, ".", 10), ts);
+		assert_eq!(Ordering::InOrder, change.ordering);
+
+		let (_, change) = table.process(c, s, &segment(start, 1, ".", 10), ts);
+		assert_eq!(Ordering::Retransmission, change.ordering);
+
+		let (_, change) = table.process(c, s, &segment(next.wrapping_add(20), 1, ".", 10), ts);
+		assert_eq!(Ordering::OutOfOrder, change.ordering);
+	}
+}
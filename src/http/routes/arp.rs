@@ -0,0 +1,32 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+
+use crate::state::{
+	appstate::AppState,
+	arp::{ArpBinding, ArpEvent},
+};
+
+#[derive(Serialize)]
+pub struct Bindings {
+	bindings: Vec<ArpBinding>,
+}
+
+#[derive(Serialize)]
+pub struct Events {
+	events: Vec<ArpEvent>,
+}
+
+/// The current IP-to-MAC bindings, ordered by IP
+pub async fn bindings(State(state): State<AppState>) -> Json<Bindings> {
+	let mut bindings: Vec<ArpBinding> = state.arp.lock().unwrap().bindings().cloned().collect();
+	bindings.sort_by_key(|b| b.ip);
+
+	Json(Bindings { bindings })
+}
+
+/// Spoofing related events, oldest first
+pub async fn events(State(state): State<AppState>) -> Json<Events> {
+	let events = state.arp.lock().unwrap().events().cloned().collect();
+
+	Json(Events { events })
+}
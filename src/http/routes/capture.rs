@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use axum::{
+	extract::{Query, State},
+	http::{StatusCode, header},
+	response::{IntoResponse, Response},
+};
+use pcap::{BpfProgram, Capture, Linktype};
+use serde::Deserialize;
+
+use crate::{packet::Frame, pcapng, state::appstate::AppState};
+
+pub const CONTENT_TYPE: &str = "application/x-pcapng";
+
+#[derive(Deserialize)]
+pub struct RecentQuery {
+	/// How far back from the newest packet to go, rather than everything held
+	seconds: Option<u64>,
+	/// A BPF expression the packets must match
+	filter: Option<String>,
+}
+
+/// The packets held in the ring, as a pcapng download
+pub async fn recent(State(state): State<AppState>, Query(query): Query<RecentQuery>) -> Response {
+	// Frames are cheap to clone, so the ring is not locked while writing
+	let frames: Vec<Frame> = {
+		let ring = state.ring.lock().unwrap();
+		if !ring.is_enabled() {
+			return (StatusCode::NOT_FOUND, "the packet ring is not enabled").into_response();
+		}
+		ring
+			.recent(query.seconds.map(Duration::from_secs))
+			.cloned()
+			.collect()
+	};
+
+	// A full ring takes a while to filter and write, so it is done off the
+	// runtime's threads
+	let body = tokio::task::spawn_blocking(move || {
+		let filter = match query.filter.as_deref().map(compile).transpose() {
+			Ok(x) => x,
+			Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
+		};
+
+		dump(
+			frames
+				.iter()
+				.filter(|f| filter.as_ref().is_none_or(|p| p.filter(f.data()))),
+		)
+		.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+	})
+	.await;
+
+	match body {
+		Ok(Ok(body)) => download(body, "psniff-recent.pcapng"),
+		Ok(Err(e)) => e.into_response(),
+		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+	}
+}
+
+fn compile(filter: &str) -> Result<BpfProgram, pcap::Error> {
+	Capture::dead(Linktype::ETHERNET)?.compile(filter, true)
+}
+
+/// Writes frames as a single pcapng section
+pub fn dump<'f>(frames: impl Iterator<Item = &'f Frame>) -> std::io::Result<Vec<u8>> {
+	let mut w = pcapng::Writer::new(vec![])?;
+	for f in frames {
+		let iface = f.iface();
+		w.packet(
+			iface.name(),
+			iface.precision(),
+			f.timestamp(),
+			f.data(),
+			f.header().len,
+		)?;
+	}
+	Ok(w.into_inner())
+}
+
+/// A pcapng body, offered to the browser as a file
+pub fn download(body: Vec<u8>, name: &str) -> Response {
+	(
+		[
+			(header::CONTENT_TYPE, CONTENT_TYPE.to_string()),
+			(
+				header::CONTENT_DISPOSITION,
+				format!("attachment; filename=\"{}\"", name),
+			),
+		],
+		body,
+	)
+		.into_response()
+}
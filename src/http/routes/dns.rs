@@ -0,0 +1,146 @@
+use axum::{
+	Json,
+	extract::{Query, State},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::state::{
+	appstate::AppState,
+	dns::{DnsEntry, DnsStats},
+};
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const DEFAULT_TOP: usize = 10;
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct RecentQuery {
+	/// Only transactions for this name, ignoring case
+	name: Option<String>,
+	/// Only transactions with this response code, such as `nxdomain`
+	rcode: Option<String>,
+	limit: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct StatsQuery {
+	/// How many of the most queried names to include
+	top: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Recent {
+	transactions: Vec<DnsEntry>,
+}
+
+/// The most recent DNS transactions, newest first
+pub async fn recent(
+	State(state): State<AppState>,
+	Query(query): Query<RecentQuery>,
+) -> Json<Recent> {
+	let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+	let transactions = state
+		.dns
+		.lock()
+		.unwrap()
+		.recent()
+		.rev()
+		.filter(|e| {
+			let t = &e.transaction;
+			query
+				.name
+				.as_ref()
+				.is_none_or(|n| n.trim_end_matches('.').eq_ignore_ascii_case(&t.name))
+				&& query.rcode.as_ref().is_none_or(|r| {
+					t.rcode
+						.as_ref()
+						.is_some_and(|rcode| r.eq_ignore_ascii_case(rcode))
+				})
+		})
+		.take(limit)
+		.cloned()
+		.collect();
+
+	Json(Recent { transactions })
+}
+
+/// Totals, the NXDOMAIN rate and the most queried names
+pub async fn stats(
+	State(state): State<AppState>,
+	Query(query): Query<StatsQuery>,
+) -> Json<DnsStats> {
+	let top = query.top.unwrap_or(DEFAULT_TOP);
+
+	Json(state.dns.lock().unwrap().stats(top))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use axum::extract::{Query, State};
+	use futures::executor::block_on;
+	use serde_json::json;
+
+	use crate::{
+		flow::Endpoint,
+		http::routes::dns::{self, RecentQuery, StatsQuery},
+		protocols::dns::{Transaction, Transport},
+		state::appstate,
+	};
+
+	#[test]
+	fn test_recent_and_stats() {
+		let state = appstate::new();
+		{
+			let mut log = state.dns.lock().unwrap();
+			let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+			let server = Endpoint::new(IpAddr::from([10, 0, 0, 53]), 53);
+			for (i, (name, rcode)) in [
+				("example.com", Some("NOERROR")),
+				("Example.com", Some("NOERROR")),
+				("nope.example", Some("NXDOMAIN")),
+				("slow.example", None),
+			]
+			.into_iter()
+			.enumerate()
+			{
+				let transaction = Transaction {
+					id: i as u16,
+					transport: Transport::Udp,
+					client,
+					server,
+					name: name.to_string(),
+					qtype: "A".to_string(),
+					rcode: rcode.map(String::from),
+					latency: rcode.map(|_| 0.01),
+					truncated: false,
+					answers: vec![],
+				};
+				log.push("eth0", Duration::from_secs(i as u64), transaction);
+			}
+		}
+
+		let query = RecentQuery {
+			rcode: Some("nxdomain".to_string()),
+			..Default::default()
+		};
+		let recent = block_on(dns::recent(State(state.clone()), Query(query))).0;
+		assert_eq!(1, recent.transactions.len());
+		assert_eq!("nope.example", recent.transactions[0].transaction.name);
+
+		let query = StatsQuery { top: Some(1) };
+		let stats = block_on(dns::stats(State(state), Query(query))).0;
+		assert_eq!(
+			json!({
+				"transactions": 4,
+				"answered": 3,
+				"nxdomain": 1,
+				"nxdomain_rate": 1.0 / 3.0,
+				"top_names": [{"name": "example.com", "queries": 2}],
+			}),
+			serde_json::to_value(stats).unwrap()
+		);
+	}
+}
@@ -0,0 +1,370 @@
+use std::{net::IpAddr, time::Duration};
+
+use axum::{
+	Json,
+	extract::{Path, Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	http::routes::capture::{download, dump},
+	packet::Frame,
+	protocols::{quic::Initial, tls::Handshake},
+	state::{
+		appstate::AppState,
+		flows::{Counts, FlowRecord, TcpHalf},
+		serialize_timestamp,
+	},
+};
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct ListQuery {
+	/// An address or CIDR block either endpoint must fall in
+	ip: Option<String>,
+	/// A port either endpoint must use
+	port: Option<u16>,
+	protocol: Option<String>,
+	/// A TCP state, such as `established`
+	state: Option<String>,
+	/// Only flows which have closed, or only those which have not
+	closed: Option<bool>,
+	min_bytes: Option<u64>,
+	/// `id`, `first_seen`, `last_seen`, `packets` or `bytes`, prefixed with
+	/// `-` for descending order
+	sort: Option<String>,
+	offset: usize,
+	limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct FlowList {
+	total: usize,
+	offset: usize,
+	flows: Vec<FlowSummary>,
+}
+
+#[derive(Serialize)]
+pub struct FlowSummary {
+	id: u64,
+	protocol: String,
+	iface: String,
+	client: String,
+	server: String,
+	state: Option<String>,
+	closed: bool,
+	#[serde(serialize_with = "serialize_timestamp")]
+	first_seen: Duration,
+	#[serde(serialize_with = "serialize_timestamp")]
+	last_seen: Duration,
+	packets: u64,
+	bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct FlowDetail {
+	#[serde(flatten)]
+	summary: FlowSummary,
+	to_server: DirectionDetail,
+	to_client: DirectionDetail,
+	/// The TCP handshake round trip, in seconds
+	rtt: Option<f64>,
+	midstream: Option<bool>,
+	icmp_errors: u64,
+	last_icmp_error: Option<String>,
+	tls: Option<Handshake>,
+	quic: Option<Initial>,
+}
+
+/// DirectionDetail is what one side of a flow sent; the TCP fields are only
+/// set for TCP flows
+#[derive(Serialize)]
+pub struct DirectionDetail {
+	packets: u64,
+	bytes: u64,
+	flags: Option<Vec<&'static str>>,
+	retransmissions: Option<u64>,
+	out_of_order: Option<u64>,
+}
+
+impl DirectionDetail {
+	fn new(counts: &Counts, half: Option<&TcpHalf>) -> DirectionDetail {
+		DirectionDetail {
+			packets: counts.packets,
+			bytes: counts.bytes,
+			flags: half.map(|h| h.flags.names()),
+			retransmissions: half.map(|h| h.retransmissions),
+			out_of_order: half.map(|h| h.out_of_order),
+		}
+	}
+}
+
+/// Cidr is an address block, a single address being a block of one
+pub struct Cidr {
+	addr: IpAddr,
+	prefix: u32,
+}
+
+impl Cidr {
+	pub fn parse(s: &str) -> Option<Cidr> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+			None => (s.parse::<IpAddr>().ok()?, None),
+		};
+		let bits = match addr {
+			IpAddr::V4(_) => 32,
+			IpAddr::V6(_) => 128,
+		};
+		let prefix = prefix.unwrap_or(bits);
+		(prefix <= bits).then_some(Cidr { addr, prefix })
+	}
+
+	pub fn contains(&self, ip: &IpAddr) -> bool {
+		let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix).unwrap_or(0);
+		match (self.addr, ip) {
+			(IpAddr::V4(a), IpAddr::V4(b)) => {
+				let mask = mask(32) as u32;
+				u32::from(a) & mask == u32::from(*b) & mask
+			},
+			(IpAddr::V6(a), IpAddr::V6(b)) => {
+				let mask = mask(128);
+				u128::from(a) & mask == u128::from(*b) & mask
+			},
+			_ => false,
+		}
+	}
+}
+
+fn summary(record: &FlowRecord, closed: bool) -> FlowSummary {
+	FlowSummary {
+		id: record.id,
+		protocol: record.protocol.to_string(),
+		iface: record.iface.clone(),
+		client: record.client.to_string(),
+		server: record.server().to_string(),
+		state: record.state.map(|s| s.to_string()),
+		closed,
+		first_seen: record.first_seen,
+		last_seen: record.last_seen,
+		packets: record.packets,
+		bytes: record.bytes,
+	}
+}
+
+fn bad_request(message: String) -> Response {
+	(StatusCode::BAD_REQUEST, message).into_response()
+}
+
+/// Live and recently closed TCP and UDP flows, filtered, sorted and paged
+pub async fn list(State(state): State<AppState>, Query(query): Query<ListQuery>) -> Response {
+	let cidr = match query
+		.ip
+		.as_deref()
+		.map(|s| Cidr::parse(s).ok_or(s))
+		.transpose()
+	{
+		Ok(x) => x,
+		Err(s) => return bad_request(format!("'{}' is not an address or CIDR block", s)),
+	};
+	let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+	let (descending, sort) = match query.sort.as_deref() {
+		Some(s) => match s.strip_prefix('-') {
+			Some(s) => (true, s),
+			None => (false, s),
+		},
+		None => (true, "last_seen"),
+	};
+	let key = match sort {
+		"id" => |s: &FlowSummary| s.id as u128,
+		"first_seen" => |s: &FlowSummary| s.first_seen.as_nanos(),
+		"last_seen" => |s: &FlowSummary| s.last_seen.as_nanos(),
+		"packets" => |s: &FlowSummary| s.packets as u128,
+		"bytes" => |s: &FlowSummary| s.bytes as u128,
+		s => return bad_request(format!("flows cannot be sorted by '{}'", s)),
+	};
+
+	let wanted = |record: &FlowRecord| {
+		let (a, b) = record.key.endpoints();
+		cidr
+			.as_ref()
+			.is_none_or(|c| c.contains(&a.ip) || c.contains(&b.ip))
+			&& query.port.is_none_or(|p| a.port == p || b.port == p)
+			&& query
+				.protocol
+				.as_ref()
+				.is_none_or(|p| p.eq_ignore_ascii_case(&record.protocol.to_string()))
+			&& query.state.as_ref().is_none_or(|s| {
+				record
+					.state
+					.is_some_and(|state| s.eq_ignore_ascii_case(&state.to_string()))
+			}) && query.min_bytes.is_none_or(|b| record.bytes >= b)
+	};
+
+	let mut flows: Vec<FlowSummary> = {
+		let flows = state.flows.lock().unwrap();
+		let live = flows.iter().map(|r| (r, false));
+		let closed = flows.closed().map(|r| (r, true));
+		live
+			.chain(closed)
+			.filter(|(r, closed)| query.closed.is_none_or(|c| c == *closed) && wanted(r))
+			.map(|(r, closed)| summary(r, closed))
+			.collect()
+	};
+
+	// Ties are broken by id, so that paging is stable
+	flows.sort_by_key(|s| (key(s), s.id));
+	if descending {
+		flows.reverse();
+	}
+
+	let total = flows.len();
+	let flows = flows.into_iter().skip(query.offset).take(limit).collect();
+
+	Json(FlowList {
+		total,
+		offset: query.offset,
+		flows,
+	})
+	.into_response()
+}
+
+/// A single live or recently closed flow, with what each side sent
+pub async fn detail(State(state): State<AppState>, Path(id): Path<u64>) -> Response {
+	let flows = state.flows.lock().unwrap();
+	let Some(record) = flows.find(id) else {
+		return (StatusCode::NOT_FOUND, format!("no flow {}", id)).into_response();
+	};
+	let closed = flows.closed().any(|r| r.id == id);
+
+	let tcp = record.tcp.as_ref();
+	Json(FlowDetail {
+		summary: summary(record, closed),
+		to_server: DirectionDetail::new(&record.to_server, tcp.map(|t| &t.to_server)),
+		to_client: DirectionDetail::new(&record.to_client, tcp.map(|t| &t.to_client)),
+		rtt: tcp.and_then(|t| t.rtt).map(|rtt| rtt.as_secs_f64()),
+		midstream: tcp.map(|t| t.midstream),
+		icmp_errors: record.icmp_errors,
+		last_icmp_error: record.last_icmp_error.clone(),
+		tls: record.tls.as_deref().cloned(),
+		quic: record.quic.as_deref().cloned(),
+	})
+	.into_response()
+}
+
+/// Every packet of a flow still held in the packet ring, as a pcapng
+/// download named `flow-<id>.pcapng`; pcapng keeps each packet's interface,
+/// which a classic pcap file cannot
+pub async fn pcap(State(state): State<AppState>, Path(id): Path<u64>) -> Response {
+	let record = match state.flows.lock().unwrap().find(id) {
+		Some(r) => r.clone(),
+		None => return (StatusCode::NOT_FOUND, format!("no flow {}", id)).into_response(),
+	};
+
+	let frames: Vec<Frame> = {
+		let ring = state.ring.lock().unwrap();
+		if !ring.is_enabled() {
+			return (StatusCode::NOT_FOUND, "the packet ring is not enabled").into_response();
+		}
+
+		// The key alone could match an earlier flow between the same
+		// endpoints
+		ring
+			.recent(None)
+			.filter(|f| {
+				let ts = f.timestamp();
+				ts >= record.first_seen
+					&& ts <= record.last_seen
+					&& f.iface().name() == record.iface
+					&& f.flow() == Some((record.protocol, record.key))
+			})
+			.cloned()
+			.collect()
+	};
+
+	// Written off the runtime's threads, as the ring dump is
+	match tokio::task::spawn_blocking(move || dump(frames.iter())).await {
+		Ok(Ok(body)) => download(body, &format!("flow-{}.pcapng", id)),
+		Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use axum::{
+		body::to_bytes,
+		extract::{Path, Query, State},
+		http::StatusCode,
+		response::Response,
+	};
+	use futures::executor::block_on;
+	use serde_json::Value;
+
+	use crate::{
+		flow::{Endpoint, FlowKey, Protocol},
+		http::routes::flows::{self, Cidr, ListQuery},
+		state::appstate,
+	};
+
+	fn json(response: Response) -> Value {
+		let body = block_on(to_bytes(response.into_body(), 1 << 20)).unwrap();
+		serde_json::from_slice(&body).unwrap()
+	}
+
+	#[test]
+	fn test_list_and_detail() {
+		let state = appstate::new();
+		{
+			let mut flows = state.flows.lock().unwrap();
+			let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+			for (i, port) in [53, 80, 443].into_iter().enumerate() {
+				let server = Endpoint::new(IpAddr::from([192, 168, 1, 1]), port);
+				let ts = Duration::from_secs(i as u64);
+				flows.record(Protocol::Udp, client, server, "eth0", ts, 100 * (i + 1));
+				flows.record(Protocol::Udp, server, client, "eth0", ts, 10);
+			}
+			let server = Endpoint::new(IpAddr::from([192, 168, 1, 1]), 53);
+			flows.remove(Protocol::Udp, &FlowKey::new(client, server));
+		}
+
+		let query = ListQuery {
+			ip: Some("192.168.0.0/16".to_string()),
+			min_bytes: Some(200),
+			sort: Some("bytes".to_string()),
+			..Default::default()
+		};
+		let body = json(block_on(flows::list(State(state.clone()), Query(query))));
+		assert_eq!(2, body["total"]);
+		assert_eq!(210, body["flows"][0]["bytes"]);
+		assert_eq!("192.168.1.1:80", body["flows"][0]["server"]);
+
+		let query = ListQuery {
+			closed: Some(true),
+			..Default::default()
+		};
+		let body = json(block_on(flows::list(State(state.clone()), Query(query))));
+		assert_eq!(1, body["flows"][0]["id"]);
+
+		let body = json(block_on(flows::detail(State(state.clone()), Path(3))));
+		assert_eq!(300, body["to_server"]["bytes"]);
+		assert_eq!(1, body["to_client"]["packets"]);
+		assert_eq!(Value::Null, body["to_client"]["flags"]);
+
+		let response = block_on(flows::detail(State(state), Path(9)));
+		assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+		assert!(Cidr::parse("10.0.0.0/33").is_none());
+		assert!(
+			Cidr::parse("::/0")
+				.unwrap()
+				.contains(&IpAddr::from([0u16; 8]))
+		);
+	}
+}
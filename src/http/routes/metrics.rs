@@ -0,0 +1,245 @@
+use std::fmt::Write;
+
+use axum::{
+	extract::State,
+	http::header,
+	response::{IntoResponse, Response},
+};
+
+use crate::{devices::Matcher, flow::Protocol, state::appstate::AppState};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Exposition accumulates metric families in the Prometheus text format
+struct Exposition {
+	out: String,
+}
+
+impl Exposition {
+	fn new() -> Exposition {
+		Exposition { out: String::new() }
+	}
+
+	/// Starts a metric family; samples of the family must follow it
+	fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+		let _ = writeln!(self.out, "# HELP {} {}", name, help);
+		let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+		self
+	}
+
+	fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: u64) -> &mut Self {
+		self.out.push_str(name);
+		if !labels.is_empty() {
+			let labels: Vec<String> = labels
+				.iter()
+				.map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+				.collect();
+			let _ = write!(self.out, "{{{}}}", labels.join(","));
+		}
+		let _ = writeln!(self.out, " {}", value);
+		self
+	}
+}
+
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+/// Every counter held in the appstate, in the Prometheus text format
+pub async fn process(State(state): State<AppState>) -> Response {
+	let body = render(&state);
+
+	([(header::CONTENT_TYPE, CONTENT_TYPE)], body).into_response()
+}
+
+fn render(state: &AppState) -> String {
+	let mut e = Exposition::new();
+
+	let mut interfaces: Vec<_> = state.interfaces.lock().unwrap().iter().cloned().collect();
+	interfaces.sort_by(|a, b| a.name().cmp(b.name()));
+	let counts: Vec<_> = interfaces.iter().map(|i| (i.name(), i.counts())).collect();
+
+	e.family(
+		"psniff_interface_received_packets_total",
+		"counter",
+		"Packets received on the interface",
+	);
+	for (name, c) in &counts {
+		e.sample(
+			"psniff_interface_received_packets_total",
+			&[("interface", name)],
+			c.total as u64,
+		);
+	}
+
+	e.family(
+		"psniff_interface_os_dropped_packets_total",
+		"counter",
+		"Packets dropped by the operating system because its buffer was full",
+	);
+	for (name, c) in &counts {
+		e.sample(
+			"psniff_interface_os_dropped_packets_total",
+			&[("interface", name)],
+			c.os_dropped as u64,
+		);
+	}
+
+	e.family(
+		"psniff_interface_if_dropped_packets_total",
+		"counter",
+		"Packets dropped by the network interface or its driver",
+	);
+	for (name, c) in &counts {
+		e.sample(
+			"psniff_interface_if_dropped_packets_total",
+			&[("interface", name)],
+			c.if_dropped as u64,
+		);
+	}
+
+	// Matchers are listed in a fixed order, rather than the map's
+	let matchers: Vec<_> = Matcher::ALL
+		.iter()
+		.filter_map(|m| state.packet_counts.get(m).map(|c| (m.name(), c)))
+		.collect();
+
+	e.family(
+		"psniff_matcher_packets_total",
+		"counter",
+		"Packets classified as each matcher",
+	);
+	for (name, c) in &matchers {
+		e.sample(
+			"psniff_matcher_packets_total",
+			&[("matcher", name)],
+			c.packets(),
+		);
+	}
+
+	e.family(
+		"psniff_matcher_bytes_total",
+		"counter",
+		"Bytes on the wire of the packets classified as each matcher",
+	);
+	for (name, c) in &matchers {
+		e.sample(
+			"psniff_matcher_bytes_total",
+			&[("matcher", name)],
+			c.bytes(),
+		);
+	}
+
+	e.family(
+		"psniff_queue_dropped_packets_total",
+		"counter",
+		"Packets which could not be queued for their listener",
+	);
+	for (name, c) in &matchers {
+		e.sample(
+			"psniff_queue_dropped_packets_total",
+			&[("matcher", name)],
+			c.dropped(),
+		);
+	}
+
+	// Closed queues have no depth to report
+	let queues: Vec<_> = {
+		let queues = state.queues.lock().unwrap();
+		Matcher::ALL
+			.iter()
+			.filter_map(|m| {
+				let s = queues.get(m)?.upgrade()?;
+				Some((m.name(), s.len(), s.max_capacity()))
+			})
+			.collect()
+	};
+
+	e.family(
+		"psniff_queue_depth",
+		"gauge",
+		"Packets waiting in each listener queue",
+	);
+	for (name, depth, _) in &queues {
+		e.sample("psniff_queue_depth", &[("matcher", name)], *depth as u64);
+	}
+
+	e.family(
+		"psniff_queue_capacity",
+		"gauge",
+		"How many packets each listener queue can hold",
+	);
+	for (name, _, capacity) in &queues {
+		e.sample(
+			"psniff_queue_capacity",
+			&[("matcher", name)],
+			*capacity as u64,
+		);
+	}
+
+	let (tcp, udp) = state
+		.flows
+		.lock()
+		.unwrap()
+		.iter()
+		.fold((0, 0), |(tcp, udp), f| match f.protocol {
+			Protocol::Tcp => (tcp + 1, udp),
+			Protocol::Udp => (tcp, udp + 1),
+		});
+
+	e.family(
+		"psniff_active_flows",
+		"gauge",
+		"Flows currently being tracked",
+	);
+	e.sample("psniff_active_flows", &[("protocol", "tcp")], tcp);
+	e.sample("psniff_active_flows", &[("protocol", "udp")], udp);
+
+	e.out
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::{
+		devices::{Matcher, ReceivedPacketData},
+		http::routes::metrics::render,
+		queue::{self, Policy},
+		state::{appstate, interface::Interface},
+	};
+
+	#[test]
+	fn test_render() {
+		let state = appstate::new();
+
+		let iface = Interface::new("eth\"0".to_string());
+		iface.update_counts(10, 2, 1);
+		state.interfaces.lock().unwrap().insert(Arc::new(iface));
+
+		state.packet_counts[&Matcher::IPv4_TCP].record(60);
+		state.packet_counts[&Matcher::IPv4_TCP].record(1514);
+
+		let (sender, _receiver) = queue::channel::<ReceivedPacketData>(8, Policy::Block);
+		state
+			.queues
+			.lock()
+			.unwrap()
+			.insert(Matcher::IPv4_TCP, sender.downgrade());
+
+		let out = render(&state);
+		assert!(out.contains("# TYPE psniff_interface_received_packets_total counter\n"));
+		assert!(out.contains("psniff_interface_os_dropped_packets_total{interface=\"eth\\\"0\"} 2\n"));
+		assert!(out.contains("psniff_matcher_packets_total{matcher=\"ipv4_tcp\"} 2\n"));
+		assert!(out.contains("psniff_matcher_bytes_total{matcher=\"ipv4_tcp\"} 1574\n"));
+		assert!(out.contains("psniff_queue_capacity{matcher=\"ipv4_tcp\"} 8\n"));
+		assert!(out.contains("psniff_active_flows{protocol=\"udp\"} 0\n"));
+
+		// Queues are only reported while a sender is alive
+		drop(sender);
+		assert!(!render(&state).contains("psniff_queue_depth{"));
+	}
+}
@@ -1 +1,7 @@
+pub mod arp;
+pub mod capture;
+pub mod dns;
+pub mod flows;
+pub mod metrics;
 pub mod status;
+pub mod stream;
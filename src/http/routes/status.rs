@@ -5,11 +5,29 @@ use axum::{
 };
 use serde::Serialize;
 
-use crate::state::appstate::AppState;
+use crate::{devices::Matcher, queue::Policy, state::appstate::AppState};
 
 #[derive(Serialize)]
 pub struct Statistics {
 	total_packet_count: u32,
+	interfaces: Vec<InterfaceStatistics>,
+	queues: Vec<QueueStatistics>,
+}
+
+#[derive(Serialize)]
+pub struct InterfaceStatistics {
+	name: String,
+	packet_count: u32,
+	filter: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct QueueStatistics {
+	matcher: &'static str,
+	policy: Policy,
+	capacity: usize,
+	depth: usize,
+	dropped: u64,
 }
 
 impl IntoResponse for Statistics {
@@ -24,13 +42,41 @@ impl IntoResponse for Statistics {
 }
 
 pub async fn process(State(state): State<AppState>) -> Statistics {
-	let total = state
-		.interfaces
-		.lock()
-		.unwrap()
+	let interfaces = state.interfaces.lock().unwrap();
+
+	let total = interfaces.iter().fold(0, |acc, item| acc + item.count());
+
+	let mut interfaces: Vec<InterfaceStatistics> = interfaces
 		.iter()
-		.fold(0, |acc, item| acc + item.count());
+		.map(|item| InterfaceStatistics {
+			name: item.name().to_string(),
+			packet_count: item.count(),
+			filter: item.filter(),
+		})
+		.collect();
+	interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+	// Only queues which are still open are listed
+	let queues = {
+		let queues = state.queues.lock().unwrap();
+		Matcher::ALL
+			.iter()
+			.filter_map(|m| {
+				let s = queues.get(m)?.upgrade()?;
+				Some(QueueStatistics {
+					matcher: m.name(),
+					policy: s.policy(),
+					capacity: s.max_capacity(),
+					depth: s.len(),
+					dropped: state.packet_counts.get(m).map_or(0, |c| c.dropped()),
+				})
+			})
+			.collect()
+	};
+
 	Statistics {
 		total_packet_count: total,
+		interfaces,
+		queues,
 	}
 }
@@ -0,0 +1,367 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	convert::Infallible,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+	extract::{
+		Query, State,
+		ws::{Message, WebSocket, WebSocketUpgrade},
+	},
+	http::StatusCode,
+	response::{
+		IntoResponse, Response,
+		sse::{self, KeepAlive, Sse},
+	},
+};
+use futures::stream;
+use log::debug;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+	sync::broadcast::{self, error::RecvError},
+	time::{self, Interval, MissedTickBehavior},
+};
+
+use crate::{
+	events::{Counters, Event, EventKind},
+	http::routes::flows::Cidr,
+	state::appstate::AppState,
+};
+
+/// How often counters are sent
+pub const COUNTERS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a WebSocket client may take to accept a message before it is
+/// dropped
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The event types a stream can carry
+pub const EVENTS: [&str; 6] = [
+	"flow_start",
+	"flow_end",
+	"packet",
+	"transaction",
+	"alert",
+	"counters",
+];
+
+/// Every packet is an event of its own, so packets are only streamed when
+/// asked for
+const DEFAULT_EVENTS: [&str; 5] = ["flow_start", "flow_end", "transaction", "alert", "counters"];
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct StreamQuery {
+	/// An address or CIDR block the event must involve
+	host: Option<String>,
+	/// A port the event must involve
+	port: Option<u16>,
+	/// A protocol such as `IPv4-TCP`, or only its transport such as `tcp`
+	protocol: Option<String>,
+	/// Comma-separated event types, such as `flow_start,flow_end`
+	event: Option<String>,
+}
+
+/// Filter decides which events a client is sent.  Counters are not about any
+/// one host or protocol, so only the event types apply to them.
+pub struct Filter {
+	host: Option<Cidr>,
+	port: Option<u16>,
+	protocol: Option<String>,
+	events: Vec<&'static str>,
+}
+
+impl TryFrom<StreamQuery> for Filter {
+	type Error = String;
+
+	fn try_from(query: StreamQuery) -> Result<Self, Self::Error> {
+		let host = match query.host {
+			Some(s) => match Cidr::parse(&s) {
+				Some(c) => Some(c),
+				None => return Err(format!("'{}' is not an address or CIDR block", s)),
+			},
+			None => None,
+		};
+
+		let events = match &query.event {
+			Some(s) => s
+				.split(',')
+				.map(|name| {
+					EVENTS
+						.into_iter()
+						.find(|e| e.eq_ignore_ascii_case(name.trim()))
+						.ok_or(format!("'{}' is not an event type", name))
+				})
+				.collect::<Result<Vec<_>, _>>()?,
+			None => DEFAULT_EVENTS.to_vec(),
+		};
+
+		Ok(Filter {
+			host,
+			port: query.port,
+			protocol: query.protocol,
+			events,
+		})
+	}
+}
+
+impl Filter {
+	pub fn wants(&self, name: &str) -> bool {
+		self.events.contains(&name)
+	}
+
+	pub fn matches(&self, kind: &EventKind) -> bool {
+		if !self.wants(kind.name()) {
+			return false;
+		}
+		if let EventKind::Counters(_) = kind {
+			return true;
+		}
+
+		let protocol = self.protocol.as_ref().is_none_or(|p| {
+			kind.protocol().is_some_and(|protocol| {
+				p.eq_ignore_ascii_case(protocol)
+					|| protocol
+						.rsplit(['-', '_'])
+						.next()
+						.is_some_and(|transport| p.eq_ignore_ascii_case(transport))
+			})
+		});
+		let hosts = kind.hosts();
+		let host = self
+			.host
+			.as_ref()
+			.is_none_or(|c| hosts.iter().any(|(ip, _)| c.contains(ip)));
+		let port = self
+			.port
+			.is_none_or(|p| hosts.iter().any(|(_, port)| *port == Some(p)));
+
+		protocol && host && port
+	}
+}
+
+enum Item {
+	Event(Arc<Event>),
+	/// Events the client was too slow to be sent
+	Lagged(u64),
+}
+
+/// Subscription is one client's view of the events, with the counters mixed
+/// in.  A client which falls behind misses events; the capture never waits.
+struct Subscription {
+	receiver: broadcast::Receiver<Arc<Event>>,
+	filter: Filter,
+	state: AppState,
+	ticks: Interval,
+	received: HashMap<String, u64>,
+	pending: VecDeque<Item>,
+}
+
+impl Subscription {
+	fn new(state: AppState, filter: Filter) -> Subscription {
+		let mut ticks = time::interval(COUNTERS_INTERVAL);
+		ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+		Subscription {
+			receiver: state.events.subscribe(),
+			filter,
+			state,
+			ticks,
+			received: HashMap::new(),
+			pending: VecDeque::new(),
+		}
+	}
+
+	async fn next(&mut self) -> Option<Item> {
+		loop {
+			if let Some(item) = self.pending.pop_front() {
+				return Some(item);
+			}
+
+			tokio::select! {
+				r = self.receiver.recv() => match r {
+					Ok(event) => {
+						if self.filter.matches(&event.kind) {
+							return Some(Item::Event(event));
+						}
+					},
+					Err(RecvError::Lagged(missed)) => return Some(Item::Lagged(missed)),
+					Err(RecvError::Closed) => return None,
+				},
+				_ = self.ticks.tick(), if self.filter.wants("counters") => self.sample(),
+			}
+		}
+	}
+
+	/// Queues the counters of every interface
+	fn sample(&mut self) {
+		let ts = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+
+		let mut interfaces: Vec<_> = self
+			.state
+			.interfaces
+			.lock()
+			.unwrap()
+			.iter()
+			.cloned()
+			.collect();
+		interfaces.sort_by(|a, b| a.name().cmp(b.name()));
+		for iface in interfaces {
+			let counts = iface.counts();
+			let received = counts.total as u64;
+			let previous = self.received.insert(iface.name().to_string(), received);
+
+			self.pending.push_back(Item::Event(Arc::new(Event {
+				ts,
+				iface: iface.name().to_string(),
+				kind: EventKind::Counters(Counters {
+					received,
+					os_dropped: counts.os_dropped as u64,
+					if_dropped: counts.if_dropped as u64,
+					received_delta: received.saturating_sub(previous.unwrap_or(received)),
+				}),
+			})));
+		}
+	}
+}
+
+impl Item {
+	fn name(&self) -> &'static str {
+		match self {
+			Item::Event(event) => event.kind.name(),
+			Item::Lagged(_) => "lagged",
+		}
+	}
+
+	fn to_json(&self) -> String {
+		match self {
+			Item::Event(event) => serde_json::to_string(event.as_ref()).unwrap_or_default(),
+			Item::Lagged(missed) => json!({ "event": "lagged", "missed": missed }).to_string(),
+		}
+	}
+}
+
+/// Events as they happen, as Server-Sent Events named after their type
+pub async fn sse(State(state): State<AppState>, Query(query): Query<StreamQuery>) -> Response {
+	let subscription = match Filter::try_from(query) {
+		Ok(filter) => Subscription::new(state, filter),
+		Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+	};
+
+	let events = stream::unfold(subscription, |mut subscription| async move {
+		let item = subscription.next().await?;
+		let event = sse::Event::default()
+			.event(item.name())
+			.data(item.to_json());
+		Some((Ok::<_, Infallible>(event), subscription))
+	});
+
+	Sse::new(events)
+		.keep_alive(KeepAlive::default())
+		.into_response()
+}
+
+/// Events as they happen, one JSON text message each
+pub async fn ws(
+	State(state): State<AppState>,
+	Query(query): Query<StreamQuery>,
+	upgrade: WebSocketUpgrade,
+) -> Response {
+	let subscription = match Filter::try_from(query) {
+		Ok(filter) => Subscription::new(state, filter),
+		Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+	};
+
+	upgrade.on_upgrade(move |socket| send_events(socket, subscription))
+}
+
+async fn send_events(mut socket: WebSocket, mut subscription: Subscription) {
+	loop {
+		tokio::select! {
+			item = subscription.next() => {
+				let Some(item) = item else { break };
+				let message = Message::Text(item.to_json().into());
+				match time::timeout(SEND_TIMEOUT, socket.send(message)).await {
+					Ok(Ok(())) => {},
+					Ok(Err(_)) => break,
+					Err(_) => {
+						debug!("dropping a websocket client which stopped reading");
+						break;
+					},
+				}
+			},
+			// Clients have nothing to say, but closing
+			message = socket.recv() => match message {
+				Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+				Some(Ok(_)) => {},
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::IpAddr;
+
+	use crate::{
+		events::{Counters, EventKind, FlowStart, PacketSummary},
+		flow::Endpoint,
+		http::routes::stream::{Filter, StreamQuery},
+	};
+
+	#[test]
+	fn test_filter() {
+		let start = EventKind::FlowStart(FlowStart {
+			protocol: "IPv4-TCP".to_string(),
+			client: Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000),
+			server: Endpoint::new(IpAddr::from([192, 168, 1, 1]), 443),
+		});
+		let ping = EventKind::Packet(Box::new(PacketSummary {
+			protocol: "IPv4-ICMP".to_string(),
+			src: "10.0.0.1".to_string(),
+			dst: "192.168.1.1".to_string(),
+			bytes: 56,
+			tcp: None,
+			icmp: None,
+			text: None,
+		}));
+		let counters = EventKind::Counters(Counters {
+			received: 10,
+			os_dropped: 0,
+			if_dropped: 0,
+			received_delta: 10,
+		});
+
+		let filter = |host: &str, port, protocol: &str, event: Option<&str>| {
+			Filter::try_from(StreamQuery {
+				host: Some(host.to_string()),
+				port,
+				protocol: Some(protocol.to_string()),
+				event: event.map(String::from),
+			})
+		};
+
+		let f = filter("192.168.0.0/16", Some(443), "tcp", None).unwrap();
+		assert!(f.matches(&start));
+		assert!(f.matches(&counters));
+		// Packets are only sent when asked for
+		assert!(!f.matches(&ping));
+
+		let f = filter("10.0.0.1", None, "icmp", Some("packet")).unwrap();
+		assert!(f.matches(&ping));
+		assert!(!f.matches(&start));
+		assert!(!f.matches(&counters));
+
+		// ICMP has no ports to match
+		let f = filter("10.0.0.1", Some(443), "ipv4-icmp", Some("packet")).unwrap();
+		assert!(!f.matches(&ping));
+
+		assert!(filter("10.0.0.0/40", None, "tcp", None).is_err());
+		assert!(filter("10.0.0.1", None, "tcp", Some("flow_start,nope")).is_err());
+	}
+}
@@ -11,7 +11,11 @@ use log::info;
 use thiserror::Error;
 use tokio::{
 	net::TcpListener,
-	sync::broadcast::{self, Receiver},
+	sync::{
+		broadcast::{self, Receiver},
+		oneshot,
+	},
+	time,
 };
 use tower::ServiceBuilder;
 
@@ -22,6 +26,10 @@ use crate::{
 	state::appstate::State,
 };
 
+/// How long connections which never finish, such as event streams, are given
+/// to close once the service is stopped
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
 // struct Unset {}
 
 // trait StateMarker {}
@@ -149,17 +157,25 @@ where
 		let listener = self.listener.take();
 		let state = mem::take(&mut self.s);
 
-		axum::serve(listener.unwrap(), app.with_state(state))
-			.with_graceful_shutdown(async move {
+		let (stopping_tx, stopping_rx) = oneshot::channel();
+		let serve =
+			axum::serve(listener.unwrap(), app.with_state(state)).with_graceful_shutdown(async move {
 				match cancel_rx.recv().await {
 					Ok(_) | Err(broadcast::error::RecvError::Closed) => {
 						info!("Received close message");
 					},
 					Err(broadcast::error::RecvError::Lagged(_)) => {},
 				}
-			})
-			.await
-			.unwrap();
+				let _ = stopping_tx.send(());
+			});
+
+		tokio::select! {
+			r = serve => r.unwrap(),
+			_ = async {
+				let _ = stopping_rx.await;
+				time::sleep(SHUTDOWN_GRACE).await;
+			} => info!("http connections still open, closing them"),
+		}
 		info!("http exited");
 	}
 }
@@ -1,8 +1,15 @@
 pub mod cli;
 pub mod config;
 pub mod devices;
+pub mod events;
+pub mod flow;
 pub mod http;
+pub mod packet;
 pub mod packet_listeners;
+pub mod pcapng;
+pub mod protocols;
+pub mod queue;
+pub mod reload;
 pub mod runtime;
 pub mod state;
 pub mod version;
@@ -0,0 +1,588 @@
+use std::{
+	cell::RefCell,
+	mem,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	ops::Deref,
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::Duration,
+};
+
+use crossbeam_queue::ArrayQueue;
+use etherparse::{
+	ArpPacketSlice, Icmpv4Slice, Icmpv6Slice, LaxNetSlice, LaxSlicedPacket, NetSlice, SlicedPacket,
+	TcpSlice, TransportSlice, UdpSlice, err::packet::SliceError,
+};
+use pcap::PacketHeader;
+
+use crate::{
+	devices::{self, Matcher},
+	flow::{Endpoint, FlowKey, Protocol},
+	state::interface::Interface,
+};
+
+/// How many idle buffers a pool keeps for reuse
+pub const DEFAULT_POOL_SIZE: usize = 4096;
+
+/// How many released buffers a listener thread gathers before handing them back
+const RETURN_BATCH: usize = 64;
+
+/// Recycler holds batches of buffers released by listeners until the capture
+/// thread reuses them
+struct Recycler {
+	batches: ArrayQueue<Vec<Arc<Slot>>>,
+	batch_size: usize,
+	closed: AtomicBool,
+}
+
+impl Recycler {
+	fn give_back(&self, batch: Vec<Arc<Slot>>) {
+		// A full pool simply frees the buffers
+		if !batch.is_empty() && !self.closed.load(Ordering::Acquire) {
+			let _ = self.batches.push(batch);
+		}
+	}
+}
+
+struct Slot {
+	bytes: Vec<u8>,
+	recycler: Option<Arc<Recycler>>,
+}
+
+/// Returning holds the buffers released on this thread which have not yet made
+/// a full batch
+struct Returning {
+	recycler: Arc<Recycler>,
+	batch: Vec<Arc<Slot>>,
+}
+
+impl Drop for Returning {
+	fn drop(&mut self) {
+		self.recycler.give_back(mem::take(&mut self.batch));
+	}
+}
+
+thread_local! {
+	static RETURNING: RefCell<Option<Returning>> = const { RefCell::new(None) };
+}
+
+/// BufferPool recycles packet buffers so that the capture thread does not
+/// allocate for every packet.  Buffers find their own way back to the pool when
+/// the last listener holding them lets go.  They travel back in batches, so
+/// neither side pays for synchronisation on every packet.
+pub struct BufferPool {
+	free: Vec<Arc<Slot>>,
+	recycler: Arc<Recycler>,
+}
+
+impl Default for BufferPool {
+	fn default() -> Self {
+		BufferPool::new(DEFAULT_POOL_SIZE)
+	}
+}
+
+impl BufferPool {
+	pub fn new(max_free: usize) -> BufferPool {
+		let batch_size = max_free.clamp(1, RETURN_BATCH);
+		BufferPool {
+			free: vec![],
+			recycler: Arc::new(Recycler {
+				batches: ArrayQueue::new(max_free.div_ceil(batch_size).max(1)),
+				batch_size,
+				closed: AtomicBool::new(false),
+			}),
+		}
+	}
+
+	/// Copies `data` into a recycled buffer, or a new one if none are idle
+	pub fn copy_from(&mut self, data: &[u8]) -> PooledBuffer {
+		if self.free.is_empty()
+			&& let Some(batch) = self.recycler.batches.pop()
+		{
+			self.free = batch;
+		}
+
+		// Returned buffers are never shared, so get_mut only fails for a new one
+		let mut slot = self.free.pop();
+		match slot.as_mut().and_then(Arc::get_mut) {
+			Some(s) => {
+				s.bytes.clear();
+				s.bytes.extend_from_slice(data);
+			},
+			None => {
+				slot = Some(Arc::new(Slot {
+					bytes: data.to_vec(),
+					recycler: Some(self.recycler.clone()),
+				}))
+			},
+		}
+
+		PooledBuffer { slot }
+	}
+}
+
+impl Drop for BufferPool {
+	fn drop(&mut self) {
+		// Slots refer back to the recycler, so it must be emptied to be freed
+		self.recycler.closed.store(true, Ordering::Release);
+		while self.recycler.batches.pop().is_some() {}
+	}
+}
+
+/// PooledBuffer is a reference counted packet buffer.  Clones share the same
+/// bytes, and the buffer returns to its pool when the last clone is dropped.
+#[derive(Clone)]
+pub struct PooledBuffer {
+	slot: Option<Arc<Slot>>,
+}
+
+impl From<Vec<u8>> for PooledBuffer {
+	/// Wraps a buffer which does not belong to any pool
+	fn from(bytes: Vec<u8>) -> Self {
+		PooledBuffer {
+			slot: Some(Arc::new(Slot {
+				bytes,
+				recycler: None,
+			})),
+		}
+	}
+}
+
+impl Deref for PooledBuffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match &self.slot {
+			Some(slot) => &slot.bytes,
+			None => &[],
+		}
+	}
+}
+
+impl Drop for PooledBuffer {
+	fn drop(&mut self) {
+		let mut slot = match self.slot.take() {
+			Some(s) => s,
+			None => return,
+		};
+
+		// Only the last holder may recycle the buffer
+		let home = match Arc::get_mut(&mut slot).and_then(|s| s.recycler.as_ref()) {
+			Some(r) if !r.closed.load(Ordering::Acquire) => Arc::as_ptr(r),
+			_ => return,
+		};
+
+		// The thread may already be shutting down, in which case the buffer is freed
+		let _ = RETURNING.try_with(|returning| {
+			let mut returning = returning.borrow_mut();
+			let returning = match &mut *returning {
+				Some(r) if Arc::as_ptr(&r.recycler) == home => r,
+				other => {
+					let recycler = slot.recycler.clone().unwrap();
+					other.insert(Returning {
+						batch: Vec::with_capacity(recycler.batch_size),
+						recycler,
+					})
+				},
+			};
+
+			returning.batch.push(slot);
+			if returning.batch.len() >= returning.recycler.batch_size {
+				let full = mem::replace(
+					&mut returning.batch,
+					Vec::with_capacity(returning.recycler.batch_size),
+				);
+				returning.recycler.give_back(full);
+			}
+		});
+	}
+}
+
+/// Span is the location of a layer within a frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Span {
+	start: u32,
+	end: u32,
+}
+
+impl Span {
+	/// Locates `inner`, which must be a sub-slice of `outer`
+	fn of(outer: &[u8], inner: &[u8]) -> Span {
+		let start = inner.as_ptr() as usize - outer.as_ptr() as usize;
+		Span {
+			start: start as u32,
+			end: (start + inner.len()) as u32,
+		}
+	}
+
+	fn slice<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+		&data[self.start as usize..self.end as usize]
+	}
+}
+
+/// Layers is what was learned about a frame when it was parsed on the capture
+/// thread, so that listeners can go straight to the layer they care about
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Layers {
+	matcher: Option<Matcher>,
+	fragmented: bool,
+	net: Option<Span>,
+	transport: Option<Span>,
+}
+
+impl Layers {
+	/// Parses an Ethernet frame, recording where each layer starts and
+	/// classifying it for dispatch
+	pub fn parse(data: &[u8]) -> Result<Layers, SliceError> {
+		let sliced = match SlicedPacket::from_ethernet(data) {
+			Ok(sliced) => sliced,
+			// A frame cut short by the snaplen still has usable headers
+			Err(e) => return Layers::parse_truncated(data).ok_or(e),
+		};
+
+		let (fragmented, net) = match &sliced.net {
+			Some(NetSlice::Arp(arp)) => (false, Some(Span::of(data, arp.slice()))),
+			Some(NetSlice::Ipv4(ipv4)) => (
+				ipv4.is_payload_fragmented(),
+				Some(Span::of(data, ipv4.header().slice())),
+			),
+			Some(NetSlice::Ipv6(ipv6)) => (
+				ipv6.is_payload_fragmented(),
+				Some(Span::of(data, ipv6.header().slice())),
+			),
+			None => (false, None),
+		};
+
+		let transport = match &sliced.transport {
+			Some(TransportSlice::Tcp(tcp)) => Some(Span::of(data, tcp.slice())),
+			Some(TransportSlice::Udp(udp)) => Some(Span::of(data, udp.slice())),
+			Some(TransportSlice::Icmpv4(icmp)) => Some(Span::of(data, icmp.slice())),
+			Some(TransportSlice::Icmpv6(icmp)) => Some(Span::of(data, icmp.slice())),
+			None => None,
+		};
+
+		Ok(Layers {
+			matcher: devices::classify(&sliced),
+			fragmented,
+			net,
+			transport,
+		})
+	}
+
+	/// Parses the headers of a frame whose payload was not captured in full.
+	/// Only frames whose transport header is complete are classified.
+	fn parse_truncated(data: &[u8]) -> Option<Layers> {
+		let sliced = LaxSlicedPacket::from_ethernet(data).ok()?;
+
+		let transport = sliced.transport.as_ref();
+		let (v4, fragmented, net) = match sliced.net.as_ref()? {
+			LaxNetSlice::Arp(arp) => {
+				return Some(Layers {
+					matcher: Some(Matcher::Arp),
+					fragmented: false,
+					net: Some(Span::of(data, arp.slice())),
+					transport: None,
+				});
+			},
+			LaxNetSlice::Ipv4(ipv4) => (true, ipv4.is_payload_fragmented(), ipv4.header().slice()),
+			LaxNetSlice::Ipv6(ipv6) => (false, ipv6.is_payload_fragmented(), ipv6.header().slice()),
+		};
+
+		let (matcher, transport) = match (v4, transport?) {
+			(true, TransportSlice::Icmpv4(icmp)) => (Matcher::IPv4_ICMPv4, icmp.slice()),
+			(true, TransportSlice::Tcp(tcp)) => (Matcher::IPv4_TCP, tcp.slice()),
+			(true, TransportSlice::Udp(udp)) => (Matcher::IPv4_UDP, udp.slice()),
+			(false, TransportSlice::Icmpv6(icmp)) => (Matcher::IPv6_ICMPv6, icmp.slice()),
+			(false, TransportSlice::Tcp(tcp)) => (Matcher::IPv6_TCP, tcp.slice()),
+			(false, TransportSlice::Udp(udp)) => (Matcher::IPv6_UDP, udp.slice()),
+			(_, TransportSlice::Icmpv4(_) | TransportSlice::Icmpv6(_)) => return None,
+		};
+
+		Some(Layers {
+			matcher: Some(matcher),
+			fragmented,
+			net: Some(Span::of(data, net)),
+			transport: Some(Span::of(data, transport)),
+		})
+	}
+
+	/// The listener the frame belongs to, if it was recognised
+	pub fn matcher(&self) -> Option<Matcher> {
+		self.matcher
+	}
+}
+
+/// Frame is a captured packet which has been parsed once.  Cloning a frame is
+/// cheap, as the bytes are shared rather than copied.
+#[derive(Clone)]
+pub struct Frame {
+	iface: Arc<Interface>,
+	header: PacketHeader,
+	data: PooledBuffer,
+	layers: Layers,
+}
+
+impl Frame {
+	/// Wraps a buffer whose layers were parsed from the same bytes
+	pub fn new(
+		iface: Arc<Interface>,
+		header: PacketHeader,
+		data: PooledBuffer,
+		layers: Layers,
+	) -> Frame {
+		Frame {
+			iface,
+			header,
+			data,
+			layers,
+		}
+	}
+
+	pub fn parse(
+		iface: Arc<Interface>,
+		header: PacketHeader,
+		data: PooledBuffer,
+	) -> Result<Frame, SliceError> {
+		let layers = Layers::parse(&data)?;
+		Ok(Frame::new(iface, header, data, layers))
+	}
+
+	/// A copy holding its bytes in a buffer of their own size, rather than one
+	/// from the capture's pool, for frames which are kept a while
+	pub fn detach(&self) -> Frame {
+		Frame::new(
+			self.iface.clone(),
+			self.header,
+			PooledBuffer::from(self.data.to_vec()),
+			self.layers,
+		)
+	}
+
+	pub fn iface(&self) -> &Arc<Interface> {
+		&self.iface
+	}
+
+	pub fn header(&self) -> &PacketHeader {
+		&self.header
+	}
+
+	/// The capture time, as an offset from the Unix epoch
+	pub fn timestamp(&self) -> Duration {
+		devices::timestamp(&self.header, self.iface.precision())
+	}
+
+	/// The captured bytes, starting with the Ethernet header
+	pub fn data(&self) -> &[u8] {
+		&self.data
+	}
+
+	/// The listener this frame belongs to, if it was recognised
+	pub fn matcher(&self) -> Option<Matcher> {
+		self.layers.matcher
+	}
+
+	/// The source and destination addresses of IP packets, read straight from
+	/// the header which was validated when the frame was parsed
+	pub fn addresses(&self) -> Option<(IpAddr, IpAddr)> {
+		let ip = self.net()?;
+		match self.layers.matcher? {
+			Matcher::IPv4_ICMPv4 | Matcher::IPv4_TCP | Matcher::IPv4_UDP => Some((
+				IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&ip[12..16]).ok()?)),
+				IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&ip[16..20]).ok()?)),
+			)),
+			Matcher::IPv6_ICMPv6 | Matcher::IPv6_TCP | Matcher::IPv6_UDP => Some((
+				IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&ip[8..24]).ok()?)),
+				IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&ip[24..40]).ok()?)),
+			)),
+			_ => None,
+		}
+	}
+
+	/// The TCP or UDP flow the packet belongs to
+	pub fn flow(&self) -> Option<(Protocol, FlowKey)> {
+		let (src_ip, dst_ip) = self.addresses()?;
+		let (protocol, src_port, dst_port) = if let Some(tcp) = self.tcp() {
+			(Protocol::Tcp, tcp.source_port(), tcp.destination_port())
+		} else {
+			let udp = self.udp()?;
+			(Protocol::Udp, udp.source_port(), udp.destination_port())
+		};
+
+		Some((
+			protocol,
+			FlowKey::new(
+				Endpoint::new(src_ip, src_port),
+				Endpoint::new(dst_ip, dst_port),
+			),
+		))
+	}
+
+	/// Whether less of the packet was captured than was on the wire, as
+	/// happens with a small snaplen
+	pub fn is_truncated(&self) -> bool {
+		self.header.caplen < self.header.len
+	}
+
+	/// The length of the TCP payload on the wire, which is more than was
+	/// captured when the frame is truncated.  It is worked out from the IP
+	/// header's length, as Ethernet padding may follow the packet.
+	pub fn tcp_payload_len(&self) -> Option<u32> {
+		let tcp = self.tcp()?;
+		let ip = self.net()?;
+		let net = self.layers.net?;
+		let transport = self.layers.transport?;
+
+		let ip_end = net.start as usize
+			+ match self.layers.matcher? {
+				Matcher::IPv4_TCP => u16::from_be_bytes([ip[2], ip[3]]) as usize,
+				Matcher::IPv6_TCP => 40 + u16::from_be_bytes([ip[4], ip[5]]) as usize,
+				_ => return None,
+			};
+		let payload_start = transport.start as usize + tcp.header_len();
+
+		// A jumbogram leaves the IPv6 payload length at 0
+		let wire = ip_end.saturating_sub(payload_start);
+		Some(wire.max(tcp.payload().len()) as u32)
+	}
+
+	/// Whether the IP packet carries a fragment of a larger datagram
+	pub fn is_fragmented(&self) -> bool {
+		self.layers.fragmented
+	}
+
+	/// Parses the whole frame again, for consumers which need every layer
+	pub fn sliced(&self) -> Option<SlicedPacket<'_>> {
+		SlicedPacket::from_ethernet(&self.data).ok()
+	}
+
+	pub fn arp(&self) -> Option<ArpPacketSlice<'_>> {
+		match self.layers.matcher {
+			Some(Matcher::Arp) => ArpPacketSlice::from_slice(self.net()?).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn tcp(&self) -> Option<TcpSlice<'_>> {
+		match self.layers.matcher {
+			Some(Matcher::IPv4_TCP | Matcher::IPv6_TCP) => TcpSlice::from_slice(self.transport()?).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn udp(&self) -> Option<UdpSlice<'_>> {
+		match self.layers.matcher {
+			Some(Matcher::IPv4_UDP | Matcher::IPv6_UDP) => {
+				UdpSlice::from_slice_lax(self.transport()?).ok()
+			},
+			_ => None,
+		}
+	}
+
+	pub fn icmpv4(&self) -> Option<Icmpv4Slice<'_>> {
+		match self.layers.matcher {
+			Some(Matcher::IPv4_ICMPv4) => Icmpv4Slice::from_slice(self.transport()?).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn icmpv6(&self) -> Option<Icmpv6Slice<'_>> {
+		match self.layers.matcher {
+			Some(Matcher::IPv6_ICMPv6) => Icmpv6Slice::from_slice(self.transport()?).ok(),
+			_ => None,
+		}
+	}
+
+	fn net(&self) -> Option<&[u8]> {
+		self.layers.net.map(|s| s.slice(&self.data))
+	}
+
+	fn transport(&self) -> Option<&[u8]> {
+		self.layers.transport.map(|s| s.slice(&self.data))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, sync::Arc, time::Duration};
+
+	use etherparse::PacketBuilder;
+	use pcap::PacketHeader;
+
+	use crate::{
+		devices::{Matcher, Precision},
+		flow::{Endpoint, FlowKey, Protocol},
+		packet::{BufferPool, Frame},
+		state::interface::Interface,
+	};
+
+	fn header(len: usize) -> PacketHeader {
+		PacketHeader {
+			ts: libc::timeval {
+				tv_sec: 1,
+				tv_usec: 0,
+			},
+			caplen: len as u32,
+			len: len as u32,
+		}
+	}
+
+	#[test]
+	fn test_frame_layers_and_pool_reuse() {
+		let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+			.ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64)
+			.tcp(40000, 80, 1000, 4096)
+			.syn();
+		let mut data = vec![];
+		builder.write(&mut data, b"hello").unwrap();
+		// Ethernet padding must not be mistaken for TCP payload
+		data.extend_from_slice(&[0, 0, 0]);
+
+		let mut pool = BufferPool::new(1);
+		let iface = Arc::new(Interface::new("eth0".to_string()));
+		let frame = Frame::parse(iface, header(data.len()), pool.copy_from(&data)).unwrap();
+
+		assert_eq!(Some(Matcher::IPv4_TCP), frame.matcher());
+		assert_eq!(
+			Some((IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 9]))),
+			frame.addresses()
+		);
+		let tcp = frame.tcp().unwrap();
+		assert!(tcp.syn());
+		assert_eq!(b"hello", tcp.payload());
+		assert_eq!(Some(5), frame.tcp_payload_len());
+		assert!(frame.udp().is_none());
+		let (protocol, key) = frame.flow().unwrap();
+		assert_eq!(Protocol::Tcp, protocol);
+		assert_eq!(
+			FlowKey::new(
+				Endpoint::new(IpAddr::from([10, 0, 0, 9]), 80),
+				Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000)
+			),
+			key
+		);
+
+		// The buffer is only recycled once every clone has gone
+		let clone = frame.clone();
+		let bytes = frame.data().as_ptr();
+		drop(frame);
+		let fresh = pool.copy_from(&data);
+		assert_ne!(bytes, fresh.as_ptr());
+		drop(clone);
+		assert_eq!(bytes, pool.copy_from(&data).as_ptr());
+
+		// A frame cut short by the snaplen is still classified by its headers
+		let mut h = header(data.len());
+		h.caplen = 14 + 20 + 20 + 2;
+		h.ts.tv_usec = 5;
+		let iface = Interface::new("eth0".to_string()).with_precision(Precision::Nano);
+		let truncated = pool.copy_from(&data[..h.caplen as usize]);
+		let frame = Frame::parse(Arc::new(iface), h, truncated).unwrap();
+		assert!(frame.is_truncated());
+		assert_eq!(Some(Matcher::IPv4_TCP), frame.matcher());
+		assert_eq!(b"he", frame.tcp().unwrap().payload());
+		assert_eq!(Some(5), frame.tcp_payload_len());
+		assert_eq!(Duration::new(1, 5), frame.timestamp());
+	}
+}
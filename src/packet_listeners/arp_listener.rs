@@ -1,19 +1,30 @@
+use std::net::Ipv4Addr;
+
 use async_trait::async_trait;
-use etherparse::{NetSlice, SlicedPacket};
-use tokio::sync::{broadcast, mpsc::Receiver};
+use tokio::sync::broadcast;
 
 use crate::{
 	devices::ReceivedPacketData,
+	events::{Alert, EventKind, Events},
+	packet::Frame,
 	packet_listeners::listener::{self, BuildError, PacketHandler},
+	queue::Receiver,
 	runtime::{Runnable, RunnableBuilder},
+	state::arp::SharedArpTable,
 };
 
 pub struct ArpListenerBuilder {
 	receiver: Option<Receiver<ReceivedPacketData>>,
+	table: Option<SharedArpTable>,
+	events: Events,
 }
 
 pub fn new() -> ArpListenerBuilder {
-	ArpListenerBuilder { receiver: None }
+	ArpListenerBuilder {
+		receiver: None,
+		table: None,
+		events: Events::default(),
+	}
 }
 
 impl ArpListenerBuilder {
@@ -21,12 +32,30 @@ impl ArpListenerBuilder {
 		self.receiver = Some(receiver);
 		self
 	}
+
+	/// Builds the IP-to-MAC bindings into a table shared with the API
+	pub fn with_table(mut self, table: SharedArpTable) -> Self {
+		self.table = Some(table);
+		self
+	}
+
+	/// Publishes gratuitous ARP and suspicious bindings as alerts
+	pub fn with_events(mut self, events: Events) -> Self {
+		self.events = events;
+		self
+	}
 }
 
+/// ArpListener learns IP-to-MAC bindings and reports gratuitous ARP, changed
+/// bindings and MACs which claim many IPs
 pub struct ArpListener {
 	receiver: Receiver<ReceivedPacketData>,
 
 	packet_count: u64,
+
+	table: SharedArpTable,
+
+	events: Events,
 }
 
 #[async_trait]
@@ -40,6 +69,8 @@ impl RunnableBuilder for ArpListenerBuilder {
 		Ok(Box::new(ArpListener {
 			receiver,
 			packet_count: 0,
+			table: self.table.unwrap_or_default(),
+			events: self.events,
 		}))
 	}
 }
@@ -57,14 +88,34 @@ impl PacketHandler for ArpListener {
 		self.receiver.recv().await
 	}
 
-	async fn handle_packet(&mut self, packet: SlicedPacket<'_>) {
+	async fn handle_packet(&mut self, frame: &Frame) {
 		self.packet_count += 1;
 
-		if let Some(NetSlice::Arp(_arp_header)) = &packet.net {}
+		if let Some(arp) = frame.arp()
+			&& let Ok(sender_ip) = <[u8; 4]>::try_from(arp.sender_protocol_addr())
+			&& let Ok(target_ip) = <[u8; 4]>::try_from(arp.target_protocol_addr())
+		{
+			let iface = frame.iface();
+			let events = self.table.lock().unwrap().observe(
+				iface.name(),
+				Ipv4Addr::from(sender_ip),
+				arp.sender_hw_addr(),
+				Ipv4Addr::from(target_ip),
+				frame.timestamp(),
+			);
 
-		// if let Some(NetSlice::Ipv4(ipv4_header)) = &packet.net && let Some(TransportSlice::Tcp(tcp_header)) = &packet.transport {
-		//   process_ipv4_tcp(&mut self.sequences, ipv4_header, tcp_header)
-		// }
+			for event in events {
+				self.events.publish(
+					event.ts,
+					&event.iface,
+					EventKind::Alert(Alert::Arp {
+						ip: event.ip,
+						mac: event.mac,
+						kind: event.kind,
+					}),
+				);
+			}
+		}
 	}
 
 	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
@@ -1,14 +1,16 @@
 use async_trait::async_trait;
-use etherparse::{NetSlice, SlicedPacket};
-use tokio::sync::{broadcast, mpsc::Receiver};
+use tokio::sync::broadcast;
 
 use crate::{
 	devices::ReceivedPacketData,
+	packet::Frame,
 	packet_listeners::listener::{self, PacketHandler},
+	queue::Receiver,
 	runtime::Runnable,
 	state::appstate::AppState,
 };
 
+#[allow(dead_code)]
 struct GenericListener {
 	state: AppState,
 	receiver: Receiver<ReceivedPacketData>,
@@ -28,10 +30,10 @@ impl PacketHandler for GenericListener {
 		self.receiver.recv().await
 	}
 
-	async fn handle_packet(&mut self, packet: SlicedPacket<'_>) {
+	async fn handle_packet(&mut self, frame: &Frame) {
 		self.packet_count += 1;
 
-		if let Some(NetSlice::Arp(_arp_header)) = &packet.net {}
+		if let Some(_arp_header) = frame.arp() {}
 
 		// if let Some(NetSlice::Ipv4(ipv4_header)) = &packet.net && let Some(TransportSlice::Tcp(tcp_header)) = &packet.transport {
 		//   process_ipv4_tcp(&mut self.sequences, ipv4_header, tcp_header)
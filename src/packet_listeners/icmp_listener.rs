@@ -0,0 +1,380 @@
+use std::{fmt, marker::PhantomData, time::Duration};
+
+use async_trait::async_trait;
+use etherparse::{Icmpv4Type, Icmpv6Type, icmpv4::DestUnreachableHeader};
+use tokio::sync::broadcast;
+
+use crate::{
+	devices::{self, ReceivedPacketData},
+	events::{
+		EchoReply, EventKind, Events, IcmpError, IcmpSummary, PacketSummary, QuotedFlow, TrackedFlow,
+	},
+	flow::{
+		FlowKey,
+		icmp::{self, EchoTracker},
+	},
+	packet::Frame,
+	packet_listeners::{
+		ip_version::{IpVersion, V4, V6},
+		listener::{self, BuildError, PacketHandler},
+	},
+	queue::Receiver,
+	runtime::{Runnable, RunnableBuilder},
+	state::flows::SharedFlows,
+};
+
+/// IcmpKind is the version-independent meaning of an ICMP message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IcmpKind {
+	EchoRequest { id: u16, seq: u16 },
+	EchoReply { id: u16, seq: u16 },
+	DestinationUnreachable,
+	PacketTooBig { mtu: u32 },
+	TimeExceeded,
+	ParameterProblem,
+	Redirect,
+	RouterSolicitation,
+	RouterAdvertisement,
+	NeighborSolicitation,
+	NeighborAdvertisement,
+	Other,
+}
+
+impl fmt::Display for IcmpKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			IcmpKind::EchoRequest { id, seq } => write!(f, "echo-request id={} seq={}", id, seq),
+			IcmpKind::EchoReply { id, seq } => write!(f, "echo-reply id={} seq={}", id, seq),
+			IcmpKind::DestinationUnreachable => write!(f, "destination-unreachable"),
+			IcmpKind::PacketTooBig { mtu } => write!(f, "packet-too-big mtu={}", mtu),
+			IcmpKind::TimeExceeded => write!(f, "time-exceeded"),
+			IcmpKind::ParameterProblem => write!(f, "parameter-problem"),
+			IcmpKind::Redirect => write!(f, "redirect"),
+			IcmpKind::RouterSolicitation => write!(f, "router-solicitation"),
+			IcmpKind::RouterAdvertisement => write!(f, "router-advertisement"),
+			IcmpKind::NeighborSolicitation => write!(f, "neighbor-solicitation"),
+			IcmpKind::NeighborAdvertisement => write!(f, "neighbor-advertisement"),
+			IcmpKind::Other => write!(f, "other"),
+		}
+	}
+}
+
+impl IcmpKind {
+	/// Whether the message reports a problem with a datagram, which it quotes
+	pub fn is_error(&self) -> bool {
+		matches!(
+			self,
+			IcmpKind::DestinationUnreachable
+				| IcmpKind::PacketTooBig { .. }
+				| IcmpKind::TimeExceeded
+				| IcmpKind::ParameterProblem
+		)
+	}
+}
+
+/// IcmpMessage is a decoded ICMP header along with the raw type and code
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IcmpMessage<'a> {
+	pub type_u8: u8,
+	pub code_u8: u8,
+	pub kind: IcmpKind,
+	/// The message body, which for errors starts with the offending datagram
+	pub payload: &'a [u8],
+}
+
+/// IcmpVersion decodes the ICMP flavour which belongs to an IP version
+pub trait IcmpVersion: IpVersion {
+	/// Name of the ICMP protocol, e.g. "ICMPv6"
+	const PROTOCOL: &'static str;
+
+	fn decode(frame: &Frame) -> Option<IcmpMessage<'_>>;
+}
+
+impl IcmpVersion for V4 {
+	const PROTOCOL: &'static str = "ICMPv4";
+
+	fn decode(frame: &Frame) -> Option<IcmpMessage<'_>> {
+		let icmp = frame.icmpv4()?;
+
+		let kind = match icmp.icmp_type() {
+			Icmpv4Type::EchoRequest(echo) => IcmpKind::EchoRequest {
+				id: echo.id,
+				seq: echo.seq,
+			},
+			Icmpv4Type::EchoReply(echo) => IcmpKind::EchoReply {
+				id: echo.id,
+				seq: echo.seq,
+			},
+			// IPv4 reports path MTU problems as a kind of unreachable
+			Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FragmentationNeeded {
+				next_hop_mtu,
+			}) => IcmpKind::PacketTooBig {
+				mtu: next_hop_mtu.into(),
+			},
+			Icmpv4Type::DestinationUnreachable(_) => IcmpKind::DestinationUnreachable,
+			Icmpv4Type::TimeExceeded(_) => IcmpKind::TimeExceeded,
+			Icmpv4Type::ParameterProblem(_) => IcmpKind::ParameterProblem,
+			Icmpv4Type::Redirect(_) => IcmpKind::Redirect,
+			Icmpv4Type::TimestampRequest(_)
+			| Icmpv4Type::TimestampReply(_)
+			| Icmpv4Type::Unknown { .. } => IcmpKind::Other,
+		};
+
+		Some(IcmpMessage {
+			type_u8: icmp.type_u8(),
+			code_u8: icmp.code_u8(),
+			kind,
+			payload: icmp.payload(),
+		})
+	}
+}
+
+impl IcmpVersion for V6 {
+	const PROTOCOL: &'static str = "ICMPv6";
+
+	fn decode(frame: &Frame) -> Option<IcmpMessage<'_>> {
+		let icmp = frame.icmpv6()?;
+
+		let kind = match icmp.icmp_type() {
+			Icmpv6Type::EchoRequest(echo) => IcmpKind::EchoRequest {
+				id: echo.id,
+				seq: echo.seq,
+			},
+			Icmpv6Type::EchoReply(echo) => IcmpKind::EchoReply {
+				id: echo.id,
+				seq: echo.seq,
+			},
+			Icmpv6Type::DestinationUnreachable(_) => IcmpKind::DestinationUnreachable,
+			Icmpv6Type::PacketTooBig { mtu } => IcmpKind::PacketTooBig { mtu },
+			Icmpv6Type::TimeExceeded(_) => IcmpKind::TimeExceeded,
+			Icmpv6Type::ParameterProblem(_) => IcmpKind::ParameterProblem,
+			Icmpv6Type::RouterSolicitation => IcmpKind::RouterSolicitation,
+			Icmpv6Type::RouterAdvertisement(_) => IcmpKind::RouterAdvertisement,
+			Icmpv6Type::NeighborSolicitation => IcmpKind::NeighborSolicitation,
+			Icmpv6Type::NeighborAdvertisement(_) => IcmpKind::NeighborAdvertisement,
+			Icmpv6Type::Redirect => IcmpKind::Redirect,
+			Icmpv6Type::Unknown { .. } => IcmpKind::Other,
+		};
+
+		Some(IcmpMessage {
+			type_u8: icmp.type_u8(),
+			code_u8: icmp.code_u8(),
+			kind,
+			payload: icmp.payload(),
+		})
+	}
+}
+
+pub struct IcmpListenerBuilder<V: IcmpVersion> {
+	receiver: Option<Receiver<devices::ReceivedPacketData>>,
+	echo_timeout: Duration,
+	shared_flows: Option<SharedFlows>,
+	events: Events,
+	version: PhantomData<V>,
+}
+
+pub fn new<V: IcmpVersion>() -> IcmpListenerBuilder<V> {
+	IcmpListenerBuilder {
+		receiver: None,
+		echo_timeout: icmp::DEFAULT_ECHO_TIMEOUT,
+		shared_flows: None,
+		events: Events::default(),
+		version: PhantomData,
+	}
+}
+
+impl<V: IcmpVersion> IcmpListenerBuilder<V> {
+	pub fn set_receiver(mut self, receiver: Receiver<devices::ReceivedPacketData>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	/// How long an echo request waits for its reply before it is forgotten
+	pub fn with_echo_timeout(mut self, echo_timeout: Duration) -> Self {
+		self.echo_timeout = echo_timeout;
+		self
+	}
+
+	/// Attributes ICMP errors to the TCP and UDP flows in a shared registry
+	pub fn with_shared_flows(mut self, shared_flows: SharedFlows) -> Self {
+		self.shared_flows = Some(shared_flows);
+		self
+	}
+
+	/// Publishes every ICMP message
+	pub fn with_events(mut self, events: Events) -> Self {
+		self.events = events;
+		self
+	}
+}
+
+/// IcmpListener publishes ICMP messages, measures echo round trip times, and
+/// attributes error messages to the flow whose datagram they quote
+pub struct IcmpListener<V: IcmpVersion> {
+	receiver: Receiver<devices::ReceivedPacketData>,
+	echoes: EchoTracker,
+	shared_flows: Option<SharedFlows>,
+	events: Events,
+	version: PhantomData<V>,
+}
+
+#[async_trait]
+impl<V: IcmpVersion> RunnableBuilder for IcmpListenerBuilder<V> {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+
+		Ok(Box::new(IcmpListener::<V> {
+			receiver,
+			echoes: EchoTracker::new(self.echo_timeout),
+			shared_flows: self.shared_flows,
+			events: self.events,
+			version: PhantomData,
+		}))
+	}
+}
+
+#[async_trait]
+impl<V: IcmpVersion> Runnable for IcmpListener<V> {
+	async fn run(&mut self, cancel_rx: broadcast::Receiver<()>) {
+		listener::run(cancel_rx, self).await
+	}
+}
+
+#[async_trait]
+impl<V: IcmpVersion> PacketHandler for IcmpListener<V> {
+	async fn recv(&mut self) -> Option<ReceivedPacketData> {
+		self.receiver.recv().await
+	}
+
+	async fn handle_packet(&mut self, frame: &Frame) {
+		if let Some((src_ip, dst_ip)) = frame.addresses()
+			&& V::is_version(&src_ip)
+			&& let Some(message) = V::decode(frame)
+		{
+			let iface = frame.iface();
+			let ts = frame.timestamp();
+
+			let (mut reply, mut error) = (None, None);
+			match message.kind {
+				IcmpKind::EchoRequest { id, seq } => {
+					self.echoes.request(src_ip, dst_ip, id, seq, ts);
+				},
+				IcmpKind::EchoReply { id, seq } => {
+					let rtt = self.echoes.reply(src_ip, dst_ip, id, seq, ts);
+					reply = Some(EchoReply {
+						rtt: rtt.map(|rtt| rtt.as_secs_f64()),
+					});
+				},
+				kind if kind.is_error() => error = Some(self.correlate(&message)),
+				_ => {},
+			}
+			self.echoes.expire(ts);
+
+			self.events.publish(
+				ts,
+				iface.name(),
+				EventKind::Packet(Box::new(PacketSummary {
+					protocol: format!("{}-{}", V::LABEL, V::PROTOCOL),
+					src: src_ip.to_string(),
+					dst: dst_ip.to_string(),
+					bytes: message.payload.len() as u64,
+					tcp: None,
+					icmp: Some(IcmpSummary {
+						type_u8: message.type_u8,
+						code_u8: message.code_u8,
+						kind: message.kind.to_string(),
+						reply,
+						error,
+					}),
+					text: None,
+				})),
+			);
+		}
+	}
+
+	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
+}
+
+impl<V: IcmpVersion> IcmpListener<V> {
+	/// Finds the flow an error message refers to, and records the error
+	/// against it when the flow is being tracked
+	fn correlate(&self, message: &IcmpMessage) -> IcmpError {
+		let quoted = match icmp::quoted_flow(message.payload) {
+			Some(q) => q,
+			None => return IcmpError { flow: None },
+		};
+		let key = FlowKey::new(quoted.src, quoted.dst);
+
+		let record = self.shared_flows.as_ref().and_then(|shared_flows| {
+			shared_flows
+				.lock()
+				.unwrap()
+				.attribute_icmp_error(
+					quoted.protocol,
+					&key,
+					format!(
+						"type={} code={} {}",
+						message.type_u8, message.code_u8, message.kind
+					),
+				)
+				.cloned()
+		});
+
+		IcmpError {
+			flow: Some(QuotedFlow {
+				protocol: quoted.protocol.to_string(),
+				src: quoted.src,
+				dst: quoted.dst,
+				tracked: record.map(|record| TrackedFlow {
+					state: record.state.map(|s| s.to_string()),
+					packets: record.packets,
+					errors: record.icmp_errors,
+				}),
+			}),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use etherparse::{IcmpEchoHeader, Icmpv6Type, PacketBuilder};
+	use pcap::PacketHeader;
+
+	use crate::{
+		packet::Frame,
+		packet_listeners::{
+			icmp_listener::{IcmpKind, IcmpVersion},
+			ip_version::V6,
+		},
+		state::interface::Interface,
+	};
+
+	#[test]
+	fn test_decode_icmpv6_echo() {
+		let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+			.ipv6([0xfe; 16], [0xfd; 16], 64)
+			.icmpv6(Icmpv6Type::EchoRequest(IcmpEchoHeader { id: 7, seq: 3 }));
+		let mut data = vec![];
+		builder.write(&mut data, b"ping").unwrap();
+
+		let header = PacketHeader {
+			ts: libc::timeval {
+				tv_sec: 1,
+				tv_usec: 0,
+			},
+			caplen: data.len() as u32,
+			len: data.len() as u32,
+		};
+		let iface = Arc::new(Interface::new("eth0".to_string()));
+		let frame = Frame::parse(iface, header, data.into()).unwrap();
+
+		let message = V6::decode(&frame).unwrap();
+		assert_eq!(128, message.type_u8);
+		assert_eq!(IcmpKind::EchoRequest { id: 7, seq: 3 }, message.kind);
+	}
+}
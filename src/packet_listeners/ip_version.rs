@@ -0,0 +1,31 @@
+use std::net::IpAddr;
+
+/// IpVersion lets a listener be written once and then instantiated separately
+/// for IPv4 and IPv6 traffic
+pub trait IpVersion: Send + Sync + 'static {
+	/// Prefix used when printing packets, e.g. "IPv4"
+	const LABEL: &'static str;
+
+	/// Whether an address belongs to this version
+	fn is_version(ip: &IpAddr) -> bool;
+}
+
+pub struct V4;
+
+pub struct V6;
+
+impl IpVersion for V4 {
+	const LABEL: &'static str = "IPv4";
+
+	fn is_version(ip: &IpAddr) -> bool {
+		ip.is_ipv4()
+	}
+}
+
+impl IpVersion for V6 {
+	const LABEL: &'static str = "IPv6";
+
+	fn is_version(ip: &IpAddr) -> bool {
+		ip.is_ipv6()
+	}
+}
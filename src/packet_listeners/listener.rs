@@ -1,16 +1,14 @@
 use async_trait::async_trait;
-use etherparse::SlicedPacket;
-use log::error;
 use thiserror::Error;
 use tokio::sync::broadcast;
 
-use crate::devices::ReceivedPacketData;
+use crate::{devices::ReceivedPacketData, packet::Frame};
 
 // Define a trait that your struct will implement
 #[async_trait]
 pub trait PacketHandler {
 	async fn recv(&mut self) -> Option<ReceivedPacketData>;
-	async fn handle_packet(&mut self, value: SlicedPacket<'_>);
+	async fn handle_packet(&mut self, frame: &Frame);
 	async fn handle_packet_count(&mut self, value: (u64, u64, u64));
 }
 
@@ -24,20 +22,15 @@ pub async fn run<T: PacketHandler>(mut cancel_rx: broadcast::Receiver<()>, handl
 				let x0 = match x {
 					Some(x) => x,
 					None => {
-						continue;
+						// Every sender is gone, so nothing more will arrive
+						let _ = cancel_rx.recv().await;
+						break;
 					}
 				};
 				match x0 {
-					ReceivedPacketData::MovingPacket { data, ..} => {
-						// let p = pcap::Packet{ &header, &data };
-						match SlicedPacket::from_ethernet(&data) {
-							Ok(value) => {
-								handler.handle_packet(value).await;
-							},
-							Err(err) => {
-								error!("Error parsing packet: {:?}", err);
-							}
-						};
+					ReceivedPacketData::MovingPacket(frame) => {
+						// Already parsed on the capture thread
+						handler.handle_packet(&frame).await;
 					},
 					ReceivedPacketData::Counts { .. } => {
 
@@ -52,4 +45,10 @@ pub async fn run<T: PacketHandler>(mut cancel_rx: broadcast::Receiver<()>, handl
 pub enum BuildError {
 	#[error("no receiver")]
 	NoReceiver,
+
+	#[error("no output directory")]
+	NoDirectory,
+
+	#[error("invalid file name template '{0}'")]
+	InvalidTemplate(String),
 }
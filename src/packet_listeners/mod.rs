@@ -1,6 +1,11 @@
 pub mod arp_listener;
-pub mod ipv4_tcp_listener;
-pub mod ipv4_udp_listener;
+pub mod icmp_listener;
+pub mod ip_version;
 pub mod listener;
+pub mod pcapng_listener;
+pub mod ring_listener;
+pub mod summary_listener;
+pub mod tcp_listener;
+pub mod udp_listener;
 
 mod generic_listener;
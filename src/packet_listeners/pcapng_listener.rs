@@ -0,0 +1,532 @@
+use std::{
+	collections::VecDeque,
+	fs::{self, File},
+	io::{self, BufWriter},
+	path::{Path, PathBuf},
+	thread,
+	time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use chrono::{
+	DateTime,
+	format::{self, Item, Parsed, StrftimeItems},
+};
+use log::{error, info};
+use pcap::{BpfProgram, Capture, Linktype};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+	devices::ReceivedPacketData,
+	packet::Frame,
+	packet_listeners::listener::{self, BuildError, PacketHandler},
+	pcapng,
+	queue::Receiver,
+	runtime::{Runnable, RunnableBuilder},
+};
+
+/// File names are the time of a file's first packet, formatted with this
+/// strftime template unless configured otherwise
+pub const DEFAULT_TEMPLATE: &str = "psniff-%Y%m%d-%H%M%S.pcapng";
+
+/// How often, in capture time, buffered packets are flushed to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many frames may wait for the writer thread
+const WRITE_QUEUE: usize = 1024;
+
+/// Rotation starts a new file once the current one is big or old enough
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+	pub bytes: Option<u64>,
+	pub interval: Option<Duration>,
+}
+
+impl Rotation {
+	fn due(&self, output: &Output, ts: Duration) -> bool {
+		self.bytes.is_some_and(|b| output.writer.written() >= b)
+			|| self
+				.interval
+				.is_some_and(|i| ts.saturating_sub(output.started) >= i)
+	}
+}
+
+/// Retention removes the oldest files written, so that at most this many
+/// files, or bytes, are kept.  Files already in the directory whose names fit
+/// the template count too, such as those left by an earlier run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+	pub files: Option<usize>,
+	pub bytes: Option<u64>,
+}
+
+/// Checks that a file name template is a valid strftime format which stays
+/// inside the output directory
+pub fn validate_template(template: &str) -> Result<(), BuildError> {
+	let invalid = StrftimeItems::new(template).any(|i| matches!(i, Item::Error));
+	if template.is_empty() || invalid || template.contains(std::path::MAIN_SEPARATOR) {
+		return Err(BuildError::InvalidTemplate(template.to_string()));
+	}
+	Ok(())
+}
+
+pub struct PcapngListenerBuilder {
+	receiver: Option<Receiver<ReceivedPacketData>>,
+	directory: Option<PathBuf>,
+	template: String,
+	filter: Option<String>,
+	rotation: Rotation,
+	retention: Retention,
+}
+
+pub fn new() -> PcapngListenerBuilder {
+	PcapngListenerBuilder {
+		receiver: None,
+		directory: None,
+		template: DEFAULT_TEMPLATE.to_string(),
+		filter: None,
+		rotation: Rotation::default(),
+		retention: Retention::default(),
+	}
+}
+
+impl PcapngListenerBuilder {
+	pub fn set_receiver(mut self, receiver: Receiver<ReceivedPacketData>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	/// The directory files are written to, which is created if need be
+	pub fn with_directory(mut self, directory: PathBuf) -> Self {
+		self.directory = Some(directory);
+		self
+	}
+
+	pub fn with_template(mut self, template: String) -> Self {
+		self.template = template;
+		self
+	}
+
+	/// Only writes packets matching this BPF expression
+	pub fn with_filter(mut self, filter: String) -> Self {
+		self.filter = Some(filter);
+		self
+	}
+
+	pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+		self.rotation = rotation;
+		self
+	}
+
+	pub fn with_retention(mut self, retention: Retention) -> Self {
+		self.retention = retention;
+		self
+	}
+
+	fn into_listener(mut self) -> Result<PcapngListener, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver.take() {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+
+		Ok(PcapngListener {
+			receiver,
+			files: Some(self.into_files()?),
+			frames: None,
+		})
+	}
+
+	fn into_files(self) -> Result<Files, Box<dyn std::error::Error>> {
+		let directory = match self.directory {
+			Some(x) => x,
+			None => return Err(BuildError::NoDirectory.into()),
+		};
+		validate_template(&self.template)?;
+
+		let filter = match &self.filter {
+			Some(filter) => Some(Capture::dead(Linktype::ETHERNET)?.compile(filter, true)?),
+			None => None,
+		};
+
+		fs::create_dir_all(&directory)?;
+		let finished = existing(&directory, &self.template)?;
+
+		Ok(Files {
+			directory,
+			template: self.template,
+			filter,
+			rotation: self.rotation,
+			retention: self.retention,
+			current: None,
+			finished,
+			packet_count: 0,
+		})
+	}
+}
+
+/// The files in `directory` which the template could have named, oldest
+/// first, with their sizes
+fn existing(directory: &Path, template: &str) -> io::Result<VecDeque<(PathBuf, u64)>> {
+	let mut files = vec![];
+	for entry in fs::read_dir(directory)? {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+		if !metadata.is_file() || !fits_template(template, &entry.file_name().to_string_lossy()) {
+			continue;
+		}
+		let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+		files.push((modified, entry.path(), metadata.len()));
+	}
+	files.sort();
+
+	Ok(
+		files
+			.into_iter()
+			.map(|(_, path, size)| (path, size))
+			.collect(),
+	)
+}
+
+/// Whether a file name is one the template gives, with or without the counter
+/// `next_path` adds
+fn fits_template(template: &str, name: &str) -> bool {
+	let fits =
+		|name: &str| format::parse(&mut Parsed::new(), name, StrftimeItems::new(template)).is_ok();
+	if fits(name) {
+		return true;
+	}
+
+	let path = Path::new(name);
+	let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+	let extension = path
+		.extension()
+		.map(|e| format!(".{}", e.to_string_lossy()))
+		.unwrap_or_default();
+	match stem.rsplit_once('-') {
+		Some((stem, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+			fits(&format!("{}{}", stem, extension))
+		},
+		_ => false,
+	}
+}
+
+struct Output {
+	path: PathBuf,
+	writer: pcapng::Writer<BufWriter<File>>,
+	started: Duration,
+	flushed: Duration,
+}
+
+/// PcapngListener writes the packets it receives to a ring of pcapng files.
+/// The files are written on a thread of their own, so that a slow disk holds
+/// up only this sink.
+pub struct PcapngListener {
+	receiver: Receiver<ReceivedPacketData>,
+
+	/// Moved to the writer thread once the listener runs
+	files: Option<Files>,
+	frames: Option<mpsc::Sender<Frame>>,
+}
+
+/// Files is the ring of pcapng files, written by blocking calls
+struct Files {
+	directory: PathBuf,
+	template: String,
+	filter: Option<BpfProgram>,
+	rotation: Rotation,
+	retention: Retention,
+
+	current: Option<Output>,
+
+	/// Files which are closed, oldest first, with their sizes; both those this
+	/// listener wrote and those found in the directory when it was built
+	finished: VecDeque<(PathBuf, u64)>,
+
+	packet_count: u64,
+}
+
+impl Files {
+	/// Writes every frame received, then finishes the current file
+	fn write(mut self, mut frames: mpsc::Receiver<Frame>) {
+		while let Some(frame) = frames.blocking_recv() {
+			// A file which cannot be written is abandoned, and the next packet
+			// starts another
+			if let Err(e) = self.record(&frame) {
+				error!("pcapng: packet could not be written: {}", e);
+				if let Some(output) = self.current.take() {
+					self
+						.finished
+						.push_back((output.path, output.writer.written()));
+				}
+			}
+		}
+
+		if let Err(e) = self.finish() {
+			error!("pcapng: file could not be finished: {}", e);
+		}
+	}
+
+	fn record(&mut self, frame: &Frame) -> io::Result<()> {
+		if let Some(filter) = &self.filter
+			&& !filter.filter(frame.data())
+		{
+			return Ok(());
+		}
+		self.packet_count += 1;
+
+		let ts = frame.timestamp();
+		if self
+			.current
+			.as_ref()
+			.is_some_and(|o| self.rotation.due(o, ts))
+		{
+			self.finish()?;
+		}
+
+		let output = match &mut self.current {
+			Some(output) => output,
+			None => self.start(ts)?,
+		};
+
+		let iface = frame.iface();
+		output.writer.packet(
+			iface.name(),
+			iface.precision(),
+			ts,
+			frame.data(),
+			frame.header().len,
+		)?;
+
+		// Keep the file readable while it is being written
+		if ts.saturating_sub(output.flushed) >= FLUSH_INTERVAL {
+			output.writer.flush()?;
+			output.flushed = ts;
+		}
+
+		Ok(())
+	}
+
+	fn start(&mut self, ts: Duration) -> io::Result<&mut Output> {
+		self.retain();
+
+		let path = self.next_path(ts);
+		let writer = pcapng::Writer::new(BufWriter::new(File::create_new(&path)?))?;
+		info!("pcapng: writing {}", path.display());
+
+		let output = self.current.insert(Output {
+			path,
+			writer,
+			started: ts,
+			flushed: ts,
+		});
+		Ok(output)
+	}
+
+	fn finish(&mut self) -> io::Result<()> {
+		let Some(mut output) = self.current.take() else {
+			return Ok(());
+		};
+		// A file which cannot be flushed is still kept within the limits
+		let flushed = output.writer.flush();
+		self
+			.finished
+			.push_back((output.path, output.writer.written()));
+		flushed
+	}
+
+	/// Removes the oldest finished files until there is room, within the
+	/// retention limits, for the file about to be written
+	fn retain(&mut self) {
+		let Retention { files, bytes } = self.retention;
+
+		loop {
+			let count = self.finished.len() + 1;
+			let total: u64 = self.finished.iter().map(|(_, size)| size).sum();
+			let over = files.is_some_and(|f| count > f) || bytes.is_some_and(|b| total > b);
+			if !over {
+				break;
+			}
+
+			let Some((path, _)) = self.finished.pop_front() else {
+				break;
+			};
+			match fs::remove_file(&path) {
+				Ok(()) => info!("pcapng: removed {}", path.display()),
+				Err(e) => error!("pcapng: {} could not be removed: {}", path.display(), e),
+			}
+		}
+	}
+
+	/// The file a packet captured at `ts` starts, distinguished by a counter if
+	/// the template would repeat a name
+	fn next_path(&self, ts: Duration) -> PathBuf {
+		let time = DateTime::from_timestamp(ts.as_secs() as i64, ts.subsec_nanos()).unwrap_or_default();
+		let name = time.format(&self.template).to_string();
+		let path = self.directory.join(&name);
+		if !path.exists() {
+			return path;
+		}
+
+		let name = Path::new(&name);
+		let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+		let extension = name
+			.extension()
+			.map(|e| format!(".{}", e.to_string_lossy()))
+			.unwrap_or_default();
+		(1..)
+			.map(|n| self.directory.join(format!("{}-{}{}", stem, n, extension)))
+			.find(|p| !p.exists())
+			.unwrap()
+	}
+}
+
+#[async_trait]
+impl RunnableBuilder for PcapngListenerBuilder {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		Ok(Box::new(self.into_listener()?))
+	}
+}
+
+#[async_trait]
+impl Runnable for PcapngListener {
+	async fn run(&mut self, cancel_rx: broadcast::Receiver<()>) {
+		let Some(files) = self.files.take() else {
+			return;
+		};
+		let (frames, receiver) = mpsc::channel(WRITE_QUEUE);
+		let writer = match thread::Builder::new()
+			.name("pcapng".to_string())
+			.spawn(move || files.write(receiver))
+		{
+			Ok(x) => x,
+			Err(e) => {
+				error!("pcapng: writer could not be started: {}", e);
+				return;
+			},
+		};
+		self.frames = Some(frames);
+
+		listener::run(cancel_rx, self).await;
+
+		// Closing the queue lets the writer finish the current file
+		self.frames = None;
+		if !matches!(
+			tokio::task::spawn_blocking(move || writer.join()).await,
+			Ok(Ok(()))
+		) {
+			error!("pcapng: writer failed");
+		}
+	}
+}
+
+#[async_trait]
+impl PacketHandler for PcapngListener {
+	async fn recv(&mut self) -> Option<ReceivedPacketData> {
+		self.receiver.recv().await
+	}
+
+	async fn handle_packet(&mut self, frame: &Frame) {
+		let Some(frames) = &self.frames else {
+			return;
+		};
+		if frames.send(frame.clone()).await.is_err() {
+			error!("pcapng: writer has stopped");
+			self.frames = None;
+		}
+	}
+
+	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		fs::{self, File},
+		sync::Arc,
+		time::{Duration, SystemTime},
+	};
+
+	use etherparse::PacketBuilder;
+	use pcap::PacketHeader;
+
+	use crate::{
+		devices::ReceivedPacketData,
+		packet::{BufferPool, Frame},
+		packet_listeners::pcapng_listener::{self, Retention, Rotation},
+		queue::{self, Policy},
+		state::interface::Interface,
+	};
+
+	#[test]
+	fn test_rotation_and_retention() {
+		let directory = std::env::temp_dir().join(format!("psniff-pcapng-{}", std::process::id()));
+		fs::create_dir_all(&directory).unwrap();
+
+		// A file from an earlier run counts towards retention, unlike one the
+		// template could not have named
+		let old = directory.join("t-59-1.pcapng");
+		File::create(&old)
+			.unwrap()
+			.set_modified(SystemTime::UNIX_EPOCH)
+			.unwrap();
+		fs::write(directory.join("notes.txt"), b"").unwrap();
+
+		let (_sender, receiver) = queue::channel::<ReceivedPacketData>(1, Policy::Block);
+		let mut files = pcapng_listener::new()
+			.set_receiver(receiver)
+			.with_directory(directory.clone())
+			.with_template("t-%S.pcapng".to_string())
+			.with_rotation(Rotation {
+				bytes: None,
+				interval: Some(Duration::from_secs(2)),
+			})
+			.with_retention(Retention {
+				files: Some(2),
+				bytes: None,
+			})
+			.into_files()
+			.unwrap();
+
+		let mut data = vec![];
+		PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+			.ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64)
+			.udp(5353, 53)
+			.write(&mut data, b"query")
+			.unwrap();
+
+		let mut pool = BufferPool::new(1);
+		let iface = Arc::new(Interface::new("eth0".to_string()));
+		for secs in [0, 1, 2, 4, 4] {
+			let header = PacketHeader {
+				ts: libc::timeval {
+					tv_sec: secs,
+					tv_usec: 0,
+				},
+				caplen: data.len() as u32,
+				len: data.len() as u32,
+			};
+			let frame = Frame::parse(iface.clone(), header, pool.copy_from(&data)).unwrap();
+			files.record(&frame).unwrap();
+		}
+
+		// Seconds 0-1 went to the first file, 2 to the second and 4 to the
+		// third, leaving the last two
+		files.finish().unwrap();
+		let mut names: Vec<String> = fs::read_dir(&directory)
+			.unwrap()
+			.map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+			.collect();
+		names.sort();
+		assert_eq!(vec!["notes.txt", "t-02.pcapng", "t-04.pcapng"], names);
+
+		// A name already taken gets a counter
+		fs::write(directory.join("t-08.pcapng"), b"").unwrap();
+		assert_eq!(
+			directory.join("t-08-1.pcapng"),
+			files.next_path(Duration::from_secs(8))
+		);
+
+		assert!(pcapng_listener::validate_template("%Q").is_err());
+		fs::remove_dir_all(&directory).unwrap();
+	}
+}
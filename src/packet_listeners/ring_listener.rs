@@ -0,0 +1,77 @@
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+use crate::{
+	devices::ReceivedPacketData,
+	packet::Frame,
+	packet_listeners::listener::{self, BuildError, PacketHandler},
+	queue::Receiver,
+	runtime::{Runnable, RunnableBuilder},
+	state::ring::SharedRing,
+};
+
+pub struct RingListenerBuilder {
+	receiver: Option<Receiver<ReceivedPacketData>>,
+	ring: SharedRing,
+}
+
+pub fn new() -> RingListenerBuilder {
+	RingListenerBuilder {
+		receiver: None,
+		ring: SharedRing::default(),
+	}
+}
+
+impl RingListenerBuilder {
+	pub fn set_receiver(mut self, receiver: Receiver<ReceivedPacketData>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	/// The ring to fill, shared with the API
+	pub fn with_ring(mut self, ring: SharedRing) -> Self {
+		self.ring = ring;
+		self
+	}
+}
+
+#[async_trait]
+impl RunnableBuilder for RingListenerBuilder {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+
+		Ok(Box::new(RingListener {
+			receiver,
+			ring: self.ring,
+		}))
+	}
+}
+
+/// RingListener keeps the packets it receives in the in-memory ring
+pub struct RingListener {
+	receiver: Receiver<ReceivedPacketData>,
+	ring: SharedRing,
+}
+
+#[async_trait]
+impl Runnable for RingListener {
+	async fn run(&mut self, cancel_rx: broadcast::Receiver<()>) {
+		listener::run(cancel_rx, self).await;
+	}
+}
+
+#[async_trait]
+impl PacketHandler for RingListener {
+	async fn recv(&mut self) -> Option<ReceivedPacketData> {
+		self.receiver.recv().await
+	}
+
+	async fn handle_packet(&mut self, frame: &Frame) {
+		self.ring.lock().unwrap().push(frame.clone());
+	}
+
+	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
+}
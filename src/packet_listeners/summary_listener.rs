@@ -0,0 +1,254 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use async_trait::async_trait;
+use etherparse::{ArpOperation, LinkSlice, NetSlice, SlicedPacket, TransportSlice};
+use tokio::sync::broadcast;
+
+use crate::{
+	devices::{self, ReceivedPacketData},
+	events::{EventKind, Events, PacketSummary},
+	packet::Frame,
+	packet_listeners::listener::{self, BuildError, PacketHandler},
+	queue::Receiver,
+	runtime::{Runnable, RunnableBuilder},
+};
+
+pub struct SummaryListenerBuilder {
+	receiver: Option<Receiver<devices::ReceivedPacketData>>,
+	events: Events,
+}
+
+pub fn new() -> SummaryListenerBuilder {
+	SummaryListenerBuilder {
+		receiver: None,
+		events: Events::default(),
+	}
+}
+
+impl SummaryListenerBuilder {
+	pub fn set_receiver(mut self, receiver: Receiver<devices::ReceivedPacketData>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	pub fn with_events(mut self, events: Events) -> Self {
+		self.events = events;
+		self
+	}
+}
+
+/// SummaryListener publishes a one-line summary of every packet it receives,
+/// regardless of protocol
+pub struct SummaryListener {
+	receiver: Receiver<devices::ReceivedPacketData>,
+	events: Events,
+}
+
+#[async_trait]
+impl RunnableBuilder for SummaryListenerBuilder {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+
+		Ok(Box::new(SummaryListener {
+			receiver,
+			events: self.events,
+		}))
+	}
+}
+
+#[async_trait]
+impl Runnable for SummaryListener {
+	async fn run(&mut self, cancel_rx: broadcast::Receiver<()>) {
+		listener::run(cancel_rx, self).await
+	}
+}
+
+#[async_trait]
+impl PacketHandler for SummaryListener {
+	async fn recv(&mut self) -> Option<ReceivedPacketData> {
+		self.receiver.recv().await
+	}
+
+	async fn handle_packet(&mut self, frame: &Frame) {
+		// Summaries describe every layer, so this is the one listener which
+		// needs the whole frame sliced again
+		let packet = match frame.sliced() {
+			Some(p) => p,
+			None => return,
+		};
+
+		let (src, dst) = match frame.addresses() {
+			Some((src, dst)) => (src.to_string(), dst.to_string()),
+			None => (String::new(), String::new()),
+		};
+		let protocol = match frame.matcher() {
+			Some(m) => m.name().to_string(),
+			None => "unclassified".to_string(),
+		};
+		self.events.publish(
+			frame.timestamp(),
+			frame.iface().name(),
+			EventKind::Packet(Box::new(PacketSummary {
+				protocol,
+				src,
+				dst,
+				bytes: frame.header().len as u64,
+				tcp: None,
+				icmp: None,
+				text: Some(summarize(&packet, frame.header().len)),
+			})),
+		);
+	}
+
+	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
+}
+
+/// Describes a packet in the style of tcpdump, using the same classification
+/// which is used to dispatch packets to listeners
+pub fn summarize(packet: &SlicedPacket, len: u32) -> String {
+	let matcher = match devices::classify(packet) {
+		Some(m) => format!("{:?}", m),
+		None => "Unclassified".to_string(),
+	};
+
+	let (src, dst) = match &packet.net {
+		Some(NetSlice::Arp(arp)) => {
+			let operation = match arp.operation() {
+				ArpOperation::REQUEST => "request".to_string(),
+				ArpOperation::REPLY => "reply".to_string(),
+				ArpOperation(op) => format!("op={}", op),
+			};
+			return format!(
+				"{} {} {} ({}) -> {} ({}) len={}",
+				matcher,
+				operation,
+				format_addr(arp.sender_protocol_addr()),
+				format_addr(arp.sender_hw_addr()),
+				format_addr(arp.target_protocol_addr()),
+				format_addr(arp.target_hw_addr()),
+				len
+			);
+		},
+		Some(NetSlice::Ipv4(ipv4)) => (
+			ipv4.header().source_addr().to_string(),
+			ipv4.header().destination_addr().to_string(),
+		),
+		Some(NetSlice::Ipv6(ipv6)) => (
+			ipv6.header().source_addr().to_string(),
+			ipv6.header().destination_addr().to_string(),
+		),
+		None => match &packet.link {
+			Some(LinkSlice::Ethernet2(eth)) => {
+				return format!(
+					"{} ethertype={:?} {} -> {} len={}",
+					matcher,
+					eth.ether_type(),
+					format_addr(&eth.source()),
+					format_addr(&eth.destination()),
+					len
+				);
+			},
+			_ => return format!("{} len={}", matcher, len),
+		},
+	};
+
+	match &packet.transport {
+		Some(TransportSlice::Tcp(tcp)) => {
+			let mut flags = String::new();
+			for (set, flag) in [
+				(tcp.syn(), 'S'),
+				(tcp.fin(), 'F'),
+				(tcp.rst(), 'R'),
+				(tcp.psh(), 'P'),
+				(tcp.ack(), '.'),
+			] {
+				if set {
+					flags.push(flag);
+				}
+			}
+			format!(
+				"{} {}:{} -> {}:{} [{}] seq={} bytes={} len={}",
+				matcher,
+				src,
+				tcp.source_port(),
+				dst,
+				tcp.destination_port(),
+				flags,
+				tcp.sequence_number(),
+				tcp.payload().len(),
+				len
+			)
+		},
+		Some(TransportSlice::Udp(udp)) => format!(
+			"{} {}:{} -> {}:{} bytes={} len={}",
+			matcher,
+			src,
+			udp.source_port(),
+			dst,
+			udp.destination_port(),
+			udp.payload().len(),
+			len
+		),
+		Some(TransportSlice::Icmpv4(icmp)) => format!(
+			"{} {} -> {} type={} code={} len={}",
+			matcher,
+			src,
+			dst,
+			icmp.type_u8(),
+			icmp.code_u8(),
+			len
+		),
+		Some(TransportSlice::Icmpv6(icmp)) => format!(
+			"{} {} -> {} type={} code={} len={}",
+			matcher,
+			src,
+			dst,
+			icmp.type_u8(),
+			icmp.code_u8(),
+			len
+		),
+		None => format!("{} {} -> {} len={}", matcher, src, dst, len),
+	}
+}
+
+/// Formats a hardware or protocol address carried as raw bytes
+fn format_addr(addr: &[u8]) -> String {
+	if let Ok(v4) = <[u8; 4]>::try_from(addr) {
+		return Ipv4Addr::from(v4).to_string();
+	}
+	if let Ok(v6) = <[u8; 16]>::try_from(addr) {
+		return Ipv6Addr::from(v6).to_string();
+	}
+
+	addr
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect::<Vec<String>>()
+		.join(":")
+}
+
+#[cfg(test)]
+mod tests {
+	use etherparse::{PacketBuilder, SlicedPacket};
+
+	use crate::packet_listeners::summary_listener::summarize;
+
+	#[test]
+	fn test_summarize_tcp() {
+		let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+			.ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64)
+			.tcp(40000, 80, 1000, 4096)
+			.syn();
+		let mut frame = vec![];
+		builder.write(&mut frame, &[]).unwrap();
+
+		let packet = SlicedPacket::from_ethernet(&frame).unwrap();
+		assert_eq!(
+			"IPv4_TCP 10.0.0.1:40000 -> 10.0.0.9:80 [S] seq=1000 bytes=0 len=54",
+			summarize(&packet, frame.len() as u32)
+		);
+	}
+}
@@ -0,0 +1,297 @@
+use std::{marker::PhantomData, time::Duration};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+use crate::{
+	devices::{self, ReceivedPacketData},
+	events::{self, EventKind, Events, FlowEnd, FlowStart, PacketSummary, TcpSummary},
+	flow::{
+		Endpoint, FlowKey, Protocol,
+		reassembly::{self, MemoryBudget, StreamConsumerFactory, StreamTable},
+		tcp::{self, Change, Ordering, Segment, TcpFlowTable},
+	},
+	packet::Frame,
+	packet_listeners::{
+		ip_version::IpVersion,
+		listener::{self, BuildError, PacketHandler},
+	},
+	queue::Receiver,
+	runtime::{Runnable, RunnableBuilder},
+	state::{
+		flows::{Counts, SharedFlows},
+		interface::Interface,
+	},
+};
+
+pub struct TcpListenerBuilder<V: IpVersion> {
+	receiver: Option<Receiver<devices::ReceivedPacketData>>,
+	idle_timeout: Duration,
+	closed_timeout: Duration,
+	stream_consumers: Vec<Box<dyn StreamConsumerFactory>>,
+	stream_flow_limit: usize,
+	stream_budget: MemoryBudget,
+	shared_flows: Option<SharedFlows>,
+	events: Events,
+	version: PhantomData<V>,
+}
+
+pub fn new<V: IpVersion>() -> TcpListenerBuilder<V> {
+	TcpListenerBuilder {
+		receiver: None,
+		idle_timeout: tcp::DEFAULT_IDLE_TIMEOUT,
+		closed_timeout: tcp::DEFAULT_CLOSED_TIMEOUT,
+		stream_consumers: vec![],
+		stream_flow_limit: reassembly::DEFAULT_FLOW_LIMIT,
+		stream_budget: MemoryBudget::default(),
+		shared_flows: None,
+		events: Events::default(),
+		version: PhantomData,
+	}
+}
+
+impl<V: IpVersion> TcpListenerBuilder<V> {
+	pub fn set_receiver(mut self, receiver: Receiver<devices::ReceivedPacketData>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	/// How long a connection may go without packets before it is forgotten
+	pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+		self.idle_timeout = idle_timeout;
+		self
+	}
+
+	/// How long a closed or reset connection is kept to absorb stragglers
+	pub fn with_closed_timeout(mut self, closed_timeout: Duration) -> Self {
+		self.closed_timeout = closed_timeout;
+		self
+	}
+
+	/// Registers a parser for reassembled TCP payloads.  Connections are only
+	/// reassembled when at least one parser asks for them.
+	pub fn add_stream_consumer(mut self, factory: Box<dyn StreamConsumerFactory>) -> Self {
+		self.stream_consumers.push(factory);
+		self
+	}
+
+	/// Limits how much out-of-order data is buffered for a single connection,
+	/// and across every connection sharing `budget`
+	pub fn with_stream_limits(mut self, flow_limit: usize, budget: MemoryBudget) -> Self {
+		self.stream_flow_limit = flow_limit;
+		self.stream_budget = budget;
+		self
+	}
+
+	/// Publishes tracked connections to a registry shared with other listeners
+	pub fn with_shared_flows(mut self, shared_flows: SharedFlows) -> Self {
+		self.shared_flows = Some(shared_flows);
+		self
+	}
+
+	/// Publishes packets and connections starting and ending
+	pub fn with_events(mut self, events: Events) -> Self {
+		self.events = events;
+		self
+	}
+}
+
+pub struct TcpListener<V: IpVersion> {
+	receiver: Receiver<devices::ReceivedPacketData>,
+
+	packet_count: u64,
+
+	flows: TcpFlowTable,
+
+	streams: StreamTable,
+
+	shared_flows: Option<SharedFlows>,
+
+	events: Events,
+
+	version: PhantomData<V>,
+}
+
+#[async_trait]
+impl<V: IpVersion> RunnableBuilder for TcpListenerBuilder<V> {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+
+		Ok(Box::new(TcpListener::<V> {
+			receiver,
+			packet_count: 0,
+			flows: TcpFlowTable::new(self.idle_timeout, self.closed_timeout),
+			streams: StreamTable::new(
+				self.stream_consumers,
+				self.stream_flow_limit,
+				self.stream_budget,
+			),
+			shared_flows: self.shared_flows,
+			events: self.events,
+			version: PhantomData,
+		}))
+	}
+}
+
+#[async_trait]
+impl<V: IpVersion> Runnable for TcpListener<V> {
+	async fn run(&mut self, cancel_rx: broadcast::Receiver<()>) {
+		listener::run(cancel_rx, self).await;
+	}
+}
+
+#[async_trait]
+impl<V: IpVersion> PacketHandler for TcpListener<V> {
+	async fn recv(&mut self) -> Option<ReceivedPacketData> {
+		self.receiver.recv().await
+	}
+
+	async fn handle_packet(&mut self, frame: &Frame) {
+		self.packet_count += 1;
+
+		if let Some((src_ip, dst_ip)) = frame.addresses()
+			&& V::is_version(&src_ip)
+			&& let Some(tcp_header) = frame.tcp()
+		{
+			let iface = frame.iface();
+			let ts = frame.timestamp();
+			let src = Endpoint::new(src_ip, tcp_header.source_port());
+			let dst = Endpoint::new(dst_ip, tcp_header.destination_port());
+			let segment = Segment::new(
+				&tcp_header,
+				frame
+					.tcp_payload_len()
+					.unwrap_or(tcp_header.payload().len() as u32),
+			);
+			let (key, change) = process_tcp::<V>(
+				&mut self.flows,
+				&mut self.streams,
+				&self.events,
+				iface,
+				src,
+				dst,
+				frame.is_fragmented(),
+				&segment,
+				tcp_header.payload(),
+				ts,
+			);
+
+			if let Some(shared_flows) = &self.shared_flows
+				&& let Some(flow) = self.flows.get(&key)
+			{
+				let mut shared_flows = shared_flows.lock().unwrap();
+				// A new connection reusing the 4-tuple closes the old one
+				if change.new_flow {
+					shared_flows.remove(Protocol::Tcp, &key);
+				}
+				let record = shared_flows.record(
+					Protocol::Tcp,
+					src,
+					dst,
+					iface.name(),
+					ts,
+					segment.payload_len as usize,
+				);
+				record.update_tcp(flow);
+			}
+
+			for (key, flow) in self.flows.expire(ts) {
+				self.streams.close(&key);
+				if let Some(shared_flows) = &self.shared_flows {
+					shared_flows.lock().unwrap().remove(Protocol::Tcp, &key);
+				}
+				self.events.publish(
+					ts,
+					iface.name(),
+					EventKind::FlowEnd(FlowEnd {
+						protocol: format!("{}-TCP", V::LABEL),
+						client: flow.client,
+						server: flow.server,
+						state: Some(flow.state.to_string()),
+						to_server: Counts {
+							packets: flow.client_half.packets,
+							bytes: flow.client_half.bytes,
+						},
+						to_client: Counts {
+							packets: flow.server_half.packets,
+							bytes: flow.server_half.bytes,
+						},
+					}),
+				);
+			}
+		}
+	}
+
+	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_tcp<V: IpVersion>(
+	flows: &mut TcpFlowTable,
+	streams: &mut StreamTable,
+	events: &Events,
+	iface: &Interface,
+	src: Endpoint,
+	dst: Endpoint,
+	fragmented: bool,
+	segment: &Segment,
+	payload: &[u8],
+	ts: Duration,
+) -> (FlowKey, Change) {
+	let (key, change) = flows.process(src, dst, segment, ts);
+	let Some(flow) = flows.get(&key) else {
+		return (key, change);
+	};
+	streams.process(key, flow, &change, iface.name(), src, segment, payload, ts);
+
+	let protocol = format!("{}-TCP", V::LABEL);
+	if change.new_flow {
+		events.publish(
+			ts,
+			iface.name(),
+			EventKind::FlowStart(FlowStart {
+				protocol: protocol.clone(),
+				client: flow.client,
+				server: flow.server,
+			}),
+		);
+	}
+
+	let ordering = match change.ordering {
+		_ if change.new_flow => events::Ordering::New,
+		Ordering::Empty => events::Ordering::Empty,
+		Ordering::InOrder => events::Ordering::InOrder,
+		Ordering::Retransmission => events::Ordering::Retransmission,
+		Ordering::OutOfOrder => events::Ordering::OutOfOrder,
+	};
+	let previous_state = change
+		.previous_state
+		.filter(|previous| *previous != change.state)
+		.map(|previous| previous.to_string());
+
+	events.publish(
+		ts,
+		iface.name(),
+		EventKind::Packet(Box::new(PacketSummary {
+			protocol,
+			src: src.to_string(),
+			dst: dst.to_string(),
+			bytes: segment.payload_len as u64,
+			tcp: Some(TcpSummary {
+				flags: segment.flags.names(),
+				seq: segment.seq,
+				fragmented,
+				ordering,
+				state: change.state.to_string(),
+				previous_state,
+			}),
+			icmp: None,
+			text: None,
+		})),
+	);
+
+	(key, change)
+}
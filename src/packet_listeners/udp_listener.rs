@@ -0,0 +1,226 @@
+use std::{marker::PhantomData, time::Duration};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+
+use crate::{
+	devices::{self, ReceivedPacketData},
+	events::{EventKind, Events, FlowEnd, FlowStart, PacketSummary},
+	flow::{Endpoint, Protocol},
+	packet::Frame,
+	packet_listeners::{
+		ip_version::IpVersion,
+		listener::{self, BuildError, PacketHandler},
+	},
+	protocols::{
+		dns::{self, Decoder, Transport},
+		quic,
+	},
+	queue::Receiver,
+	runtime::{Runnable, RunnableBuilder},
+	state::{dns::SharedDnsLog, flows::SharedFlows},
+};
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often, in capture time, the shared flows are swept for idle UDP flows
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct UdpListenerBuilder<V: IpVersion> {
+	receiver: Option<Receiver<devices::ReceivedPacketData>>,
+	idle_timeout: Duration,
+	shared_flows: Option<SharedFlows>,
+	events: Events,
+	dns: Option<SharedDnsLog>,
+	quic: bool,
+	version: PhantomData<V>,
+}
+
+pub fn new<V: IpVersion>() -> UdpListenerBuilder<V> {
+	UdpListenerBuilder {
+		receiver: None,
+		idle_timeout: DEFAULT_IDLE_TIMEOUT,
+		shared_flows: None,
+		events: Events::default(),
+		dns: None,
+		quic: false,
+		version: PhantomData,
+	}
+}
+
+impl<V: IpVersion> UdpListenerBuilder<V> {
+	pub fn set_receiver(mut self, receiver: Receiver<devices::ReceivedPacketData>) -> Self {
+		self.receiver = Some(receiver);
+		self
+	}
+
+	/// How long a UDP flow may go without packets before it is forgotten
+	pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+		self.idle_timeout = idle_timeout;
+		self
+	}
+
+	/// Publishes UDP flows to a registry shared with other listeners
+	pub fn with_shared_flows(mut self, shared_flows: SharedFlows) -> Self {
+		self.shared_flows = Some(shared_flows);
+		self
+	}
+
+	/// Publishes packets, and flows of the shared registry starting and ending
+	pub fn with_events(mut self, events: Events) -> Self {
+		self.events = events;
+		self
+	}
+
+	/// Decodes DNS on port 53 into the given log
+	pub fn with_dns(mut self, log: SharedDnsLog) -> Self {
+		self.dns = Some(log);
+		self
+	}
+
+	/// Decodes QUIC Initial packets, and follows QUIC connections onto new
+	/// paths
+	pub fn with_quic(mut self) -> Self {
+		self.quic = true;
+		self
+	}
+}
+
+pub struct UdpListener<V: IpVersion> {
+	receiver: Receiver<devices::ReceivedPacketData>,
+	idle_timeout: Duration,
+	shared_flows: Option<SharedFlows>,
+	events: Events,
+	dns: Option<Decoder>,
+	quic: Option<quic::Decoder>,
+	last_sweep: Duration,
+	version: PhantomData<V>,
+}
+
+#[async_trait]
+impl<V: IpVersion> RunnableBuilder for UdpListenerBuilder<V> {
+	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>> {
+		let receiver = match self.receiver {
+			Some(x) => x,
+			None => return Err(BuildError::NoReceiver.into()),
+		};
+		let quic = self
+			.quic
+			.then(|| quic::Decoder::new(self.shared_flows.clone(), self.events.clone()));
+
+		Ok(Box::new(UdpListener::<V> {
+			receiver,
+			idle_timeout: self.idle_timeout,
+			shared_flows: self.shared_flows,
+			dns: self
+				.dns
+				.map(|log| Decoder::new(Transport::Udp, log, self.events.clone())),
+			quic,
+			events: self.events,
+			last_sweep: Duration::ZERO,
+			version: PhantomData,
+		}))
+	}
+}
+
+#[async_trait]
+impl<V: IpVersion> Runnable for UdpListener<V> {
+	async fn run(&mut self, cancel_rx: broadcast::Receiver<()>) {
+		listener::run(cancel_rx, self).await
+	}
+}
+
+#[async_trait]
+impl<V: IpVersion> PacketHandler for UdpListener<V> {
+	async fn recv(&mut self) -> Option<ReceivedPacketData> {
+		self.receiver.recv().await
+	}
+
+	async fn handle_packet(&mut self, frame: &Frame) {
+		if let Some((src_ip, dst_ip)) = frame.addresses()
+			&& V::is_version(&src_ip)
+			&& let Some(udp_header) = frame.udp()
+		{
+			let iface = frame.iface();
+			let src = Endpoint::new(src_ip, udp_header.source_port());
+			let dst = Endpoint::new(dst_ip, udp_header.destination_port());
+			let ts = frame.timestamp();
+			let protocol = format!("{}-UDP", V::LABEL);
+			self.events.publish(
+				ts,
+				iface.name(),
+				EventKind::Packet(Box::new(PacketSummary {
+					protocol: protocol.clone(),
+					src: src.to_string(),
+					dst: dst.to_string(),
+					bytes: udp_header.payload().len() as u64,
+					tcp: None,
+					icmp: None,
+					text: None,
+				})),
+			);
+
+			if let Some(decoder) = &mut self.dns {
+				if src.port == dns::PORT || dst.port == dns::PORT {
+					decoder.message(iface.name(), src, dst, ts, udp_header.payload());
+				}
+				decoder.expire(ts);
+			}
+
+			if let Some(shared_flows) = &self.shared_flows {
+				let mut shared_flows = shared_flows.lock().unwrap();
+				let record = shared_flows.record(
+					Protocol::Udp,
+					src,
+					dst,
+					iface.name(),
+					ts,
+					udp_header.payload().len(),
+				);
+				if record.packets == 1 {
+					self.events.publish(
+						ts,
+						iface.name(),
+						EventKind::FlowStart(FlowStart {
+							protocol: protocol.clone(),
+							client: src,
+							server: dst,
+						}),
+					);
+				}
+
+				if ts.saturating_sub(self.last_sweep) >= SWEEP_INTERVAL {
+					self.last_sweep = ts;
+					let expired = shared_flows.expire(
+						Protocol::Udp,
+						|key| V::is_version(&key.endpoints().0.ip),
+						ts,
+						self.idle_timeout,
+					);
+					for record in expired {
+						self.events.publish(
+							ts,
+							&record.iface,
+							EventKind::FlowEnd(FlowEnd {
+								protocol: protocol.clone(),
+								client: record.client,
+								server: record.server(),
+								state: None,
+								to_server: record.to_server,
+								to_client: record.to_client,
+							}),
+						);
+					}
+				}
+			}
+
+			// After the flow is recorded, so that it can be marked as QUIC
+			if let Some(decoder) = &mut self.quic {
+				decoder.datagram(iface.name(), src, dst, ts, udp_header.payload());
+				decoder.expire(ts);
+			}
+		}
+	}
+
+	async fn handle_packet_count(&mut self, _count: (u64, u64, u64)) {}
+}
@@ -0,0 +1,189 @@
+use std::{
+	collections::HashMap,
+	io::{self, Write},
+	time::Duration,
+};
+
+use crate::{devices::Precision, version::built_info};
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+
+/// Writer produces a pcapng section, describing each interface the first time
+/// one of its packets is written.  Blocks are written in the host's byte
+/// order, which readers detect from the section header.
+pub struct Writer<W: Write> {
+	out: W,
+	interfaces: HashMap<String, u32>,
+	written: u64,
+}
+
+impl<W: Write> Writer<W> {
+	/// Starts a section by writing its header
+	pub fn new(out: W) -> io::Result<Writer<W>> {
+		let mut w = Writer {
+			out,
+			interfaces: HashMap::new(),
+			written: 0,
+		};
+
+		let mut body = vec![];
+		body.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+		body.extend_from_slice(&1u16.to_ne_bytes());
+		body.extend_from_slice(&0u16.to_ne_bytes());
+		// The section length is not known up front
+		body.extend_from_slice(&(-1i64).to_ne_bytes());
+		let userappl = format!("{} {}", built_info::PKG_NAME, built_info::PKG_VERSION);
+		option(&mut body, OPT_SHB_USERAPPL, userappl.as_bytes());
+		option(&mut body, OPT_END, &[]);
+
+		w.block(SECTION_HEADER, &body)?;
+		Ok(w)
+	}
+
+	/// Writes a packet captured on the named interface
+	pub fn packet(
+		&mut self,
+		iface: &str,
+		precision: Precision,
+		ts: Duration,
+		data: &[u8],
+		original_len: u32,
+	) -> io::Result<()> {
+		let id = match self.interfaces.get(iface) {
+			Some(id) => *id,
+			None => self.interface(iface, precision)?,
+		};
+
+		let units = match precision {
+			Precision::Micro => ts.as_micros() as u64,
+			Precision::Nano => ts.as_nanos() as u64,
+		};
+
+		let mut body = Vec::with_capacity(20 + data.len() + 4);
+		body.extend_from_slice(&id.to_ne_bytes());
+		body.extend_from_slice(&((units >> 32) as u32).to_ne_bytes());
+		body.extend_from_slice(&(units as u32).to_ne_bytes());
+		body.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+		body.extend_from_slice(&original_len.to_ne_bytes());
+		body.extend_from_slice(data);
+		pad(&mut body);
+
+		self.block(ENHANCED_PACKET, &body)
+	}
+
+	/// How many bytes have been written, including block framing
+	pub fn written(&self) -> u64 {
+		self.written
+	}
+
+	pub fn flush(&mut self) -> io::Result<()> {
+		self.out.flush()
+	}
+
+	pub fn into_inner(self) -> W {
+		self.out
+	}
+
+	fn interface(&mut self, iface: &str, precision: Precision) -> io::Result<u32> {
+		let id = self.interfaces.len() as u32;
+
+		let mut body = vec![];
+		body.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+		body.extend_from_slice(&0u16.to_ne_bytes());
+		// No snaplen is recorded, as each packet carries its captured length
+		body.extend_from_slice(&0u32.to_ne_bytes());
+		option(&mut body, OPT_IF_NAME, iface.as_bytes());
+		let resolution = match precision {
+			Precision::Micro => 6u8,
+			Precision::Nano => 9u8,
+		};
+		option(&mut body, OPT_IF_TSRESOL, &[resolution]);
+		option(&mut body, OPT_END, &[]);
+
+		self.block(INTERFACE_DESCRIPTION, &body)?;
+		self.interfaces.insert(iface.to_string(), id);
+		Ok(id)
+	}
+
+	fn block(&mut self, kind: u32, body: &[u8]) -> io::Result<()> {
+		let total = (12 + body.len()) as u32;
+		self.out.write_all(&kind.to_ne_bytes())?;
+		self.out.write_all(&total.to_ne_bytes())?;
+		self.out.write_all(body)?;
+		self.out.write_all(&total.to_ne_bytes())?;
+		self.written += total as u64;
+		Ok(())
+	}
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+	body.extend_from_slice(&code.to_ne_bytes());
+	body.extend_from_slice(&(value.len() as u16).to_ne_bytes());
+	body.extend_from_slice(value);
+	pad(body);
+}
+
+/// Pads to the 32-bit boundary every block and option ends on
+fn pad(body: &mut Vec<u8>) {
+	body.resize(body.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use crate::{devices::Precision, pcapng::Writer};
+
+	fn u32_at(b: &[u8], at: usize) -> u32 {
+		u32::from_ne_bytes(b[at..at + 4].try_into().unwrap())
+	}
+
+	#[test]
+	fn test_blocks() {
+		let mut w = Writer::new(vec![]).unwrap();
+		let ts = Duration::new(1, 2_000);
+		w.packet("eth0", Precision::Micro, ts, &[0xAA; 5], 60)
+			.unwrap();
+		w.packet("eth0", Precision::Micro, ts, &[0xBB; 4], 4)
+			.unwrap();
+		w.packet("eth1", Precision::Nano, ts, &[], 0).unwrap();
+		let written = w.written();
+		let b = w.into_inner();
+		assert_eq!(written, b.len() as u64);
+
+		// Walk the blocks, checking each is framed by its length
+		let mut blocks = vec![];
+		let mut at = 0;
+		while at < b.len() {
+			let len = u32_at(&b, at + 4) as usize;
+			assert_eq!(0, len % 4);
+			assert_eq!(len as u32, u32_at(&b, at + len - 4));
+			blocks.push((u32_at(&b, at), at));
+			at += len;
+		}
+		let kinds: Vec<u32> = blocks.iter().map(|(k, _)| *k).collect();
+		assert_eq!(vec![0x0A0D0D0A, 1, 6, 6, 1, 6], kinds);
+
+		// The first packet: interface 0, microseconds since the epoch, 5 of
+		// 60 bytes captured
+		let epb = blocks[2].1;
+		assert_eq!(0, u32_at(&b, epb + 8));
+		assert_eq!(1_000_002, u32_at(&b, epb + 16));
+		assert_eq!(5, u32_at(&b, epb + 20));
+		assert_eq!(60, u32_at(&b, epb + 24));
+
+		// The second interface is described with nanosecond timestamps
+		assert_eq!(1, u32_at(&b, blocks[5].1 + 8));
+		assert_eq!(1_000_002_000, u32_at(&b, blocks[5].1 + 16));
+	}
+}
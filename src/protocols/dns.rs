@@ -0,0 +1,657 @@
+use std::{
+	collections::HashMap,
+	net::{Ipv4Addr, Ipv6Addr},
+	time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+	events::{self, EventKind, Events},
+	flow::{
+		Direction, Endpoint,
+		reassembly::{StreamConsumer, StreamConsumerFactory, StreamInfo},
+	},
+	state::dns::SharedDnsLog,
+};
+
+pub const PORT: u16 = 53;
+
+/// How long a query may go unanswered before it is recorded as such
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many queries may wait for a response at once; further queries are not
+/// tracked
+pub const DEFAULT_PENDING_LIMIT: usize = 64 * 1024;
+
+/// How often, in capture time, unanswered queries are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Names are at most 255 bytes, which bounds how many labels and compression
+/// pointers a well-formed name can have
+const MAX_NAME_LEN: usize = 255;
+
+const HEADER_LEN: usize = 12;
+
+/// Message is the part of a DNS message worth logging; the authority and
+/// additional sections are not read
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+	pub id: u16,
+	pub response: bool,
+	pub truncated: bool,
+	pub rcode: u8,
+	pub questions: Vec<Question>,
+	pub answers: Vec<Answer>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Question {
+	pub name: String,
+	pub qtype: u16,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Answer {
+	pub name: String,
+	#[serde(rename = "type")]
+	pub rtype: String,
+	pub ttl: u32,
+	pub data: String,
+}
+
+/// Parses a DNS message as carried in a UDP datagram, or in TCP without its
+/// length prefix
+pub fn parse(data: &[u8]) -> Option<Message> {
+	if data.len() < HEADER_LEN {
+		return None;
+	}
+	let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+	let flags = u16_at(2);
+	let qdcount = u16_at(4);
+	let ancount = u16_at(6);
+
+	let mut message = Message {
+		id: u16_at(0),
+		response: flags & 0x8000 != 0,
+		truncated: flags & 0x0200 != 0,
+		rcode: (flags & 0x000f) as u8,
+		..Default::default()
+	};
+
+	let mut pos = HEADER_LEN;
+	for _ in 0..qdcount {
+		let (name, next) = read_name(data, pos)?;
+		let fixed = data.get(next..next + 4)?;
+		message.questions.push(Question {
+			name,
+			qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+		});
+		pos = next + 4;
+	}
+
+	for _ in 0..ancount {
+		let (name, next) = read_name(data, pos)?;
+		let fixed = data.get(next..next + 10)?;
+		let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+		let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+		let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+		let start = next + 10;
+		data.get(start..start + rdlength)?;
+
+		message.answers.push(Answer {
+			name,
+			rtype: type_name(rtype),
+			ttl,
+			data: rdata(data, rtype, start, rdlength),
+		});
+		pos = start + rdlength;
+	}
+
+	Some(message)
+}
+
+/// Reads a possibly compressed name starting at `pos`, returning it along
+/// with the position just after it
+fn read_name(data: &[u8], mut pos: usize) -> Option<(String, usize)> {
+	let mut name = String::new();
+	let mut end = None;
+	let mut jumps = 0;
+
+	loop {
+		let len = *data.get(pos)? as usize;
+		match len & 0xc0 {
+			0x00 if len == 0 => break,
+			0x00 => {
+				let label = data.get(pos + 1..pos + 1 + len)?;
+				if !name.is_empty() {
+					name.push('.');
+				}
+				name.push_str(&String::from_utf8_lossy(label));
+				if name.len() > MAX_NAME_LEN {
+					return None;
+				}
+				pos += 1 + len;
+			},
+			0xc0 => {
+				// Pointers may loop, so a name may only follow so many of them
+				jumps += 1;
+				if jumps > MAX_NAME_LEN {
+					return None;
+				}
+				let target = ((len & 0x3f) << 8) | *data.get(pos + 1)? as usize;
+				end.get_or_insert(pos + 2);
+				pos = target;
+			},
+			_ => return None,
+		}
+	}
+
+	Some((name, end.unwrap_or(pos + 1)))
+}
+
+/// Formats record data the way dig does for the common types, and as hex for
+/// the rest
+fn rdata(data: &[u8], rtype: u16, start: usize, len: usize) -> String {
+	let rdata = &data[start..start + len];
+	let name_at = |pos: usize| read_name(data, pos).map(|(name, _)| name);
+
+	let formatted = match (rtype, len) {
+		(1, 4) => Some(Ipv4Addr::from(<[u8; 4]>::try_from(rdata).unwrap()).to_string()),
+		(28, 16) => Some(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap()).to_string()),
+		// NS, CNAME and PTR
+		(2 | 5 | 12, _) => name_at(start),
+		// MX
+		(15, 3..) => name_at(start + 2)
+			.map(|name| format!("{} {}", u16::from_be_bytes([rdata[0], rdata[1]]), name)),
+		// TXT
+		(16, _) => {
+			let mut strings = vec![];
+			let mut rest = rdata;
+			while let Some((&n, tail)) = rest.split_first() {
+				let n = (n as usize).min(tail.len());
+				strings.push(format!("\"{}\"", String::from_utf8_lossy(&tail[..n])));
+				rest = &tail[n..];
+			}
+			Some(strings.join(" "))
+		},
+		_ => None,
+	};
+
+	formatted.unwrap_or_else(|| rdata.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub fn type_name(rtype: u16) -> String {
+	let name = match rtype {
+		1 => "A",
+		2 => "NS",
+		5 => "CNAME",
+		6 => "SOA",
+		12 => "PTR",
+		15 => "MX",
+		16 => "TXT",
+		28 => "AAAA",
+		33 => "SRV",
+		41 => "OPT",
+		43 => "DS",
+		46 => "RRSIG",
+		48 => "DNSKEY",
+		64 => "SVCB",
+		65 => "HTTPS",
+		255 => "ANY",
+		257 => "CAA",
+		n => return format!("TYPE{}", n),
+	};
+	name.to_string()
+}
+
+pub fn rcode_name(rcode: u8) -> String {
+	let name = match rcode {
+		0 => "NOERROR",
+		1 => "FORMERR",
+		2 => "SERVFAIL",
+		3 => "NXDOMAIN",
+		4 => "NOTIMP",
+		5 => "REFUSED",
+		n => return format!("RCODE{}", n),
+	};
+	name.to_string()
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+	Udp,
+	Tcp,
+}
+
+/// Transaction is a query along with its response.  Either may be missing:
+/// a query which was never answered, or a response to a query which was not
+/// seen.
+#[derive(Clone, Debug, Serialize)]
+pub struct Transaction {
+	pub id: u16,
+	pub transport: Transport,
+	pub client: Endpoint,
+	pub server: Endpoint,
+	pub name: String,
+	pub qtype: String,
+	/// Not set when the query went unanswered
+	pub rcode: Option<String>,
+	/// Seconds between the query and its response, when both were seen
+	pub latency: Option<f64>,
+	pub truncated: bool,
+	pub answers: Vec<Answer>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct Key {
+	id: u16,
+	client: Endpoint,
+	server: Endpoint,
+}
+
+struct Pending {
+	ts: Duration,
+	iface: String,
+	question: Option<Question>,
+}
+
+/// Tracker pairs responses with their queries by transaction ID and
+/// endpoints
+pub struct Tracker {
+	transport: Transport,
+	pending: HashMap<Key, Pending>,
+	timeout: Duration,
+	limit: usize,
+	last_sweep: Duration,
+}
+
+impl Tracker {
+	pub fn new(transport: Transport, timeout: Duration, limit: usize) -> Tracker {
+		Tracker {
+			transport,
+			pending: HashMap::new(),
+			timeout,
+			limit,
+			last_sweep: Duration::ZERO,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.pending.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.pending.is_empty()
+	}
+
+	/// Holds on to a query, or returns the transaction a response completes
+	pub fn observe(
+		&mut self,
+		iface: &str,
+		src: Endpoint,
+		dst: Endpoint,
+		ts: Duration,
+		message: Message,
+	) -> Option<Transaction> {
+		if !message.response {
+			let key = Key {
+				id: message.id,
+				client: src,
+				server: dst,
+			};
+			// A retransmitted query is timed from the first copy
+			if self.pending.len() < self.limit || self.pending.contains_key(&key) {
+				self.pending.entry(key).or_insert(Pending {
+					ts,
+					iface: iface.to_string(),
+					question: message.questions.into_iter().next(),
+				});
+			}
+			return None;
+		}
+
+		let key = Key {
+			id: message.id,
+			client: dst,
+			server: src,
+		};
+		let query = self.pending.remove(&key);
+		let question = match &query {
+			Some(Pending {
+				question: Some(q), ..
+			}) => Some(q.clone()),
+			_ => message.questions.first().cloned(),
+		};
+
+		Some(Transaction {
+			latency: query.map(|q| ts.saturating_sub(q.ts).as_secs_f64()),
+			rcode: Some(rcode_name(message.rcode)),
+			truncated: message.truncated,
+			answers: message.answers,
+			..self.transaction(key, question)
+		})
+	}
+
+	/// Queries which have waited longer than the timeout, along with the
+	/// interfaces they were seen on.  Sweeps at most once per interval.
+	pub fn expire(&mut self, ts: Duration) -> Vec<(String, Transaction)> {
+		if ts.saturating_sub(self.last_sweep) < SWEEP_INTERVAL {
+			return vec![];
+		}
+		self.last_sweep = ts;
+
+		let timeout = self.timeout;
+		let expired: Vec<Key> = self
+			.pending
+			.iter()
+			.filter(|(_, p)| ts.saturating_sub(p.ts) > timeout)
+			.map(|(k, _)| *k)
+			.collect();
+		self.unanswered(expired)
+	}
+
+	/// Every query still waiting, such as when a connection closes
+	pub fn drain(&mut self) -> Vec<(String, Transaction)> {
+		let keys = self.pending.keys().copied().collect();
+		self.unanswered(keys)
+	}
+
+	fn unanswered(&mut self, keys: Vec<Key>) -> Vec<(String, Transaction)> {
+		keys
+			.into_iter()
+			.filter_map(|key| {
+				let pending = self.pending.remove(&key)?;
+				Some((pending.iface, self.transaction(key, pending.question)))
+			})
+			.collect()
+	}
+
+	fn transaction(&self, key: Key, question: Option<Question>) -> Transaction {
+		let (name, qtype) = match question {
+			Some(q) => (q.name, type_name(q.qtype)),
+			None => (String::new(), String::new()),
+		};
+		Transaction {
+			id: key.id,
+			transport: self.transport,
+			client: key.client,
+			server: key.server,
+			name,
+			qtype,
+			rcode: None,
+			latency: None,
+			truncated: false,
+			answers: vec![],
+		}
+	}
+}
+
+/// Decoder tracks the DNS messages of one transport, keeping the
+/// transactions in the shared log and publishing them as events
+pub struct Decoder {
+	tracker: Tracker,
+	log: SharedDnsLog,
+	events: Events,
+}
+
+impl Decoder {
+	pub fn new(transport: Transport, log: SharedDnsLog, events: Events) -> Decoder {
+		Decoder {
+			tracker: Tracker::new(transport, DEFAULT_QUERY_TIMEOUT, DEFAULT_PENDING_LIMIT),
+			log,
+			events,
+		}
+	}
+
+	/// Decodes one message; anything which is not DNS is ignored
+	pub fn message(&mut self, iface: &str, src: Endpoint, dst: Endpoint, ts: Duration, data: &[u8]) {
+		let Some(message) = parse(data) else {
+			return;
+		};
+		if let Some(transaction) = self.tracker.observe(iface, src, dst, ts, message) {
+			self.record(iface, ts, transaction);
+		}
+	}
+
+	/// Records the queries which have gone unanswered for too long
+	pub fn expire(&mut self, ts: Duration) {
+		for (iface, transaction) in self.tracker.expire(ts) {
+			self.record(&iface, ts, transaction);
+		}
+	}
+
+	/// Records every query still waiting as unanswered
+	pub fn close(&mut self, ts: Duration) {
+		for (iface, transaction) in self.tracker.drain() {
+			self.record(&iface, ts, transaction);
+		}
+	}
+
+	fn record(&self, iface: &str, ts: Duration, transaction: Transaction) {
+		self
+			.log
+			.lock()
+			.unwrap()
+			.push(iface, ts, transaction.clone());
+		self.events.publish(
+			ts,
+			iface,
+			EventKind::Transaction(events::Transaction::Dns(Box::new(transaction))),
+		);
+	}
+}
+
+/// StreamFactory decodes DNS carried over TCP, where each message is prefixed
+/// with its length
+pub struct StreamFactory {
+	log: SharedDnsLog,
+	events: Events,
+}
+
+impl StreamFactory {
+	pub fn new(log: SharedDnsLog, events: Events) -> StreamFactory {
+		StreamFactory { log, events }
+	}
+}
+
+impl StreamConsumerFactory for StreamFactory {
+	fn new_consumer(&mut self, info: &StreamInfo) -> Option<Box<dyn StreamConsumer>> {
+		if info.server.port != PORT {
+			return None;
+		}
+
+		Some(Box::new(StreamDecoder {
+			info: info.clone(),
+			decoder: Decoder::new(Transport::Tcp, self.log.clone(), self.events.clone()),
+			to_server: Some(vec![]),
+			to_client: Some(vec![]),
+			last_seen: Duration::ZERO,
+		}))
+	}
+}
+
+struct StreamDecoder {
+	info: StreamInfo,
+	decoder: Decoder,
+	/// None once a direction has missed bytes, after which it is not decoded
+	to_server: Option<Vec<u8>>,
+	to_client: Option<Vec<u8>>,
+	last_seen: Duration,
+}
+
+impl StreamConsumer for StreamDecoder {
+	fn data(&mut self, direction: Direction, ts: Duration, data: &[u8]) {
+		self.last_seen = ts;
+		self.decoder.expire(ts);
+
+		let (buffer, src, dst) = match direction {
+			Direction::ClientToServer => (&mut self.to_server, self.info.client, self.info.server),
+			Direction::ServerToClient => (&mut self.to_client, self.info.server, self.info.client),
+		};
+		let Some(buffer) = buffer else {
+			return;
+		};
+		buffer.extend_from_slice(data);
+
+		while buffer.len() >= 2 {
+			let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+			if buffer.len() < 2 + len {
+				break;
+			}
+			self
+				.decoder
+				.message(&self.info.iface, src, dst, ts, &buffer[2..2 + len]);
+			buffer.drain(..2 + len);
+		}
+	}
+
+	/// Messages cannot be found again after missing bytes
+	fn gap(&mut self, direction: Direction, _len: u64) {
+		match direction {
+			Direction::ClientToServer => self.to_server = None,
+			Direction::ServerToClient => self.to_client = None,
+		}
+	}
+
+	fn close(&mut self) {
+		self.decoder.close(self.last_seen);
+	}
+
+	fn done(&self) -> bool {
+		self.to_server.is_none() && self.to_client.is_none()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::IpAddr,
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	use crate::{
+		events::Events,
+		flow::{
+			Direction, Endpoint, FlowKey,
+			reassembly::{StreamConsumerFactory, StreamInfo},
+		},
+		protocols::dns::{Answer, StreamFactory, Tracker, Transport, parse},
+		state::dns::DnsLog,
+	};
+
+	/// A response to an A query for example.com, with the answer's name
+	/// compressed to point at the question
+	fn response(id: u16, rcode: u8) -> Vec<u8> {
+		let mut m = vec![];
+		m.extend_from_slice(&id.to_be_bytes());
+		m.extend_from_slice(&(0x8180u16 | rcode as u16).to_be_bytes());
+		m.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]);
+		m.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+		m.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+		m.extend_from_slice(&[93, 184, 216, 34]);
+		m
+	}
+
+	/// The A query for example.com which `response` answers
+	fn query(id: u16) -> Vec<u8> {
+		let mut query = response(id, 0)[..29].to_vec();
+		query[2] = 0x01;
+		query[3] = 0x00;
+		query[7] = 0;
+		query
+	}
+
+	/// A message as sent over TCP, after its length
+	fn framed(message: &[u8]) -> Vec<u8> {
+		let mut m = (message.len() as u16).to_be_bytes().to_vec();
+		m.extend_from_slice(message);
+		m
+	}
+
+	#[test]
+	fn test_parse_and_pair() {
+		let query = query(0x1234);
+
+		let q = parse(&query).unwrap();
+		assert!(!q.response);
+		assert_eq!("example.com", q.questions[0].name);
+
+		let r = parse(&response(0x1234, 0)).unwrap();
+		assert_eq!(
+			vec![Answer {
+				name: "example.com".to_string(),
+				rtype: "A".to_string(),
+				ttl: 3600,
+				data: "93.184.216.34".to_string(),
+			}],
+			r.answers
+		);
+		// A pointer to itself never ends
+		assert!(parse(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1]).is_none());
+
+		let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+		let server = Endpoint::new(IpAddr::from([10, 0, 0, 53]), 53);
+		let mut tracker = Tracker::new(Transport::Udp, Duration::from_secs(5), 16);
+
+		let ts = Duration::from_secs(100);
+		assert!(tracker.observe("eth0", client, server, ts, q).is_none());
+		let t = tracker
+			.observe("eth0", server, client, ts + Duration::from_millis(20), r)
+			.unwrap();
+		assert_eq!(Some("NOERROR".to_string()), t.rcode);
+		assert_eq!("A", t.qtype);
+		assert!((t.latency.unwrap() - 0.02).abs() < 1e-9);
+		assert!(tracker.is_empty());
+
+		// A query nobody answers is expired as unanswered
+		let q = parse(&query).unwrap();
+		tracker.observe("eth0", client, server, ts, q);
+		assert!(tracker.expire(ts + Duration::from_secs(2)).is_empty());
+		let expired = tracker.expire(ts + Duration::from_secs(10));
+		assert_eq!(1, expired.len());
+		assert_eq!(None, expired[0].1.rcode);
+	}
+
+	#[test]
+	fn test_stream_after_gap() {
+		let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+		let server = Endpoint::new(IpAddr::from([10, 0, 0, 53]), 53);
+		let info = StreamInfo {
+			key: FlowKey::new(client, server),
+			client,
+			server,
+			iface: "eth0".to_string(),
+		};
+		let log = Arc::new(Mutex::new(DnsLog::default()));
+		let mut factory = StreamFactory::new(log.clone(), Events::default());
+		let mut consumer = factory.new_consumer(&info).unwrap();
+
+		let ts = Duration::from_secs(100);
+		consumer.data(Direction::ClientToServer, ts, &framed(&query(0x1234)));
+
+		// Once bytes are missed, whatever follows is not taken for a message
+		consumer.gap(Direction::ServerToClient, 7);
+		consumer.data(
+			Direction::ServerToClient,
+			ts + Duration::from_millis(20),
+			&framed(&response(0x1234, 0)),
+		);
+		assert_eq!(0, log.lock().unwrap().recent().count());
+
+		// The query still expires while the connection lives on
+		consumer.data(Direction::ClientToServer, ts + Duration::from_secs(10), b"");
+		{
+			let log = log.lock().unwrap();
+			let entries: Vec<_> = log.recent().collect();
+			assert_eq!(1, entries.len());
+			assert_eq!(None, entries[0].transaction.rcode);
+		}
+
+		assert!(!consumer.done());
+		consumer.gap(Direction::ClientToServer, 7);
+		assert!(consumer.done());
+	}
+}
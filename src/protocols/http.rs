@@ -0,0 +1,657 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+	events::{self, EventKind, Events},
+	flow::{
+		Direction, Endpoint,
+		reassembly::{StreamConsumer, StreamConsumerFactory, StreamInfo},
+	},
+};
+
+/// A head longer than this is not HTTP, or not worth reading
+pub const MAX_HEAD: usize = 64 * 1024;
+
+/// How many pipelined requests may wait for their responses; beyond this the
+/// oldest is given up as unanswered
+pub const MAX_PENDING: usize = 64;
+
+/// A chunk size line longer than this is not chunked encoding
+const MAX_CHUNK_LINE: usize = 1024;
+
+const METHODS: [&str; 9] = [
+	"GET ", "HEAD ", "POST ", "PUT ", "DELETE ", "CONNECT ", "OPTIONS ", "TRACE ", "PATCH ",
+];
+const RESPONSE: &str = "HTTP/1.";
+
+/// Transaction is a request and, unless the connection ended first, its
+/// response
+#[derive(Clone, Debug, Serialize)]
+pub struct Transaction {
+	pub client: Endpoint,
+	pub server: Endpoint,
+	pub method: String,
+	pub host: Option<String>,
+	pub uri: String,
+	pub version: String,
+	pub user_agent: Option<String>,
+	pub request_content_type: Option<String>,
+	/// Body bytes, after any chunked encoding is removed
+	pub request_bytes: u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub request_body: Option<String>,
+	pub status: Option<u16>,
+	pub reason: Option<String>,
+	pub response_content_type: Option<String>,
+	pub response_bytes: u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub response_body: Option<String>,
+	/// Seconds from the request to the response
+	pub latency: Option<f64>,
+}
+
+/// Head is the start line and headers of a request or response
+#[derive(Debug)]
+struct Head {
+	start: [String; 3],
+	headers: Vec<(String, String)>,
+}
+
+impl Head {
+	fn parse(data: &[u8]) -> Option<Head> {
+		let text = std::str::from_utf8(data).ok()?;
+		let mut lines = text.lines();
+
+		let mut start = lines.next()?.splitn(3, ' ');
+		let start = [
+			start.next()?.to_string(),
+			start.next()?.to_string(),
+			start.next().unwrap_or_default().to_string(),
+		];
+
+		let mut headers = vec![];
+		for line in lines.take_while(|l| !l.is_empty()) {
+			let (name, value) = line.split_once(':')?;
+			headers.push((name.trim().to_string(), value.trim().to_string()));
+		}
+
+		Some(Head { start, headers })
+	}
+
+	fn header(&self, name: &str) -> Option<&str> {
+		self
+			.headers
+			.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str())
+	}
+
+	/// How the body of a message with this head ends, when nothing but the
+	/// head decides it
+	fn framing(&self, until_close: bool) -> Option<Framing> {
+		let chunked = self
+			.header("transfer-encoding")
+			.is_some_and(|t| t.to_ascii_lowercase().contains("chunked"));
+		if chunked {
+			return Some(Framing::Chunked(Chunk::Size));
+		}
+		match self.header("content-length") {
+			Some(len) => len.parse().ok().map(Framing::Length),
+			None if until_close => Some(Framing::Close),
+			None => Some(Framing::Length(0)),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Framing {
+	/// How many bytes are left
+	Length(u64),
+	Chunked(Chunk),
+	/// Everything until the connection closes
+	Close,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Chunk {
+	Size,
+	/// How many bytes of the chunk are left
+	Data(u64),
+	/// The line break after a chunk's data
+	DataEnd,
+	Trailer,
+}
+
+#[derive(Debug)]
+enum Part {
+	Head(Head),
+	/// The end of a body, with its size and whatever of it was kept
+	End(u64, Vec<u8>),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum State {
+	Head,
+	/// A head has been read, and the body waits on `Side::start`
+	Framing,
+	Body(Framing),
+	/// Not HTTP, or no longer readable
+	Broken,
+}
+
+/// Side reads the messages sent by one side of a connection
+struct Side {
+	/// What every head must start with, to tell HTTP from anything else
+	starts: &'static [&'static str],
+	buffer: Vec<u8>,
+	state: State,
+	seen: u64,
+	kept: Vec<u8>,
+	limit: usize,
+}
+
+impl Side {
+	fn new(starts: &'static [&'static str], limit: usize) -> Side {
+		Side {
+			starts,
+			buffer: vec![],
+			state: State::Head,
+			seen: 0,
+			kept: vec![],
+			limit,
+		}
+	}
+
+	fn is_broken(&self) -> bool {
+		self.state == State::Broken
+	}
+
+	/// Reads the body which follows the last head
+	fn start(&mut self, framing: Framing) {
+		self.state = State::Body(framing);
+		self.seen = 0;
+		self.kept.clear();
+	}
+
+	/// Counts `n` body bytes off the front of the buffer, keeping them
+	/// within the limit
+	fn consume(&mut self, n: usize) {
+		let keep = n.min(self.limit.saturating_sub(self.kept.len()));
+		self.kept.extend_from_slice(&self.buffer[..keep]);
+		self.buffer.drain(..n);
+		self.seen += n as u64;
+	}
+
+	/// Finishes the body, returning its size and whatever of it was kept
+	fn end(&mut self) -> (u64, Vec<u8>) {
+		self.state = State::Head;
+		(self.seen, std::mem::take(&mut self.kept))
+	}
+
+	/// Takes a line off the front of the buffer, without its line break
+	fn line(&mut self) -> Option<String> {
+		let end = self.buffer.iter().position(|b| *b == b'\n')?;
+		let line = String::from_utf8_lossy(&self.buffer[..end])
+			.trim_end_matches('\r')
+			.to_string();
+		self.buffer.drain(..=end);
+		Some(line)
+	}
+
+	/// The next head or body end in the buffer, if it holds one
+	fn next(&mut self) -> Option<Part> {
+		loop {
+			match self.state {
+				State::Head => {
+					let n = self.buffer.len();
+					let plausible = self.starts.iter().any(|s| {
+						let m = n.min(s.len());
+						self.buffer[..m] == s.as_bytes()[..m]
+					});
+					if !plausible || n > MAX_HEAD {
+						self.state = State::Broken;
+						return None;
+					}
+					let end = find(&self.buffer, b"\r\n\r\n")
+						.map(|i| i + 4)
+						.or_else(|| find(&self.buffer, b"\n\n").map(|i| i + 2))?;
+					let Some(head) = Head::parse(&self.buffer[..end]) else {
+						self.state = State::Broken;
+						return None;
+					};
+					self.buffer.drain(..end);
+					self.state = State::Framing;
+					return Some(Part::Head(head));
+				},
+				State::Framing | State::Broken => return None,
+				State::Body(Framing::Length(0)) => {
+					let (bytes, kept) = self.end();
+					return Some(Part::End(bytes, kept));
+				},
+				State::Body(Framing::Length(left)) => {
+					if self.buffer.is_empty() {
+						return None;
+					}
+					let n = (left as usize).min(self.buffer.len());
+					self.consume(n);
+					self.state = State::Body(Framing::Length(left - n as u64));
+				},
+				State::Body(Framing::Close) => {
+					let n = self.buffer.len();
+					self.consume(n);
+					return None;
+				},
+				State::Body(Framing::Chunked(Chunk::Size)) => {
+					let Some(line) = self.line() else {
+						if self.buffer.len() > MAX_CHUNK_LINE {
+							self.state = State::Broken;
+						}
+						return None;
+					};
+					// Chunk extensions follow a ';'
+					let size = line.split(';').next().unwrap_or_default().trim();
+					self.state = match u64::from_str_radix(size, 16) {
+						Ok(0) => State::Body(Framing::Chunked(Chunk::Trailer)),
+						Ok(size) => State::Body(Framing::Chunked(Chunk::Data(size))),
+						Err(_) => State::Broken,
+					};
+				},
+				State::Body(Framing::Chunked(Chunk::Data(left))) => {
+					if self.buffer.is_empty() {
+						return None;
+					}
+					let n = (left as usize).min(self.buffer.len());
+					self.consume(n);
+					self.state = State::Body(Framing::Chunked(match left - n as u64 {
+						0 => Chunk::DataEnd,
+						left => Chunk::Data(left),
+					}));
+				},
+				State::Body(Framing::Chunked(Chunk::DataEnd)) => {
+					// Chunk data is followed by nothing but the line ending
+					let n = match self.buffer.as_slice() {
+						[] | [b'\r'] => return None,
+						[b'\n', ..] => 1,
+						[b'\r', b'\n', ..] => 2,
+						_ => {
+							self.state = State::Broken;
+							return None;
+						},
+					};
+					self.buffer.drain(..n);
+					self.state = State::Body(Framing::Chunked(Chunk::Size));
+				},
+				State::Body(Framing::Chunked(Chunk::Trailer)) => {
+					let Some(line) = self.line() else {
+						if self.buffer.len() > MAX_HEAD {
+							self.state = State::Broken;
+						}
+						return None;
+					};
+					if line.is_empty() {
+						let (bytes, kept) = self.end();
+						return Some(Part::End(bytes, kept));
+					}
+				},
+			}
+		}
+	}
+
+	/// Missing bytes can only be skipped in a body of known length
+	fn gap(&mut self, len: u64) {
+		match self.state {
+			State::Body(Framing::Length(left)) if self.buffer.is_empty() && len <= left => {
+				self.seen += len;
+				self.state = State::Body(Framing::Length(left - len));
+			},
+			_ => self.state = State::Broken,
+		}
+	}
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn body(kept: Vec<u8>, limit: usize) -> Option<String> {
+	(limit > 0).then(|| String::from_utf8_lossy(&kept).into_owned())
+}
+
+/// StreamFactory looks for HTTP/1.0 and 1.1 on every TCP connection,
+/// publishing each request and its response as a transaction
+pub struct StreamFactory {
+	events: Events,
+	body_limit: usize,
+}
+
+impl StreamFactory {
+	pub fn new(events: Events) -> StreamFactory {
+		StreamFactory {
+			events,
+			body_limit: 0,
+		}
+	}
+
+	/// Keeps up to `limit` bytes of each body with its transaction
+	pub fn with_body_limit(mut self, limit: usize) -> StreamFactory {
+		self.body_limit = limit;
+		self
+	}
+}
+
+impl StreamConsumerFactory for StreamFactory {
+	fn new_consumer(&mut self, info: &StreamInfo) -> Option<Box<dyn StreamConsumer>> {
+		Some(Box::new(StreamDecoder {
+			info: info.clone(),
+			events: self.events.clone(),
+			limit: self.body_limit,
+			client: Side::new(&METHODS, self.body_limit),
+			server: Side::new(&[RESPONSE], self.body_limit),
+			pending: VecDeque::new(),
+			response: None,
+			last_seen: Duration::ZERO,
+			tunnel: false,
+		}))
+	}
+}
+
+/// Pending is a request which waits for its response
+struct Pending {
+	transaction: Transaction,
+	ts: Duration,
+	/// Whether the request's body has been read
+	complete: bool,
+}
+
+struct StreamDecoder {
+	info: StreamInfo,
+	events: Events,
+	limit: usize,
+	client: Side,
+	server: Side,
+	pending: VecDeque<Pending>,
+	/// The head of the response being read
+	response: Option<(u16, String, Option<String>, Duration)>,
+	last_seen: Duration,
+	/// Whether the connection went on to something other than HTTP, after a
+	/// CONNECT or an upgrade
+	tunnel: bool,
+}
+
+impl StreamDecoder {
+	fn publish(&self, ts: Duration, transaction: Transaction) {
+		self.events.publish(
+			ts,
+			&self.info.iface,
+			EventKind::Transaction(events::Transaction::Http(Box::new(transaction))),
+		);
+	}
+
+	fn request(&mut self, ts: Duration) {
+		while let Some(part) = self.client.next() {
+			match part {
+				Part::Head(head) => {
+					let framing = head.framing(false);
+					let [method, uri, version] = head.start.clone();
+					let transaction = Transaction {
+						client: self.info.client,
+						server: self.info.server,
+						method,
+						host: head.header("host").map(String::from),
+						uri,
+						version,
+						user_agent: head.header("user-agent").map(String::from),
+						request_content_type: head.header("content-type").map(String::from),
+						request_bytes: 0,
+						request_body: None,
+						status: None,
+						reason: None,
+						response_content_type: None,
+						response_bytes: 0,
+						response_body: None,
+						latency: None,
+					};
+					if self.pending.len() == MAX_PENDING
+						&& let Some(oldest) = self.pending.pop_front()
+					{
+						self.publish(oldest.ts, oldest.transaction);
+					}
+					self.pending.push_back(Pending {
+						transaction,
+						ts,
+						complete: false,
+					});
+					match framing {
+						Some(framing) => self.client.start(framing),
+						None => self.client.state = State::Broken,
+					}
+				},
+				Part::End(bytes, kept) => {
+					if let Some(p) = self.pending.iter_mut().rev().find(|p| !p.complete) {
+						p.complete = true;
+						p.transaction.request_bytes = bytes;
+						p.transaction.request_body = body(kept, self.limit);
+					}
+				},
+			}
+		}
+	}
+
+	fn response(&mut self, ts: Duration) {
+		while let Some(part) = self.server.next() {
+			match part {
+				Part::Head(head) => {
+					let status: u16 = match head.start[1].parse() {
+						Ok(status) => status,
+						Err(_) => {
+							self.server.state = State::Broken;
+							return;
+						},
+					};
+					let method = self.pending.front().map(|p| p.transaction.method.as_str());
+
+					// A 101 or a successful CONNECT hands the connection to
+					// another protocol
+					if status == 101 || (method == Some("CONNECT") && (200..300).contains(&status)) {
+						self.tunnel = true;
+					}
+					// Interim responses come before the real one
+					if (100..200).contains(&status) && status != 101 {
+						self.server.start(Framing::Length(0));
+						self.response = None;
+						continue;
+					}
+
+					let framing = match status {
+						_ if self.tunnel => Some(Framing::Length(0)),
+						204 | 304 => Some(Framing::Length(0)),
+						_ if method == Some("HEAD") => Some(Framing::Length(0)),
+						_ => head.framing(true),
+					};
+					self.response = Some((
+						status,
+						head.start[2].clone(),
+						head.header("content-type").map(String::from),
+						ts,
+					));
+					match framing {
+						Some(framing) => self.server.start(framing),
+						None => self.server.state = State::Broken,
+					}
+				},
+				Part::End(bytes, kept) => self.answer(bytes, kept),
+			}
+		}
+	}
+
+	/// Completes the oldest request with the response just read
+	fn answer(&mut self, bytes: u64, kept: Vec<u8>) {
+		let Some((status, reason, content_type, response_ts)) = self.response.take() else {
+			return;
+		};
+		// A response without a request was to something sent before the
+		// capture started
+		let Some(Pending {
+			mut transaction,
+			ts,
+			..
+		}) = self.pending.pop_front()
+		else {
+			return;
+		};
+
+		transaction.status = Some(status);
+		transaction.reason = Some(reason);
+		transaction.response_content_type = content_type;
+		transaction.response_bytes = bytes;
+		transaction.response_body = body(kept, self.limit);
+		transaction.latency = Some(response_ts.saturating_sub(ts).as_secs_f64());
+		self.publish(response_ts, transaction);
+	}
+}
+
+impl StreamConsumer for StreamDecoder {
+	fn data(&mut self, direction: Direction, ts: Duration, data: &[u8]) {
+		self.last_seen = ts;
+		if self.tunnel {
+			return;
+		}
+		match direction {
+			Direction::ClientToServer => {
+				self.client.buffer.extend_from_slice(data);
+				self.request(ts);
+			},
+			Direction::ServerToClient => {
+				self.server.buffer.extend_from_slice(data);
+				self.response(ts);
+			},
+		}
+	}
+
+	fn gap(&mut self, direction: Direction, len: u64) {
+		match direction {
+			Direction::ClientToServer => self.client.gap(len),
+			Direction::ServerToClient => self.server.gap(len),
+		}
+	}
+
+	/// A response read until the connection closed ends here, and any
+	/// request still waiting is published unanswered
+	fn close(&mut self) {
+		if self.server.state == State::Body(Framing::Close) {
+			let (bytes, kept) = self.server.end();
+			self.answer(bytes, kept);
+		}
+		while let Some(p) = self.pending.pop_front() {
+			self.publish(self.last_seen, p.transaction);
+		}
+	}
+
+	fn done(&self) -> bool {
+		self.tunnel || self.client.is_broken() || self.server.is_broken()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use crate::{
+		events::{EventKind, Events, Transaction},
+		flow::{
+			Direction, Endpoint, FlowKey,
+			reassembly::{StreamConsumerFactory, StreamInfo},
+		},
+		protocols::http::StreamFactory,
+	};
+
+	#[test]
+	fn test_pipelined_transactions() {
+		let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+		let server = Endpoint::new(IpAddr::from([10, 0, 0, 2]), 8080);
+		let info = StreamInfo {
+			key: FlowKey::new(client, server),
+			client,
+			server,
+			iface: "eth0".to_string(),
+		};
+		let events = Events::default();
+		let mut rx = events.subscribe();
+		let mut factory = StreamFactory::new(events).with_body_limit(4);
+		let mut consumer = factory.new_consumer(&info).unwrap();
+
+		// Two pipelined requests, the first with a body
+		consumer.data(
+			Direction::ClientToServer,
+			Duration::from_millis(0),
+			b"POST /items HTTP/1.1\r\nHost: api.internal\r\nUser-Agent: curl/8.5.0\r\n\
+			Content-Type: application/json\r\nContent-Length: 11\r\n\r\n{\"id\": 42}\n\
+			GET /items/42 HTTP/1.1\r\nHost: api.internal\r\n\r\n",
+		);
+
+		// A chunked response split mid-chunk, then one of known length
+		consumer.data(
+			Direction::ServerToClient,
+			Duration::from_millis(20),
+			b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+		);
+		consumer.data(
+			Direction::ServerToClient,
+			Duration::from_millis(25),
+			b"lo\r\n6;x=y\r\n world\r\n0\r\n\r\n\
+			HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nnot found",
+		);
+		assert!(!consumer.done());
+
+		// The last request is never answered
+		consumer.data(
+			Direction::ClientToServer,
+			Duration::from_millis(30),
+			b"HEAD / HTTP/1.0\r\n\r\n",
+		);
+		consumer.close();
+
+		let mut transactions = vec![];
+		while let Ok(event) = rx.try_recv() {
+			if let EventKind::Transaction(Transaction::Http(t)) = &event.kind {
+				transactions.push(t.clone());
+			}
+		}
+		assert_eq!(3, transactions.len());
+
+		let post = &transactions[0];
+		assert_eq!("POST", post.method);
+		assert_eq!(Some("api.internal"), post.host.as_deref());
+		assert_eq!(Some("curl/8.5.0"), post.user_agent.as_deref());
+		assert_eq!(11, post.request_bytes);
+		assert_eq!(Some("{\"id"), post.request_body.as_deref());
+		assert_eq!(Some(201), post.status);
+		assert_eq!(11, post.response_bytes);
+		assert_eq!(Some("hell"), post.response_body.as_deref());
+		assert_eq!(Some(0.02), post.latency);
+
+		let get = &transactions[1];
+		assert_eq!("/items/42", get.uri);
+		assert_eq!(Some(404), get.status);
+		assert_eq!(Some("text/plain"), get.response_content_type.as_deref());
+		assert_eq!(9, get.response_bytes);
+
+		assert_eq!("HEAD", transactions[2].method);
+		assert_eq!(None, transactions[2].status);
+
+		// Anything else is given up at once
+		let mut consumer = factory.new_consumer(&info).unwrap();
+		consumer.data(Direction::ClientToServer, Duration::ZERO, b"\x16\x03\x01");
+		assert!(consumer.done());
+
+		// As is a chunk longer than its size line says
+		let mut consumer = factory.new_consumer(&info).unwrap();
+		consumer.data(
+			Direction::ServerToClient,
+			Duration::ZERO,
+			b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhello",
+		);
+		assert!(consumer.done());
+	}
+}
@@ -0,0 +1,5 @@
+pub mod dns;
+pub mod http;
+pub mod quic;
+pub mod tls;
+pub mod x509;
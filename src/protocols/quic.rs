@@ -0,0 +1,805 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	time::Duration,
+};
+
+use aes::{
+	Aes128,
+	cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+use aes_gcm::{
+	Aes128Gcm, Nonce,
+	aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use log::debug;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::{
+	events::{self, EventKind, Events},
+	flow::{Endpoint, FlowKey, Protocol},
+	protocols::tls::{self, Handshake},
+	state::flows::SharedFlows,
+};
+
+pub const VERSION_1: u32 = 0x0000_0001;
+pub const VERSION_2: u32 = 0x6b33_43cf;
+pub const DRAFT_29: u32 = 0xff00_001d;
+
+/// How long a connection may go without packets before it is forgotten
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many connections are tracked; Initial packets of further connections
+/// are still decoded, but their connection IDs are not followed
+pub const MAX_CONNECTIONS: usize = 16 * 1024;
+
+/// How often, in capture time, idle connections are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shorter connection IDs would too often match a short header packet of
+/// some other protocol by chance
+const MIN_TRACKED_CID: usize = 4;
+
+/// The longest connection ID of versions 1 and 2
+const MAX_CID: usize = 20;
+
+const FRAME_PADDING: u8 = 0x00;
+const FRAME_PING: u8 = 0x01;
+const FRAME_ACK: u8 = 0x02;
+const FRAME_ACK_ECN: u8 = 0x03;
+const FRAME_CRYPTO: u8 = 0x06;
+
+/// Params are what differs between the versions whose Initial packets can
+/// be read
+struct Params {
+	salt: [u8; 20],
+	/// Prefixes the labels of the key, IV and header protection key
+	label: &'static str,
+	initial: u8,
+	retry: u8,
+}
+
+impl Params {
+	fn of(version: u32) -> Option<Params> {
+		match version {
+			VERSION_1 => Some(Params {
+				salt: [
+					0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c,
+					0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+				],
+				label: "quic",
+				initial: 0,
+				retry: 3,
+			}),
+			VERSION_2 => Some(Params {
+				salt: [
+					0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d,
+					0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+				],
+				label: "quicv2",
+				initial: 1,
+				retry: 0,
+			}),
+			DRAFT_29 => Some(Params {
+				salt: [
+					0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61, 0x11,
+					0xe0, 0x43, 0x90, 0xa8, 0x99,
+				],
+				label: "quic",
+				initial: 0,
+				retry: 3,
+			}),
+			_ => None,
+		}
+	}
+}
+
+pub fn version_name(version: u32) -> String {
+	match version {
+		VERSION_1 => "1".to_string(),
+		VERSION_2 => "2".to_string(),
+		DRAFT_29 => "draft-29".to_string(),
+		v => format!("0x{:08x}", v),
+	}
+}
+
+/// Keys protect the client's Initial packets of a connection
+#[derive(Debug, Eq, PartialEq)]
+pub struct Keys {
+	pub key: [u8; 16],
+	pub iv: [u8; 12],
+	pub hp: [u8; 16],
+}
+
+/// HKDF-Expand-Label of TLS 1.3, with an empty context
+fn expand_label(hkdf: &Hkdf<Sha256>, label: &str, out: &mut [u8]) {
+	let label = format!("tls13 {}", label);
+	let mut info = (out.len() as u16).to_be_bytes().to_vec();
+	info.push(label.len() as u8);
+	info.extend_from_slice(label.as_bytes());
+	info.push(0);
+	hkdf
+		.expand(&info, out)
+		.expect("keys are far shorter than HKDF can expand to");
+}
+
+impl Keys {
+	/// Derives the client's Initial keys from the connection ID the client
+	/// first sent to
+	pub fn client(version: u32, dcid: &[u8]) -> Option<Keys> {
+		let params = Params::of(version)?;
+		let initial = Hkdf::<Sha256>::new(Some(&params.salt), dcid);
+		let mut secret = [0; 32];
+		expand_label(&initial, "client in", &mut secret);
+
+		let client = Hkdf::<Sha256>::from_prk(&secret).ok()?;
+		let mut keys = Keys {
+			key: [0; 16],
+			iv: [0; 12],
+			hp: [0; 16],
+		};
+		expand_label(&client, &format!("{} key", params.label), &mut keys.key);
+		expand_label(&client, &format!("{} iv", params.label), &mut keys.iv);
+		expand_label(&client, &format!("{} hp", params.label), &mut keys.hp);
+		Some(keys)
+	}
+}
+
+/// Takes a variable-length integer off the front of `data`
+fn varint(data: &mut &[u8]) -> Option<u64> {
+	let first = *data.first()?;
+	let len = 1 << (first >> 6);
+	let bytes = data.get(1..len)?;
+	let value = bytes
+		.iter()
+		.fold((first & 0x3f) as u64, |v, b| (v << 8) | *b as u64);
+	*data = &data[len..];
+	Some(value)
+}
+
+fn cid<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+	let len = *data.first()? as usize;
+	if len > MAX_CID {
+		return None;
+	}
+	let cid = data.get(1..1 + len)?;
+	*data = &data[1 + len..];
+	Some(cid)
+}
+
+/// Packet is a long header packet of a version which can be read
+#[derive(Debug)]
+pub struct Packet<'a> {
+	pub version: u32,
+	pub dcid: &'a [u8],
+	pub scid: &'a [u8],
+	pub initial: bool,
+	/// The whole packet, so that packets coalesced into one datagram can be
+	/// told apart
+	pub bytes: &'a [u8],
+	/// Where the packet number starts, in packets which have one
+	pn_offset: usize,
+}
+
+impl Packet<'_> {
+	/// Parses the long header packet at the start of `data`
+	pub fn parse(data: &[u8]) -> Option<Packet<'_>> {
+		let mut r = data;
+		let first = *r.first()?;
+		if first & 0x80 == 0 {
+			return None;
+		}
+		let version = u32::from_be_bytes(r.get(1..5)?.try_into().ok()?);
+		let params = Params::of(version)?;
+		r = &r[5..];
+		let dcid = cid(&mut r)?;
+		let scid = cid(&mut r)?;
+
+		let kind = (first >> 4) & 0x03;
+		if kind == params.retry {
+			return Some(Packet {
+				version,
+				dcid,
+				scid,
+				initial: false,
+				bytes: data,
+				pn_offset: 0,
+			});
+		}
+		if kind == params.initial {
+			let token = varint(&mut r)? as usize;
+			r = r.get(token..)?;
+		}
+		let len = varint(&mut r)? as usize;
+		let pn_offset = data.len() - r.len();
+
+		Some(Packet {
+			version,
+			dcid,
+			scid,
+			initial: kind == params.initial,
+			bytes: data.get(..pn_offset + len)?,
+			pn_offset,
+		})
+	}
+
+	/// Removes the header protection and decrypts the payload of an Initial
+	/// packet
+	pub fn open(&self, keys: &Keys) -> Option<Vec<u8>> {
+		if !self.initial {
+			return None;
+		}
+		let pn_offset = self.pn_offset;
+		let sample = self.bytes.get(pn_offset + 4..pn_offset + 20)?;
+		let mut mask = GenericArray::clone_from_slice(sample);
+		Aes128::new_from_slice(&keys.hp)
+			.ok()?
+			.encrypt_block(&mut mask);
+
+		let mut header = self.bytes[..pn_offset].to_vec();
+		header[0] ^= mask[0] & 0x0f;
+		let pn_len = (header[0] & 0x03) as usize + 1;
+		let mut pn: u64 = 0;
+		for i in 0..pn_len {
+			let b = self.bytes.get(pn_offset + i)? ^ mask[1 + i];
+			header.push(b);
+			pn = (pn << 8) | b as u64;
+		}
+
+		let mut nonce = keys.iv;
+		for (n, p) in nonce[4..].iter_mut().zip(pn.to_be_bytes()) {
+			*n ^= p;
+		}
+		Aes128Gcm::new_from_slice(&keys.key)
+			.ok()?
+			.decrypt(
+				Nonce::from_slice(&nonce),
+				Payload {
+					msg: self.bytes.get(pn_offset + pn_len..)?,
+					aad: &header,
+				},
+			)
+			.ok()
+	}
+}
+
+/// Collects the CRYPTO frames of a decrypted Initial packet as their offset
+/// and data
+fn crypto_frames(mut p: &[u8], frames: &mut Vec<(u64, Vec<u8>)>) -> Option<()> {
+	while let Some(&kind) = p.first() {
+		p = &p[1..];
+		match kind {
+			FRAME_PADDING | FRAME_PING => {},
+			FRAME_ACK | FRAME_ACK_ECN => {
+				varint(&mut p)?;
+				varint(&mut p)?;
+				let ranges = varint(&mut p)?;
+				varint(&mut p)?;
+				for _ in 0..ranges {
+					varint(&mut p)?;
+					varint(&mut p)?;
+				}
+				if kind == FRAME_ACK_ECN {
+					for _ in 0..3 {
+						varint(&mut p)?;
+					}
+				}
+			},
+			FRAME_CRYPTO => {
+				let offset = varint(&mut p)?;
+				let len = varint(&mut p)? as usize;
+				frames.push((offset, p.get(..len)?.to_vec()));
+				p = &p[len..];
+			},
+			// Nothing else is sent by a client before its hello is answered
+			_ => return None,
+		}
+	}
+	Some(())
+}
+
+/// Initial is what a client's Initial packets reveal of a connection
+#[derive(Clone, Debug, Serialize)]
+pub struct Initial {
+	/// Numbers the connections seen, so that the flows of a connection which
+	/// moved to another path can be matched up
+	pub connection: u64,
+	pub quic_version: String,
+	pub dcid: String,
+	pub scid: String,
+	#[serde(flatten)]
+	pub tls: Handshake,
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+struct Connection {
+	client: Endpoint,
+	server: Endpoint,
+	version: u32,
+	/// The connection ID the client first sent to, which its Initial keys
+	/// derive from
+	original_dcid: Vec<u8>,
+	scid: Vec<u8>,
+	cids: Vec<Vec<u8>>,
+	crypto: BTreeMap<u64, Vec<u8>>,
+	initial: Option<Box<Initial>>,
+	last_seen: Duration,
+}
+
+impl Connection {
+	/// The ClientHello, once every CRYPTO frame it spans has been seen
+	fn client_hello(&mut self, frames: Vec<(u64, Vec<u8>)>) -> Option<tls::ClientHello> {
+		for (offset, data) in frames {
+			let buffered: usize = self.crypto.values().map(|d| d.len()).sum();
+			if buffered + data.len() <= tls::MAX_HANDSHAKE {
+				self.crypto.insert(offset, data);
+			}
+		}
+
+		let mut stream: Vec<u8> = vec![];
+		for (offset, data) in &self.crypto {
+			let offset = *offset as usize;
+			if offset > stream.len() {
+				break;
+			}
+			if offset + data.len() > stream.len() {
+				stream.extend_from_slice(&data[stream.len() - offset..]);
+			}
+		}
+
+		if stream.len() < 4 || stream[0] != tls::CLIENT_HELLO {
+			return None;
+		}
+		let len = u32::from_be_bytes([0, stream[1], stream[2], stream[3]]) as usize;
+		tls::parse_client_hello(stream.get(4..4 + len)?)
+	}
+}
+
+/// Decoder follows QUIC connections through their long header packets,
+/// reading the ClientHello out of the client's Initial packets, and through
+/// short header packets on new paths which carry a connection ID it has
+/// already seen
+pub struct Decoder {
+	flows: Option<SharedFlows>,
+	events: Events,
+	connections: HashMap<u64, Connection>,
+	/// Connections by their client and server
+	paths: HashMap<(Endpoint, Endpoint), u64>,
+	/// Connections by the IDs of either side, and whether the ID is the
+	/// server's
+	cids: HashMap<Vec<u8>, (u64, bool)>,
+	/// How many tracked connection IDs there are of each length
+	cid_lens: BTreeMap<usize, usize>,
+	last_id: u64,
+	last_sweep: Duration,
+}
+
+impl Decoder {
+	pub fn new(flows: Option<SharedFlows>, events: Events) -> Decoder {
+		Decoder {
+			flows,
+			events,
+			connections: HashMap::new(),
+			paths: HashMap::new(),
+			cids: HashMap::new(),
+			cid_lens: BTreeMap::new(),
+			last_id: 0,
+			last_sweep: Duration::ZERO,
+		}
+	}
+
+	/// Decodes a UDP payload, which is ignored unless it is QUIC
+	pub fn datagram(
+		&mut self,
+		iface: &str,
+		src: Endpoint,
+		dst: Endpoint,
+		ts: Duration,
+		payload: &[u8],
+	) {
+		match payload.first() {
+			Some(b) if b & 0x80 != 0 => {
+				let mut rest = payload;
+				while let Some(packet) = Packet::parse(rest) {
+					self.long(iface, src, dst, ts, &packet);
+					rest = &rest[packet.bytes.len()..];
+				}
+			},
+			Some(b) if b & 0x40 != 0 => self.short(src, dst, ts, payload),
+			_ => {},
+		}
+	}
+
+	/// Forgets connections which have been idle too long.  Sweeps at most
+	/// once per interval.
+	pub fn expire(&mut self, ts: Duration) {
+		if ts.saturating_sub(self.last_sweep) < SWEEP_INTERVAL {
+			return;
+		}
+		self.last_sweep = ts;
+
+		let idle: Vec<u64> = self
+			.connections
+			.iter()
+			.filter(|(_, c)| ts.saturating_sub(c.last_seen) > IDLE_TIMEOUT)
+			.map(|(id, _)| *id)
+			.collect();
+		for id in idle {
+			if let Some(c) = self.connections.remove(&id) {
+				self.paths.remove(&(c.client, c.server));
+				for cid in c.cids {
+					self.untrack(&cid);
+				}
+			}
+		}
+	}
+
+	fn track(&mut self, id: u64, cid: &[u8], server: bool) {
+		if cid.len() < MIN_TRACKED_CID || self.cids.contains_key(cid) {
+			return;
+		}
+		let Some(c) = self.connections.get_mut(&id) else {
+			return;
+		};
+		c.cids.push(cid.to_vec());
+		self.cids.insert(cid.to_vec(), (id, server));
+		*self.cid_lens.entry(cid.len()).or_default() += 1;
+	}
+
+	fn untrack(&mut self, cid: &[u8]) {
+		self.cids.remove(cid);
+		if let Some(n) = self.cid_lens.get_mut(&cid.len()) {
+			*n -= 1;
+			if *n == 0 {
+				self.cid_lens.remove(&cid.len());
+			}
+		}
+	}
+
+	/// The connection a packet belongs to, and whether the client sent it
+	fn find(&self, src: Endpoint, dst: Endpoint, dcid: Option<&[u8]>) -> Option<(u64, bool)> {
+		if let Some(id) = self.paths.get(&(src, dst)) {
+			return Some((*id, true));
+		}
+		if let Some(id) = self.paths.get(&(dst, src)) {
+			return Some((*id, false));
+		}
+		let (id, server) = self.cids.get(dcid?)?;
+		Some((*id, *server))
+	}
+
+	fn long(&mut self, iface: &str, src: Endpoint, dst: Endpoint, ts: Duration, packet: &Packet) {
+		let id = match self.find(src, dst, Some(packet.dcid)) {
+			Some((id, from_client)) => {
+				self.moved(id, src, dst, from_client);
+				if !from_client {
+					// The server's ID is what the client sends to from now on
+					self.track(id, packet.scid, true);
+				}
+				if !from_client || !packet.initial {
+					if let Some(c) = self.connections.get_mut(&id) {
+						c.last_seen = ts;
+					}
+					return;
+				}
+				id
+			},
+			None if packet.initial => {
+				if self.connections.len() >= MAX_CONNECTIONS {
+					return;
+				}
+				self.last_id += 1;
+				let id = self.last_id;
+				self.connections.insert(
+					id,
+					Connection {
+						client: src,
+						server: dst,
+						version: packet.version,
+						original_dcid: packet.dcid.to_vec(),
+						scid: packet.scid.to_vec(),
+						cids: vec![],
+						crypto: BTreeMap::new(),
+						initial: None,
+						last_seen: ts,
+					},
+				);
+				self.paths.insert((src, dst), id);
+				self.track(id, packet.dcid, true);
+				self.track(id, packet.scid, false);
+				id
+			},
+			None => return,
+		};
+
+		let Some(c) = self.connections.get_mut(&id) else {
+			return;
+		};
+		c.last_seen = ts;
+		if c.initial.is_some() {
+			return;
+		}
+
+		// After a Retry, the client's keys derive from the ID the server
+		// chose rather than its own
+		let plaintext = Keys::client(packet.version, &c.original_dcid)
+			.and_then(|keys| packet.open(&keys))
+			.or_else(|| {
+				let plaintext = packet.open(&Keys::client(packet.version, packet.dcid)?)?;
+				c.original_dcid = packet.dcid.to_vec();
+				Some(plaintext)
+			});
+		let Some(plaintext) = plaintext else {
+			return;
+		};
+		let mut frames = vec![];
+		if crypto_frames(&plaintext, &mut frames).is_none() {
+			return;
+		}
+		let Some(hello) = c.client_hello(frames) else {
+			return;
+		};
+
+		let initial = Initial {
+			connection: id,
+			quic_version: version_name(c.version),
+			dcid: hex(&c.original_dcid),
+			scid: hex(&c.scid),
+			tls: Handshake::new(c.client, c.server, 'q', &hello, None, vec![]),
+		};
+		c.crypto.clear();
+		c.initial = Some(Box::new(initial.clone()));
+		let (client, server) = (c.client, c.server);
+
+		self.attach(client, server, &initial);
+		self.events.publish(
+			ts,
+			iface,
+			EventKind::Transaction(events::Transaction::Quic(Box::new(initial))),
+		);
+	}
+
+	fn short(&mut self, src: Endpoint, dst: Endpoint, ts: Duration, payload: &[u8]) {
+		if self.connections.is_empty() {
+			return;
+		}
+		let found = self.find(src, dst, None).or_else(|| {
+			self
+				.cid_lens
+				.keys()
+				.find_map(|len| self.cids.get(payload.get(1..1 + len)?).copied())
+		});
+		let Some((id, from_client)) = found else {
+			return;
+		};
+		self.moved(id, src, dst, from_client);
+		if let Some(c) = self.connections.get_mut(&id) {
+			c.last_seen = ts;
+		}
+	}
+
+	/// Follows a connection onto a new path, such as after a NAT rebinding
+	/// or a client moving between networks
+	fn moved(&mut self, id: u64, src: Endpoint, dst: Endpoint, from_client: bool) {
+		let (client, server) = if from_client { (src, dst) } else { (dst, src) };
+		let Some(c) = self.connections.get_mut(&id) else {
+			return;
+		};
+		if (c.client, c.server) == (client, server) {
+			return;
+		}
+		debug!(
+			"QUIC connection {} moved from {} -> {} to {} -> {}",
+			id, c.client, c.server, client, server
+		);
+		self.paths.remove(&(c.client, c.server));
+		self.paths.insert((client, server), id);
+		c.client = client;
+		c.server = server;
+
+		if let Some(initial) = c.initial.clone() {
+			self.attach(client, server, &initial);
+		}
+	}
+
+	/// Marks the flow between `client` and `server` as the connection
+	fn attach(&self, client: Endpoint, server: Endpoint, initial: &Initial) {
+		let Some(flows) = &self.flows else {
+			return;
+		};
+		if let Some(record) = flows
+			.lock()
+			.unwrap()
+			.get_mut(Protocol::Udp, &FlowKey::new(client, server))
+		{
+			record.quic = Some(Box::new(initial.clone()));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::IpAddr,
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	use aes::{
+		Aes128,
+		cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+	};
+	use aes_gcm::{
+		Aes128Gcm, Nonce,
+		aead::{Aead, Payload},
+	};
+
+	use crate::{
+		events::{EventKind, Events, Transaction},
+		flow::{Endpoint, FlowKey, Protocol},
+		protocols::quic::{Decoder, Keys, VERSION_1, VERSION_2},
+		state::flows::FlowRegistry,
+	};
+
+	fn unhex(s: &str) -> Vec<u8> {
+		(0..s.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+			.collect()
+	}
+
+	/// A minimal ClientHello for example.com, offering h3
+	fn client_hello() -> Vec<u8> {
+		let mut body = vec![3, 3];
+		body.extend_from_slice(&[0; 32]);
+		body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+		let extensions = [
+			&b"\x00\x00\x00\x10\x00\x0e\x00\x00\x0bexample.com"[..],
+			b"\x00\x10\x00\x05\x00\x03\x02h3",
+			b"\x00\x2b\x00\x03\x02\x03\x04",
+		]
+		.concat();
+		body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+		body.extend_from_slice(&extensions);
+
+		let mut message = vec![1, 0];
+		message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+		message.extend_from_slice(&body);
+		message
+	}
+
+	/// Protects a client Initial carrying `frames` the way RFC 9001 describes
+	fn initial(dcid: &[u8], scid: &[u8], frames: &[u8]) -> Vec<u8> {
+		let keys = Keys::client(VERSION_1, dcid).unwrap();
+		let mut plaintext = frames.to_vec();
+		plaintext.resize(1162, 0);
+
+		// A two byte packet number, and two bytes of length
+		let mut header = vec![0xc1, 0, 0, 0, 1, dcid.len() as u8];
+		header.extend_from_slice(dcid);
+		header.push(scid.len() as u8);
+		header.extend_from_slice(scid);
+		header.push(0);
+		header.extend_from_slice(&(0x4000 | (2 + plaintext.len() + 16) as u16).to_be_bytes());
+		let pn_offset = header.len();
+		header.extend_from_slice(&[0, 2]);
+
+		let mut nonce = keys.iv;
+		nonce[11] ^= 2;
+		let sealed = Aes128Gcm::new_from_slice(&keys.key)
+			.unwrap()
+			.encrypt(
+				Nonce::from_slice(&nonce),
+				Payload {
+					msg: &plaintext,
+					aad: &header,
+				},
+			)
+			.unwrap();
+
+		let mut mask = GenericArray::clone_from_slice(&sealed[2..18]);
+		Aes128::new_from_slice(&keys.hp)
+			.unwrap()
+			.encrypt_block(&mut mask);
+		let mut packet = header;
+		packet[0] ^= mask[0] & 0x0f;
+		packet[pn_offset] ^= mask[1];
+		packet[pn_offset + 1] ^= mask[2];
+		packet.extend_from_slice(&sealed);
+		packet
+	}
+
+	fn crypto(offset: u8, data: &[u8]) -> Vec<u8> {
+		let mut frame = vec![
+			0x06,
+			offset,
+			0x40 | (data.len() >> 8) as u8,
+			data.len() as u8,
+		];
+		frame.extend_from_slice(data);
+		frame
+	}
+
+	#[test]
+	fn test_initial_keys_and_migration() {
+		// The keys of RFC 9001 appendix A.1 and RFC 9369 appendix A.1
+		let dcid = unhex("8394c8f03e515708");
+		assert_eq!(
+			Keys {
+				key: unhex("1f369613dd76d5467730efcbe3b1a22d")
+					.try_into()
+					.unwrap(),
+				iv: unhex("fa044b2f42a3fd3b46fb255c").try_into().unwrap(),
+				hp: unhex("9f50449e04a0e810283a1e9933adedd2")
+					.try_into()
+					.unwrap(),
+			},
+			Keys::client(VERSION_1, &dcid).unwrap()
+		);
+		assert_eq!(
+			Keys {
+				key: unhex("8b1a0bc121284290a29e0971b5cd045d")
+					.try_into()
+					.unwrap(),
+				iv: unhex("91f73e2351d8fa91660e909f").try_into().unwrap(),
+				hp: unhex("45b95e15235d6f45a6b19cbcb0294ba9")
+					.try_into()
+					.unwrap(),
+			},
+			Keys::client(VERSION_2, &dcid).unwrap()
+		);
+
+		let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 50000);
+		let server = Endpoint::new(IpAddr::from([10, 0, 0, 2]), 443);
+		let flows = Arc::new(Mutex::new(FlowRegistry::new()));
+		flows
+			.lock()
+			.unwrap()
+			.record(Protocol::Udp, client, server, "eth0", Duration::ZERO, 0);
+		let events = Events::default();
+		let mut rx = events.subscribe();
+		let mut decoder = Decoder::new(Some(flows.clone()), events);
+
+		// The ClientHello is split across two Initial packets
+		let scid = unhex("c0ffee00");
+		let hello = client_hello();
+		let (first, second) = hello.split_at(40);
+		let packet = initial(&dcid, &scid, &crypto(0, first));
+		decoder.datagram("eth0", client, server, Duration::ZERO, &packet);
+		assert!(rx.try_recv().is_err());
+		let packet = initial(&dcid, &scid, &crypto(40, second));
+		decoder.datagram("eth0", client, server, Duration::ZERO, &packet);
+
+		let event = rx.try_recv().unwrap();
+		let EventKind::Transaction(Transaction::Quic(quic)) = &event.kind else {
+			panic!("expected a QUIC transaction, got {:?}", event.kind);
+		};
+		assert_eq!("1", quic.quic_version);
+		assert_eq!("8394c8f03e515708", quic.dcid);
+		assert_eq!(Some("example.com"), quic.tls.sni.as_deref());
+		assert_eq!(vec!["h3"], quic.tls.alpn_offered);
+		assert!(quic.tls.ja4.starts_with("q13d0103h3_"));
+
+		// The client moves, and sends to the server's ID from its new address
+		let moved = Endpoint::new(IpAddr::from([192, 168, 1, 7]), 61000);
+		let mut short = vec![0x40];
+		short.extend_from_slice(&dcid);
+		short.extend_from_slice(&[0; 24]);
+		flows
+			.lock()
+			.unwrap()
+			.record(Protocol::Udp, moved, server, "eth0", Duration::ZERO, 0);
+		decoder.datagram("eth0", moved, server, Duration::from_secs(1), &short);
+
+		let flows = flows.lock().unwrap();
+		let quic = flows
+			.get(Protocol::Udp, &FlowKey::new(moved, server))
+			.and_then(|r| r.quic.as_ref())
+			.unwrap();
+		assert_eq!(1, quic.connection);
+	}
+}
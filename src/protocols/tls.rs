@@ -0,0 +1,733 @@
+use std::time::Duration;
+
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+	events::{self, EventKind, Events},
+	flow::{
+		Direction, Endpoint, Protocol,
+		reassembly::{StreamConsumer, StreamConsumerFactory, StreamInfo},
+	},
+	protocols::x509::{self, Certificate},
+	state::flows::SharedFlows,
+};
+
+/// How much of one side of a handshake is buffered before giving up on it;
+/// certificate chains are rarely more than a few kilobytes
+pub const MAX_HANDSHAKE: usize = 64 * 1024;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_HANDSHAKE: u8 = 22;
+
+pub const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE: u8 = 11;
+const SERVER_HELLO_DONE: u8 = 14;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Reader takes the fields of a handshake message off the front
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+		let b = self.0.get(..n)?;
+		self.0 = &self.0[n..];
+		Some(b)
+	}
+
+	fn u8(&mut self) -> Option<u8> {
+		self.bytes(1).map(|b| b[0])
+	}
+
+	fn u16(&mut self) -> Option<u16> {
+		self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	/// A vector prefixed with a one byte length
+	fn vec8(&mut self) -> Option<Reader<'a>> {
+		let n = self.u8()? as usize;
+		self.bytes(n).map(Reader)
+	}
+
+	/// A vector prefixed with a two byte length
+	fn vec16(&mut self) -> Option<Reader<'a>> {
+		let n = self.u16()? as usize;
+		self.bytes(n).map(Reader)
+	}
+
+	/// A vector prefixed with a three byte length
+	fn vec24(&mut self) -> Option<Reader<'a>> {
+		let b = self.bytes(3)?;
+		let n = u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize;
+		self.bytes(n).map(Reader)
+	}
+
+	fn u16s(&self) -> Vec<u16> {
+		self
+			.0
+			.chunks_exact(2)
+			.map(|c| u16::from_be_bytes([c[0], c[1]]))
+			.collect()
+	}
+
+	fn string(&self) -> String {
+		String::from_utf8_lossy(self.0).into_owned()
+	}
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClientHello {
+	pub version: u16,
+	pub ciphers: Vec<u16>,
+	/// Extension types, in the order they were sent
+	pub extensions: Vec<u16>,
+	pub sni: Option<String>,
+	pub alpn: Vec<String>,
+	pub groups: Vec<u16>,
+	pub point_formats: Vec<u8>,
+	pub signature_algorithms: Vec<u16>,
+	pub supported_versions: Vec<u16>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ServerHello {
+	pub version: u16,
+	pub cipher: u16,
+	pub extensions: Vec<u16>,
+	pub alpn: Option<String>,
+	/// The version chosen by a TLS 1.3 server, which leaves `version` at 1.2
+	pub selected_version: Option<u16>,
+}
+
+/// Parses the body of a ClientHello handshake message
+pub fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+	let mut r = Reader(body);
+	let version = r.u16()?;
+	r.bytes(32)?;
+	r.vec8()?;
+	let ciphers = r.vec16()?.u16s();
+	r.vec8()?;
+
+	let mut hello = ClientHello {
+		version,
+		ciphers,
+		..Default::default()
+	};
+	if r.is_empty() {
+		return Some(hello);
+	}
+
+	let mut extensions = r.vec16()?;
+	while !extensions.is_empty() {
+		let kind = extensions.u16()?;
+		let mut data = extensions.vec16()?;
+		hello.extensions.push(kind);
+		match kind {
+			EXT_SERVER_NAME => {
+				let mut names = data.vec16()?;
+				while !names.is_empty() {
+					let name_type = names.u8()?;
+					let name = names.vec16()?;
+					if name_type == 0 && hello.sni.is_none() {
+						hello.sni = Some(name.string());
+					}
+				}
+			},
+			EXT_ALPN => {
+				let mut protocols = data.vec16()?;
+				while !protocols.is_empty() {
+					hello.alpn.push(protocols.vec8()?.string());
+				}
+			},
+			EXT_SUPPORTED_GROUPS => hello.groups = data.vec16()?.u16s(),
+			EXT_EC_POINT_FORMATS => hello.point_formats = data.vec8()?.0.to_vec(),
+			EXT_SIGNATURE_ALGORITHMS => hello.signature_algorithms = data.vec16()?.u16s(),
+			EXT_SUPPORTED_VERSIONS => hello.supported_versions = data.vec8()?.u16s(),
+			_ => {},
+		}
+	}
+
+	Some(hello)
+}
+
+/// Parses the body of a ServerHello handshake message
+pub fn parse_server_hello(body: &[u8]) -> Option<ServerHello> {
+	let mut r = Reader(body);
+	let version = r.u16()?;
+	r.bytes(32)?;
+	r.vec8()?;
+	let cipher = r.u16()?;
+	r.u8()?;
+
+	let mut hello = ServerHello {
+		version,
+		cipher,
+		..Default::default()
+	};
+	if r.is_empty() {
+		return Some(hello);
+	}
+
+	let mut extensions = r.vec16()?;
+	while !extensions.is_empty() {
+		let kind = extensions.u16()?;
+		let mut data = extensions.vec16()?;
+		hello.extensions.push(kind);
+		match kind {
+			EXT_ALPN => hello.alpn = Some(data.vec16()?.vec8()?.string()),
+			EXT_SUPPORTED_VERSIONS => hello.selected_version = Some(data.u16()?),
+			_ => {},
+		}
+	}
+
+	Some(hello)
+}
+
+/// Parses the certificate chain of a TLS 1.2 Certificate message, leaving
+/// out any certificate which cannot be read
+pub fn parse_certificates(body: &[u8]) -> Vec<Certificate> {
+	let mut certificates = vec![];
+	let Some(mut list) = Reader(body).vec24() else {
+		return certificates;
+	};
+	while let Some(der) = list.vec24() {
+		if let Some(c) = x509::parse(der.0) {
+			certificates.push(c);
+		}
+	}
+	certificates
+}
+
+/// GREASE values are reserved at random by clients to keep servers honest,
+/// and are left out of fingerprints
+fn is_grease(v: u16) -> bool {
+	v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+	values.iter().copied().filter(|v| !is_grease(*v)).collect()
+}
+
+fn decimal(values: &[u16]) -> String {
+	without_grease(values)
+		.iter()
+		.map(|v| v.to_string())
+		.collect::<Vec<String>>()
+		.join("-")
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The first twelve hex digits of a SHA-256, or zeros for an empty list
+fn ja4_hash(s: &str) -> String {
+	if s.is_empty() {
+		return "000000000000".to_string();
+	}
+	hex(&Sha256::digest(s.as_bytes()))[..12].to_string()
+}
+
+pub fn version_name(version: u16) -> String {
+	let name = match version {
+		0x0300 => "SSL 3.0",
+		0x0301 => "TLS 1.0",
+		0x0302 => "TLS 1.1",
+		0x0303 => "TLS 1.2",
+		0x0304 => "TLS 1.3",
+		v => return format!("0x{:04x}", v),
+	};
+	name.to_string()
+}
+
+impl ClientHello {
+	/// The versions offered, from supported_versions if the client sent it
+	pub fn versions(&self) -> Vec<u16> {
+		match without_grease(&self.supported_versions) {
+			v if v.is_empty() => vec![self.version],
+			v => v,
+		}
+	}
+
+	/// The JA3 fingerprint before it is hashed
+	pub fn ja3_string(&self) -> String {
+		let point_formats: Vec<String> = self.point_formats.iter().map(|p| p.to_string()).collect();
+		format!(
+			"{},{},{},{},{}",
+			self.version,
+			decimal(&self.ciphers),
+			decimal(&self.extensions),
+			decimal(&self.groups),
+			point_formats.join("-")
+		)
+	}
+
+	pub fn ja3(&self) -> String {
+		hex(&Md5::digest(self.ja3_string().as_bytes()))
+	}
+
+	/// The JA4 fingerprint, where `transport` is 't' for TCP and 'q' for QUIC
+	pub fn ja4(&self, transport: char) -> String {
+		let version = match self.versions().into_iter().max() {
+			Some(0x0304) => "13",
+			Some(0x0303) => "12",
+			Some(0x0302) => "11",
+			Some(0x0301) => "10",
+			Some(0x0300) => "s3",
+			_ => "00",
+		};
+		let sni = if self.sni.is_some() { 'd' } else { 'i' };
+		let ciphers = without_grease(&self.ciphers);
+		let extensions = without_grease(&self.extensions);
+
+		// The first and last characters of the first protocol, or of its hex
+		// when they are not alphanumeric
+		let alpn = match self.alpn.first().map(|a| a.as_bytes()) {
+			Some([first, .., last]) | Some([first @ last])
+				if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() =>
+			{
+				format!("{}{}", *first as char, *last as char)
+			},
+			Some(a) if !a.is_empty() => {
+				let h = hex(a);
+				format!("{}{}", &h[..1], &h[h.len() - 1..])
+			},
+			_ => "00".to_string(),
+		};
+
+		let mut sorted_ciphers: Vec<String> = ciphers.iter().map(|c| format!("{:04x}", c)).collect();
+		sorted_ciphers.sort();
+
+		// The server name and ALPN are already counted in the first part
+		let mut sorted_extensions: Vec<String> = extensions
+			.iter()
+			.filter(|e| **e != EXT_SERVER_NAME && **e != EXT_ALPN)
+			.map(|e| format!("{:04x}", e))
+			.collect();
+		sorted_extensions.sort();
+		let mut extensions_input = sorted_extensions.join(",");
+		let signature_algorithms: Vec<String> = without_grease(&self.signature_algorithms)
+			.iter()
+			.map(|s| format!("{:04x}", s))
+			.collect();
+		if !signature_algorithms.is_empty() {
+			extensions_input.push('_');
+			extensions_input.push_str(&signature_algorithms.join(","));
+		}
+
+		format!(
+			"{}{}{}{:02}{:02}{}_{}_{}",
+			transport,
+			version,
+			sni,
+			ciphers.len().min(99),
+			extensions.len().min(99),
+			alpn,
+			ja4_hash(&sorted_ciphers.join(",")),
+			ja4_hash(&extensions_input)
+		)
+	}
+}
+
+impl ServerHello {
+	/// The JA3S fingerprint before it is hashed
+	pub fn ja3s_string(&self) -> String {
+		format!(
+			"{},{},{}",
+			self.version,
+			self.cipher,
+			decimal(&self.extensions)
+		)
+	}
+
+	pub fn ja3s(&self) -> String {
+		hex(&Md5::digest(self.ja3s_string().as_bytes()))
+	}
+}
+
+/// Handshake is what a TLS handshake reveals before encryption starts
+#[derive(Clone, Debug, Serialize)]
+pub struct Handshake {
+	pub client: Endpoint,
+	pub server: Endpoint,
+	pub sni: Option<String>,
+	/// Not set when the server's hello was not seen
+	pub version: Option<String>,
+	pub versions_offered: Vec<String>,
+	pub cipher: Option<String>,
+	pub ciphers_offered: Vec<String>,
+	pub alpn: Option<String>,
+	pub alpn_offered: Vec<String>,
+	/// The server's certificate chain, which TLS 1.3 encrypts
+	pub certificates: Vec<Certificate>,
+	pub ja3: String,
+	pub ja3s: Option<String>,
+	pub ja4: String,
+}
+
+impl Handshake {
+	pub fn new(
+		client: Endpoint,
+		server: Endpoint,
+		transport: char,
+		hello: &ClientHello,
+		reply: Option<&ServerHello>,
+		certificates: Vec<Certificate>,
+	) -> Handshake {
+		Handshake {
+			client,
+			server,
+			sni: hello.sni.clone(),
+			version: reply.map(|r| version_name(r.selected_version.unwrap_or(r.version))),
+			versions_offered: hello.versions().into_iter().map(version_name).collect(),
+			cipher: reply.map(|r| format!("0x{:04x}", r.cipher)),
+			ciphers_offered: without_grease(&hello.ciphers)
+				.iter()
+				.map(|c| format!("0x{:04x}", c))
+				.collect(),
+			alpn: reply.and_then(|r| r.alpn.clone()),
+			alpn_offered: hello.alpn.clone(),
+			certificates,
+			ja3: hello.ja3(),
+			ja3s: reply.map(|r| r.ja3s()),
+			ja4: hello.ja4(transport),
+		}
+	}
+}
+
+/// Side collects the handshake messages sent by one side of a connection,
+/// until the handshake is encrypted or turns out not to be TLS at all
+#[derive(Default)]
+struct Side {
+	records: Vec<u8>,
+	handshake: Vec<u8>,
+	done: bool,
+}
+
+impl Side {
+	/// Takes record layer bytes, returning the handshake messages they
+	/// complete as their type and body
+	fn feed(&mut self, data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+		let mut messages = vec![];
+		if self.done {
+			return messages;
+		}
+		self.records.extend_from_slice(data);
+
+		while self.records.len() >= RECORD_HEADER_LEN {
+			// Anything but a plaintext handshake record ends what can be seen
+			let r = &self.records;
+			if r[0] != CONTENT_HANDSHAKE || r[1] != 3 {
+				self.done = true;
+				break;
+			}
+			let len = u16::from_be_bytes([r[3], r[4]]) as usize;
+			if r.len() < RECORD_HEADER_LEN + len {
+				break;
+			}
+			self
+				.handshake
+				.extend_from_slice(&r[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len]);
+			self.records.drain(..RECORD_HEADER_LEN + len);
+
+			while self.handshake.len() >= 4 {
+				let h = &self.handshake;
+				let len = u32::from_be_bytes([0, h[1], h[2], h[3]]) as usize;
+				if h.len() < 4 + len {
+					break;
+				}
+				messages.push((h[0], h[4..4 + len].to_vec()));
+				self.handshake.drain(..4 + len);
+			}
+		}
+
+		if self.records.len() + self.handshake.len() > MAX_HANDSHAKE {
+			self.done = true;
+		}
+		messages
+	}
+}
+
+/// StreamFactory watches every TCP connection for a TLS handshake, attaching
+/// what it finds to the connection's flow and publishing it
+pub struct StreamFactory {
+	flows: SharedFlows,
+	events: Events,
+}
+
+impl StreamFactory {
+	pub fn new(flows: SharedFlows, events: Events) -> StreamFactory {
+		StreamFactory { flows, events }
+	}
+}
+
+impl StreamConsumerFactory for StreamFactory {
+	fn new_consumer(&mut self, info: &StreamInfo) -> Option<Box<dyn StreamConsumer>> {
+		Some(Box::new(StreamDecoder {
+			info: info.clone(),
+			flows: self.flows.clone(),
+			events: self.events.clone(),
+			client: Side::default(),
+			server: Side::default(),
+			hello: None,
+			reply: None,
+			certificates: vec![],
+			last_seen: Duration::ZERO,
+			published: false,
+		}))
+	}
+}
+
+struct StreamDecoder {
+	info: StreamInfo,
+	flows: SharedFlows,
+	events: Events,
+	client: Side,
+	server: Side,
+	hello: Option<ClientHello>,
+	reply: Option<ServerHello>,
+	certificates: Vec<Certificate>,
+	last_seen: Duration,
+	published: bool,
+}
+
+impl StreamDecoder {
+	fn publish(&mut self) {
+		let Some(hello) = &self.hello else {
+			return;
+		};
+		if self.published {
+			return;
+		}
+		self.published = true;
+
+		let handshake = Handshake::new(
+			self.info.client,
+			self.info.server,
+			't',
+			hello,
+			self.reply.as_ref(),
+			std::mem::take(&mut self.certificates),
+		);
+		if let Some(record) = self
+			.flows
+			.lock()
+			.unwrap()
+			.get_mut(Protocol::Tcp, &self.info.key)
+		{
+			record.tls = Some(Box::new(handshake.clone()));
+		}
+		self.events.publish(
+			self.last_seen,
+			&self.info.iface,
+			EventKind::Transaction(events::Transaction::Tls(Box::new(handshake))),
+		);
+	}
+}
+
+impl StreamConsumer for StreamDecoder {
+	fn data(&mut self, direction: Direction, ts: Duration, data: &[u8]) {
+		self.last_seen = ts;
+		match direction {
+			Direction::ClientToServer => {
+				for (kind, body) in self.client.feed(data) {
+					if kind == CLIENT_HELLO && self.hello.is_none() {
+						self.hello = parse_client_hello(&body);
+					}
+				}
+			},
+			Direction::ServerToClient => {
+				for (kind, body) in self.server.feed(data) {
+					match kind {
+						SERVER_HELLO if self.reply.is_none() => {
+							self.reply = parse_server_hello(&body);
+						},
+						CERTIFICATE => self.certificates = parse_certificates(&body),
+						SERVER_HELLO_DONE => self.server.done = true,
+						_ => {},
+					}
+				}
+			},
+		}
+
+		if self.reply.is_some() && self.server.done {
+			self.publish();
+		}
+	}
+
+	/// A handshake missing bytes cannot be read any further
+	fn gap(&mut self, direction: Direction, _len: u64) {
+		match direction {
+			Direction::ClientToServer => self.client.done = true,
+			Direction::ServerToClient => self.server.done = true,
+		}
+	}
+
+	fn close(&mut self) {
+		self.publish();
+	}
+
+	fn done(&self) -> bool {
+		self.published
+			|| (self.client.done && self.hello.is_none())
+			|| (self.server.done && self.reply.is_none())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::IpAddr,
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	use crate::{
+		events::{EventKind, Events, Transaction},
+		flow::{
+			Direction, Endpoint, FlowKey,
+			reassembly::{StreamConsumerFactory, StreamInfo},
+		},
+		protocols::tls::{StreamFactory, parse_client_hello},
+		state::flows::FlowRegistry,
+	};
+
+	/// Wraps a handshake message in a record
+	fn record(kind: u8, body: &[u8]) -> Vec<u8> {
+		let mut message = vec![kind, 0];
+		message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+		message.extend_from_slice(body);
+
+		let mut r = vec![22, 3, 1];
+		r.extend_from_slice(&(message.len() as u16).to_be_bytes());
+		r.extend_from_slice(&message);
+		r
+	}
+
+	fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+		let mut e = kind.to_be_bytes().to_vec();
+		e.extend_from_slice(&(data.len() as u16).to_be_bytes());
+		e.extend_from_slice(data);
+		e
+	}
+
+	fn client_hello() -> Vec<u8> {
+		let mut extensions = vec![];
+		extensions.extend(extension(0x0a0a, b""));
+		extensions.extend(extension(0, b"\x00\x0e\x00\x00\x0bexample.com"));
+		extensions.extend(extension(10, b"\x00\x04\x00\x1d\x00\x17"));
+		extensions.extend(extension(11, b"\x01\x00"));
+		extensions.extend(extension(13, b"\x00\x04\x04\x03\x08\x04"));
+		extensions.extend(extension(16, b"\x00\x0c\x02h2\x08http/1.1"));
+		extensions.extend(extension(43, b"\x04\x03\x04\x03\x03"));
+
+		let mut body = vec![3, 3];
+		body.extend_from_slice(&[0; 32]);
+		body.push(0);
+		body.extend_from_slice(&[0, 6, 0x1a, 0x1a, 0x13, 0x01, 0xc0, 0x2f]);
+		body.extend_from_slice(&[1, 0]);
+		body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+		body.extend_from_slice(&extensions);
+		body
+	}
+
+	fn server_hello() -> Vec<u8> {
+		let mut extensions = vec![];
+		extensions.extend(extension(43, b"\x03\x04"));
+		extensions.extend(extension(16, b"\x00\x03\x02h2"));
+
+		let mut body = vec![3, 3];
+		body.extend_from_slice(&[0; 32]);
+		body.push(0);
+		body.extend_from_slice(&[0x13, 0x01, 0]);
+		body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+		body.extend_from_slice(&extensions);
+		body
+	}
+
+	#[test]
+	fn test_handshake_and_fingerprints() {
+		let hello = parse_client_hello(&client_hello()).unwrap();
+		assert_eq!(Some("example.com".to_string()), hello.sni);
+		assert_eq!(vec!["h2", "http/1.1"], hello.alpn);
+		assert_eq!(
+			"771,4865-49199,0-10-11-13-16-43,29-23,0",
+			hello.ja3_string()
+		);
+		assert_eq!("97737df38853b88c4324af06e211c4a1", hello.ja3());
+		assert_eq!("t13d0206h2_c1929292aa6b_fb71836bce29", hello.ja4('t'));
+
+		let client = Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000);
+		let server = Endpoint::new(IpAddr::from([93, 184, 216, 34]), 443);
+		let key = FlowKey::new(client, server);
+		let flows = Arc::new(Mutex::new(FlowRegistry::new()));
+		flows.lock().unwrap().record(
+			crate::flow::Protocol::Tcp,
+			client,
+			server,
+			"eth0",
+			Duration::ZERO,
+			0,
+		);
+		let events = Events::default();
+		let mut rx = events.subscribe();
+
+		let mut factory = StreamFactory::new(flows.clone(), events);
+		let info = StreamInfo {
+			key,
+			client,
+			server,
+			iface: "eth0".to_string(),
+		};
+		let mut consumer = factory.new_consumer(&info).unwrap();
+
+		// The hello is split across two deliveries
+		let hello = record(1, &client_hello());
+		consumer.data(Direction::ClientToServer, Duration::ZERO, &hello[..20]);
+		consumer.data(Direction::ClientToServer, Duration::ZERO, &hello[20..]);
+		consumer.data(
+			Direction::ServerToClient,
+			Duration::ZERO,
+			&record(2, &server_hello()),
+		);
+		assert!(!consumer.done());
+
+		// TLS 1.3 encrypts the rest
+		consumer.data(
+			Direction::ServerToClient,
+			Duration::ZERO,
+			&[20, 3, 3, 0, 1, 1],
+		);
+		assert!(consumer.done());
+
+		let tls = flows
+			.lock()
+			.unwrap()
+			.get(crate::flow::Protocol::Tcp, &key)
+			.unwrap()
+			.tls
+			.clone();
+		let tls = tls.unwrap();
+		assert_eq!(Some("TLS 1.3".to_string()), tls.version);
+		assert_eq!(Some("h2".to_string()), tls.alpn);
+		assert_eq!(Some("0x1301".to_string()), tls.cipher);
+
+		let event = rx.try_recv().unwrap();
+		assert!(matches!(
+			&event.kind,
+			EventKind::Transaction(Transaction::Tls(t)) if t.sni.as_deref() == Some("example.com")
+		));
+	}
+}
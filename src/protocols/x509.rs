@@ -0,0 +1,219 @@
+use serde::Serialize;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const BMP_STRING: u8 = 0x1e;
+/// The explicit tag around the version of a v2 or v3 certificate
+const VERSION: u8 = 0xa0;
+
+/// Certificate is what is shown of an X.509 certificate: who it was issued to
+/// and by, and when it is valid
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Certificate {
+	pub subject: String,
+	pub issuer: String,
+	pub not_before: String,
+	pub not_after: String,
+}
+
+/// Reads the subject, issuer and validity of a DER encoded certificate; the
+/// signature is not checked
+pub fn parse(der: &[u8]) -> Option<Certificate> {
+	let (certificate, _) = expect(der, SEQUENCE)?;
+	let (tbs, _) = expect(certificate, SEQUENCE)?;
+
+	let mut rest = tbs;
+	if rest.first() == Some(&VERSION) {
+		(_, _, rest) = tlv(rest)?;
+	}
+	let (_serial, _, rest) = tlv(rest)?;
+	let (_signature, rest) = expect(rest, SEQUENCE)?;
+	let (issuer, rest) = expect(rest, SEQUENCE)?;
+	let (validity, rest) = expect(rest, SEQUENCE)?;
+	let (subject, _) = expect(rest, SEQUENCE)?;
+
+	let (not_before, not_before_tag, validity) = tlv(validity)?;
+	let (not_after, not_after_tag, _) = tlv(validity)?;
+
+	Some(Certificate {
+		subject: name(subject)?,
+		issuer: name(issuer)?,
+		not_before: time(not_before_tag, not_before)?,
+		not_after: time(not_after_tag, not_after)?,
+	})
+}
+
+/// Splits off the first element, returning its content, tag and whatever
+/// follows it
+fn tlv(data: &[u8]) -> Option<(&[u8], u8, &[u8])> {
+	let (&tag, data) = data.split_first()?;
+	let (&first, data) = data.split_first()?;
+
+	let (len, data) = match first {
+		0..=0x7f => (first as usize, data),
+		0x81..=0x84 => {
+			let n = (first & 0x7f) as usize;
+			let bytes = data.get(..n)?;
+			let len = bytes.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+			(len, &data[n..])
+		},
+		_ => return None,
+	};
+
+	let content = data.get(..len)?;
+	Some((content, tag, &data[len..]))
+}
+
+fn expect(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+	match tlv(data)? {
+		(content, t, rest) if t == tag => Some((content, rest)),
+		_ => None,
+	}
+}
+
+/// Formats a distinguished name the way openssl does, e.g.
+/// "C=US, O=Example, CN=example.com"
+fn name(mut rdns: &[u8]) -> Option<String> {
+	let mut parts = vec![];
+	while !rdns.is_empty() {
+		let (mut set, rest) = expect(rdns, SET)?;
+		rdns = rest;
+		while !set.is_empty() {
+			let (attribute, rest) = expect(set, SEQUENCE)?;
+			set = rest;
+			let (oid, rest) = expect(attribute, OID)?;
+			let (value, tag, _) = tlv(rest)?;
+			parts.push(format!("{}={}", attribute_name(oid), string(tag, value)));
+		}
+	}
+
+	Some(parts.join(", "))
+}
+
+fn attribute_name(oid: &[u8]) -> String {
+	let name = match oid {
+		[0x55, 0x04, 0x03] => "CN",
+		[0x55, 0x04, 0x05] => "serialNumber",
+		[0x55, 0x04, 0x06] => "C",
+		[0x55, 0x04, 0x07] => "L",
+		[0x55, 0x04, 0x08] => "ST",
+		[0x55, 0x04, 0x0a] => "O",
+		[0x55, 0x04, 0x0b] => "OU",
+		[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+		_ => return dotted(oid),
+	};
+	name.to_string()
+}
+
+/// Formats an object identifier which has no short name, e.g. "2.5.4.97"
+fn dotted(oid: &[u8]) -> String {
+	let mut arcs = vec![];
+	let mut arc: u64 = 0;
+	for b in oid {
+		arc = (arc << 7) | (b & 0x7f) as u64;
+		if b & 0x80 == 0 {
+			if arcs.is_empty() {
+				let first = (arc / 40).min(2);
+				arcs.push(first);
+				arcs.push(arc - first * 40);
+			} else {
+				arcs.push(arc);
+			}
+			arc = 0;
+		}
+	}
+
+	arcs
+		.iter()
+		.map(|a| a.to_string())
+		.collect::<Vec<String>>()
+		.join(".")
+}
+
+fn string(tag: u8, value: &[u8]) -> String {
+	match tag {
+		BMP_STRING => {
+			let units: Vec<u16> = value
+				.chunks_exact(2)
+				.map(|c| u16::from_be_bytes([c[0], c[1]]))
+				.collect();
+			String::from_utf16_lossy(&units)
+		},
+		_ => String::from_utf8_lossy(value).into_owned(),
+	}
+}
+
+/// Formats a UTCTime or GeneralizedTime as RFC 3339, e.g.
+/// "2025-01-31T23:59:59Z"
+fn time(tag: u8, value: &[u8]) -> Option<String> {
+	let s = std::str::from_utf8(value).ok()?;
+	let (year, rest) = match tag {
+		UTC_TIME => {
+			let yy: u32 = s.get(..2)?.parse().ok()?;
+			// Two digit years from 50 on are in the twentieth century
+			let year = if yy >= 50 { 1900 + yy } else { 2000 + yy };
+			(year, s.get(2..)?)
+		},
+		GENERALIZED_TIME => (s.get(..4)?.parse().ok()?, s.get(4..)?),
+		_ => return None,
+	};
+
+	let field = |i: usize| {
+		rest
+			.get(i..i + 2)
+			.filter(|f| f.bytes().all(|b| b.is_ascii_digit()))
+	};
+	Some(format!(
+		"{:04}-{}-{}T{}:{}:{}Z",
+		year,
+		field(0)?,
+		field(2)?,
+		field(4)?,
+		field(6)?,
+		field(8).unwrap_or("00")
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::protocols::x509::parse;
+
+	/// A self-signed certificate made with `openssl req -x509`
+	const CERTIFICATE: &[&str] = &[
+		"308201bf30820165a003020102021445bb2e741537b8e3776cb86e6bd6ad36be",
+		"be0c3c300a06082a8648ce3d0403023035310b30090603550406130255533110",
+		"300e060355040a0c074578616d706c653114301206035504030c0b6578616d70",
+		"6c652e636f6d301e170d3236313031373138313032355a170d32363131313631",
+		"38313032355a3035310b30090603550406130255533110300e060355040a0c07",
+		"4578616d706c653114301206035504030c0b6578616d706c652e636f6d305930",
+		"1306072a8648ce3d020106082a8648ce3d030107034200041b21eae31a4669e0",
+		"229bf6cc4654e7c614d7a72a521ba49661f49cdb5cccd04f5ae9656d8d98d677",
+		"2487950457090875623e819261a8287728357967a06668e3a3533051301d0603",
+		"551d0e04160414bcf4c8d83892eed9889b5deeca98f91d1e56e4c9301f060355",
+		"1d23041830168014bcf4c8d83892eed9889b5deeca98f91d1e56e4c9300f0603",
+		"551d130101ff040530030101ff300a06082a8648ce3d04030203480030450220",
+		"0241b90ed575e785a78d30116d7fccf272904e88bc5f8dd09aa4e2f064ffa6bb",
+		"022100f5e98071262f5a647b39d53f1c85948c8ea80c829d502d530acf5b2a4f",
+		"89091e",
+	];
+
+	#[test]
+	fn test_parse() {
+		let hex = CERTIFICATE.concat();
+		let der: Vec<u8> = (0..hex.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+			.collect();
+
+		let certificate = parse(&der).unwrap();
+		assert_eq!("C=US, O=Example, CN=example.com", certificate.subject);
+		assert_eq!(certificate.subject, certificate.issuer);
+		assert_eq!("2026-10-17T18:10:25Z", certificate.not_before);
+		assert_eq!("2026-11-16T18:10:25Z", certificate.not_after);
+
+		assert_eq!(None, parse(&der[..100]));
+	}
+}
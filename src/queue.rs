@@ -0,0 +1,321 @@
+use std::{
+	collections::VecDeque,
+	fmt,
+	sync::{Arc, Condvar, Mutex, Weak},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// How many packets a listener queue holds unless configured otherwise
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Policy decides what happens to a packet sent to a full queue
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+	/// Wait for the listener to make room, stalling the capture
+	#[default]
+	Block,
+
+	/// Discard the packet being sent
+	DropNewest,
+
+	/// Discard the packet which has waited longest, to make room
+	DropOldest,
+}
+
+impl fmt::Display for Policy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Policy::Block => write!(f, "block"),
+			Policy::DropNewest => write!(f, "drop-newest"),
+			Policy::DropOldest => write!(f, "drop-oldest"),
+		}
+	}
+}
+
+/// Sent is what became of a packet handed to a queue
+#[derive(Debug, Eq, PartialEq)]
+pub enum Sent {
+	Queued,
+
+	/// The queue was full, so a packet was discarded according to its policy
+	Dropped,
+}
+
+/// Closed is returned along with the packet when the listener has gone away
+#[derive(Debug)]
+pub struct Closed<T>(pub T);
+
+struct Inner<T> {
+	items: VecDeque<T>,
+	senders: usize,
+	closed: bool,
+}
+
+struct Shared<T> {
+	inner: Mutex<Inner<T>>,
+	not_full: Condvar,
+	not_empty: Notify,
+	capacity: usize,
+	policy: Policy,
+}
+
+/// Creates a bounded queue between capture threads, which send from blocking
+/// code, and a single async listener.  Unlike a plain channel, a full queue can
+/// shed packets instead of stalling the capture, so that one slow listener
+/// does not blind the others.
+pub fn channel<T>(capacity: usize, policy: Policy) -> (Sender<T>, Receiver<T>) {
+	assert!(capacity > 0, "queue capacity must be positive");
+
+	let shared = Arc::new(Shared {
+		inner: Mutex::new(Inner {
+			items: VecDeque::with_capacity(capacity),
+			senders: 1,
+			closed: false,
+		}),
+		not_full: Condvar::new(),
+		not_empty: Notify::new(),
+		capacity,
+		policy,
+	});
+
+	(
+		Sender {
+			shared: shared.clone(),
+		},
+		Receiver { shared },
+	)
+}
+
+pub struct Sender<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+	/// Queues a packet, applying the queue's policy if it is full.  Only the
+	/// block policy ever waits.
+	pub fn blocking_send(&self, value: T) -> Result<Sent, Closed<T>> {
+		let shared = &*self.shared;
+		let mut inner = shared.inner.lock().unwrap();
+		if inner.closed {
+			return Err(Closed(value));
+		}
+
+		let mut discarded = None;
+		let sent = if inner.items.len() < shared.capacity {
+			Sent::Queued
+		} else {
+			match shared.policy {
+				Policy::Block => {
+					inner = shared
+						.not_full
+						.wait_while(inner, |i| i.items.len() >= shared.capacity && !i.closed)
+						.unwrap();
+					if inner.closed {
+						return Err(Closed(value));
+					}
+					Sent::Queued
+				},
+				Policy::DropNewest => {
+					// Freed once the lock is released
+					drop(inner);
+					return Ok(Sent::Dropped);
+				},
+				Policy::DropOldest => {
+					discarded = inner.items.pop_front();
+					Sent::Dropped
+				},
+			}
+		};
+
+		inner.items.push_back(value);
+		drop(inner);
+		drop(discarded);
+
+		shared.not_empty.notify_one();
+		Ok(sent)
+	}
+
+	/// How many packets are waiting
+	pub fn len(&self) -> usize {
+		self.shared.inner.lock().unwrap().items.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// How many more packets fit before the policy applies
+	pub fn capacity(&self) -> usize {
+		self.max_capacity() - self.len()
+	}
+
+	pub fn max_capacity(&self) -> usize {
+		self.shared.capacity
+	}
+
+	pub fn policy(&self) -> Policy {
+		self.shared.policy
+	}
+
+	/// A handle which does not keep the queue open
+	pub fn downgrade(&self) -> WeakSender<T> {
+		WeakSender {
+			shared: Arc::downgrade(&self.shared),
+		}
+	}
+}
+
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		self.shared.inner.lock().unwrap().senders += 1;
+		Sender {
+			shared: self.shared.clone(),
+		}
+	}
+}
+
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		let mut inner = self.shared.inner.lock().unwrap();
+		inner.senders -= 1;
+		if inner.senders == 0 {
+			drop(inner);
+			// Wake the listener so that it sees the queue has closed
+			self.shared.not_empty.notify_one();
+		}
+	}
+}
+
+pub struct WeakSender<T> {
+	shared: Weak<Shared<T>>,
+}
+
+impl<T> WeakSender<T> {
+	/// A sender, as long as another sender is still alive
+	pub fn upgrade(&self) -> Option<Sender<T>> {
+		let shared = self.shared.upgrade()?;
+		{
+			let mut inner = shared.inner.lock().unwrap();
+			if inner.senders == 0 {
+				return None;
+			}
+			inner.senders += 1;
+		}
+		Some(Sender { shared })
+	}
+}
+
+impl<T> Clone for WeakSender<T> {
+	fn clone(&self) -> Self {
+		WeakSender {
+			shared: self.shared.clone(),
+		}
+	}
+}
+
+pub struct Receiver<T> {
+	shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+	/// Waits for the next packet.  Returns None once every sender has gone and
+	/// the queue has been drained.
+	pub async fn recv(&mut self) -> Option<T> {
+		loop {
+			if let Some(next) = self.try_next() {
+				return next;
+			}
+			self.shared.not_empty.notified().await;
+		}
+	}
+
+	/// Waits for the next packet from outside of an async context
+	pub fn blocking_recv(&mut self) -> Option<T> {
+		futures::executor::block_on(self.recv())
+	}
+
+	pub fn len(&self) -> usize {
+		self.shared.inner.lock().unwrap().items.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	fn try_next(&self) -> Option<Option<T>> {
+		let mut inner = self.shared.inner.lock().unwrap();
+		match inner.items.pop_front() {
+			Some(value) => {
+				drop(inner);
+				self.shared.not_full.notify_one();
+				Some(Some(value))
+			},
+			None if inner.senders == 0 => Some(None),
+			None => None,
+		}
+	}
+}
+
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		let items = {
+			let mut inner = self.shared.inner.lock().unwrap();
+			inner.closed = true;
+			std::mem::take(&mut inner.items)
+		};
+		drop(items);
+
+		// Release any sender waiting for room
+		self.shared.not_full.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use crate::queue::{self, Policy, Sent};
+
+	#[test]
+	fn test_full_queue_policies() {
+		let (tx, mut rx) = queue::channel::<u32>(2, Policy::DropNewest);
+		assert_eq!(Sent::Queued, tx.blocking_send(1).unwrap());
+		assert_eq!(Sent::Queued, tx.blocking_send(2).unwrap());
+		assert_eq!(Sent::Dropped, tx.blocking_send(3).unwrap());
+		assert_eq!(0, tx.capacity());
+		drop(tx);
+		assert_eq!(Some(1), rx.blocking_recv());
+		assert_eq!(Some(2), rx.blocking_recv());
+		assert_eq!(None, rx.blocking_recv());
+
+		let (tx, mut rx) = queue::channel::<u32>(2, Policy::DropOldest);
+		for i in 1..=3 {
+			tx.blocking_send(i).unwrap();
+		}
+		let weak = tx.downgrade();
+		drop(tx);
+		assert!(weak.upgrade().is_none());
+		assert_eq!(Some(2), rx.blocking_recv());
+		assert_eq!(Some(3), rx.blocking_recv());
+		assert_eq!(None, rx.blocking_recv());
+
+		// A blocked sender resumes once the listener makes room, and gives
+		// the packet back if the listener goes away instead
+		let (tx, mut rx) = queue::channel::<u32>(1, Policy::Block);
+		tx.blocking_send(1).unwrap();
+		let sender = thread::spawn(move || {
+			let sent = tx.blocking_send(2).is_ok();
+			(sent, tx.blocking_send(3).map_err(|e| e.0))
+		});
+		assert_eq!(Some(1), rx.blocking_recv());
+		while rx.is_empty() {
+			thread::yield_now();
+		}
+		drop(rx);
+		assert_eq!((true, Err(3)), sender.join().unwrap());
+	}
+}
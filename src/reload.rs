@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use log::{error, info, warn};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+	cli::logging,
+	config::{self, File, RunConfig},
+	devices::{self, Matcher, ReceivedPacketData, Tap, Update},
+	queue::Sender,
+	runtime::{Generation, Reload, RunnableBuilder},
+	state::appstate::AppState,
+};
+
+/// Load reads the configuration as it is now
+pub type Load = Box<dyn FnMut() -> Result<File, config::Error> + Send>;
+
+/// Outputs builds the packet sinks and the event output of a configuration,
+/// along with the taps which feed the sinks from the captures
+pub type Outputs = Box<dyn FnMut(&RunConfig) -> (Vec<Tap>, Vec<Box<dyn RunnableBuilder>>) + Send>;
+
+/// Reloader reads the configuration of `run` again and applies what can
+/// change while capturing: the log level, the capture filter, which listeners
+/// are fed, the sinks, the event output and the alert thresholds.  Captures, listeners and their
+/// flow tables keep running.  Sinks and the event output are built again and
+/// replace the old ones, which may lose whatever was still queued for them;
+/// other changes are reported and wait for a restart.
+pub struct Reloader {
+	load: Load,
+	current: File,
+	senders: HashMap<Matcher, Sender<ReceivedPacketData>>,
+	outputs: Outputs,
+	taps: Vec<Tap>,
+	first: Option<Vec<Box<dyn RunnableBuilder>>>,
+	retire: broadcast::Sender<()>,
+	updates: watch::Sender<Update>,
+	state: AppState,
+}
+
+impl Reloader {
+	/// Takes the configuration which is running, along with the senders of
+	/// every listener which was started, enabled or not.  The receiver is for
+	/// the live captures to follow, and already holds the first taps.
+	pub fn new(
+		load: Load,
+		current: File,
+		rc: &RunConfig,
+		senders: HashMap<Matcher, Sender<ReceivedPacketData>>,
+		mut outputs: Outputs,
+		state: AppState,
+	) -> (Reloader, watch::Receiver<Update>) {
+		let (taps, first) = outputs(rc);
+		let update = update(&current, &senders, &taps);
+		let (updates, receiver) = watch::channel(update);
+		let (retire, _) = broadcast::channel(1);
+
+		(
+			Reloader {
+				load,
+				current,
+				senders,
+				outputs,
+				taps,
+				first: Some(first),
+				retire,
+				updates,
+				state,
+			},
+			receiver,
+		)
+	}
+
+	/// Starts the outputs over again, retiring the ones running
+	fn replace_outputs(&mut self, rc: &RunConfig) -> Generation {
+		let (taps, runnables) = (self.outputs)(rc);
+		self.taps = taps;
+
+		let (retire, _) = broadcast::channel(1);
+		let _ = std::mem::replace(&mut self.retire, retire).send(());
+
+		Generation {
+			runnables,
+			retire: self.retire.subscribe(),
+		}
+	}
+
+	fn apply(&mut self, file: File, rc: &RunConfig) -> Option<Generation> {
+		let mut generation = None;
+		if file.sinks != self.current.sinks || file.events != self.current.events {
+			generation = Some(self.replace_outputs(rc));
+			info!("sinks and event output restarted");
+			self.current.sinks = file.sinks.clone();
+			self.current.events = file.events.clone();
+		}
+
+		let current = &mut self.current;
+
+		if file.http != current.http {
+			warn!("http settings changed, restart to apply them");
+		}
+		if file.queues != current.queues {
+			warn!("queue settings changed, restart to apply them");
+		}
+		if file.decoders != current.decoders {
+			warn!("decoder settings changed, restart to apply them");
+		}
+		let mut capture = file.capture.clone();
+		capture.filter = current.capture.filter.clone();
+		if capture != current.capture {
+			warn!("capture settings other than the filter changed, restart to apply them");
+		}
+
+		if file.alerts != current.alerts {
+			let threshold = file.alerts.mac_ip_threshold;
+			self
+				.state
+				.arp
+				.lock()
+				.unwrap()
+				.set_mac_ip_threshold(threshold);
+			info!(
+				"alerts now report a MAC claiming more than {} IPs",
+				threshold
+			);
+			current.alerts = file.alerts.clone();
+		}
+
+		if file.log_level != current.log_level {
+			logging::set_level(file.log_level);
+			info!("log level is now {}", file.log_level);
+			current.log_level = file.log_level;
+		}
+
+		if file.capture.filter == current.capture.filter
+			&& file.listeners == current.listeners
+			&& generation.is_none()
+		{
+			return None;
+		}
+		current.capture.filter = file.capture.filter;
+		current.listeners = file.listeners;
+
+		let update = update(current, &self.senders, &self.taps);
+		{
+			// Disabled listeners stay open, but are no longer reported
+			let mut queues = self.state.queues.lock().unwrap();
+			queues.retain(|m, _| update.senders.contains_key(m));
+		}
+		devices::register_queues(&self.state, &update.senders);
+
+		info!(
+			"capture filter '{}', listeners {:?}",
+			update.filter.as_deref().unwrap_or(""),
+			current
+				.listeners
+				.iter()
+				.map(|m| m.name())
+				.collect::<Vec<_>>()
+		);
+		self.updates.send_replace(update);
+
+		generation
+	}
+}
+
+impl Reload for Reloader {
+	fn start(&mut self) -> Option<Generation> {
+		Some(Generation {
+			runnables: self.first.take()?,
+			retire: self.retire.subscribe(),
+		})
+	}
+
+	fn reload(&mut self) -> Option<Generation> {
+		// A configuration which does not load or validate changes nothing
+		let file = match (self.load)() {
+			Ok(file) => file,
+			Err(e) => {
+				error!("configuration was not reloaded: {}", e);
+				return None;
+			},
+		};
+		let rc = match RunConfig::try_from(file.clone()) {
+			Ok(rc) => rc,
+			Err(e) => {
+				error!("configuration was not reloaded: {}", e);
+				return None;
+			},
+		};
+
+		self.apply(file, &rc)
+	}
+}
+
+fn update(
+	file: &File,
+	senders: &HashMap<Matcher, Sender<ReceivedPacketData>>,
+	taps: &[Tap],
+) -> Update {
+	Update {
+		filter: file.capture.filter.clone(),
+		senders: senders
+			.iter()
+			.filter(|(m, _)| file.listeners.contains(m))
+			.map(|(m, s)| (*m, s.clone()))
+			.collect(),
+		taps: taps.to_vec(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		sync::{Arc, Mutex},
+	};
+
+	use log::LevelFilter;
+
+	use crate::{
+		config::{File, LISTENERS, PcapngSink, RunConfig},
+		devices::{Matcher, ReceivedPacketData, Tap},
+		queue::{self, Policy},
+		reload::{Outputs, Reloader},
+		runtime::Reload,
+		state::appstate,
+	};
+
+	/// One tap for each pcapng sink, and no tasks
+	fn outputs() -> Outputs {
+		Box::new(|rc: &RunConfig| {
+			let taps = rc
+				.sinks
+				.pcapng
+				.iter()
+				.map(|sink| Tap {
+					matchers: sink.matchers.clone(),
+					sender: queue::channel::<ReceivedPacketData>(4, Policy::Block).0,
+				})
+				.collect();
+			(taps, vec![])
+		})
+	}
+
+	#[test]
+	fn test_reload_listeners_and_log_level() {
+		let state = appstate::new();
+		let mut senders = HashMap::new();
+		let mut receivers = vec![];
+		for m in LISTENERS {
+			let (s, r) = queue::channel::<ReceivedPacketData>(4, Policy::Block);
+			senders.insert(m, s);
+			receivers.push(r);
+		}
+
+		let next = Arc::new(Mutex::new(File::default()));
+		let load = {
+			let next = next.clone();
+			Box::new(move || Ok(next.lock().unwrap().clone()))
+		};
+		let rc = RunConfig::try_from(File::default()).unwrap();
+		let (mut reloader, mut updates) = Reloader::new(
+			load,
+			File::default(),
+			&rc,
+			senders,
+			outputs(),
+			state.clone(),
+		);
+		assert_eq!(LISTENERS.len(), updates.borrow().senders.len());
+		let mut first = reloader.start().unwrap();
+		assert!(reloader.start().is_none());
+
+		// Nothing changed, so the captures are left alone
+		reloader.reload();
+		assert!(!updates.has_changed().unwrap());
+
+		{
+			let mut next = next.lock().unwrap();
+			next.listeners = vec![Matcher::Arp, Matcher::IPv4_TCP];
+			next.log_level = LevelFilter::Debug;
+			next.http.port = 9000;
+			next.alerts.mac_ip_threshold = 2;
+		}
+		reloader.reload();
+		assert!(updates.has_changed().unwrap());
+		let mut routed: Vec<_> = updates
+			.borrow_and_update()
+			.senders
+			.keys()
+			.copied()
+			.collect();
+		routed.sort();
+		assert_eq!(vec![Matcher::Arp, Matcher::IPv4_TCP], routed);
+		assert_eq!(2, state.queues.lock().unwrap().len());
+		assert_eq!(LevelFilter::Debug, log::max_level());
+		assert_eq!(2, state.arp.lock().unwrap().mac_ip_threshold());
+
+		assert!(first.retire.try_recv().is_err());
+
+		// New sinks replace the old ones, which are retired
+		next.lock().unwrap().sinks.pcapng = vec![PcapngSink {
+			directory: "/var/lib/psniff".into(),
+			matchers: Some(vec![Matcher::IPv4_TCP]),
+			..Default::default()
+		}];
+		let mut second = reloader.reload().unwrap();
+		assert!(first.retire.try_recv().is_ok());
+		assert!(second.retire.try_recv().is_err());
+		assert_eq!(1, updates.borrow_and_update().taps.len());
+
+		// An invalid configuration is ignored
+		next.lock().unwrap().listeners = vec![Matcher::Missing];
+		assert!(reloader.reload().is_none());
+		assert!(!updates.has_changed().unwrap());
+	}
+}
@@ -7,7 +7,10 @@ use log::{error, info};
 use thiserror::Error;
 use tokio::{
 	runtime,
-	signal::ctrl_c,
+	signal::{
+		ctrl_c,
+		unix::{SignalKind, signal},
+	},
 	sync::broadcast::{self, Receiver},
 	task::JoinSet,
 };
@@ -37,6 +40,23 @@ pub trait RunnableBuilder: Send {
 	async fn build(self: Box<Self>) -> Result<Box<dyn Runnable>, Box<dyn std::error::Error>>;
 }
 
+/// Generation is a set of tasks started by a reload, which run until shutdown
+/// or until `retire` fires because a later reload has replaced them
+pub struct Generation {
+	pub runnables: Vec<Box<dyn RunnableBuilder>>,
+	pub retire: Receiver<()>,
+}
+
+/// Reload re-applies configuration to the running tasks, without restarting
+/// them, when the process receives SIGHUP.  Tasks which cannot be changed in
+/// place are started again as a new generation.
+pub trait Reload: Send {
+	/// The first generation, started along with everything else
+	fn start(&mut self) -> Option<Generation>;
+
+	fn reload(&mut self) -> Option<Generation>;
+}
+
 pub fn blocking_build<'l>(
 	buildables: impl IntoIterator<Item = Box<dyn BlockingRunnableBuilder>>,
 ) -> Result<Vec<Box<dyn BlockingRunnable + Send + 'l>>, Box<dyn std::error::Error>> {
@@ -76,9 +96,46 @@ pub async fn build(
 	Ok(v)
 }
 
+/// Builds and starts a generation, whose tasks are cancelled by whichever of
+/// shutdown or retirement comes first
+async fn spawn_generation(
+	generations: &mut JoinSet<()>,
+	shutdown_tx: &broadcast::Sender<()>,
+	generation: Generation,
+) {
+	let runnables = match build(generation.runnables).await {
+		Ok(x) => x,
+		Err(_e) => {
+			error!("failed to build one or more runners: {}", _e);
+			return;
+		},
+	};
+
+	let (cancel_tx, _) = broadcast::channel::<()>(1);
+	for mut r in runnables {
+		let rx = cancel_tx.subscribe();
+		generations.spawn(async move {
+			info!("starting async task");
+			r.run(rx).await;
+		});
+	}
+
+	// A retire sender which is dropped retires the generation too
+	let mut shutdown_rx = shutdown_tx.subscribe();
+	let mut retire = generation.retire;
+	generations.spawn(async move {
+		tokio::select! {
+			_ = shutdown_rx.recv() => {},
+			_ = retire.recv() => {},
+		}
+		let _ = cancel_tx.send(());
+	});
+}
+
 pub fn run(
 	blocking_runnable_builders: impl IntoIterator<Item = Box<dyn BlockingRunnableBuilder>>,
 	runnable_builders: impl IntoIterator<Item = Box<dyn RunnableBuilder>>,
+	mut reload: Option<Box<dyn Reload>>,
 ) -> Result<()> {
 	// Create runtime for network listened
 	let rt = runtime::Builder::new_multi_thread()
@@ -115,10 +172,25 @@ pub fn run(
 
 		info!("building complete");
 
+		let mut hangup = match signal(SignalKind::hangup()) {
+			Ok(x) => x,
+			Err(_e) => {
+				error!("failed to handle SIGHUP: {}", _e);
+				return;
+			},
+		};
+
 		let (shutdown_tx, _) = broadcast::channel::<()>(1);
 
 		let mut futures = JoinSet::new();
 
+		// Generations are kept apart, as one ending when it is retired is no
+		// reason to halt
+		let mut generations = JoinSet::new();
+		if let Some(g) = reload.as_mut().and_then(|r| r.start()) {
+			spawn_generation(&mut generations, &shutdown_tx, g).await;
+		}
+
 		// Start the sync tasks
 		for r in blocking_runnables {
 			let rx = shutdown_tx.subscribe();
@@ -142,12 +214,25 @@ pub fn run(
 			});
 		}
 
-		// Wait for a reason to halt
-		tokio::select! {
-			_ = ctrl_c() => {
-				info!("captured ctrl-c");
-			},
-			_ = futures.join_next() => {},
+		// Wait for a reason to halt, reloading along the way
+		loop {
+			tokio::select! {
+				_ = ctrl_c() => {
+					info!("captured ctrl-c");
+					break;
+				},
+				_ = hangup.recv() => match reload.as_mut() {
+					Some(r) => {
+						info!("captured SIGHUP, reloading");
+						if let Some(g) = r.reload() {
+							spawn_generation(&mut generations, &shutdown_tx, g).await;
+						}
+					},
+					None => info!("captured SIGHUP, nothing to reload"),
+				},
+				Some(_) = generations.join_next() => {},
+				_ = futures.join_next() => break,
+			}
 		}
 
 		// Shutdown everything
@@ -161,6 +246,11 @@ pub fn run(
 				Err(e) => error!("Task failed: {:?}", e),
 			}
 		}
+		while let Some(res) = generations.join_next().await {
+			if let Err(e) = res {
+				error!("Task failed: {:?}", e);
+			}
+		}
 
 		info!("ending block");
 	});
@@ -4,22 +4,50 @@ use std::{
 };
 
 use crate::{
-	devices::Matcher,
-	state::{interface::Interface, packet_count::PacketCount},
+	devices::{Matcher, ReceivedPacketData},
+	events::Events,
+	queue::WeakSender,
+	state::{
+		arp::{ArpTable, SharedArpTable},
+		dns::{DnsLog, SharedDnsLog},
+		flows::{FlowRegistry, SharedFlows},
+		interface::Interface,
+		packet_count::MatcherCount,
+		ring::{PacketRing, SharedRing},
+	},
 };
 
 pub trait State: Clone + Default + Send + Sync {}
 
+/// Queues are the listener channels, held weakly so that they still close when
+/// the capture threads finish
+pub type Queues = Arc<Mutex<HashMap<Matcher, WeakSender<ReceivedPacketData>>>>;
+
 #[derive(Default)]
 pub struct AppState {
 	pub interfaces: Arc<Mutex<HashSet<Arc<Interface>>>>,
-	pub packet_counts: HashMap<Matcher, Arc<Mutex<PacketCount>>>,
+	pub packet_counts: HashMap<Matcher, Arc<MatcherCount>>,
+	pub queues: Queues,
+	pub flows: SharedFlows,
+	pub arp: SharedArpTable,
+	pub dns: SharedDnsLog,
+	pub ring: SharedRing,
+	pub events: Events,
 }
 
 pub fn new() -> AppState {
 	AppState {
 		interfaces: Arc::new(Mutex::new(HashSet::new())),
-		packet_counts: HashMap::new(),
+		packet_counts: Matcher::ALL
+			.into_iter()
+			.map(|m| (m, Arc::new(MatcherCount::default())))
+			.collect(),
+		queues: Arc::new(Mutex::new(HashMap::new())),
+		flows: Arc::new(Mutex::new(FlowRegistry::new())),
+		arp: Arc::new(Mutex::new(ArpTable::default())),
+		dns: Arc::new(Mutex::new(DnsLog::default())),
+		ring: Arc::new(Mutex::new(PacketRing::default())),
+		events: Events::default(),
 	}
 }
 
@@ -28,6 +56,12 @@ impl Clone for AppState {
 		Self {
 			interfaces: self.interfaces.clone(),
 			packet_counts: self.packet_counts.clone(),
+			queues: self.queues.clone(),
+			flows: self.flows.clone(),
+			arp: self.arp.clone(),
+			dns: self.dns.clone(),
+			ring: self.ring.clone(),
+			events: self.events.clone(),
 		}
 	}
 }
@@ -0,0 +1,251 @@
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	net::Ipv4Addr,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::state::serialize_timestamp;
+
+/// How many IPs a single MAC may claim before it is reported
+pub const DEFAULT_MAC_IP_THRESHOLD: usize = 8;
+
+/// How many events are retained for the API
+pub const DEFAULT_EVENT_LIMIT: usize = 1024;
+
+/// ArpBinding is the MAC address last seen claiming an IP
+#[derive(Clone, Debug, Serialize)]
+pub struct ArpBinding {
+	pub ip: Ipv4Addr,
+	pub mac: String,
+	pub iface: String,
+	#[serde(serialize_with = "serialize_timestamp")]
+	pub first_seen: Duration,
+	#[serde(serialize_with = "serialize_timestamp")]
+	pub last_seen: Duration,
+	pub packets: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ArpEventKind {
+	/// A host announced its own binding unprompted
+	Gratuitous,
+	/// A known IP is now claimed by a different MAC
+	MacChanged { previous_mac: String },
+	/// A single MAC is claiming an unusual number of IPs
+	ManyIps { count: usize },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ArpEvent {
+	#[serde(flatten)]
+	pub kind: ArpEventKind,
+	pub ip: Ipv4Addr,
+	pub mac: String,
+	pub iface: String,
+	#[serde(serialize_with = "serialize_timestamp")]
+	pub ts: Duration,
+}
+
+/// ArpTable is the live IP-to-MAC binding table built from observed ARP
+/// traffic, along with the suspicious changes seen while building it
+pub struct ArpTable {
+	bindings: HashMap<Ipv4Addr, ArpBinding>,
+	events: VecDeque<ArpEvent>,
+	event_limit: usize,
+	mac_ip_threshold: usize,
+}
+
+pub type SharedArpTable = Arc<Mutex<ArpTable>>;
+
+impl Default for ArpTable {
+	fn default() -> Self {
+		ArpTable::new(DEFAULT_MAC_IP_THRESHOLD, DEFAULT_EVENT_LIMIT)
+	}
+}
+
+impl ArpTable {
+	pub fn new(mac_ip_threshold: usize, event_limit: usize) -> ArpTable {
+		ArpTable {
+			bindings: HashMap::new(),
+			events: VecDeque::new(),
+			event_limit,
+			mac_ip_threshold,
+		}
+	}
+
+	/// Changes how many IPs a MAC may claim before it is reported.  A MAC
+	/// already past a lowered threshold is not reported.
+	pub fn set_mac_ip_threshold(&mut self, mac_ip_threshold: usize) {
+		self.mac_ip_threshold = mac_ip_threshold;
+	}
+
+	pub fn mac_ip_threshold(&self) -> usize {
+		self.mac_ip_threshold
+	}
+
+	pub fn len(&self) -> usize {
+		self.bindings.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.bindings.is_empty()
+	}
+
+	pub fn get(&self, ip: &Ipv4Addr) -> Option<&ArpBinding> {
+		self.bindings.get(ip)
+	}
+
+	pub fn bindings(&self) -> impl Iterator<Item = &ArpBinding> {
+		self.bindings.values()
+	}
+
+	/// Events, oldest first
+	pub fn events(&self) -> impl Iterator<Item = &ArpEvent> {
+		self.events.iter()
+	}
+
+	/// Learns the sender binding of an ARP packet, returning any events it
+	/// raised
+	pub fn observe(
+		&mut self,
+		iface: &str,
+		sender_ip: Ipv4Addr,
+		sender_mac: &[u8],
+		target_ip: Ipv4Addr,
+		ts: Duration,
+	) -> Vec<ArpEvent> {
+		// Probes from hosts without an address yet carry no binding
+		if sender_ip.is_unspecified() {
+			return vec![];
+		}
+
+		let mac = format_mac(sender_mac);
+		let mut kinds = vec![];
+
+		if sender_ip == target_ip {
+			kinds.push(ArpEventKind::Gratuitous);
+		}
+
+		match self.bindings.get_mut(&sender_ip) {
+			Some(binding) => {
+				if binding.mac != mac {
+					kinds.push(ArpEventKind::MacChanged {
+						previous_mac: binding.mac.clone(),
+					});
+					binding.mac = mac.clone();
+					binding.first_seen = ts;
+					binding.packets = 0;
+				}
+				binding.iface = iface.to_string();
+				binding.last_seen = ts;
+				binding.packets += 1;
+			},
+			None => {
+				self.bindings.insert(
+					sender_ip,
+					ArpBinding {
+						ip: sender_ip,
+						mac: mac.clone(),
+						iface: iface.to_string(),
+						first_seen: ts,
+						last_seen: ts,
+						packets: 1,
+					},
+				);
+			},
+		}
+
+		// Only a new or changed binding can add to the MAC's claims, and the
+		// event is raised once, when the threshold is crossed
+		if self.bindings[&sender_ip].packets == 1 {
+			let count = self.ips_claimed_by(&mac).len();
+			if count == self.mac_ip_threshold + 1 {
+				kinds.push(ArpEventKind::ManyIps { count });
+			}
+		}
+
+		let events: Vec<ArpEvent> = kinds
+			.into_iter()
+			.map(|kind| ArpEvent {
+				kind,
+				ip: sender_ip,
+				mac: mac.clone(),
+				iface: iface.to_string(),
+				ts,
+			})
+			.collect();
+
+		for event in &events {
+			if self.events.len() == self.event_limit {
+				self.events.pop_front();
+			}
+			self.events.push_back(event.clone());
+		}
+
+		events
+	}
+
+	fn ips_claimed_by(&self, mac: &str) -> HashSet<Ipv4Addr> {
+		self
+			.bindings
+			.values()
+			.filter(|b| b.mac == mac)
+			.map(|b| b.ip)
+			.collect()
+	}
+}
+
+fn format_mac(mac: &[u8]) -> String {
+	mac
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect::<Vec<String>>()
+		.join(":")
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::Ipv4Addr, time::Duration};
+
+	use crate::state::arp::{ArpEventKind, ArpTable};
+
+	#[test]
+	fn test_spoofing_events() {
+		let mut table = ArpTable::new(2, 16);
+		let (router, host) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 9));
+		let (good, evil) = ([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 66]);
+		let ts = Duration::from_secs(1);
+
+		assert!(table.observe("eth0", router, &good, host, ts).is_empty());
+		assert!(table.observe("eth0", router, &good, host, ts).is_empty());
+		assert_eq!(2, table.get(&router).unwrap().packets);
+
+		let events = table.observe("eth0", router, &evil, router, ts);
+		let kinds: Vec<ArpEventKind> = events.into_iter().map(|e| e.kind).collect();
+		assert_eq!(
+			vec![
+				ArpEventKind::Gratuitous,
+				ArpEventKind::MacChanged {
+					previous_mac: "02:00:00:00:00:01".to_string()
+				}
+			],
+			kinds
+		);
+		assert_eq!("02:00:00:00:00:42", table.get(&router).unwrap().mac);
+
+		table.observe("eth0", Ipv4Addr::new(10, 0, 0, 2), &evil, host, ts);
+		let events = table.observe("eth0", Ipv4Addr::new(10, 0, 0, 3), &evil, host, ts);
+		assert_eq!(ArpEventKind::ManyIps { count: 3 }, events[0].kind);
+		assert!(
+			table
+				.observe("eth0", Ipv4Addr::new(10, 0, 0, 3), &evil, host, ts)
+				.is_empty()
+		);
+
+		assert_eq!(3, table.events().count());
+	}
+}
@@ -0,0 +1,132 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{protocols::dns::Transaction, state::serialize_timestamp};
+
+/// How many transactions are retained for the API
+pub const DEFAULT_RECENT_LIMIT: usize = 1024;
+
+/// How many distinct names are counted; queries for further names are left
+/// out of the top names, though not out of the totals
+pub const DEFAULT_NAME_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DnsEntry {
+	#[serde(serialize_with = "serialize_timestamp")]
+	pub ts: Duration,
+	pub iface: String,
+	#[serde(flatten)]
+	pub transaction: Transaction,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct NameCount {
+	pub name: String,
+	pub queries: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DnsStats {
+	pub transactions: u64,
+	pub answered: u64,
+	pub nxdomain: u64,
+	/// The share of answered queries which were NXDOMAIN
+	pub nxdomain_rate: f64,
+	pub top_names: Vec<NameCount>,
+}
+
+/// DnsLog keeps the most recent DNS transactions, and statistics over every
+/// transaction seen
+pub struct DnsLog {
+	recent: VecDeque<DnsEntry>,
+	recent_limit: usize,
+	names: HashMap<String, u64>,
+	name_limit: usize,
+	transactions: u64,
+	answered: u64,
+	nxdomain: u64,
+}
+
+pub type SharedDnsLog = Arc<Mutex<DnsLog>>;
+
+impl Default for DnsLog {
+	fn default() -> Self {
+		DnsLog::new(DEFAULT_RECENT_LIMIT, DEFAULT_NAME_LIMIT)
+	}
+}
+
+impl DnsLog {
+	pub fn new(recent_limit: usize, name_limit: usize) -> DnsLog {
+		DnsLog {
+			recent: VecDeque::new(),
+			recent_limit,
+			names: HashMap::new(),
+			name_limit,
+			transactions: 0,
+			answered: 0,
+			nxdomain: 0,
+		}
+	}
+
+	pub fn push(&mut self, iface: &str, ts: Duration, transaction: Transaction) {
+		self.transactions += 1;
+		match transaction.rcode.as_deref() {
+			Some("NXDOMAIN") => {
+				self.answered += 1;
+				self.nxdomain += 1;
+			},
+			Some(_) => self.answered += 1,
+			None => {},
+		}
+
+		// Names are case-insensitive
+		let name = transaction.name.to_ascii_lowercase();
+		if self.names.len() < self.name_limit || self.names.contains_key(&name) {
+			*self.names.entry(name).or_default() += 1;
+		}
+
+		self.recent.push_back(DnsEntry {
+			ts,
+			iface: iface.to_string(),
+			transaction,
+		});
+		while self.recent.len() > self.recent_limit {
+			self.recent.pop_front();
+		}
+	}
+
+	/// Transactions, oldest first
+	pub fn recent(&self) -> impl DoubleEndedIterator<Item = &DnsEntry> {
+		self.recent.iter()
+	}
+
+	/// The totals, and the `top` most queried names
+	pub fn stats(&self, top: usize) -> DnsStats {
+		let mut names: Vec<NameCount> = self
+			.names
+			.iter()
+			.map(|(name, queries)| NameCount {
+				name: name.clone(),
+				queries: *queries,
+			})
+			.collect();
+		names.sort_by(|a, b| b.queries.cmp(&a.queries).then(a.name.cmp(&b.name)));
+		names.truncate(top);
+
+		DnsStats {
+			transactions: self.transactions,
+			answered: self.answered,
+			nxdomain: self.nxdomain,
+			nxdomain_rate: match self.answered {
+				0 => 0.0,
+				n => self.nxdomain as f64 / n as f64,
+			},
+			top_names: names,
+		}
+	}
+}
@@ -0,0 +1,343 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+	flow::{
+		Endpoint, FlowKey, Protocol,
+		tcp::{Flags, Half, TcpFlow, TcpState},
+	},
+	protocols::{quic::Initial, tls::Handshake},
+};
+
+/// How many closed flows are retained for the API
+pub const DEFAULT_CLOSED_LIMIT: usize = 1024;
+
+/// Counts are the packets, and payload bytes, sent in one direction
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Counts {
+	pub packets: u64,
+	pub bytes: u64,
+}
+
+/// TcpHalf is what TCP tracking adds to the counts of one direction
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpHalf {
+	pub flags: Flags,
+	pub retransmissions: u64,
+	pub out_of_order: u64,
+}
+
+/// TcpDetail is the part of a TCP connection's tracking which is served by
+/// the API, copied from the flow table as each packet is tracked
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpDetail {
+	pub to_server: TcpHalf,
+	pub to_client: TcpHalf,
+	pub rtt: Option<Duration>,
+	pub midstream: bool,
+}
+
+/// FlowRecord is the view of a flow which is shared between listeners
+#[derive(Clone, Debug)]
+pub struct FlowRecord {
+	/// Unique for the life of the process, unlike the key which a later
+	/// connection may reuse
+	pub id: u64,
+	pub protocol: Protocol,
+	pub key: FlowKey,
+	pub iface: String,
+	pub first_seen: Duration,
+	pub last_seen: Duration,
+	pub packets: u64,
+	pub bytes: u64,
+	/// The endpoint which sent the first packet, or which opened the TCP
+	/// connection
+	pub client: Endpoint,
+	pub to_server: Counts,
+	pub to_client: Counts,
+	/// Only set for TCP flows
+	pub state: Option<TcpState>,
+	pub tcp: Option<TcpDetail>,
+	pub icmp_errors: u64,
+	pub last_icmp_error: Option<String>,
+	/// The TLS handshake, once one has been seen on the connection
+	pub tls: Option<Box<Handshake>>,
+	/// What the client's Initial packets showed of a QUIC connection, on
+	/// every path it was seen on
+	pub quic: Option<Box<Initial>>,
+}
+
+impl FlowRecord {
+	/// The endpoint which is not the client
+	pub fn server(&self) -> Endpoint {
+		match self.key.endpoints() {
+			(a, b) if a == self.client => b,
+			(a, _) => a,
+		}
+	}
+
+	/// Takes the connection tracking of a TCP flow, which knows better than
+	/// the first packet which side is the client
+	pub fn update_tcp(&mut self, flow: &TcpFlow) {
+		self.state = Some(flow.state);
+		self.client = flow.client;
+
+		let tcp = self.tcp.get_or_insert_default();
+		tcp.rtt = flow.rtt;
+		tcp.midstream = flow.midstream;
+		for (counts, tcp_half, half) in [
+			(&mut self.to_server, &mut tcp.to_server, &flow.client_half),
+			(&mut self.to_client, &mut tcp.to_client, &flow.server_half),
+		] {
+			counts.packets = half.packets;
+			counts.bytes = half.bytes;
+			*tcp_half = TcpHalf::from(half);
+		}
+	}
+}
+
+impl From<&Half> for TcpHalf {
+	fn from(half: &Half) -> Self {
+		TcpHalf {
+			flags: half.flags,
+			retransmissions: half.retransmissions,
+			out_of_order: half.out_of_order,
+		}
+	}
+}
+
+/// FlowRegistry holds every TCP and UDP flow currently being tracked, so that
+/// listeners other than the one which owns a flow can refer to it; ICMP errors
+/// for instance are attributed to the flow whose datagram they quote.  The
+/// most recently closed flows are kept as well, for the API.
+pub struct FlowRegistry {
+	flows: HashMap<(Protocol, FlowKey), FlowRecord>,
+	closed: VecDeque<FlowRecord>,
+	closed_limit: usize,
+	last_id: u64,
+}
+
+impl Default for FlowRegistry {
+	fn default() -> Self {
+		FlowRegistry::with_closed_limit(DEFAULT_CLOSED_LIMIT)
+	}
+}
+
+pub type SharedFlows = Arc<Mutex<FlowRegistry>>;
+
+impl FlowRegistry {
+	pub fn new() -> FlowRegistry {
+		FlowRegistry::default()
+	}
+
+	pub fn with_closed_limit(closed_limit: usize) -> FlowRegistry {
+		FlowRegistry {
+			flows: HashMap::new(),
+			closed: VecDeque::new(),
+			closed_limit,
+			last_id: 0,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.flows.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.flows.is_empty()
+	}
+
+	pub fn get(&self, protocol: Protocol, key: &FlowKey) -> Option<&FlowRecord> {
+		self.flows.get(&(protocol, *key))
+	}
+
+	pub fn get_mut(&mut self, protocol: Protocol, key: &FlowKey) -> Option<&mut FlowRecord> {
+		self.flows.get_mut(&(protocol, *key))
+	}
+
+	/// A live or recently closed flow
+	pub fn find(&self, id: u64) -> Option<&FlowRecord> {
+		self
+			.flows
+			.values()
+			.chain(self.closed.iter())
+			.find(|r| r.id == id)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &FlowRecord> {
+		self.flows.values()
+	}
+
+	/// The most recently closed flows, oldest first
+	pub fn closed(&self) -> impl Iterator<Item = &FlowRecord> {
+		self.closed.iter()
+	}
+
+	/// Accounts for a packet of `bytes` payload sent from `src` to `dst`,
+	/// creating the flow if it is new
+	pub fn record(
+		&mut self,
+		protocol: Protocol,
+		src: Endpoint,
+		dst: Endpoint,
+		iface: &str,
+		ts: Duration,
+		bytes: usize,
+	) -> &mut FlowRecord {
+		let key = FlowKey::new(src, dst);
+		let record = self.flows.entry((protocol, key)).or_insert_with(|| {
+			self.last_id += 1;
+			FlowRecord {
+				id: self.last_id,
+				protocol,
+				key,
+				iface: iface.to_string(),
+				first_seen: ts,
+				last_seen: ts,
+				packets: 0,
+				bytes: 0,
+				client: src,
+				to_server: Counts::default(),
+				to_client: Counts::default(),
+				state: None,
+				tcp: None,
+				icmp_errors: 0,
+				last_icmp_error: None,
+				tls: None,
+				quic: None,
+			}
+		});
+		record.last_seen = ts;
+		record.packets += 1;
+		record.bytes += bytes as u64;
+		let counts = match src == record.client {
+			true => &mut record.to_server,
+			false => &mut record.to_client,
+		};
+		counts.packets += 1;
+		counts.bytes += bytes as u64;
+		record
+	}
+
+	/// Removes a flow which has ended, keeping it among the closed flows
+	pub fn remove(&mut self, protocol: Protocol, key: &FlowKey) -> Option<FlowRecord> {
+		let record = self.flows.remove(&(protocol, *key))?;
+		self.close(record.clone());
+		Some(record)
+	}
+
+	fn close(&mut self, record: FlowRecord) {
+		if self.closed_limit == 0 {
+			return;
+		}
+		if self.closed.len() == self.closed_limit {
+			self.closed.pop_front();
+		}
+		self.closed.push_back(record);
+	}
+
+	/// Removes and returns flows of `protocol` selected by `owned` which have
+	/// been idle for longer than `idle_timeout`
+	pub fn expire(
+		&mut self,
+		protocol: Protocol,
+		owned: impl Fn(&FlowKey) -> bool,
+		now: Duration,
+		idle_timeout: Duration,
+	) -> Vec<FlowRecord> {
+		let expired: Vec<(Protocol, FlowKey)> = self
+			.flows
+			.iter()
+			.filter(|((p, key), record)| {
+				*p == protocol && owned(key) && now.saturating_sub(record.last_seen) >= idle_timeout
+			})
+			.map(|(k, _)| *k)
+			.collect();
+
+		expired
+			.into_iter()
+			.filter_map(|(p, key)| self.remove(p, &key))
+			.collect()
+	}
+
+	/// Attributes an ICMP error to a flow, returning the flow if it is known
+	pub fn attribute_icmp_error(
+		&mut self,
+		protocol: Protocol,
+		key: &FlowKey,
+		error: String,
+	) -> Option<&FlowRecord> {
+		let record = self.flows.get_mut(&(protocol, *key))?;
+		record.icmp_errors += 1;
+		record.last_icmp_error = Some(error);
+		Some(record)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::IpAddr, time::Duration};
+
+	use crate::{
+		flow::{Endpoint, FlowKey, Protocol},
+		state::flows::FlowRegistry,
+	};
+
+	#[test]
+	fn test_record_attribute_and_expire() {
+		let key = FlowKey::new(
+			Endpoint::new(IpAddr::from([10, 0, 0, 1]), 40000),
+			Endpoint::new(IpAddr::from([10, 0, 0, 9]), 53),
+		);
+		let mut registry = FlowRegistry::new();
+
+		let (a, b) = key.endpoints();
+		registry.record(Protocol::Udp, a, b, "eth0", Duration::from_secs(1), 30);
+		registry.record(Protocol::Udp, b, a, "eth0", Duration::from_secs(2), 70);
+		assert!(
+			registry
+				.attribute_icmp_error(Protocol::Tcp, &key, "unreachable".to_string())
+				.is_none()
+		);
+
+		let record = registry
+			.attribute_icmp_error(Protocol::Udp, &key, "unreachable".to_string())
+			.unwrap();
+		assert_eq!(
+			(1, 2, 100, 1),
+			(record.id, record.packets, record.bytes, record.icmp_errors)
+		);
+		assert_eq!((a, 70), (record.client, record.to_client.bytes));
+		assert!(registry.find(1).is_some());
+
+		assert!(
+			registry
+				.expire(
+					Protocol::Udp,
+					|_| true,
+					Duration::from_secs(5),
+					Duration::from_secs(10)
+				)
+				.is_empty()
+		);
+		assert_eq!(
+			1,
+			registry
+				.expire(
+					Protocol::Udp,
+					|_| true,
+					Duration::from_secs(12),
+					Duration::from_secs(10)
+				)
+				.len()
+		);
+		assert!(registry.is_empty());
+		assert_eq!(1, registry.closed().count());
+		assert!(registry.find(1).is_some());
+	}
+}
@@ -4,11 +4,13 @@ use std::{
 	sync::Mutex,
 };
 
-use crate::state::packet_count::PacketCount;
+use crate::{devices::Precision, state::packet_count::PacketCount};
 
 pub struct Interface {
 	name: String,
 	counts: Mutex<PacketCount>,
+	filter: Mutex<Option<String>>,
+	precision: Precision,
 	watching: bool,
 }
 
@@ -17,14 +19,49 @@ impl Interface {
 		Interface {
 			name,
 			counts: Default::default(),
+			filter: Mutex::new(None),
+			precision: Precision::default(),
 			watching: true,
 		}
 	}
 
+	pub fn with_filter(self, filter: Option<String>) -> Interface {
+		self.set_filter(filter);
+		self
+	}
+
+	/// Records the precision of the capture's timestamps
+	pub fn with_precision(mut self, precision: Precision) -> Interface {
+		self.precision = precision;
+		self
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn filter(&self) -> Option<String> {
+		self.filter.lock().unwrap().clone()
+	}
+
+	/// Records the filter a running capture has switched to
+	pub fn set_filter(&self, filter: Option<String>) {
+		*self.filter.lock().unwrap() = filter;
+	}
+
+	pub fn precision(&self) -> Precision {
+		self.precision
+	}
+
 	pub fn count(&self) -> u32 {
 		self.counts.lock().unwrap().total
 	}
 
+	/// The received and dropped counts last reported by the capture
+	pub fn counts(&self) -> PacketCount {
+		self.counts.lock().unwrap().clone()
+	}
+
 	pub fn update_counts(&self, total: u32, os_dropped: u32, if_dropped: u32) {
 		let mut counts = self.counts.lock().unwrap();
 		counts.total = total;
@@ -44,6 +81,8 @@ impl Clone for Interface {
 		Self {
 			name: self.name.clone(),
 			counts: Mutex::new(self.counts.lock().unwrap().clone()),
+			filter: Mutex::new(self.filter()),
+			precision: self.precision,
 			watching: self.watching,
 		}
 	}
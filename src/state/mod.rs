@@ -1,3 +1,16 @@
 pub mod appstate;
+pub mod arp;
+pub mod dns;
+pub mod flows;
 pub mod interface;
 pub mod packet_count;
+pub mod ring;
+
+use std::time::Duration;
+
+use serde::Serializer;
+
+/// Serializes a capture timestamp as fractional seconds since the epoch
+pub fn serialize_timestamp<S: Serializer>(ts: &Duration, s: S) -> Result<S::Ok, S::Error> {
+	s.serialize_f64(ts.as_secs_f64())
+}
@@ -1,6 +1,43 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 #[derive(Clone, Default, Eq, PartialEq)]
 pub struct PacketCount {
 	pub total: u32,
 	pub os_dropped: u32,
 	pub if_dropped: u32,
 }
+
+/// MatcherCount is the traffic classified as one Matcher.  It is updated on
+/// the capture thread for every packet, so the counters are atomics rather than
+/// sitting behind a lock.
+#[derive(Debug, Default)]
+pub struct MatcherCount {
+	packets: AtomicU64,
+	bytes: AtomicU64,
+	dropped: AtomicU64,
+}
+
+impl MatcherCount {
+	/// Accounts for a packet of `bytes` on the wire
+	pub fn record(&self, bytes: u64) {
+		self.packets.fetch_add(1, Ordering::Relaxed);
+		self.bytes.fetch_add(bytes, Ordering::Relaxed);
+	}
+
+	/// Accounts for a packet which never reached its listener
+	pub fn record_drop(&self) {
+		self.dropped.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn packets(&self) -> u64 {
+		self.packets.load(Ordering::Relaxed)
+	}
+
+	pub fn bytes(&self) -> u64 {
+		self.bytes.load(Ordering::Relaxed)
+	}
+
+	pub fn dropped(&self) -> u64 {
+		self.dropped.load(Ordering::Relaxed)
+	}
+}
@@ -0,0 +1,166 @@
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use crate::packet::Frame;
+
+/// Limits bound how much a ring holds.  A ring without either limit holds
+/// nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+	pub bytes: Option<u64>,
+	pub age: Option<Duration>,
+}
+
+/// PacketRing keeps the most recent frames in memory, so that what led up to
+/// an event can be dumped after the fact.  Frames share their bytes with the
+/// listeners, and go back to the capture's buffer pool once evicted.
+#[derive(Default)]
+pub struct PacketRing {
+	frames: VecDeque<Frame>,
+	bytes: u64,
+	limits: Limits,
+}
+
+pub type SharedRing = Arc<Mutex<PacketRing>>;
+
+impl PacketRing {
+	pub fn new(limits: Limits) -> PacketRing {
+		PacketRing {
+			frames: VecDeque::new(),
+			bytes: 0,
+			limits,
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.limits.bytes.is_some() || self.limits.age.is_some()
+	}
+
+	pub fn len(&self) -> usize {
+		self.frames.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.frames.is_empty()
+	}
+
+	/// The captured bytes held, not counting bookkeeping
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+
+	/// Adds a frame, evicting the oldest frames which no longer fit.  Pooled
+	/// buffers keep the capacity of the largest packet they have held, so the
+	/// frame is copied to make the bytes counted the bytes used.
+	pub fn push(&mut self, frame: Frame) {
+		if !self.is_enabled() {
+			return;
+		}
+		let frame = frame.detach();
+
+		let ts = frame.timestamp();
+		self.bytes += frame.data().len() as u64;
+		self.frames.push_back(frame);
+
+		while let Some(oldest) = self.frames.front() {
+			let too_big = self.limits.bytes.is_some_and(|b| self.bytes > b);
+			let too_old = self
+				.limits
+				.age
+				.is_some_and(|a| ts.saturating_sub(oldest.timestamp()) > a);
+			if !too_big && !too_old {
+				break;
+			}
+
+			self.bytes -= oldest.data().len() as u64;
+			self.frames.pop_front();
+		}
+	}
+
+	/// The frames captured within `age` of the newest, or every frame held,
+	/// oldest first.  Time is taken from the capture, so a replay is dumped
+	/// the same way as a live capture.
+	pub fn recent(&self, age: Option<Duration>) -> impl Iterator<Item = &Frame> {
+		let since = match (age, self.frames.back()) {
+			(Some(age), Some(newest)) => newest.timestamp().saturating_sub(age),
+			_ => Duration::ZERO,
+		};
+		let start = self.frames.partition_point(|f| f.timestamp() < since);
+
+		self.frames.range(start..)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::Arc, time::Duration};
+
+	use pcap::PacketHeader;
+
+	use crate::{
+		packet::{BufferPool, Frame},
+		state::{
+			interface::Interface,
+			ring::{Limits, PacketRing},
+		},
+	};
+
+	#[test]
+	fn test_ring_limits() {
+		let mut pool = BufferPool::new(1);
+		let iface = Arc::new(Interface::new("eth0".to_string()));
+		let frame = |pool: &mut BufferPool, secs: i64, len: usize| {
+			let header = PacketHeader {
+				ts: libc::timeval {
+					tv_sec: secs,
+					tv_usec: 0,
+				},
+				caplen: len as u32,
+				len: len as u32,
+			};
+			Frame::new(
+				iface.clone(),
+				header,
+				pool.copy_from(&vec![0; len]),
+				Default::default(),
+			)
+		};
+
+		// Without limits nothing is held
+		let mut ring = PacketRing::default();
+		ring.push(frame(&mut pool, 0, 10));
+		assert!(ring.is_empty());
+
+		let mut ring = PacketRing::new(Limits {
+			bytes: Some(100),
+			age: Some(Duration::from_secs(10)),
+		});
+		for secs in 0..5 {
+			ring.push(frame(&mut pool, secs, 30));
+		}
+		// Only three frames fit in 100 bytes
+		assert_eq!(3, ring.len());
+		assert_eq!(90, ring.bytes());
+
+		let secs: Vec<u64> = ring
+			.recent(Some(Duration::from_secs(1)))
+			.map(|f| f.timestamp().as_secs())
+			.collect();
+		assert_eq!(vec![3, 4], secs);
+
+		// A frame from much later ages out everything before it
+		ring.push(frame(&mut pool, 60, 10));
+		assert_eq!(1, ring.len());
+		assert_eq!(1, ring.recent(None).count());
+
+		// The ring holds copies, so buffers go back to the capture's pool
+		let mut pool = BufferPool::new(1);
+		let pooled = frame(&mut pool, 61, 10);
+		let bytes = pooled.data().as_ptr();
+		ring.push(pooled);
+		assert_eq!(bytes, pool.copy_from(&[0; 10]).as_ptr());
+	}
+}
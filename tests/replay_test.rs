@@ -0,0 +1,140 @@
+use std::{fs, path::PathBuf, process, thread};
+
+use etherparse::PacketBuilder;
+use psniff_rs::{
+	devices::{self, Matcher, Pacing, ReceivedPacketData},
+	queue::{self, Policy},
+	runtime::BlockingRunnableBuilder,
+	state::appstate,
+};
+use tokio::sync::broadcast;
+
+fn tcp_frame(payload: &[u8]) -> Vec<u8> {
+	let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+		.ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64)
+		.tcp(40000, 80, 1000, 4096);
+
+	let mut frame = Vec::with_capacity(builder.size(payload.len()));
+	builder.write(&mut frame, payload).unwrap();
+	frame
+}
+
+fn udp_frame(payload: &[u8]) -> Vec<u8> {
+	let builder = PacketBuilder::ethernet2([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+		.ipv4([10, 0, 0, 1], [10, 0, 0, 9], 64)
+		.udp(40000, 53);
+
+	let mut frame = Vec::with_capacity(builder.size(payload.len()));
+	builder.write(&mut frame, payload).unwrap();
+	frame
+}
+
+/// Writes a classic (microsecond) pcap file with an Ethernet link type
+fn write_pcap(name: &str, frames: &[(u32, u32, Vec<u8>)]) -> PathBuf {
+	let mut out = vec![];
+	out.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+	out.extend_from_slice(&2u16.to_le_bytes());
+	out.extend_from_slice(&4u16.to_le_bytes());
+	out.extend_from_slice(&0i32.to_le_bytes());
+	out.extend_from_slice(&0u32.to_le_bytes());
+	out.extend_from_slice(&65535u32.to_le_bytes());
+	out.extend_from_slice(&1u32.to_le_bytes());
+
+	for (sec, usec, frame) in frames {
+		out.extend_from_slice(&sec.to_le_bytes());
+		out.extend_from_slice(&usec.to_le_bytes());
+		out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+		out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+		out.extend_from_slice(frame);
+	}
+
+	let path = std::env::temp_dir().join(format!("psniff-{}-{}.pcap", name, process::id()));
+	fs::write(&path, out).unwrap();
+	path
+}
+
+#[test]
+fn test_replay_dispatches_by_matcher() {
+	let path = write_pcap(
+		"dispatch",
+		&[
+			(1, 0, tcp_frame(b"hello")),
+			(1, 10, udp_frame(b"world")),
+			(1, 20, tcp_frame(b"again")),
+		],
+	);
+
+	let app_state = appstate::new();
+	let (tcp_sender, mut tcp_receiver) = queue::channel::<ReceivedPacketData>(16, Policy::Block);
+	let (udp_sender, mut udp_receiver) = queue::channel::<ReceivedPacketData>(16, Policy::Block);
+
+	let builder = devices::Builder::new()
+		.with_file(path.clone(), Pacing::Fast)
+		.with_state(app_state.clone())
+		.set_typed_sender(Matcher::IPv4_TCP, tcp_sender)
+		.set_typed_sender(Matcher::IPv4_UDP, udp_sender);
+
+	let d = Box::new(builder).build().unwrap();
+	let (cancel_tx, cancel_rx) = broadcast::channel::<()>(1);
+	let handle = thread::spawn(move || d.run(cancel_rx).is_ok());
+
+	let mut tcp = vec![];
+	while let Some(ReceivedPacketData::MovingPacket(frame)) = tcp_receiver.blocking_recv() {
+		assert_eq!(path.display().to_string(), frame.iface().name());
+		assert_eq!(Some(Matcher::IPv4_TCP), frame.matcher());
+		tcp.push(frame.data().to_vec());
+	}
+	let mut udp = vec![];
+	while let Some(ReceivedPacketData::MovingPacket(frame)) = udp_receiver.blocking_recv() {
+		udp.push(frame.data().to_vec());
+	}
+
+	cancel_tx.send(()).unwrap();
+	assert!(handle.join().unwrap());
+	fs::remove_file(path).unwrap();
+
+	assert_eq!(vec![tcp_frame(b"hello"), tcp_frame(b"again")], tcp);
+	assert_eq!(vec![udp_frame(b"world")], udp);
+
+	let total = app_state
+		.interfaces
+		.lock()
+		.unwrap()
+		.iter()
+		.fold(0, |acc, item| acc + item.count());
+	assert_eq!(3, total);
+}
+
+#[test]
+fn test_replay_missing_file() {
+	let builder = devices::Builder::new()
+		.with_file(PathBuf::from("/nonexistent/psniff.pcap"), Pacing::Fast)
+		.with_state(appstate::new());
+
+	assert!(Box::new(builder).build().is_err());
+}
+
+#[test]
+fn test_replay_invalid_filter() {
+	let path = write_pcap("filter", &[(1, 0, tcp_frame(b"hello"))]);
+
+	let builder = devices::Builder::new()
+		.with_file(path.clone(), Pacing::Fast)
+		.with_filter("tcp and (((".to_string())
+		.with_state(appstate::new());
+
+	let err = Box::new(builder).build().err().unwrap();
+	fs::remove_file(path).unwrap();
+
+	assert!(
+		err
+			.to_string()
+			.starts_with("invalid capture filter 'tcp and ((('")
+	);
+}
+
+#[test]
+fn test_validate_filter() {
+	assert!(devices::validate_filter("tcp port 80 or udp").is_ok());
+	assert!(devices::validate_filter("tcp and (((").is_err());
+}